@@ -0,0 +1,131 @@
+//! Runs shell commands against an image, for scripts and Makefiles:
+//!
+//!     fat12 [-r] <image> <command> [args]...
+//!     fat12 [-r] [image] -f <script> [-k]
+//!     fat12 help [command]
+//!
+//! Changes are saved back to the image when the command or script succeeds.
+//! The image is locked meanwhile, or with `-r` only read, refusing commands that would change it.
+//! Errors go to stderr and exit with status 1, usage mistakes with status 2.
+
+use fat12_image_driver::commands::{find_command, help, run_command};
+use fat12_image_driver::fat_error::{FatError, Result};
+use fat12_image_driver::scripts::run_script;
+use fat12_image_driver::shell_images::discarded_message;
+use fat12_image_driver::shell_state::{ShellState, FIRST_DRIVE};
+use std::fs::File;
+use std::path::Path;
+use std::process::exit;
+
+/// Size of the image `format` creates when it doesn't exist yet (a 1.44 MB floppy)
+///
+/// `build` sizes the image from its manifest.
+const NEW_IMAGE_SIZE: u64 = 1_474_560;
+
+const USAGE: &str = "usage: fat12 [-r] <image> <command> [args]...
+       fat12 [-r] [image] -f <script> [-k]
+       fat12 help [command]";
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("help") {
+        match help(args.get(1).map(String::as_str)) {
+            Ok(text) => println!("{}", text),
+            Err(error) => usage_error(Some(error.to_string())),
+        }
+        return;
+    }
+    let read_only = args.first().map(String::as_str) == Some("-r");
+    let args = if read_only { &args[1..] } else { &args[..] };
+    if args.len() < 2 {
+        usage_error(None);
+    }
+
+    // A -f further along belongs to the command, like `fat12 a.img close -f`
+    let result = if args[0] == "-f" {
+        run_script_file(None, &args[1..], read_only)
+    } else if args[1] == "-f" {
+        run_script_file(Some(&args[0]), &args[2..], read_only)
+    } else {
+        run_single_command(&args[0], &args[1..], read_only)
+    };
+
+    if let Err(error) = result {
+        eprintln!("fat12: {}", error);
+        exit(1);
+    }
+}
+
+fn usage_error(message: Option<String>) -> ! {
+    if let Some(message) = message {
+        eprintln!("fat12: {}", message);
+    }
+    eprintln!("{}", USAGE);
+    exit(2);
+}
+
+fn run_single_command(image_filename: &str, args: &[String], read_only: bool) -> Result<()> {
+    let command_args: Vec<&str> = args.iter().map(String::as_str).collect();
+    if find_command(command_args[0]).is_none() {
+        usage_error(Some(format!("unknown command {}", command_args[0])));
+    }
+
+    let creates_image = command_args[0] == "format" || command_args[0] == "build";
+    let created = creates_image && !Path::new(image_filename).exists();
+    if created {
+        File::create(image_filename)
+            .and_then(|file| file.set_len(NEW_IMAGE_SIZE))
+            .map_err(FatError::io(format!("Can't create {}", image_filename)))?;
+    }
+
+    let result = ShellState::new()
+        .load_file(image_filename.to_owned(), read_only)
+        .and_then(|shell_state| {
+            let new_state = run_command(&shell_state, command_args)?;
+            if new_state.is_modified() {
+                new_state.save_file()?;
+            }
+            Ok(())
+        });
+
+    // Don't leave an empty image behind when there was nothing there before
+    if result.is_err() && created {
+        let _ = std::fs::remove_file(image_filename);
+    }
+    result
+}
+
+/// Without an image the script is expected to `open` (and `save`) one itself
+fn run_script_file(image_filename: Option<&str>, args: &[String], read_only: bool) -> Result<()> {
+    let keep_going = args.iter().any(|arg| arg == "-k");
+    let script_filename = args
+        .iter()
+        .find(|arg| *arg != "-k")
+        .unwrap_or_else(|| usage_error(Some("no script given after -f".to_owned())));
+
+    let shell_state = match image_filename {
+        Some(image_filename) => ShellState::new().load_file(image_filename.to_owned(), read_only)?,
+        None => ShellState::new(),
+    };
+
+    let result = run_script(shell_state, script_filename, keep_going);
+    // The image given on the command line is on the first drive, whichever the script ended on
+    let shell_state = result.shell_state.use_drive(FIRST_DRIVE);
+    let mut discarded = shell_state.modified_images();
+    // A script that stopped part way leaves the image half changed, so that isn't saved either
+    if image_filename.is_some() && shell_state.is_modified() && (result.failures == 0 || keep_going) {
+        discarded.retain(|filename| filename != shell_state.get_image_filename());
+        shell_state.save_file()?;
+    }
+    for filename in discarded {
+        eprintln!("fat12: {}", discarded_message(&filename));
+    }
+
+    if result.failures > 0 {
+        return Err(FatError::CheckFailed(format!(
+            "{} failed on {} of {} lines",
+            script_filename, result.failures, result.lines_run
+        )));
+    }
+    Ok(())
+}
@@ -1,22 +1,310 @@
-pub const OEM: &str = "My OS   ";
-pub const BYTES_PER_SECTOR: usize = 512;
-pub const SECTORS_PER_CLUSTER: usize = 1;
-pub const RESERVED_SECTORS: usize = 1;
-pub const NUMBER_FATS: usize = 2;
-pub const ROOT_ENTRIES: usize = 224;
-pub const TOTAL_SECTORS: usize = 2880;
-pub const MEDIA: usize = 0xf8;
-pub const SECTORS_PER_FAT: usize = 9;
-pub const SECTORS_PER_TRACK: usize = 18;
-pub const HEADS_PER_CYLINDER: usize = 2;
-pub const HIDDEN_SECTORS: usize = 0;
-pub const TOTAL_SECTORS_BIG: usize = 0;
-pub const DRIVE_NUMBER: usize = 0;
-pub const UNUSED: usize = 0;
-pub const EXT_BOOT_SIGNATURE: usize = 0x29;
-pub const SERIAL_NUMBER: usize = 0xa0a1a2a3;
-pub const VOLUME_LABEL: &str = "MOS FLOPPY ";
-pub const FILE_SYSTEM: &str = "FAT12   ";
-pub const BITS_PER_FAT_ENTRY: usize = 12;
-pub const BYTES_PER_ENTRY: f64 = 3.0 / 2.0;
-pub const BYTES_PER_DIRECTORY_ENTRY: usize = 32;
+use alloc::borrow::ToOwned;
+use alloc::string::String;
+use alloc::vec::Vec;
+use alloc::{format, vec};
+use core::convert::TryInto;
+
+pub const OEM: &str = "My OS   ";
+pub const BYTES_PER_SECTOR: usize = 512;
+pub const SECTORS_PER_CLUSTER: usize = 1;
+pub const RESERVED_SECTORS: usize = 1;
+pub const NUMBER_FATS: usize = 2;
+pub const ROOT_ENTRIES: usize = 224;
+pub const TOTAL_SECTORS: usize = 2880;
+pub const MEDIA: usize = 0xf8;
+pub const SECTORS_PER_FAT: usize = 9;
+pub const SECTORS_PER_TRACK: usize = 18;
+pub const HEADS_PER_CYLINDER: usize = 2;
+pub const HIDDEN_SECTORS: usize = 0;
+pub const TOTAL_SECTORS_BIG: usize = 0;
+pub const DRIVE_NUMBER: usize = 0;
+pub const UNUSED: usize = 0;
+pub const EXT_BOOT_SIGNATURE: usize = 0x29;
+pub const SERIAL_NUMBER: usize = 0xa0a1a2a3;
+pub const VOLUME_LABEL: &str = "MOS FLOPPY ";
+pub const FILE_SYSTEM: &str = "FAT12   ";
+pub const BITS_PER_FAT_ENTRY: usize = 12;
+pub const BYTES_PER_ENTRY: f64 = 3.0 / 2.0;
+pub const BYTES_PER_DIRECTORY_ENTRY: usize = 32;
+
+// Byte offsets of the BPB fields inside the boot sector
+pub const OEM_OFFSET: usize = 0x03;
+pub const BYTES_PER_SECTOR_OFFSET: usize = 0x0B;
+pub const SECTORS_PER_CLUSTER_OFFSET: usize = 0x0D;
+pub const RESERVED_SECTORS_OFFSET: usize = 0x0E;
+pub const NUMBER_FATS_OFFSET: usize = 0x10;
+pub const ROOT_ENTRIES_OFFSET: usize = 0x11;
+pub const SECTORS_PER_FAT_OFFSET: usize = 0x16;
+pub const SECTORS_PER_TRACK_OFFSET: usize = 0x18;
+pub const HEADS_PER_CYLINDER_OFFSET: usize = 0x1A;
+pub const DRIVE_NUMBER_OFFSET: usize = 0x24;
+/// First byte after the extended BPB, where boot code can start
+pub const BPB_END: usize = 0x3E;
+
+/// The BPB and extended BPB as read from (or written to) a boot sector
+///
+/// The layout helpers all work in sectors; multiply by `bytes_per_sector` for offsets.
+#[derive(Clone)]
+pub struct BiosParameterBlock {
+    pub oem: [u8; 8],
+    pub bytes_per_sector: usize,
+    pub sectors_per_cluster: usize,
+    pub reserved_sectors: usize,
+    pub number_fats: usize,
+    pub root_entries: usize,
+    pub total_sectors: usize,
+    pub media: u8,
+    pub sectors_per_fat: usize,
+    pub sectors_per_track: usize,
+    pub heads_per_cylinder: usize,
+    pub hidden_sectors: usize,
+    pub total_sectors_big: usize,
+    pub drive_number: u8,
+    pub ext_boot_signature: u8,
+    pub serial_number: u32,
+    pub volume_label: [u8; 11],
+    pub file_system: [u8; 8],
+}
+
+impl Default for BiosParameterBlock {
+    /// The BPB described by the constants above
+    fn default() -> Self {
+        BiosParameterBlock {
+            oem: padded(OEM),
+            bytes_per_sector: BYTES_PER_SECTOR,
+            sectors_per_cluster: SECTORS_PER_CLUSTER,
+            reserved_sectors: RESERVED_SECTORS,
+            number_fats: NUMBER_FATS,
+            root_entries: ROOT_ENTRIES,
+            total_sectors: TOTAL_SECTORS,
+            media: MEDIA as u8,
+            sectors_per_fat: SECTORS_PER_FAT,
+            sectors_per_track: SECTORS_PER_TRACK,
+            heads_per_cylinder: HEADS_PER_CYLINDER,
+            hidden_sectors: HIDDEN_SECTORS,
+            total_sectors_big: TOTAL_SECTORS_BIG,
+            drive_number: DRIVE_NUMBER as u8,
+            ext_boot_signature: EXT_BOOT_SIGNATURE as u8,
+            serial_number: SERIAL_NUMBER as u32,
+            volume_label: padded(VOLUME_LABEL),
+            file_system: padded(FILE_SYSTEM),
+        }
+    }
+}
+
+impl BiosParameterBlock {
+    /// A BPB laid out for a disk of `total_sectors`, using the standard floppy formats where they fit
+    pub fn for_sectors(total_sectors: usize) -> Self {
+        let mut bpb = BiosParameterBlock::default();
+        // (sectors, sectors per cluster, root entries, sectors per FAT, sectors per track, media)
+        let floppy = [
+            (720, 2, 112, 2, 9, 0xfd),
+            (1440, 2, 112, 3, 9, 0xf9),
+            (2400, 1, 224, 7, 15, 0xf9),
+            (2880, 1, 224, 9, 18, 0xf0),
+            (5760, 2, 240, 9, 36, 0xf0),
+        ]
+        .iter()
+        .find(|format| format.0 == total_sectors);
+
+        if let Some(&(_, sectors_per_cluster, root_entries, sectors_per_fat, sectors_per_track, media)) = floppy {
+            bpb.sectors_per_cluster = sectors_per_cluster;
+            bpb.root_entries = root_entries;
+            bpb.sectors_per_fat = sectors_per_fat;
+            bpb.sectors_per_track = sectors_per_track;
+            bpb.media = media;
+        }
+
+        if total_sectors > 0xFFFF {
+            bpb.total_sectors = 0;
+            bpb.total_sectors_big = total_sectors;
+        } else {
+            bpb.total_sectors = total_sectors;
+        }
+
+        if floppy.is_none() {
+            // Grow the clusters until the disk fits in a 12 bit FAT
+            loop {
+                bpb.fit_fat();
+                if bpb.cluster_count() < 4085 || bpb.sectors_per_cluster == 128 {
+                    break;
+                }
+                bpb.sectors_per_cluster *= 2;
+            }
+        }
+        bpb
+    }
+
+    /// Sizes the FATs for the rest of the layout
+    pub fn fit_fat(&mut self) {
+        // Size the FAT as if every sector after the root directory held data
+        let data_sectors = self.sectors().saturating_sub(self.reserved_sectors + self.root_sectors());
+        let fat_bytes = (data_sectors / self.sectors_per_cluster + 2) * BITS_PER_FAT_ENTRY / 8 + 1;
+        self.sectors_per_fat = fat_bytes.div_ceil(self.bytes_per_sector);
+    }
+
+    /// Parses the BPB of a boot sector, or returns None if it doesn't hold a usable one
+    pub fn parse(boot_sector: &[u8]) -> Option<Self> {
+        if boot_sector.len() < BPB_END {
+            return None;
+        }
+        let byte = |offset: usize| boot_sector[offset];
+        let word = |offset: usize| boot_sector[offset] as usize | (boot_sector[offset + 1] as usize) << 8;
+        let dword = |offset: usize| word(offset) | word(offset + 2) << 16;
+
+        let bpb = BiosParameterBlock {
+            oem: boot_sector[OEM_OFFSET..OEM_OFFSET + 8].try_into().unwrap(),
+            bytes_per_sector: word(0x0B),
+            sectors_per_cluster: byte(0x0D) as usize,
+            reserved_sectors: word(0x0E),
+            number_fats: byte(0x10) as usize,
+            root_entries: word(0x11),
+            total_sectors: word(0x13),
+            media: byte(0x15),
+            sectors_per_fat: word(0x16),
+            sectors_per_track: word(0x18),
+            heads_per_cylinder: word(0x1A),
+            hidden_sectors: dword(0x1C),
+            total_sectors_big: dword(0x20),
+            drive_number: byte(0x24),
+            ext_boot_signature: byte(0x26),
+            serial_number: dword(0x27) as u32,
+            volume_label: boot_sector[0x2B..0x36].try_into().unwrap(),
+            file_system: boot_sector[0x36..0x3E].try_into().unwrap(),
+        };
+
+        let valid = bpb.bytes_per_sector.is_power_of_two()
+            && bpb.bytes_per_sector >= 128
+            && bpb.sectors_per_cluster.is_power_of_two()
+            && bpb.reserved_sectors >= 1
+            && bpb.number_fats >= 1
+            && bpb.sectors_per_fat >= 1;
+        if valid {
+            Some(bpb)
+        } else {
+            None
+        }
+    }
+
+    /// The image's BPB, or the default one for images that haven't been given a boot sector yet
+    pub fn from_image(bytes: &[u8]) -> Self {
+        Self::parse(bytes).unwrap_or_default()
+    }
+
+    /// The BPB and extended BPB (boot sector bytes 3..62)
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bpb = vec![];
+        bpb.extend_from_slice(&self.oem);
+        bpb.extend_from_slice(&(self.bytes_per_sector as u16).to_le_bytes());
+        bpb.push(self.sectors_per_cluster as u8);
+        bpb.extend_from_slice(&(self.reserved_sectors as u16).to_le_bytes());
+        bpb.push(self.number_fats as u8);
+        bpb.extend_from_slice(&(self.root_entries as u16).to_le_bytes());
+        bpb.extend_from_slice(&(self.total_sectors as u16).to_le_bytes());
+        bpb.push(self.media);
+        bpb.extend_from_slice(&(self.sectors_per_fat as u16).to_le_bytes());
+        bpb.extend_from_slice(&(self.sectors_per_track as u16).to_le_bytes());
+        bpb.extend_from_slice(&(self.heads_per_cylinder as u16).to_le_bytes());
+        bpb.extend_from_slice(&(self.hidden_sectors as u32).to_le_bytes());
+        bpb.extend_from_slice(&(self.total_sectors_big as u32).to_le_bytes());
+        bpb.push(self.drive_number);
+        bpb.push(UNUSED as u8);
+        bpb.push(self.ext_boot_signature);
+        bpb.extend_from_slice(&self.serial_number.to_le_bytes());
+        bpb.extend_from_slice(&self.volume_label);
+        bpb.extend_from_slice(&self.file_system);
+
+        assert_eq!(bpb.len(), BPB_END - OEM_OFFSET);
+        bpb
+    }
+
+    /// Writes the BPB into the boot sector of an image
+    pub fn write_to(&self, bytes: &mut [u8]) {
+        bytes[OEM_OFFSET..BPB_END].copy_from_slice(&self.to_bytes());
+    }
+
+    pub fn sectors(&self) -> usize {
+        if self.total_sectors != 0 {
+            self.total_sectors
+        } else {
+            self.total_sectors_big
+        }
+    }
+
+    pub fn bytes_per_cluster(&self) -> usize {
+        self.bytes_per_sector * self.sectors_per_cluster
+    }
+
+    /// First sector of FAT number `fat_index`
+    pub fn fat_start(&self, fat_index: usize) -> usize {
+        self.reserved_sectors + fat_index * self.sectors_per_fat
+    }
+
+    pub fn root_start(&self) -> usize {
+        self.fat_start(self.number_fats)
+    }
+
+    pub fn root_sectors(&self) -> usize {
+        (self.root_entries * BYTES_PER_DIRECTORY_ENTRY).div_ceil(self.bytes_per_sector)
+    }
+
+    pub fn data_start(&self) -> usize {
+        self.root_start() + self.root_sectors()
+    }
+
+    /// Number of data clusters, which decides the FAT type
+    pub fn cluster_count(&self) -> usize {
+        self.sectors().saturating_sub(self.data_start()) / self.sectors_per_cluster
+    }
+
+    pub fn fat_type(&self) -> &'static str {
+        match self.cluster_count() {
+            0..=4084 => "FAT12",
+            4085..=65524 => "FAT16",
+            _ => "FAT32",
+        }
+    }
+
+    /// Number of FAT entries, including the two reserved ones, that can address a data cluster
+    pub fn fat_entries(&self) -> usize {
+        let entries_in_fat = self.sectors_per_fat * self.bytes_per_sector * 8 / BITS_PER_FAT_ENTRY;
+        entries_in_fat.min(self.cluster_count() + 2)
+    }
+
+    /// First sector of a data cluster, which are numbered from 2
+    pub fn cluster_start(&self, cluster: usize) -> usize {
+        self.data_start() + (cluster - 2) * self.sectors_per_cluster
+    }
+
+    /// Names the region of the image a sector belongs to
+    pub fn sector_region(&self, lba: usize) -> String {
+        if lba == 0 {
+            "boot sector".to_owned()
+        } else if lba < self.fat_start(0) {
+            format!("reserved sector {}", lba)
+        } else if lba < self.root_start() {
+            let fat_sector = lba - self.fat_start(0);
+            format!(
+                "FAT{} sector {}",
+                fat_sector / self.sectors_per_fat + 1,
+                fat_sector % self.sectors_per_fat
+            )
+        } else if lba < self.data_start() {
+            format!("root directory sector {}", lba - self.root_start())
+        } else {
+            format!(
+                "data cluster {}",
+                (lba - self.data_start()) / self.sectors_per_cluster + 2
+            )
+        }
+    }
+}
+
+/// Space pads a string constant into a fixed size field
+fn padded<const N: usize>(text: &str) -> [u8; N] {
+    let mut field = [b' '; N];
+    for (i, byte) in text.bytes().take(N).enumerate() {
+        field[i] = byte;
+    }
+    field
+}
@@ -0,0 +1,287 @@
+//! Where the file system's sectors live.
+//!
+//! The volume layer only reads and writes whole sectors through `BlockDevice`, so the same code
+//! works on an image held in memory, an image file too big to load, or one partition of a disk.
+
+use crate::bios_parameter_block::BYTES_PER_SECTOR;
+use crate::fat_error::{FatError, Result};
+use alloc::borrow::ToOwned;
+use alloc::format;
+use alloc::vec::Vec;
+use core::ops::Range;
+#[cfg(feature = "std")]
+use std::fs::{File, OpenOptions};
+#[cfg(feature = "std")]
+use std::io::{Read, Seek, SeekFrom, Write};
+#[cfg(feature = "std")]
+use std::path::Path;
+
+/// What a volume did with a range of bytes, see `BlockDevice::observe`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// Storage made of fixed size sectors, numbered from 0
+pub trait BlockDevice {
+    fn sector_size(&self) -> usize;
+
+    fn sector_count(&self) -> usize;
+
+    /// Fills `buffer`, whose length is a whole number of sectors, starting at `first_sector`
+    fn read_sectors(&mut self, first_sector: usize, buffer: &mut [u8]) -> Result<()>;
+
+    /// Writes `data`, whose length is a whole number of sectors, starting at `first_sector`
+    fn write_sectors(&mut self, first_sector: usize, data: &[u8]) -> Result<()>;
+
+    /// Makes sure everything written has reached the storage underneath
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Told the byte range of each read and write a volume makes, before it reads or writes the
+    /// sectors holding it, for devices that keep track of what's used, like the shell's for `trace`
+    fn observe(&mut self, _access: Access, _offset: usize, _length: usize) {}
+}
+
+impl<D: BlockDevice + ?Sized> BlockDevice for &mut D {
+    fn sector_size(&self) -> usize {
+        (**self).sector_size()
+    }
+
+    fn sector_count(&self) -> usize {
+        (**self).sector_count()
+    }
+
+    fn read_sectors(&mut self, first_sector: usize, buffer: &mut [u8]) -> Result<()> {
+        (**self).read_sectors(first_sector, buffer)
+    }
+
+    fn write_sectors(&mut self, first_sector: usize, data: &[u8]) -> Result<()> {
+        (**self).write_sectors(first_sector, data)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        (**self).flush()
+    }
+
+    fn observe(&mut self, access: Access, offset: usize, length: usize) {
+        (**self).observe(access, offset, length)
+    }
+}
+
+/// Byte range of `length` bytes of sectors starting at `first_sector`, checked against the device
+pub(crate) fn sector_range<D: BlockDevice + ?Sized>(device: &D, first_sector: usize, length: usize) -> Result<Range<usize>> {
+    let sector_size = device.sector_size();
+    if !length.is_multiple_of(sector_size) {
+        return Err(FatError::Internal(format!(
+            "{} bytes isn't a whole number of {} byte sectors",
+            length, sector_size
+        )));
+    }
+    let sectors = length / sector_size;
+    if first_sector + sectors > device.sector_count() {
+        return Err(FatError::PastEndOfDevice {
+            sector: first_sector + sectors - 1,
+            sectors: device.sector_count(),
+        });
+    }
+    Ok(first_sector * sector_size..(first_sector + sectors) * sector_size)
+}
+
+/// An image held in memory, in 512 byte sectors
+impl BlockDevice for [u8] {
+    fn sector_size(&self) -> usize {
+        BYTES_PER_SECTOR
+    }
+
+    fn sector_count(&self) -> usize {
+        self.len() / BYTES_PER_SECTOR
+    }
+
+    fn read_sectors(&mut self, first_sector: usize, buffer: &mut [u8]) -> Result<()> {
+        let range = sector_range(self, first_sector, buffer.len())?;
+        buffer.copy_from_slice(&self[range]);
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, first_sector: usize, data: &[u8]) -> Result<()> {
+        let range = sector_range(self, first_sector, data.len())?;
+        self[range].copy_from_slice(data);
+        Ok(())
+    }
+}
+
+impl BlockDevice for Vec<u8> {
+    fn sector_size(&self) -> usize {
+        self.as_slice().sector_size()
+    }
+
+    fn sector_count(&self) -> usize {
+        self.as_slice().sector_count()
+    }
+
+    fn read_sectors(&mut self, first_sector: usize, buffer: &mut [u8]) -> Result<()> {
+        self.as_mut_slice().read_sectors(first_sector, buffer)
+    }
+
+    fn write_sectors(&mut self, first_sector: usize, data: &[u8]) -> Result<()> {
+        self.as_mut_slice().write_sectors(first_sector, data)
+    }
+}
+
+/// An image that's only being looked at, which refuses writes
+impl BlockDevice for &[u8] {
+    fn sector_size(&self) -> usize {
+        BYTES_PER_SECTOR
+    }
+
+    fn sector_count(&self) -> usize {
+        self.len() / BYTES_PER_SECTOR
+    }
+
+    fn read_sectors(&mut self, first_sector: usize, buffer: &mut [u8]) -> Result<()> {
+        let range = sector_range(self, first_sector, buffer.len())?;
+        buffer.copy_from_slice(&self[range]);
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, _first_sector: usize, _data: &[u8]) -> Result<()> {
+        Err(FatError::ReadOnly("The image".to_owned()))
+    }
+}
+
+/// An image file on the host, read and written a few sectors at a time rather than loaded whole
+#[cfg(feature = "std")]
+pub struct FileDevice {
+    file: File,
+    sector_size: usize,
+    sector_count: usize,
+}
+
+#[cfg(feature = "std")]
+impl FileDevice {
+    /// Opens an image file, for writing as well as reading if `writable`
+    pub fn open(path: impl AsRef<Path>, writable: bool) -> Result<Self> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(writable)
+            .open(path)
+            .map_err(FatError::io(format!("Can't open {}", path.display())))?;
+        Self::from_file(file, BYTES_PER_SECTOR)
+    }
+
+    /// Any bytes after the last whole sector are left alone
+    pub fn from_file(file: File, sector_size: usize) -> Result<Self> {
+        let length = file
+            .metadata()
+            .map_err(FatError::io("Can't read the image's size"))?
+            .len();
+        Ok(FileDevice {
+            file,
+            sector_size,
+            sector_count: (length / sector_size as u64) as usize,
+        })
+    }
+
+    pub fn into_inner(self) -> File {
+        self.file
+    }
+
+    fn seek_to(&mut self, first_sector: usize) -> Result<()> {
+        let offset = (first_sector * self.sector_size) as u64;
+        self.file
+            .seek(SeekFrom::Start(offset))
+            .map_err(FatError::io(format!("Can't seek to sector {}", first_sector)))?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+impl BlockDevice for FileDevice {
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn sector_count(&self) -> usize {
+        self.sector_count
+    }
+
+    fn read_sectors(&mut self, first_sector: usize, buffer: &mut [u8]) -> Result<()> {
+        sector_range(self, first_sector, buffer.len())?;
+        self.seek_to(first_sector)?;
+        self.file
+            .read_exact(buffer)
+            .map_err(FatError::io(format!("Can't read sector {}", first_sector)))
+    }
+
+    fn write_sectors(&mut self, first_sector: usize, data: &[u8]) -> Result<()> {
+        sector_range(self, first_sector, data.len())?;
+        self.seek_to(first_sector)?;
+        self.file
+            .write_all(data)
+            .map_err(FatError::io(format!("Can't write sector {}", first_sector)))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.file.sync_data().map_err(FatError::io("Can't flush the image"))
+    }
+}
+
+/// A run of sectors of another device, like a partition, numbered from 0
+pub struct SubDevice<D: BlockDevice> {
+    device: D,
+    first_sector: usize,
+    sector_count: usize,
+}
+
+impl<D: BlockDevice> SubDevice<D> {
+    pub fn new(device: D, first_sector: usize, sector_count: usize) -> Result<Self> {
+        if first_sector + sector_count > device.sector_count() {
+            return Err(FatError::PastEndOfDevice {
+                sector: first_sector + sector_count - 1,
+                sectors: device.sector_count(),
+            });
+        }
+        Ok(SubDevice {
+            device,
+            first_sector,
+            sector_count,
+        })
+    }
+
+    pub fn into_inner(self) -> D {
+        self.device
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for SubDevice<D> {
+    fn sector_size(&self) -> usize {
+        self.device.sector_size()
+    }
+
+    fn sector_count(&self) -> usize {
+        self.sector_count
+    }
+
+    fn read_sectors(&mut self, first_sector: usize, buffer: &mut [u8]) -> Result<()> {
+        sector_range(self, first_sector, buffer.len())?;
+        self.device.read_sectors(self.first_sector + first_sector, buffer)
+    }
+
+    fn write_sectors(&mut self, first_sector: usize, data: &[u8]) -> Result<()> {
+        sector_range(self, first_sector, data.len())?;
+        self.device.write_sectors(self.first_sector + first_sector, data)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.device.flush()
+    }
+
+    fn observe(&mut self, access: Access, offset: usize, length: usize) {
+        let start = self.first_sector * self.device.sector_size();
+        self.device.observe(access, start + offset, length)
+    }
+}
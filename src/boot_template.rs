@@ -0,0 +1,361 @@
+use crate::bios_parameter_block::{
+    BPB_END, BYTES_PER_SECTOR, BYTES_PER_SECTOR_OFFSET, DRIVE_NUMBER_OFFSET,
+    HEADS_PER_CYLINDER_OFFSET, NUMBER_FATS_OFFSET, OEM_OFFSET, RESERVED_SECTORS_OFFSET,
+    ROOT_ENTRIES_OFFSET, SECTORS_PER_CLUSTER_OFFSET, SECTORS_PER_FAT_OFFSET,
+    SECTORS_PER_TRACK_OFFSET,
+};
+use crate::root_dir_util::to_short_name;
+use std::collections::HashMap;
+
+/// The BIOS loads the boot sector at 0000:7C00
+pub const BOOT_ORIGIN: u16 = 0x7C00;
+
+/// Scratch buffer for the root directory and the FAT, right after the boot sector
+const BUFFER: u16 = 0x7E00;
+
+const DISK_ERROR_MESSAGE: &str = "Disk error\r\n";
+const NOT_FOUND_MESSAGE: &str = "Non-system disk\r\nInsert system disk and press any key\r\n";
+
+/// Parameters for the generated boot sector
+///
+/// The generated code reads the BPB at runtime, looks for `filename` in the root
+/// directory, loads its cluster chain to `load_segment:load_offset` and far jumps to
+/// `jump_segment:jump_offset` with the boot drive in DL.
+pub struct BootTemplate {
+    filename: [u8; 11],
+    load_segment: u16,
+    load_offset: u16,
+    jump_segment: u16,
+    jump_offset: u16,
+}
+
+impl BootTemplate {
+    pub fn new(filename: &str) -> Self {
+        BootTemplate {
+            filename: to_short_name(filename),
+            load_segment: 0x1000,
+            load_offset: 0x0000,
+            jump_segment: 0x1000,
+            jump_offset: 0x0000,
+        }
+    }
+
+    pub fn set_load_address(mut self, segment: u16, offset: u16) -> Self {
+        self.load_segment = segment;
+        self.load_offset = offset;
+        self
+    }
+
+    pub fn set_jump_address(mut self, segment: u16, offset: u16) -> Self {
+        self.jump_segment = segment;
+        self.jump_offset = offset;
+        self
+    }
+
+    /// Generates a 512 byte boot sector around the given BPB (boot sector bytes 3..62)
+    pub fn generate(&self, bpb: &[u8]) -> Vec<u8> {
+        let mut asm = Assembler::new(BOOT_ORIGIN);
+
+        // Jump over the BPB
+        asm.emit_rel8(&[0xEB], "start"); // jmp short start
+        asm.emit(&[0x90]); // nop
+        asm.emit(bpb);
+        assert_eq!(asm.len(), BPB_END);
+
+        asm.label("start");
+        asm.emit(&[0xFA]); // cli
+        asm.emit(&[0x31, 0xC0]); // xor ax, ax
+        asm.emit(&[0x8E, 0xD8]); // mov ds, ax
+        asm.emit(&[0x8E, 0xC0]); // mov es, ax
+        asm.emit(&[0x8E, 0xD0]); // mov ss, ax
+        asm.emit_imm16(&[0xBC], BOOT_ORIGIN); // mov sp, 0x7C00
+        asm.emit(&[0xFB]); // sti
+        asm.emit(&[0xFC]); // cld
+        asm.emit_bpb16(&[0x88, 0x16], DRIVE_NUMBER_OFFSET); // mov [drive], dl
+
+        // root_lba = reserved + fats * sectors_per_fat
+        asm.emit_bpb16(&[0xA0], NUMBER_FATS_OFFSET); // mov al, [fats]
+        asm.emit(&[0x98]); // cbw
+        asm.emit_bpb16(&[0xF7, 0x26], SECTORS_PER_FAT_OFFSET); // mul word [sectors_per_fat]
+        asm.emit_bpb16(&[0x03, 0x06], RESERVED_SECTORS_OFFSET); // add ax, [reserved]
+        asm.emit_abs16(&[0xA3], "root_lba"); // mov [root_lba], ax
+
+        // root_sectors = (root_entries * 32 + bytes_per_sector - 1) / bytes_per_sector
+        asm.emit_imm16(&[0xB8], 32); // mov ax, 32
+        asm.emit_bpb16(&[0xF7, 0x26], ROOT_ENTRIES_OFFSET); // mul word [root_entries]
+        asm.emit_bpb16(&[0x03, 0x06], BYTES_PER_SECTOR_OFFSET); // add ax, [bytes_per_sector]
+        asm.emit(&[0x48]); // dec ax
+        asm.emit(&[0x31, 0xD2]); // xor dx, dx
+        asm.emit_bpb16(&[0xF7, 0x36], BYTES_PER_SECTOR_OFFSET); // div word [bytes_per_sector]
+        asm.emit(&[0x89, 0xC1]); // mov cx, ax
+
+        // data_lba = root_lba + root_sectors
+        asm.emit_abs16(&[0x03, 0x06], "root_lba"); // add ax, [root_lba]
+        asm.emit_abs16(&[0xA3], "data_lba"); // mov [data_lba], ax
+
+        // Read the root directory into the buffer
+        asm.emit_abs16(&[0xA1], "root_lba"); // mov ax, [root_lba]
+        asm.emit_imm16(&[0xBB], BUFFER); // mov bx, BUFFER
+        asm.emit_rel16(&[0xE8], "read_sectors"); // call read_sectors
+        asm.emit(&[0x31, 0xC0]); // xor ax, ax
+        asm.emit(&[0x8E, 0xC0]); // mov es, ax
+
+        // Compare every root entry against the filename
+        asm.emit_bpb16(&[0x8B, 0x0E], ROOT_ENTRIES_OFFSET); // mov cx, [root_entries]
+        asm.emit_imm16(&[0xBF], BUFFER); // mov di, BUFFER
+        asm.label("find");
+        asm.emit(&[0x51]); // push cx
+        asm.emit(&[0x57]); // push di
+        asm.emit_abs16(&[0xBE], "filename"); // mov si, filename
+        asm.emit_imm16(&[0xB9], 11); // mov cx, 11
+        asm.emit(&[0xF3, 0xA6]); // repe cmpsb
+        asm.emit(&[0x5F]); // pop di
+        asm.emit(&[0x59]); // pop cx
+        asm.emit_rel8(&[0x74], "found"); // je found
+        asm.emit(&[0x83, 0xC7, 0x20]); // add di, 32
+        asm.emit_rel8(&[0xE2], "find"); // loop find
+        asm.emit_rel16(&[0xE9], "not_found"); // jmp not_found
+
+        asm.label("found");
+        asm.emit(&[0x8B, 0x45, 0x1A]); // mov ax, [di+26]
+        asm.emit_imm16(&[0x3D], 2); // cmp ax, 2
+        asm.emit_rel8(&[0x72], "not_found"); // jb not_found
+        asm.emit_abs16(&[0xA3], "cluster"); // mov [cluster], ax
+
+        // Read the first FAT into the buffer
+        asm.emit_bpb16(&[0xA1], RESERVED_SECTORS_OFFSET); // mov ax, [reserved]
+        asm.emit_bpb16(&[0x8B, 0x0E], SECTORS_PER_FAT_OFFSET); // mov cx, [sectors_per_fat]
+        asm.emit_imm16(&[0xBB], BUFFER); // mov bx, BUFFER
+        asm.emit_rel16(&[0xE8], "read_sectors"); // call read_sectors
+
+        // Load the cluster chain, read_sectors advances ES after every sector
+        asm.emit_imm16(&[0xB8], self.load_segment); // mov ax, load_segment
+        asm.emit(&[0x8E, 0xC0]); // mov es, ax
+        asm.emit_imm16(&[0xBB], self.load_offset); // mov bx, load_offset
+        asm.label("load_cluster");
+        asm.emit_abs16(&[0xA1], "cluster"); // mov ax, [cluster]
+        asm.emit(&[0x83, 0xE8, 0x02]); // sub ax, 2
+        asm.emit(&[0x31, 0xC9]); // xor cx, cx
+        asm.emit_bpb16(&[0x8A, 0x0E], SECTORS_PER_CLUSTER_OFFSET); // mov cl, [sectors_per_cluster]
+        asm.emit(&[0xF7, 0xE1]); // mul cx
+        asm.emit_abs16(&[0x03, 0x06], "data_lba"); // add ax, [data_lba]
+        asm.emit_rel16(&[0xE8], "read_sectors"); // call read_sectors
+
+        // Next cluster is the 12 bit entry at cluster * 3 / 2
+        asm.emit_abs16(&[0xA1], "cluster"); // mov ax, [cluster]
+        asm.emit(&[0x89, 0xC6]); // mov si, ax
+        asm.emit(&[0xD1, 0xEE]); // shr si, 1
+        asm.emit(&[0x01, 0xC6]); // add si, ax
+        asm.emit_imm16(&[0x8B, 0x94], BUFFER); // mov dx, [si+BUFFER]
+        asm.emit(&[0xA8, 0x01]); // test al, 1
+        asm.emit_rel8(&[0x74], "even_cluster"); // jz even_cluster
+        asm.emit(&[0xB1, 0x04]); // mov cl, 4
+        asm.emit(&[0xD3, 0xEA]); // shr dx, cl
+        asm.emit_rel8(&[0xEB], "next_cluster"); // jmp short next_cluster
+        asm.label("even_cluster");
+        asm.emit(&[0x81, 0xE2, 0xFF, 0x0F]); // and dx, 0x0FFF
+        asm.label("next_cluster");
+        asm.emit_abs16(&[0x89, 0x16], "cluster"); // mov [cluster], dx
+        asm.emit(&[0x81, 0xFA, 0xF8, 0x0F]); // cmp dx, 0x0FF8
+        asm.emit_rel8(&[0x72], "load_cluster"); // jb load_cluster
+
+        // Hand over to the loaded file with the boot drive in DL
+        asm.emit_bpb16(&[0x8A, 0x16], DRIVE_NUMBER_OFFSET); // mov dl, [drive]
+        asm.emit(&[0xEA]); // jmp far jump_segment:jump_offset
+        asm.emit(&self.jump_offset.to_le_bytes());
+        asm.emit(&self.jump_segment.to_le_bytes());
+
+        asm.label("disk_error");
+        asm.emit_abs16(&[0xBE], "disk_error_message"); // mov si, disk_error_message
+        asm.emit_rel16(&[0xE8], "print"); // call print
+        asm.label("not_found");
+        asm.emit_abs16(&[0xBE], "not_found_message"); // mov si, not_found_message
+        asm.emit_rel16(&[0xE8], "print"); // call print
+        asm.emit(&[0x31, 0xC0]); // xor ax, ax
+        asm.emit(&[0xCD, 0x16]); // int 0x16 (wait for a key)
+        asm.emit(&[0xCD, 0x19]); // int 0x19 (reboot)
+
+        // print: writes the zero terminated string at DS:SI
+        asm.label("print");
+        asm.emit(&[0xAC]); // lodsb
+        asm.emit(&[0x08, 0xC0]); // or al, al
+        asm.emit_rel8(&[0x74], "print_done"); // jz print_done
+        asm.emit(&[0xB4, 0x0E]); // mov ah, 0x0E
+        asm.emit_imm16(&[0xBB], 0x0007); // mov bx, 7
+        asm.emit(&[0xCD, 0x10]); // int 0x10
+        asm.emit_rel8(&[0xEB], "print"); // jmp short print
+        asm.label("print_done");
+        asm.emit(&[0xC3]); // ret
+
+        // read_sectors: reads CX sectors starting at LBA AX into ES:BX
+        asm.label("read_sectors");
+        asm.emit(&[0x50]); // push ax
+        asm.emit(&[0x51]); // push cx
+        asm.emit(&[0x31, 0xD2]); // xor dx, dx
+        asm.emit_bpb16(&[0xF7, 0x36], SECTORS_PER_TRACK_OFFSET); // div word [sectors_per_track]
+        asm.emit(&[0x42]); // inc dx
+        asm.emit(&[0x88, 0xD1]); // mov cl, dl
+        asm.emit(&[0x31, 0xD2]); // xor dx, dx
+        asm.emit_bpb16(&[0xF7, 0x36], HEADS_PER_CYLINDER_OFFSET); // div word [heads]
+        asm.emit(&[0x88, 0xD6]); // mov dh, dl
+        asm.emit(&[0x88, 0xC5]); // mov ch, al
+        asm.emit(&[0xD0, 0xCC]); // ror ah, 1
+        asm.emit(&[0xD0, 0xCC]); // ror ah, 1
+        asm.emit(&[0x80, 0xE4, 0xC0]); // and ah, 0xC0
+        asm.emit(&[0x08, 0xE1]); // or cl, ah
+        asm.emit_bpb16(&[0x8A, 0x16], DRIVE_NUMBER_OFFSET); // mov dl, [drive]
+        asm.emit_imm16(&[0xBF], 5); // mov di, 5
+        asm.label("retry");
+        asm.emit_imm16(&[0xB8], 0x0201); // mov ax, 0x0201
+        asm.emit(&[0xCD, 0x13]); // int 0x13
+        asm.emit_rel8(&[0x73], "read_ok"); // jnc read_ok
+        asm.emit(&[0x31, 0xC0]); // xor ax, ax
+        asm.emit(&[0xCD, 0x13]); // int 0x13 (reset the drive)
+        asm.emit(&[0x4F]); // dec di
+        asm.emit_rel8(&[0x75], "retry"); // jnz retry
+        asm.emit_rel16(&[0xE9], "disk_error"); // jmp disk_error
+        asm.label("read_ok");
+        asm.emit_bpb16(&[0xA1], BYTES_PER_SECTOR_OFFSET); // mov ax, [bytes_per_sector]
+        asm.emit(&[0xB1, 0x04]); // mov cl, 4
+        asm.emit(&[0xD3, 0xE8]); // shr ax, cl
+        asm.emit(&[0x8C, 0xC2]); // mov dx, es
+        asm.emit(&[0x01, 0xD0]); // add ax, dx
+        asm.emit(&[0x8E, 0xC0]); // mov es, ax
+        asm.emit(&[0x59]); // pop cx
+        asm.emit(&[0x58]); // pop ax
+        asm.emit(&[0x40]); // inc ax
+        asm.emit_rel8(&[0xE2], "read_sectors"); // loop read_sectors
+        asm.emit(&[0xC3]); // ret
+
+        // Variables
+        asm.label("cluster");
+        asm.emit(&[0, 0]);
+        asm.label("root_lba");
+        asm.emit(&[0, 0]);
+        asm.label("data_lba");
+        asm.emit(&[0, 0]);
+        asm.label("filename");
+        asm.emit(&self.filename);
+        asm.label("disk_error_message");
+        asm.emit(DISK_ERROR_MESSAGE.as_bytes());
+        asm.emit(&[0]);
+        asm.label("not_found_message");
+        asm.emit(NOT_FOUND_MESSAGE.as_bytes());
+        asm.emit(&[0]);
+
+        let mut sector = asm.finish();
+        assert!(
+            sector.len() <= BYTES_PER_SECTOR - 2,
+            "Boot template doesn't fit in one sector!"
+        );
+
+        // Pad and add the boot signature
+        sector.resize(BYTES_PER_SECTOR - 2, 0);
+        sector.extend_from_slice(&[0x55, 0xAA]);
+        sector
+    }
+}
+
+/// Returns the BPB of an existing boot sector, if it has the boot signature
+pub fn existing_bpb(boot_sector: &[u8]) -> Option<Vec<u8>> {
+    if boot_sector[BYTES_PER_SECTOR - 2..BYTES_PER_SECTOR] == [0x55, 0xAA] {
+        Some(boot_sector[OEM_OFFSET..BPB_END].to_vec())
+    } else {
+        None
+    }
+}
+
+enum Fixup {
+    /// 8 bit displacement from the end of the instruction
+    Relative8,
+    /// 16 bit displacement from the end of the instruction
+    Relative16,
+    /// 16 bit address of the label
+    Absolute16,
+}
+
+/// Just enough of an assembler to lay out the boot sector with labels
+struct Assembler {
+    origin: u16,
+    bytes: Vec<u8>,
+    labels: HashMap<&'static str, usize>,
+    // (position of the operand, label, kind)
+    fixups: Vec<(usize, &'static str, Fixup)>,
+}
+
+impl Assembler {
+    fn new(origin: u16) -> Self {
+        Assembler {
+            origin,
+            bytes: vec![],
+            labels: HashMap::new(),
+            fixups: vec![],
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    fn label(&mut self, name: &'static str) {
+        self.labels.insert(name, self.bytes.len());
+    }
+
+    fn emit(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    fn emit_imm16(&mut self, opcode: &[u8], value: u16) {
+        self.emit(opcode);
+        self.emit(&value.to_le_bytes());
+    }
+
+    /// Emits an instruction addressing a BPB field in the loaded boot sector
+    fn emit_bpb16(&mut self, opcode: &[u8], field_offset: usize) {
+        self.emit_imm16(opcode, self.origin + field_offset as u16);
+    }
+
+    fn emit_abs16(&mut self, opcode: &[u8], label: &'static str) {
+        self.emit(opcode);
+        self.fixups.push((self.bytes.len(), label, Fixup::Absolute16));
+        self.emit(&[0, 0]);
+    }
+
+    fn emit_rel8(&mut self, opcode: &[u8], label: &'static str) {
+        self.emit(opcode);
+        self.fixups.push((self.bytes.len(), label, Fixup::Relative8));
+        self.emit(&[0]);
+    }
+
+    fn emit_rel16(&mut self, opcode: &[u8], label: &'static str) {
+        self.emit(opcode);
+        self.fixups.push((self.bytes.len(), label, Fixup::Relative16));
+        self.emit(&[0, 0]);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        for (position, label, kind) in &self.fixups {
+            let target = self.labels[label];
+            match kind {
+                Fixup::Relative8 => {
+                    let displacement = target as isize - (*position as isize + 1);
+                    assert!(
+                        (-128..128).contains(&displacement),
+                        "Short jump to {} is out of range!",
+                        label
+                    );
+                    self.bytes[*position] = displacement as u8;
+                }
+                Fixup::Relative16 => {
+                    let displacement = (target as isize - (*position as isize + 2)) as u16;
+                    self.bytes[*position..*position + 2].copy_from_slice(&displacement.to_le_bytes());
+                }
+                Fixup::Absolute16 => {
+                    let address = self.origin + target as u16;
+                    self.bytes[*position..*position + 2].copy_from_slice(&address.to_le_bytes());
+                }
+            }
+        }
+        self.bytes
+    }
+}
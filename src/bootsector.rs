@@ -1,21 +1,97 @@
-use crate::shell_parsing::get_arg;
-use crate::shell_state::ShellState;
-
-pub fn edit_bootsector(mut shell_state: ShellState, args: Vec<&str>) -> ShellState {
-  // Get cmdline args
-  let bootloader_filename = get_arg(&args, 1).expect("No bootloader filename provided!");
-
-  // std::fs::read returns a vector of u8's
-  let boot_bytes = std::fs::read(bootloader_filename).expect("Can't read the bootloader file!");
-
-  // Make sure that the boot sector is only 512 bytes
-  println!("Boot bytes len: {}", boot_bytes.len());
-  assert_eq!(boot_bytes.len(), 512);
-
-  // Replace first 512 bytes with boot sector
-  shell_state.bytes.splice(..512, boot_bytes);
-
-  println!("Attached bootsector!");
-
-  shell_state
-}
+use crate::bios_parameter_block::{BiosParameterBlock, BPB_END, BYTES_PER_SECTOR, OEM_OFFSET};
+use crate::boot_template::{existing_bpb, BootTemplate};
+use crate::fat_error::{FatError, Result};
+use crate::shell_parsing::Args;
+use crate::shell_state::ShellState;
+
+pub fn edit_bootsector(mut shell_state: ShellState, args: &Args) -> Result<ShellState> {
+  // Get cmdline args
+  let bootloader_filename = args.expect(1, "bootloader filename")?;
+  let boot_bytes = read_boot_sector_file(&bootloader_filename)?;
+
+  // Replace first 512 bytes with boot sector
+  shell_state.volume_mut()?.write(0, &boot_bytes)?;
+
+  println!("Attached bootsector!");
+
+  Ok(shell_state)
+}
+
+/// boot <boot sector file>
+///
+/// Installs the boot code of a 512 byte file, keeping the image's BPB (bytes 3..62) so the file
+/// system stays as it is.
+pub fn install_boot_code(mut shell_state: ShellState, args: &Args) -> Result<ShellState> {
+  let boot_filename = args.expect(1, "boot sector file")?;
+  let mut boot_bytes = read_boot_sector_file(&boot_filename)?;
+
+  let mut volume = shell_state.volume_mut()?;
+  let current = volume.read(0, BYTES_PER_SECTOR)?;
+  boot_bytes[OEM_OFFSET..BPB_END].copy_from_slice(&current[OEM_OFFSET..BPB_END]);
+  volume.write(0, &boot_bytes)?;
+
+  println!("Installed the boot code of {}, keeping the BPB!", boot_filename);
+  Ok(shell_state)
+}
+
+/// Reads a boot sector from the host, which has to be exactly one sector long
+fn read_boot_sector_file(filename: &str) -> Result<Vec<u8>> {
+  // std::fs::read returns a vector of u8's
+  let boot_bytes = std::fs::read(filename).map_err(FatError::io(format!("Can't read {}", filename)))?;
+
+  // Make sure that the boot sector is only 512 bytes
+  println!("Boot bytes len: {}", boot_bytes.len());
+  if boot_bytes.len() != BYTES_PER_SECTOR {
+    return Err(FatError::InvalidBootSector(format!(
+      "{} is {} bytes, a boot sector is {}",
+      filename,
+      boot_bytes.len(),
+      BYTES_PER_SECTOR
+    )));
+  }
+  Ok(boot_bytes)
+}
+
+/// genboot KERNEL.BIN [load segment:offset] [jump segment:offset]
+pub fn generate_bootsector(mut shell_state: ShellState, args: &Args) -> Result<ShellState> {
+  let filename = args.expect(1, "filename to boot")?;
+  let (load_segment, load_offset) = match args.get(2) {
+    Some(address) => parse_segment_offset(&address)?,
+    None => (0x1000, 0x0000),
+  };
+  // Jump to wherever the file was loaded unless told otherwise
+  let (jump_segment, jump_offset) = match args.get(3) {
+    Some(address) => parse_segment_offset(&address)?,
+    None => (load_segment, load_offset),
+  };
+
+  // Keep the image's BPB if it already has a boot sector
+  let mut volume = shell_state.volume_mut()?;
+  let bpb = existing_bpb(&volume.read(0, BYTES_PER_SECTOR)?)
+    .unwrap_or_else(|| BiosParameterBlock::default().to_bytes());
+
+  let boot_bytes = BootTemplate::new(&filename)
+    .set_load_address(load_segment, load_offset)
+    .set_jump_address(jump_segment, jump_offset)
+    .generate(&bpb);
+
+  volume.write(0, &boot_bytes)?;
+
+  println!(
+    "Generated bootsector loading {} to {:04X}:{:04X}!",
+    filename, load_segment, load_offset
+  );
+
+  Ok(shell_state)
+}
+
+/// Parses a hex `segment:offset` pair like `1000:0000`
+pub fn parse_segment_offset(address: &str) -> Result<(u16, u16)> {
+  let bad_address = || FatError::BadArgument(format!("{} should look like segment:offset in hex", address));
+  let (segment, offset) = address.split_once(':').ok_or_else(bad_address)?;
+
+  Ok((
+    u16::from_str_radix(segment.trim_start_matches("0x"), 16).map_err(|_| bad_address())?,
+    u16::from_str_radix(offset.trim_start_matches("0x"), 16).map_err(|_| bad_address())?,
+  ))
+}
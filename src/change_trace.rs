@@ -0,0 +1,288 @@
+//! Shows which sectors commands touch: `trace` logs what each command reads and writes,
+//! and `dryrun` runs commands against a scratch copy, reporting what they would have changed.
+//!
+//! Reads and writes are recorded as `Volume::read` and `Volume::write`, which everything working
+//! with files goes through, tell the image about them, and by the commands that work on boot code
+//! and raw sectors. A write counts even when it puts back what was there, the sector was written
+//! all the same. Looking up the layout in the BPB isn't counted, a driver keeps that from when the
+//! disk was mounted.
+
+use crate::bios_parameter_block::{BiosParameterBlock, BYTES_PER_SECTOR};
+use crate::block_device::BlockDevice;
+use crate::fat_error::{FatError, Result};
+use crate::fat_section_util::cluster_chain;
+use crate::image_device::ImageDevice;
+use crate::read_dir::{walk, Visit, WalkOrder};
+use crate::shell_parsing::Args;
+use crate::shell_state::ShellState;
+use crate::volume::Volume;
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+use std::ops::RangeInclusive;
+
+/// The sectors a traced command read and wrote
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Accesses {
+    /// In the order they were read, with repeats
+    pub reads: Vec<usize>,
+    pub writes: BTreeSet<usize>,
+}
+
+thread_local! {
+    // What each command being traced has done, innermost last, so a command that runs others
+    // (like `source`) counts what they do as its own
+    static TRACES: RefCell<Vec<Accesses>> = const { RefCell::new(Vec::new()) };
+}
+
+/// The sectors `length` bytes at `offset` are in
+fn sectors_of(offset: usize, length: usize) -> RangeInclusive<usize> {
+    offset / BYTES_PER_SECTOR..=(offset + length - 1) / BYTES_PER_SECTOR
+}
+
+/// Notes that `length` bytes at `offset` in the image were read, if a command is being traced
+pub fn record_read(offset: usize, length: usize) {
+    if length == 0 {
+        return;
+    }
+    TRACES.with(|traces| {
+        if let Some(current) = traces.borrow_mut().last_mut() {
+            current.reads.extend(sectors_of(offset, length));
+        }
+    });
+}
+
+/// Notes that `length` bytes at `offset` in the image were written, if a command is being traced
+pub fn record_write(offset: usize, length: usize) {
+    if length == 0 {
+        return;
+    }
+    TRACES.with(|traces| {
+        if let Some(current) = traces.borrow_mut().last_mut() {
+            current.writes.extend(sectors_of(offset, length));
+        }
+    });
+}
+
+/// Starts recording the reads and writes of a command
+pub fn start_trace() {
+    TRACES.with(|traces| traces.borrow_mut().push(Accesses::default()));
+}
+
+/// Stops recording the innermost command, returning what it read and wrote
+pub fn finish_trace() -> Accesses {
+    TRACES.with(|traces| {
+        let mut traces = traces.borrow_mut();
+        let finished = traces.pop().unwrap_or_default();
+        if let Some(outer) = traces.last_mut() {
+            outer.reads.extend(&finished.reads);
+            outer.writes.extend(&finished.writes);
+        }
+        finished
+    })
+}
+
+/// Runs `f` without recording what it reads, for looking at the image to describe a trace
+fn untraced<T>(f: impl FnOnce() -> T) -> T {
+    let traces = TRACES.with(RefCell::take);
+    let result = f();
+    TRACES.with(|current| *current.borrow_mut() = traces);
+    result
+}
+
+/// Names the part of the image sectors belong to: the boot sector, a FAT, the root directory,
+/// or for data clusters the file or directory that owns them
+struct Regions {
+    bpb: BiosParameterBlock,
+    owners: HashMap<usize, String>,
+}
+
+impl Regions {
+    /// Data clusters are looked up in each image in turn, so removed files can still be named
+    fn new(images: &[&ImageDevice]) -> Self {
+        let owners = untraced(|| {
+            let mut owners = HashMap::new();
+            for &image in images.iter().filter(|image| image.sector_count() > 0) {
+                if let Ok(mut volume) = Volume::mount(image) {
+                    // fsck is the place to complain about a broken tree, here the rest just goes unnamed
+                    let _ = add_owners(&mut volume, &mut owners);
+                }
+            }
+            owners
+        });
+        let boot_sector = images.first().and_then(|image| image.boot_sector().ok());
+        Regions {
+            bpb: BiosParameterBlock::from_image(&boot_sector.unwrap_or_default()),
+            owners,
+        }
+    }
+
+    fn name(&self, sector: usize) -> String {
+        let bpb = &self.bpb;
+        if sector == 0 {
+            "boot sector".to_owned()
+        } else if sector < bpb.fat_start(0) {
+            "reserved sectors".to_owned()
+        } else if sector < bpb.root_start() {
+            format!("FAT{}", (sector - bpb.fat_start(0)) / bpb.sectors_per_fat + 1)
+        } else if sector < bpb.data_start() {
+            "root directory".to_owned()
+        } else {
+            let cluster = (sector - bpb.data_start()) / bpb.sectors_per_cluster + 2;
+            match self.owners.get(&cluster) {
+                Some(path) => path.clone(),
+                None if cluster < bpb.fat_entries() => "free clusters".to_owned(),
+                None => "past the last cluster".to_owned(),
+            }
+        }
+    }
+
+    /// The regions the sectors are in with the sectors in each, in the order the regions come on the disk
+    fn group(&self, sectors: &BTreeSet<usize>) -> Vec<(String, Vec<usize>)> {
+        let mut regions: Vec<(String, Vec<usize>)> = vec![];
+        for &sector in sectors {
+            let name = self.name(sector);
+            match regions.iter_mut().find(|(region, _)| *region == name) {
+                Some((_, region_sectors)) => region_sectors.push(sector),
+                None => regions.push((name, vec![sector])),
+            }
+        }
+        regions
+    }
+}
+
+/// Records which file or directory owns each cluster of a directory and the ones below it
+fn add_owners<D: BlockDevice>(volume: &mut Volume<D>, owners: &mut HashMap<usize, String>) -> Result<()> {
+    walk(volume, 0, "", WalkOrder::DepthFirst, |volume, found| {
+        // Broken chains just go unnamed
+        let chain = cluster_chain(volume, found.entry.first_cluster()).unwrap_or_default();
+        // Don't go into a directory whose clusters already belong to something else
+        let is_new = chain.first().is_some_and(|first| !owners.contains_key(first));
+        for &cluster in &chain {
+            owners.entry(cluster).or_insert_with(|| found.path.clone());
+        }
+        Ok(if is_new { Visit::Continue } else { Visit::SkipDirectory })
+    })
+}
+
+/// Formats sorted sector numbers as runs, e.g. `1-3, 19, 33-34`
+fn sector_ranges(sectors: &[usize]) -> String {
+    let mut ranges: Vec<(usize, usize)> = vec![];
+    for &sector in sectors {
+        match ranges.last_mut() {
+            Some((_, last)) if *last + 1 == sector => *last = sector,
+            _ => ranges.push((sector, sector)),
+        }
+    }
+    ranges
+        .iter()
+        .map(|&(first, last)| {
+            if first == last {
+                format!("{}", first)
+            } else {
+                format!("{}-{}", first, last)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Prints what a command read and wrote, for `trace`
+///
+/// Reads are listed in order, with runs of the same or neighbouring sectors in one region on one line.
+/// The images before and after the command are only looked at to name the regions.
+pub fn report_trace(command: &str, accesses: &Accesses, before: &ImageDevice, after: &ImageDevice) {
+    let regions = Regions::new(&[after, before]);
+    let (reads, written) = (&accesses.reads, &accesses.writes);
+    let distinct: BTreeSet<usize> = reads.iter().copied().collect();
+
+    // (first sector, last sector, region, number of reads)
+    let mut runs: Vec<(usize, usize, String, usize)> = vec![];
+    for &sector in reads {
+        let name = regions.name(sector);
+        match runs.last_mut() {
+            Some((first, last, region, count)) if *region == name && (*first..=*last + 1).contains(&sector) => {
+                *last = (*last).max(sector);
+                *count += 1;
+            }
+            _ => runs.push((sector, sector, name, 1)),
+        }
+    }
+
+    println!("trace: {}", command);
+    for (first, last, region, count) in &runs {
+        let sectors = sector_ranges(&(*first..=*last).collect::<Vec<_>>());
+        println!("  read   {:<12} {}, {}", sectors, region, counted(*count, "time"));
+    }
+    for (region, sectors) in regions.group(written) {
+        println!("  wrote  {:<12} {}", sector_ranges(&sectors), region);
+    }
+    println!(
+        "  {} read {}, {} written",
+        counted(distinct.len(), "sector"),
+        counted(reads.len(), "time"),
+        counted(written.len(), "sector")
+    );
+}
+
+/// Prints what a command would have changed, for `dryrun`
+pub fn report_dry_run(command: &str, before: &ShellState, after: &ShellState) -> Result<()> {
+    let (before_image, after_image) = (before.image(), after.image());
+    // Like closing it or opening another
+    if before.get_image_filename() != after.get_image_filename()
+        || before_image.sector_count() != after_image.sector_count()
+    {
+        println!("Dry run: {} would replace the whole image", command);
+        return Ok(());
+    }
+    let written = ImageDevice::changed_sectors(before_image, after_image)?;
+    if written.is_empty() {
+        println!("Dry run: {} wouldn't change the image", command);
+        return Ok(());
+    }
+
+    println!("Dry run: {} would write {}:", command, counted(written.len(), "sector"));
+    for (region, sectors) in Regions::new(&[after_image, before_image]).group(&written) {
+        println!("  {:<24} {}", region, sector_ranges(&sectors));
+    }
+    Ok(())
+}
+
+/// `1 sector`, `2 sectors`
+fn counted(count: usize, thing: &str) -> String {
+    if count == 1 {
+        format!("1 {}", thing)
+    } else {
+        format!("{} {}s", count, thing)
+    }
+}
+
+/// dryrun [on|off]
+///
+/// Until it's turned off commands run against a scratch copy of the image and are thrown away
+/// after saying which sectors they'd have written. Commands that write host files are refused.
+pub fn dry_run(shell_state: ShellState, args: &Args) -> Result<ShellState> {
+    let dry_run = parse_switch(args, shell_state.is_dry_run())?;
+    if dry_run {
+        println!("Dry run is on, commands won't change the image");
+    } else {
+        println!("Dry run is off");
+    }
+    Ok(shell_state.set_dry_run(dry_run))
+}
+
+/// trace [on|off]
+pub fn trace(shell_state: ShellState, args: &Args) -> Result<ShellState> {
+    let tracing = parse_switch(args, shell_state.is_tracing())?;
+    println!("Tracing is {}", if tracing { "on" } else { "off" });
+    Ok(shell_state.set_tracing(tracing))
+}
+
+/// `on` or `off`, flipping the setting when neither is given
+fn parse_switch(args: &Args, current: bool) -> Result<bool> {
+    match args.get(1).as_deref() {
+        None => Ok(!current),
+        Some("on") => Ok(true),
+        Some("off") => Ok(false),
+        Some(other) => Err(FatError::BadArgument(format!("Expected on or off, not {}", other))),
+    }
+}
@@ -0,0 +1,873 @@
+//! Every shell command, looked up by name for the shell, scripts and the command line.
+//!
+//! Other crates can add their own commands by implementing [`Command`] and calling [`register`]
+//! before starting the shell.
+
+use crate::bootsector::{edit_bootsector, generate_bootsector, install_boot_code};
+use crate::change_trace::{dry_run, finish_trace, report_dry_run, report_trace, start_trace, trace, Accesses};
+use crate::directories::expand_glob;
+use crate::disassembler::disasm;
+use crate::drives::{copy_file, move_file, use_drive};
+use crate::emulator::boot_test;
+use crate::fat_error::{FatError, Result};
+use crate::fsck::check_image;
+use crate::hexdump::{hexdump, peek, poke};
+use crate::host_files::{
+    change_host_directory, get_files, import_tree, list_host_directory, print_host_directory, put_files,
+};
+use crate::manifest::build_manifest;
+use crate::scripts::{record, source};
+use crate::shell_files::{change_directory, list_directory, make_directory, newfile, remove_file, save_file_to_os};
+use crate::shell_images::{cache, close_image, create_new_image, format_image, open_image, save_image};
+use crate::shell_parsing::{ArgSpec, Args, Chain, CommandLine, Flag, Word};
+use crate::shell_state::ShellState;
+use crate::undo::{redo, undo};
+use crate::volume_info::{info, label, set_bpb};
+use std::cell::Cell;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, OnceLock, RwLock};
+
+pub trait Command: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Other names the command answers to
+    fn aliases(&self) -> &[&str] {
+        &[]
+    }
+
+    /// The arguments after the name, one line per form, e.g. `<file> [host file]`
+    fn usage(&self) -> &str;
+
+    /// One line saying what the command does, for `help`
+    fn summary(&self) -> &str;
+
+    fn arg_spec(&self) -> ArgSpec {
+        ArgSpec::at_least(0)
+    }
+
+    /// Commands that create or open an image return false
+    fn needs_image(&self) -> bool {
+        true
+    }
+
+    /// Whether wildcards in its arguments are matched against the image, running it once per match
+    fn expands_globs(&self) -> bool {
+        false
+    }
+
+    /// Whether it always changes the image, so it's refused before running when the image is read only
+    ///
+    /// Commands that only change it sometimes, like `label` with a new label, fail when they go to write instead.
+    fn modifies_image(&self) -> bool {
+        false
+    }
+
+    /// Whether it writes files outside the image, which a dry run couldn't take back
+    fn writes_host_files(&self) -> bool {
+        false
+    }
+
+    fn run(&self, shell_state: ShellState, args: &Args) -> Result<ShellState>;
+}
+
+/// A command backed by one of the functions in this crate
+pub struct Builtin {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    pub usage: &'static str,
+    pub summary: &'static str,
+    pub args: ArgSpec,
+    pub needs_image: bool,
+    pub expands_globs: bool,
+    pub modifies_image: bool,
+    pub writes_host_files: bool,
+    pub function: fn(ShellState, &Args) -> Result<ShellState>,
+}
+
+impl Command for Builtin {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn aliases(&self) -> &[&str] {
+        self.aliases
+    }
+
+    fn usage(&self) -> &str {
+        self.usage
+    }
+
+    fn summary(&self) -> &str {
+        self.summary
+    }
+
+    fn arg_spec(&self) -> ArgSpec {
+        self.args
+    }
+
+    fn needs_image(&self) -> bool {
+        self.needs_image
+    }
+
+    fn expands_globs(&self) -> bool {
+        self.expands_globs
+    }
+
+    fn modifies_image(&self) -> bool {
+        self.modifies_image
+    }
+
+    fn writes_host_files(&self) -> bool {
+        self.writes_host_files
+    }
+
+    fn run(&self, shell_state: ShellState, args: &Args) -> Result<ShellState> {
+        (self.function)(shell_state, args)
+    }
+}
+
+/// For commands that drop the open image, which otherwise ask before losing changes
+const DISCARD_FLAGS: &[Flag] = &[Flag {
+    name: "-f",
+    value: None,
+    help: "discard unsaved changes to the open image",
+}];
+
+const BUILTINS: [Builtin; 40] = [
+    Builtin {
+        name: "open",
+        aliases: &[],
+        usage: "[-f] [-r] <image>",
+        summary: "Opens an image file, locking it against other sessions unless it's only read",
+        args: ArgSpec::exactly(1).with_host_paths(&[1]).with_flags(&[
+            Flag {
+                name: "-f",
+                value: None,
+                help: "discard unsaved changes to the open image",
+            },
+            Flag {
+                name: "-r",
+                value: None,
+                help: "open the image read only, refusing commands that would change it",
+            },
+        ]),
+        needs_image: false,
+        expands_globs: false,
+        modifies_image: false,
+        writes_host_files: false,
+        function: open_image,
+    },
+    Builtin {
+        name: "close",
+        aliases: &[],
+        usage: "[-f]",
+        summary: "Closes the image, asking first if it has unsaved changes",
+        args: ArgSpec::none().with_flags(DISCARD_FLAGS),
+        needs_image: false,
+        expands_globs: false,
+        modifies_image: false,
+        writes_host_files: false,
+        function: close_image,
+    },
+    Builtin {
+        name: "new",
+        aliases: &[],
+        usage: "[-f] <image> <size in MB>",
+        summary: "Creates an empty image file and opens it",
+        args: ArgSpec::exactly(2).with_host_paths(&[1]).with_flags(DISCARD_FLAGS),
+        needs_image: false,
+        expands_globs: false,
+        modifies_image: false,
+        writes_host_files: true,
+        function: create_new_image,
+    },
+    Builtin {
+        name: "save",
+        aliases: &[],
+        usage: "[-a] [-b]\n[-b] as <image>",
+        summary: "Writes the changes back to the image's file, or the image to a new one it's saved to from then on",
+        args: ArgSpec::between(0, 2).with_host_paths(&[2]).with_flags(&[
+            Flag {
+                name: "-a",
+                value: None,
+                help: "rewrite the whole file through a temporary one, so a crash can't leave it half saved",
+            },
+            Flag {
+                name: "-b",
+                value: None,
+                help: "keep the file's previous contents as <image>.bak",
+            },
+        ]),
+        needs_image: true,
+        expands_globs: false,
+        modifies_image: false,
+        writes_host_files: true,
+        function: save_image,
+    },
+    Builtin {
+        name: "cache",
+        aliases: &[],
+        usage: "[sectors] [through|back]",
+        summary: "Shows how well the image file is cached, or sets its size and how saving writes",
+        args: ArgSpec::between(0, 2),
+        needs_image: true,
+        expands_globs: false,
+        modifies_image: false,
+        writes_host_files: false,
+        function: cache,
+    },
+    Builtin {
+        name: "use",
+        aliases: &[],
+        usage: "[drive]",
+        summary: "Switches to another drive, like B:, or lists the drives in use",
+        args: ArgSpec::between(0, 1),
+        needs_image: false,
+        expands_globs: false,
+        modifies_image: false,
+        writes_host_files: false,
+        function: use_drive,
+    },
+    Builtin {
+        name: "undo",
+        aliases: &[],
+        usage: "[count]\n-l\n-depth <changes>",
+        summary: "Takes back the last changes to the image",
+        args: ArgSpec::between(0, 1).with_flags(&[
+            Flag {
+                name: "-l",
+                value: None,
+                help: "list the changes that can be undone and redone",
+            },
+            Flag {
+                name: "-depth",
+                value: Some("changes"),
+                help: "set how many changes are kept to undo",
+            },
+        ]),
+        // -l and -depth work without an image, undoing changes checks for one itself
+        needs_image: false,
+        expands_globs: false,
+        modifies_image: true,
+        writes_host_files: false,
+        function: undo,
+    },
+    Builtin {
+        name: "redo",
+        aliases: &[],
+        usage: "[count]",
+        summary: "Makes changes taken back by undo again",
+        args: ArgSpec::between(0, 1),
+        needs_image: true,
+        expands_globs: false,
+        modifies_image: true,
+        writes_host_files: false,
+        function: redo,
+    },
+    Builtin {
+        name: "dryrun",
+        aliases: &[],
+        usage: "[on|off]",
+        summary: "Runs commands against a scratch copy, showing the sectors they'd write",
+        args: ArgSpec::between(0, 1),
+        needs_image: false,
+        expands_globs: false,
+        modifies_image: false,
+        writes_host_files: false,
+        function: dry_run,
+    },
+    Builtin {
+        name: "trace",
+        aliases: &[],
+        usage: "[on|off]",
+        summary: "Logs the sectors each command reads and writes",
+        args: ArgSpec::between(0, 1),
+        needs_image: false,
+        expands_globs: false,
+        modifies_image: false,
+        writes_host_files: false,
+        function: trace,
+    },
+    Builtin {
+        name: "format",
+        aliases: &[],
+        usage: "[label]",
+        summary: "Writes an empty FAT12 file system sized to the image",
+        args: ArgSpec::between(0, 1),
+        needs_image: true,
+        expands_globs: false,
+        modifies_image: true,
+        writes_host_files: false,
+        function: format_image,
+    },
+    Builtin {
+        name: "editboot",
+        aliases: &[],
+        usage: "<boot sector file>",
+        summary: "Replaces the boot sector with a 512 byte file",
+        args: ArgSpec::exactly(1).with_host_paths(&[1]),
+        needs_image: true,
+        expands_globs: false,
+        modifies_image: true,
+        writes_host_files: false,
+        function: edit_bootsector,
+    },
+    Builtin {
+        name: "boot",
+        aliases: &[],
+        usage: "<boot sector file>",
+        summary: "Installs the boot code of a 512 byte file, keeping the image's BPB",
+        args: ArgSpec::exactly(1).with_host_paths(&[1]),
+        needs_image: true,
+        expands_globs: false,
+        modifies_image: true,
+        writes_host_files: false,
+        function: install_boot_code,
+    },
+    Builtin {
+        name: "genboot",
+        aliases: &[],
+        usage: "<file> [load segment:offset] [jump segment:offset]",
+        summary: "Generates a boot sector that loads a file from the root directory",
+        args: ArgSpec::between(1, 3),
+        needs_image: true,
+        expands_globs: false,
+        modifies_image: true,
+        writes_host_files: false,
+        function: generate_bootsector,
+    },
+    Builtin {
+        name: "disasm",
+        aliases: &[],
+        usage: "[boot]\nsectors <first sector> [count] [origin]\nfile <name> [origin]",
+        summary: "Disassembles 16 bit x86 code",
+        args: ArgSpec::between(0, 4),
+        needs_image: true,
+        expands_globs: false,
+        modifies_image: false,
+        writes_host_files: false,
+        function: disasm,
+    },
+    Builtin {
+        name: "boottest",
+        aliases: &[],
+        usage: "[until <segment:offset>] [marker <text>] [limit <instructions>] [keys <text>] [trace]",
+        summary: "Boots the image in an 8086 emulator",
+        args: ArgSpec::at_least(0),
+        needs_image: true,
+        expands_globs: false,
+        modifies_image: false,
+        writes_host_files: false,
+        function: boot_test,
+    },
+    Builtin {
+        name: "info",
+        aliases: &[],
+        usage: "",
+        summary: "Shows the BIOS parameter block and the disk layout",
+        args: ArgSpec::none(),
+        needs_image: true,
+        expands_globs: false,
+        modifies_image: false,
+        writes_host_files: false,
+        function: info,
+    },
+    Builtin {
+        name: "setbpb",
+        aliases: &[],
+        usage: "<field> <value>",
+        summary: "Sets one field of the BIOS parameter block",
+        args: ArgSpec::at_least(2),
+        needs_image: true,
+        expands_globs: false,
+        modifies_image: true,
+        writes_host_files: false,
+        function: set_bpb,
+    },
+    Builtin {
+        name: "label",
+        aliases: &[],
+        usage: "[label]",
+        summary: "Shows or sets the volume label",
+        args: ArgSpec::between(0, 1),
+        needs_image: true,
+        expands_globs: false,
+        modifies_image: false,
+        writes_host_files: false,
+        function: label,
+    },
+    Builtin {
+        name: "hexdump",
+        aliases: &[],
+        usage: "<lba> [count]\nfile <name>",
+        summary: "Dumps sectors or a file's clusters as hex",
+        args: ArgSpec::between(1, 2),
+        needs_image: true,
+        expands_globs: false,
+        modifies_image: false,
+        writes_host_files: false,
+        function: hexdump,
+    },
+    Builtin {
+        name: "peek",
+        aliases: &[],
+        usage: "<offset> [length]",
+        summary: "Dumps bytes at an offset into the image",
+        args: ArgSpec::between(1, 2),
+        needs_image: true,
+        expands_globs: false,
+        modifies_image: false,
+        writes_host_files: false,
+        function: peek,
+    },
+    Builtin {
+        name: "poke",
+        aliases: &[],
+        usage: "<offset> <hex bytes>...\nlba <lba> <hex bytes>...\nfile <host file> <lba> [count]",
+        summary: "Writes raw bytes into the image",
+        args: ArgSpec::at_least(2),
+        needs_image: true,
+        expands_globs: false,
+        modifies_image: true,
+        writes_host_files: false,
+        function: poke,
+    },
+    Builtin {
+        name: "put",
+        aliases: &["newfile"],
+        usage: "<host file> [name or directory]",
+        summary: "Copies a host file into the image",
+        args: ArgSpec::between(1, 2).with_host_paths(&[1]),
+        needs_image: true,
+        expands_globs: false,
+        modifies_image: true,
+        writes_host_files: false,
+        function: newfile,
+    },
+    Builtin {
+        name: "mput",
+        aliases: &[],
+        usage: "<host files or patterns...>",
+        summary: "Copies host files into the current directory of the image",
+        args: ArgSpec::at_least(1).with_host_paths_from(1),
+        needs_image: true,
+        expands_globs: false,
+        modifies_image: true,
+        writes_host_files: false,
+        function: put_files,
+    },
+    Builtin {
+        name: "import",
+        aliases: &[],
+        usage: "<host directory> [directory]",
+        summary: "Copies a host directory tree into the image, making directories as needed",
+        args: ArgSpec::between(1, 2).with_host_paths(&[1]),
+        needs_image: true,
+        expands_globs: false,
+        modifies_image: true,
+        writes_host_files: false,
+        function: import_tree,
+    },
+    Builtin {
+        name: "get",
+        aliases: &[],
+        usage: "<file> [host file or directory]",
+        summary: "Copies a file out of the image",
+        args: ArgSpec::between(1, 2).with_host_paths(&[2]),
+        needs_image: true,
+        expands_globs: true,
+        modifies_image: false,
+        writes_host_files: true,
+        function: save_file_to_os,
+    },
+    Builtin {
+        name: "mget",
+        aliases: &[],
+        usage: "<files or patterns...>",
+        summary: "Copies files out of the image into the host directory",
+        args: ArgSpec::at_least(1),
+        needs_image: true,
+        expands_globs: false,
+        modifies_image: false,
+        writes_host_files: true,
+        function: get_files,
+    },
+    Builtin {
+        name: "lcd",
+        aliases: &[],
+        usage: "[host directory]",
+        summary: "Changes the host directory, to the home directory without an argument",
+        args: ArgSpec::between(0, 1).with_host_paths(&[1]),
+        needs_image: false,
+        expands_globs: false,
+        modifies_image: false,
+        writes_host_files: false,
+        function: change_host_directory,
+    },
+    Builtin {
+        name: "lls",
+        aliases: &[],
+        usage: "[host directory, file or pattern]",
+        summary: "Lists a host directory, or the host files matching a pattern",
+        args: ArgSpec::between(0, 1).with_host_paths(&[1]),
+        needs_image: false,
+        expands_globs: false,
+        modifies_image: false,
+        writes_host_files: false,
+        function: list_host_directory,
+    },
+    Builtin {
+        name: "lpwd",
+        aliases: &[],
+        usage: "",
+        summary: "Shows the host directory that host paths start from",
+        args: ArgSpec::none(),
+        needs_image: false,
+        expands_globs: false,
+        modifies_image: false,
+        writes_host_files: false,
+        function: print_host_directory,
+    },
+    Builtin {
+        name: "cp",
+        aliases: &["copy"],
+        usage: "<file or pattern> <file or directory>",
+        summary: "Copies files, between drives too, keeping their attributes and times",
+        args: ArgSpec::exactly(2),
+        needs_image: false,
+        expands_globs: false,
+        modifies_image: false,
+        writes_host_files: false,
+        function: copy_file,
+    },
+    Builtin {
+        name: "mv",
+        aliases: &["move"],
+        usage: "<file, directory or pattern> <name or directory>",
+        summary: "Moves or renames files and directories, or moves files between drives",
+        args: ArgSpec::exactly(2),
+        needs_image: false,
+        expands_globs: false,
+        modifies_image: false,
+        writes_host_files: false,
+        function: move_file,
+    },
+    Builtin {
+        name: "rm",
+        aliases: &[],
+        usage: "<file or empty directory>",
+        summary: "Removes a file or an empty directory",
+        args: ArgSpec::exactly(1),
+        needs_image: true,
+        expands_globs: true,
+        modifies_image: true,
+        writes_host_files: false,
+        function: remove_file,
+    },
+    Builtin {
+        name: "ls",
+        aliases: &[],
+        usage: "[directory, file or pattern]",
+        summary: "Lists a directory, or the files matching a pattern like *.BIN",
+        args: ArgSpec::between(0, 1),
+        needs_image: true,
+        expands_globs: false,
+        modifies_image: false,
+        writes_host_files: false,
+        function: list_directory,
+    },
+    Builtin {
+        name: "cd",
+        aliases: &[],
+        usage: "[directory]",
+        summary: "Changes the current directory, to the root without an argument",
+        args: ArgSpec::between(0, 1),
+        needs_image: true,
+        expands_globs: false,
+        modifies_image: false,
+        writes_host_files: false,
+        function: change_directory,
+    },
+    Builtin {
+        name: "mkdir",
+        aliases: &[],
+        usage: "<directory>",
+        summary: "Makes a directory",
+        args: ArgSpec::exactly(1),
+        needs_image: true,
+        expands_globs: false,
+        modifies_image: true,
+        writes_host_files: false,
+        function: make_directory,
+    },
+    Builtin {
+        name: "fsck",
+        aliases: &[],
+        usage: "",
+        summary: "Checks the file system for problems",
+        args: ArgSpec::none(),
+        needs_image: true,
+        expands_globs: false,
+        modifies_image: false,
+        writes_host_files: false,
+        function: check_image,
+    },
+    Builtin {
+        name: "build",
+        aliases: &[],
+        usage: "[-f] <manifest> [image]",
+        summary: "Builds an image from a manifest",
+        args: ArgSpec::between(1, 2).with_host_paths(&[1, 2]).with_flags(DISCARD_FLAGS),
+        needs_image: false,
+        expands_globs: false,
+        modifies_image: false,
+        writes_host_files: true,
+        function: build_manifest,
+    },
+    Builtin {
+        name: "source",
+        aliases: &[],
+        usage: "[-k] <script>",
+        summary: "Runs the commands in a script file",
+        args: ArgSpec::exactly(1).with_host_paths(&[1]).with_flags(&[Flag {
+            name: "-k",
+            value: None,
+            help: "keep going after a line fails",
+        }]),
+        needs_image: false,
+        expands_globs: false,
+        modifies_image: false,
+        writes_host_files: false,
+        function: source,
+    },
+    Builtin {
+        name: "record",
+        aliases: &[],
+        usage: "<script>",
+        summary: "Saves the commands typed so far as a script",
+        args: ArgSpec::exactly(1).with_host_paths(&[1]),
+        needs_image: false,
+        expands_globs: false,
+        modifies_image: false,
+        writes_host_files: true,
+        function: record,
+    },
+];
+
+thread_local! {
+    // How many commands are running others, like `source`, so a dry run knows which command is the whole one
+    static NESTING: Cell<usize> = const { Cell::new(0) };
+}
+
+fn registry() -> &'static RwLock<Vec<Arc<dyn Command>>> {
+    static REGISTRY: OnceLock<RwLock<Vec<Arc<dyn Command>>>> = OnceLock::new();
+    REGISTRY.get_or_init(|| {
+        let builtins = Vec::from(BUILTINS).into_iter().map(|builtin| Arc::new(builtin) as Arc<dyn Command>);
+        RwLock::new(builtins.collect())
+    })
+}
+
+/// Adds a command, replacing any command of the same name
+pub fn register(command: impl Command + 'static) {
+    let mut commands = registry().write().unwrap_or_else(|poisoned| poisoned.into_inner());
+    commands.retain(|existing| existing.name() != command.name());
+    commands.push(Arc::new(command));
+}
+
+/// Every registered command, in the order they were added
+pub fn all_commands() -> Vec<Arc<dyn Command>> {
+    registry().read().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+}
+
+/// Looks up a command by name or alias
+pub fn find_command(name: &str) -> Option<Arc<dyn Command>> {
+    all_commands()
+        .into_iter()
+        .find(|command| command.name() == name || command.aliases().contains(&name))
+}
+
+/// Runs the commands of one line in order, handing each error to `report` as it happens
+///
+/// Returns the state after the last command and whether every command that ran succeeded.
+pub fn run_line(
+    mut shell_state: ShellState,
+    commands: &[CommandLine],
+    mut report: impl FnMut(FatError),
+) -> (ShellState, bool) {
+    let mut all_succeeded = true;
+    let mut last_succeeded = true;
+
+    for command in commands {
+        if command.chain == Chain::IfSucceeded && !last_succeeded {
+            continue;
+        }
+        last_succeeded = match run_words(&shell_state, &command.words) {
+            Ok(new_state) => {
+                shell_state = new_state;
+                true
+            }
+            Err(error) => {
+                report(error);
+                false
+            }
+        };
+        all_succeeded &= last_succeeded;
+    }
+    (shell_state, all_succeeded)
+}
+
+/// Runs one command, for callers that have already split up its words
+///
+/// Wildcards in any word count as patterns, since there are no quotes to tell otherwise.
+pub fn run_command(shell_state: &ShellState, words: Vec<&str>) -> Result<ShellState> {
+    let words: Vec<Word> = words.into_iter().map(Word::new).collect();
+    run_words(shell_state, &words)
+}
+
+/// The state passed in is left alone, so a failed command changes nothing.
+/// A panic in a command is reported as an internal error instead of ending the program.
+fn run_words(shell_state: &ShellState, words: &[Word]) -> Result<ShellState> {
+    let name = words.first().map(|word| word.text.as_str()).unwrap_or_default();
+    if name == "help" {
+        println!("{}", help(words.get(1).map(|word| word.text.as_str()))?);
+        return Ok(shell_state.clone());
+    }
+
+    let command = find_command(name).ok_or_else(|| FatError::UnknownCommand(name.to_owned()))?;
+    if command.needs_image() {
+        shell_state.require_image()?;
+    }
+    // A dry run only changes a scratch copy
+    if command.modifies_image() && shell_state.is_read_only() && !shell_state.is_dry_run() {
+        return Err(FatError::ReadOnly(shell_state.get_image_filename().to_owned()));
+    }
+    if shell_state.is_dry_run() && command.writes_host_files() {
+        return Err(FatError::BadArgument(format!(
+            "{} writes files on the host, which a dry run can't take back",
+            name
+        )));
+    }
+
+    // A wildcard argument runs the command once for each name it matches
+    let patterns: Vec<usize> = (1..words.len()).filter(|&index| words[index].is_pattern).collect();
+    let texts: Vec<&str> = words.iter().map(|word| word.text.as_str()).collect();
+    let matches = match patterns[..] {
+        [] => None,
+        _ if !command.expands_globs() => None,
+        [index] => Some((index, expand_glob(&mut shell_state.volume()?, shell_state.get_cwd(), texts[index])?)),
+        _ => return Err(FatError::BadArgument(format!("{} takes one wildcard argument at most", name))),
+    };
+    let runs: Vec<Vec<&str>> = match &matches {
+        Some((index, paths)) => paths
+            .iter()
+            .map(|path| {
+                let mut run = texts.clone();
+                run[*index] = path;
+                run
+            })
+            .collect(),
+        None => vec![texts.clone()],
+    };
+    let runs = runs
+        .iter()
+        .map(|run| {
+            let spec = command.arg_spec();
+            Args::parse(run, &spec).map(|args| args.map_host_paths(&spec, |path| shell_state.host_path(path)))
+        })
+        .collect::<Result<Vec<Args>>>()?;
+
+    let is_outermost = NESTING.with(Cell::get) == 0;
+    if shell_state.is_tracing() {
+        start_trace();
+    }
+
+    // Keep the default panic message out of the output, the caller reports the error
+    let previous_hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    NESTING.with(|nesting| nesting.set(nesting.get() + 1));
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        runs.iter()
+            .try_fold(shell_state.clone(), |shell_state, args| command.run(shell_state, args))
+    }));
+    NESTING.with(|nesting| nesting.set(nesting.get() - 1));
+    panic::set_hook(previous_hook);
+    let accesses = if shell_state.is_tracing() { finish_trace() } else { Accesses::default() };
+
+    let mut new_state = result.unwrap_or_else(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic".to_owned());
+        Err(FatError::Internal(message))
+    })?;
+
+    let command_line = texts.join(" ");
+    // `trace off` has nothing to say about itself
+    if shell_state.is_tracing() && new_state.is_tracing() {
+        report_trace(&command_line, &accesses, shell_state.image(), new_state.image());
+    }
+
+    // A dry run throws away whole commands, the ones they run (like a script's) see each other's changes
+    let changes_settings =
+        new_state.is_dry_run() != shell_state.is_dry_run() || new_state.is_tracing() != shell_state.is_tracing();
+    if is_outermost && shell_state.is_dry_run() && !changes_settings {
+        report_dry_run(&command_line, shell_state, &new_state)?;
+        return Ok(shell_state.clone());
+    }
+
+    // Commands that ran others, like `source`, and undo itself have seen to the history already
+    new_state.record_changes(&command_line, shell_state)?;
+    Ok(new_state)
+}
+
+/// help [command]
+///
+/// Built from the registry, so it covers commands added with `register` too.
+pub fn help(name: Option<&str>) -> Result<String> {
+    let name = match name {
+        Some(name) => name,
+        None => {
+            let commands = all_commands();
+            let width = commands.iter().map(|command| command.name().len()).max().unwrap_or_default();
+            let mut text = String::from("Commands:\n");
+            for command in &commands {
+                text.push_str(&format!("  {:<width$}  {}\n", command.name(), command.summary(), width = width));
+            }
+            text.push_str("Type help <command> for its arguments, exit to quit.");
+            return Ok(text);
+        }
+    };
+
+    let command = find_command(name).ok_or_else(|| FatError::UnknownCommand(name.to_owned()))?;
+    let mut text = String::new();
+    let forms: Vec<&str> = match command.usage() {
+        "" => vec![""],
+        usage => usage.lines().collect(),
+    };
+    for (index, form) in forms.iter().enumerate() {
+        let prefix = if index == 0 { "usage:" } else { "      " };
+        text.push_str(format!("{} {} {}", prefix, command.name(), form).trim_end());
+        text.push('\n');
+    }
+    text.push_str(command.summary());
+    if !command.aliases().is_empty() {
+        text.push_str(&format!("\naliases: {}", command.aliases().join(", ")));
+    }
+    for flag in command.arg_spec().flags {
+        let flag_name = match flag.value {
+            Some(value) => format!("{} <{}>", flag.name, value),
+            None => flag.name.to_owned(),
+        };
+        text.push_str(&format!("\n  {:<12} {}", flag_name, flag.help));
+    }
+    if !command.needs_image() {
+        text.push_str("\nWorks without an open image.");
+    }
+    Ok(text)
+}
+
+/// Names of every command, for usage messages
+pub fn command_names() -> Vec<String> {
+    all_commands().iter().map(|command| command.name().to_owned()).collect()
+}
@@ -0,0 +1,128 @@
+//! Tab completion for the shell: command names, host paths and names in the open image
+
+use crate::commands::{all_commands, find_command};
+use crate::directories::{expand_glob, resolve_directory, split_path};
+use crate::line_editor::Completion;
+use crate::shell_state::ShellState;
+
+/// Completes the last word of `line`, which ends at the cursor
+pub fn complete(shell_state: &ShellState, line: &str) -> Completion {
+    // Only the last command of a chain matters
+    let command_start = [line.rfind(';').map(|at| at + 1), line.rfind("&&").map(|at| at + 2)]
+        .iter()
+        .flatten()
+        .copied()
+        .max()
+        .unwrap_or(0);
+    // Whitespace like a no-break space takes more than one byte
+    let word_start = line
+        .char_indices()
+        .rfind(|(_, c)| c.is_whitespace())
+        .map(|(at, c)| at + c.len_utf8())
+        .unwrap_or(0)
+        .max(command_start);
+    let word = &line[word_start..];
+    let words_before: Vec<&str> = line[command_start..word_start].split_whitespace().collect();
+
+    let candidates = match words_before.first() {
+        None => command_names(word),
+        Some(&"help") => command_names(word),
+        Some(&name) => {
+            let argnum = words_before.iter().filter(|word| !word.starts_with('-')).count();
+            let is_host_path = find_command(name).is_some_and(|command| command.arg_spec().is_host_path(argnum));
+            if is_host_path {
+                host_paths(shell_state, word)
+            } else {
+                image_paths(shell_state, word)
+            }
+        }
+    };
+
+    Completion {
+        start: line[..word_start].chars().count(),
+        candidates,
+    }
+}
+
+fn command_names(prefix: &str) -> Vec<String> {
+    let mut names: Vec<String> = all_commands()
+        .iter()
+        .flat_map(|command| {
+            let mut names = vec![command.name().to_owned()];
+            names.extend(command.aliases().iter().map(|alias| alias.to_string()));
+            names
+        })
+        .chain(["help".to_owned(), "exit".to_owned()])
+        .filter(|name| name.starts_with(prefix))
+        .collect();
+    names.sort();
+    names
+}
+
+/// Files on the host, with spaces escaped so the tokenizer keeps them in one word
+///
+/// Relative paths start from the shell's host directory, but are completed as typed.
+fn host_paths(shell_state: &ShellState, prefix: &str) -> Vec<String> {
+    let (dir, name_prefix) = match prefix.rfind('/') {
+        Some(slash) => (&prefix[..slash + 1], &prefix[slash + 1..]),
+        None => ("", prefix),
+    };
+    let entries = match std::fs::read_dir(shell_state.host_path(if dir.is_empty() { "." } else { dir })) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+
+    let mut paths: Vec<String> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            // Hidden files only when asked for
+            if !name.starts_with(name_prefix) || (name.starts_with('.') && !name_prefix.starts_with('.')) {
+                return None;
+            }
+            let suffix = if entry.path().is_dir() { "/" } else { "" };
+            Some(format!("{}{}{}", dir, name, suffix).replace(' ', "\\ "))
+        })
+        .collect();
+    paths.sort();
+    paths
+}
+
+/// Files and directories in the open image, matched without regard to case
+fn image_paths(shell_state: &ShellState, prefix: &str) -> Vec<String> {
+    if shell_state.require_image().is_err() || prefix.contains(['*', '?']) {
+        return vec![];
+    }
+    let mut volume = match shell_state.volume() {
+        Ok(volume) => volume,
+        Err(_) => return vec![],
+    };
+    let (dir_path, _) = split_path(prefix);
+    if resolve_directory(&mut volume, shell_state.get_cwd(), dir_path).is_err() {
+        return vec![];
+    }
+
+    expand_glob(&mut volume, shell_state.get_cwd(), &format!("{}*", prefix))
+        .unwrap_or_default()
+        .into_iter()
+        .map(|path| {
+            if resolve_directory(&mut volume, shell_state.get_cwd(), &path).is_ok() {
+                format!("{}/", path)
+            } else {
+                path
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn completes_after_multibyte_whitespace() {
+        let completion = complete(&ShellState::new(), "help\u{a0}he");
+        assert_eq!(completion.start, 5);
+        assert_eq!(completion.candidates, vec!["help".to_owned(), "hexdump".to_owned()]);
+    }
+}
@@ -1,128 +1,243 @@
-use crate::bios_parameter_block::{
-    BYTES_PER_DIRECTORY_ENTRY, BYTES_PER_SECTOR, NUMBER_FATS, RESERVED_SECTORS, ROOT_ENTRIES,
-    SECTORS_PER_FAT,
-};
-use crate::fat_section_util::write_to_fat;
-use crate::new_file::get_next_free_cluster;
-use crate::root_dir_util::{append_to_root_dir, read_root_entry};
-use crate::shell_parsing::get_arg;
-use crate::shell_state::ShellState;
-
-pub fn list_directory(shell_state: ShellState, _args: Vec<&str>) -> ShellState {
-    println!("Listing files in current directory:");
-    println!("-----------------------");
-
-    for root_entry_index in 0..ROOT_ENTRIES {
-        let root_entry = read_root_entry(&shell_state.bytes, root_entry_index);
-        if root_entry[0] != 0 {
-            let filename = String::from_utf8(root_entry[0..12].to_vec()).unwrap();
-            println!("{}", filename);
-        }
-    }
-
-    println!("-----------------------");
-    shell_state
-}
-
-pub fn change_directory(shell_state: ShellState, _args: Vec<&str>) -> ShellState {
-    println!("Changing directories!");
-
-    shell_state
-}
-
-pub fn make_directory(mut shell_state: ShellState, args: Vec<&str>) -> ShellState {
-    println!("Made directory!");
-
-    let mut dirname = get_arg(&args, 1).expect("Missing directory name!").clone();
-    dirname.truncate(11);
-    let formatted_dirname = format!("{:11}", dirname);
-
-    let next_free_cluster = get_next_free_cluster(&shell_state.bytes, 0);
-    shell_state.bytes = write_to_fat(shell_state.bytes, 0xFFF, next_free_cluster);
-
-    if shell_state.is_root() {
-        shell_state.bytes = append_to_root_dir(
-            shell_state.bytes,
-            formatted_dirname,
-            next_free_cluster,
-            true,
-        );
-    } else {
-        shell_state.bytes = append_to_dir(
-            shell_state.bytes,
-            formatted_dirname,
-            next_free_cluster,
-            true,
-            shell_state.get_cwd(),
-        )
-    }
-
-    shell_state
-}
-
-pub fn append_to_dir(
-    mut bytes: Vec<u8>,
-    newfilename: String,
-    first_entry: usize,
-    is_subdir: bool,
-    current_dir_fat_entry: usize,
-) -> Vec<u8> {
-    let dir_start = (RESERVED_SECTORS + NUMBER_FATS * SECTORS_PER_FAT) * BYTES_PER_SECTOR
-        + ROOT_ENTRIES * BYTES_PER_DIRECTORY_ENTRY;
-    let dir_entry = get_first_free_directory_entry(&bytes, current_dir_fat_entry);
-    println!("First free root entry: {}", dir_entry);
-
-    let entry_start = dir_entry + BYTES_PER_DIRECTORY_ENTRY * dir_entry;
-
-    // Make sure filename is 11 letters long
-    let mut truncated_filename = newfilename.clone();
-    truncated_filename.truncate(11);
-    let truncated_filename = format!("{:11}", truncated_filename).into_bytes();
-
-    // I would make this a constant but it won't compile
-    let mut entry_to_add = vec![0; 32];
-
-    // Bytes 0-10: Filename and extension
-    entry_to_add.splice(0..11, truncated_filename);
-
-    // Byte 11: File attributes
-    if is_subdir {
-        entry_to_add[11] = 0b00001000;
-    } else {
-        entry_to_add[11] = 0b00000000;
-    }
-
-    // Bytes 26-27: First cluster
-    entry_to_add[26] = (first_entry >> 8) as u8;
-    entry_to_add[27] = first_entry as u8;
-
-    // Bytes 28-32: File size
-    // TODO
-
-    bytes.splice(
-        entry_start..entry_start + BYTES_PER_DIRECTORY_ENTRY,
-        entry_to_add,
-    );
-    bytes
-}
-
-pub fn get_first_free_directory_entry(bytes: &Vec<u8>, dir_start: usize) -> usize {
-    for entry_index in 0.. {
-        let dir_entry = read_directory_entry(&bytes, root_entry_index);
-        // ! This is a hack, figure out how to test if entry is free
-        if root_entry[0] == 0 {
-            return root_entry_index;
-        }
-    }
-
-    // This should crash
-    0
-}
-
-/// Returns 32 bit entry
-pub fn read_directory_entry(bytes: &Vec<u8>, root_entry: usize) -> Vec<u8> {
-    let root_start = (RESERVED_SECTORS + NUMBER_FATS * SECTORS_PER_FAT) * BYTES_PER_SECTOR;
-    let entry_start = root_start + BYTES_PER_DIRECTORY_ENTRY * root_entry;
-
-    bytes[entry_start..entry_start + BYTES_PER_DIRECTORY_ENTRY].to_vec()
-}
+use crate::bios_parameter_block::BYTES_PER_DIRECTORY_ENTRY;
+use crate::block_device::BlockDevice;
+use crate::fat_error::{FatError, Result};
+use crate::fat_section_util::{free_chain, get_fat_entry, write_to_fat, END_OF_CHAIN};
+use crate::long_names::{add_named_entry, find_long_name, needs_long_name, validate_long_name};
+use crate::new_file::{get_cluster_from_entry, get_next_free_cluster};
+use crate::read_dir::read_dir;
+use crate::root_dir_util::{
+    build_directory_entry, directory_entry, entry_first_cluster, root_entry_count, root_entry_start, to_short_name,
+    write_directory_entry, ATTR_DIRECTORY, DELETED_ENTRY,
+};
+use crate::volume::Volume;
+use alloc::borrow::ToOwned;
+use alloc::string::String;
+use alloc::vec::Vec;
+use alloc::{format, vec};
+
+/// Makes an empty directory, returning its first cluster
+pub fn create_directory<D: BlockDevice>(volume: &mut Volume<D>, cwd: usize, path: &str) -> Result<usize> {
+    let (parent_path, dirname) = split_path(path);
+    validate_long_name(dirname)?;
+
+    let parent = resolve_directory(volume, cwd, parent_path)?;
+    if find_in_directory(volume, parent, dirname)?.is_some() {
+        return Err(FatError::AlreadyExists(path.to_owned()));
+    }
+
+    let next_free_cluster = get_next_free_cluster(volume, 0)?;
+    if next_free_cluster == 0 {
+        return Err(FatError::DiskFull { needed: 1, free: 0 });
+    }
+    write_to_fat(volume, 0xFFF, next_free_cluster)?;
+    zero_cluster(volume, next_free_cluster)?;
+
+    // Every subdirectory starts with "." (itself) and ".." (its parent, 0 for the root)
+    add_directory_entry(volume, next_free_cluster, build_directory_entry(".", next_free_cluster, 0, true))?;
+    add_directory_entry(volume, next_free_cluster, build_directory_entry("..", parent, 0, true))?;
+    let entry = build_directory_entry("", next_free_cluster, 0, true);
+    if let Err(error) = add_named_entry(volume, parent, dirname, entry) {
+        // Don't leave the cluster taken by a directory that isn't there
+        free_chain(volume, next_free_cluster)?;
+        return Err(error);
+    }
+    Ok(next_free_cluster)
+}
+
+pub fn is_directory(entry: &[u8]) -> bool {
+    entry[11] & ATTR_DIRECTORY != 0
+}
+
+/// Byte offsets of every entry slot in a directory, 0 being the root
+///
+/// Subdirectories can span several clusters, so their chain is followed.
+pub fn directory_slots<D: BlockDevice>(volume: &mut Volume<D>, dir_cluster: usize) -> Result<Vec<usize>> {
+    if dir_cluster == 0 {
+        return Ok((0..root_entry_count(volume))
+            .map(|root_entry| root_entry_start(volume, root_entry))
+            .collect());
+    }
+
+    let bpb = volume.bpb().clone();
+    let entries_per_cluster = bpb.bytes_per_cluster() / BYTES_PER_DIRECTORY_ENTRY;
+    let mut slots = vec![];
+    let mut cluster = dir_cluster;
+    // A chain can't be longer than the disk, so stop there if the FAT has a loop
+    for _ in 0..bpb.cluster_count() {
+        // fsck reports chains that run off the disk, here they just end
+        if !(2..END_OF_CHAIN).contains(&cluster) || cluster >= bpb.fat_entries() {
+            break;
+        }
+        let cluster_start = get_cluster_from_entry(volume, cluster);
+        slots.extend((0..entries_per_cluster).map(|entry| cluster_start + entry * BYTES_PER_DIRECTORY_ENTRY));
+        cluster = get_fat_entry(volume, cluster)?;
+    }
+    Ok(slots)
+}
+
+/// Finds a file or directory by name, returning the byte offset of its entry
+///
+/// Names that don't fit the 8.3 form are looked for among the long names.
+pub fn find_in_directory<D: BlockDevice>(
+    volume: &mut Volume<D>,
+    dir_cluster: usize,
+    name: &str,
+) -> Result<Option<usize>> {
+    if needs_long_name(name) {
+        return find_long_name(volume, dir_cluster, name);
+    }
+    let short_name = to_short_name(name);
+
+    for entry in read_dir(volume, dir_cluster)? {
+        let entry = entry?;
+        if entry.bytes[0..11] == short_name {
+            return Ok(Some(entry.offset));
+        }
+    }
+    Ok(None)
+}
+
+/// Splits `DIR/SUB/NAME.EXT` into the directory part and the final name
+pub fn split_path(path: &str) -> (&str, &str) {
+    match path.rfind('/') {
+        Some(0) => ("/", &path[1..]),
+        Some(slash) => (&path[..slash], &path[slash + 1..]),
+        None => ("", path),
+    }
+}
+
+pub fn has_wildcards(name: &str) -> bool {
+    name.contains(['*', '?'])
+}
+
+/// Matches a name against a pattern like `*.BIN` or `KERN?.SYS`, ignoring case
+///
+/// `*` matches any run of characters and `?` any one character.
+/// As in DOS, `*.*` also matches names without an extension.
+pub fn glob_matches(pattern: &str, name: &str) -> bool {
+    fn matches(pattern: &[u8], name: &[u8]) -> bool {
+        match (pattern.first(), name.first()) {
+            (None, None) => true,
+            (Some(b'*'), _) => matches(&pattern[1..], name) || (!name.is_empty() && matches(pattern, &name[1..])),
+            (Some(b'?'), Some(_)) => matches(&pattern[1..], &name[1..]),
+            (Some(p), Some(n)) if p.eq_ignore_ascii_case(n) => matches(&pattern[1..], &name[1..]),
+            _ => false,
+        }
+    }
+
+    matches(pattern.as_bytes(), name.as_bytes())
+        || (!name.contains('.') && matches(pattern.as_bytes(), format!("{}.", name).as_bytes()))
+}
+
+/// Every path a pattern like `SYS/*.BIN` matches, in directory order
+///
+/// Only the last part of the path can have wildcards. `.` and `..` are never matched.
+pub fn expand_glob<D: BlockDevice>(volume: &mut Volume<D>, cwd: usize, pattern: &str) -> Result<Vec<String>> {
+    let (dir_path, name_pattern) = split_path(pattern);
+    let dir_cluster = resolve_directory(volume, cwd, dir_path)?;
+
+    let mut paths = vec![];
+    for entry in read_dir(volume, dir_cluster)?.without_dots() {
+        let name = entry?.name();
+        if !glob_matches(name_pattern, &name) {
+            continue;
+        }
+        paths.push(match dir_path {
+            "" => name,
+            "/" => format!("/{}", name),
+            _ => format!("{}/{}", dir_path, name),
+        });
+    }
+
+    if paths.is_empty() {
+        return Err(FatError::NotFound(pattern.to_owned()));
+    }
+    Ok(paths)
+}
+
+/// Resolves a directory path, absolute or relative to `cwd`, to its first cluster (0 for the root)
+pub fn resolve_directory<D: BlockDevice>(volume: &mut Volume<D>, cwd: usize, path: &str) -> Result<usize> {
+    let mut cluster = if path.starts_with('/') { 0 } else { cwd };
+
+    for component in path.split('/').filter(|component| !component.is_empty() && *component != ".") {
+        // The root has no ".." entry of its own
+        if component == ".." && cluster == 0 {
+            continue;
+        }
+
+        let offset =
+            find_in_directory(volume, cluster, component)?.ok_or_else(|| FatError::NotFound(path.to_owned()))?;
+        let entry = directory_entry(volume, offset)?;
+        if !is_directory(&entry) {
+            return Err(FatError::NotADirectory(path.to_owned()));
+        }
+        cluster = entry_first_cluster(&entry);
+    }
+
+    Ok(cluster)
+}
+
+/// Finds the entry a path names, returning the directory it's in and the entry's byte offset
+pub fn find_path<D: BlockDevice>(volume: &mut Volume<D>, cwd: usize, path: &str) -> Result<(usize, usize)> {
+    let (dir_path, name) = split_path(path);
+    let dir_cluster = resolve_directory(volume, cwd, dir_path)?;
+    find_in_directory(volume, dir_cluster, name)?
+        .map(|offset| (dir_cluster, offset))
+        .ok_or_else(|| FatError::NotFound(path.to_owned()))
+}
+
+/// Writes an entry into the first free slot of a directory, returning its byte offset
+///
+/// Subdirectories get another cluster when they're full, the root directory has a fixed size.
+pub fn add_directory_entry<D: BlockDevice>(
+    volume: &mut Volume<D>,
+    dir_cluster: usize,
+    entry_to_add: Vec<u8>,
+) -> Result<usize> {
+    let mut free_slot = None;
+    for offset in directory_slots(volume, dir_cluster)? {
+        if matches!(directory_entry(volume, offset)?[0], 0 | DELETED_ENTRY) {
+            free_slot = Some(offset);
+            break;
+        }
+    }
+
+    let entry_start = match free_slot {
+        Some(offset) => offset,
+        None if dir_cluster == 0 => return Err(FatError::RootDirectoryFull),
+        None => grow_directory(volume, dir_cluster)?,
+    };
+
+    write_directory_entry(volume, entry_start, &entry_to_add)?;
+    Ok(entry_start)
+}
+
+/// Adds a cleared cluster to the end of a subdirectory, returning the byte offset of its first slot
+pub(crate) fn grow_directory<D: BlockDevice>(volume: &mut Volume<D>, dir_cluster: usize) -> Result<usize> {
+    let bpb = volume.bpb().clone();
+    let mut last_cluster = dir_cluster;
+    let mut steps = 0;
+    while get_fat_entry(volume, last_cluster)? < END_OF_CHAIN {
+        last_cluster = get_fat_entry(volume, last_cluster)?;
+        steps += 1;
+        if !(2..bpb.fat_entries()).contains(&last_cluster) || steps > bpb.cluster_count() {
+            return Err(FatError::CorruptFat(format!("directory at cluster {} has a broken chain", dir_cluster)));
+        }
+    }
+    let new_cluster = get_next_free_cluster(volume, 0)?;
+    if new_cluster == 0 {
+        return Err(FatError::DiskFull { needed: 1, free: 0 });
+    }
+
+    write_to_fat(volume, new_cluster, last_cluster)?;
+    write_to_fat(volume, 0xFFF, new_cluster)?;
+    zero_cluster(volume, new_cluster)?;
+    Ok(get_cluster_from_entry(volume, new_cluster))
+}
+
+/// Clears a cluster so a new directory doesn't pick up stale entries
+fn zero_cluster<D: BlockDevice>(volume: &mut Volume<D>, cluster: usize) -> Result<()> {
+    let cluster_start = get_cluster_from_entry(volume, cluster);
+    let cluster_size = volume.bpb().bytes_per_cluster();
+    volume.write(cluster_start, &vec![0; cluster_size])
+}
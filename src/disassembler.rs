@@ -0,0 +1,1070 @@
+use crate::bios_parameter_block::{BPB_END, BYTES_PER_SECTOR};
+use crate::boot_template::BOOT_ORIGIN;
+use crate::fat_error::{FatError, Result};
+use crate::read_file::read_file_at_path;
+use crate::shell_parsing::{expect_number, Args};
+use crate::shell_state::ShellState;
+use std::collections::HashSet;
+
+const REGISTERS_8: [&str; 8] = ["al", "cl", "dl", "bl", "ah", "ch", "dh", "bh"];
+const REGISTERS_16: [&str; 8] = ["ax", "cx", "dx", "bx", "sp", "bp", "si", "di"];
+const REGISTERS_32: [&str; 8] = ["eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi"];
+const SEGMENT_REGISTERS: [&str; 8] = ["es", "cs", "ss", "ds", "fs", "gs", "?", "?"];
+const ADDRESSES_16: [&str; 8] = ["bx+si", "bx+di", "bp+si", "bp+di", "si", "di", "bp", "bx"];
+
+const ALU_OPS: [&str; 8] = ["add", "or", "adc", "sbb", "and", "sub", "xor", "cmp"];
+const SHIFT_OPS: [&str; 8] = ["rol", "ror", "rcl", "rcr", "shl", "shr", "sal", "sar"];
+const CONDITIONS: [&str; 16] = [
+    "o", "no", "b", "nb", "z", "nz", "be", "a", "s", "ns", "pe", "po", "l", "ge", "le", "g",
+];
+
+/// Names of the BPB fields, with their offset and size, for annotating the boot sector
+const BPB_FIELDS: [(usize, usize, &str); 19] = [
+    (0x03, 8, "OEM name"),
+    (0x0B, 2, "bytes per sector"),
+    (0x0D, 1, "sectors per cluster"),
+    (0x0E, 2, "reserved sectors"),
+    (0x10, 1, "number of FATs"),
+    (0x11, 2, "root entries"),
+    (0x13, 2, "total sectors"),
+    (0x15, 1, "media descriptor"),
+    (0x16, 2, "sectors per FAT"),
+    (0x18, 2, "sectors per track"),
+    (0x1A, 2, "heads"),
+    (0x1C, 4, "hidden sectors"),
+    (0x20, 4, "total sectors (big)"),
+    (0x24, 1, "drive number"),
+    (0x25, 1, "reserved"),
+    (0x26, 1, "extended boot signature"),
+    (0x27, 4, "serial number"),
+    (0x2B, 11, "volume label"),
+    (0x36, 8, "file system type"),
+];
+
+/// One decoded instruction
+pub struct Instruction {
+    /// Offset of the instruction in its code segment
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    pub text: String,
+    /// Destination of a near jump, call or loop
+    pub target: Option<u16>,
+}
+
+/// Decodes real-mode code starting at `origin` until the end of `code`
+pub fn disassemble(code: &[u8], origin: u16) -> Vec<Instruction> {
+    let mut instructions = vec![];
+    let mut position = 0;
+
+    while position < code.len() {
+        // Show padding as one line instead of pages of `add [bx+si],al`
+        let zeros = code[position..].iter().take_while(|&&byte| byte == 0).count();
+        let instruction = if zeros >= 4 {
+            Instruction {
+                address: origin.wrapping_add(position as u16),
+                bytes: vec![0; zeros],
+                text: format!("times {} db 0", zeros),
+                target: None,
+            }
+        } else {
+            decode(code, position, origin)
+        };
+
+        position += instruction.bytes.len();
+        instructions.push(instruction);
+    }
+
+    instructions
+}
+
+/// Decodes the instruction at `position`, falling back to a `db` when it is invalid or cut off
+pub fn decode(code: &[u8], position: usize, origin: u16) -> Instruction {
+    let address = origin.wrapping_add(position as u16);
+    let mut decoder = Decoder::new(code, position, address);
+
+    match decoder.instruction() {
+        Some(text) => Instruction {
+            address,
+            bytes: code[position..decoder.position].to_vec(),
+            text,
+            target: decoder.target,
+        },
+        None => Instruction {
+            address,
+            bytes: vec![code[position]],
+            text: format!("db 0x{:02x}", code[position]),
+            target: None,
+        },
+    }
+}
+
+/// Formats a listing, labelling branch targets and the `entry` instruction
+pub fn format_listing(instructions: &[Instruction], entry: Option<u16>) -> Vec<String> {
+    let targets: HashSet<u16> = instructions
+        .iter()
+        .filter_map(|instruction| instruction.target)
+        .collect();
+
+    let mut lines = vec![];
+    for instruction in instructions {
+        if Some(instruction.address) == entry {
+            lines.push("entry:                              ; jump target of the boot sector".to_owned());
+        } else if targets.contains(&instruction.address) {
+            lines.push(format!("loc_{:04X}:", instruction.address));
+        }
+
+        let hex: String = instruction
+            .bytes
+            .iter()
+            .take(8)
+            .map(|byte| format!("{:02X}", byte))
+            .collect();
+        let ellipsis = if instruction.bytes.len() > 8 { "+" } else { "" };
+
+        let mut line = format!(
+            "{:04X}  {:<18}{}",
+            instruction.address,
+            format!("{}{}", hex, ellipsis),
+            instruction.text
+        );
+        if let Some(target) = instruction.target {
+            if !targets.contains(&target) || Some(target) == entry {
+                line = format!("{:<52}; -> {:04X}", line, target);
+            }
+        }
+        lines.push(line);
+    }
+
+    lines
+}
+
+/// Formats the boot sector: the jump, the BPB as data, the code and the signature
+pub fn format_boot_sector(sector: &[u8]) -> Vec<String> {
+    let first = decode(sector, 0, BOOT_ORIGIN);
+    let has_bpb = first.bytes[0] == 0xEB || first.bytes[0] == 0xE9;
+    if !has_bpb {
+        let mut lines = vec!["; no jump over a BPB, decoding the whole sector as code".to_owned()];
+        lines.append(&mut format_listing(&disassemble(sector, BOOT_ORIGIN), None));
+        return lines;
+    }
+
+    let code_end = if sector[BYTES_PER_SECTOR - 2..BYTES_PER_SECTOR] == [0x55, 0xAA] {
+        BYTES_PER_SECTOR - 2
+    } else {
+        BYTES_PER_SECTOR
+    };
+
+    let mut instructions = vec![first];
+    // A `jmp short` is followed by a nop before the OEM name
+    let bpb_start = BPB_FIELDS[0].0;
+    if instructions[0].bytes.len() < bpb_start {
+        instructions.push(decode(&sector[..bpb_start], instructions[0].bytes.len(), BOOT_ORIGIN));
+    }
+    let entry = instructions[0].target;
+
+    let mut lines = format_listing(&instructions, None);
+    lines.push("; BIOS parameter block".to_owned());
+    for (offset, size, name) in BPB_FIELDS.iter() {
+        let field = &sector[*offset..*offset + *size];
+        let mut hex: String = field.iter().take(8).map(|byte| format!("{:02X}", byte)).collect();
+        if field.len() > 8 {
+            hex.push('+');
+        }
+        let value = match size {
+            1 => format!("db 0x{:02x}", field[0]),
+            2 => format!("dw 0x{:04x}", u16::from_le_bytes([field[0], field[1]])),
+            4 => format!(
+                "dd 0x{:08x}",
+                u32::from_le_bytes([field[0], field[1], field[2], field[3]])
+            ),
+            _ => format!("db '{}'", String::from_utf8_lossy(field)),
+        };
+        lines.push(format!(
+            "{:04X}  {:<18}{:<34}; {}",
+            BOOT_ORIGIN as usize + offset,
+            hex,
+            value,
+            name
+        ));
+    }
+
+    lines.push("; boot code".to_owned());
+    let code = disassemble(&sector[BPB_END..code_end], BOOT_ORIGIN + BPB_END as u16);
+    lines.append(&mut format_listing(&code, entry));
+
+    if code_end != BYTES_PER_SECTOR {
+        lines.push(format!(
+            "{:04X}  55AA              dw 0xaa55                         ; boot signature",
+            BOOT_ORIGIN as usize + code_end
+        ));
+    }
+
+    lines
+}
+
+/// disasm
+/// disasm sectors <first sector> [count] [origin]
+/// disasm file <name> [origin]
+pub fn disasm(shell_state: ShellState, args: &Args) -> Result<ShellState> {
+    let lines = match args.get(1).as_deref() {
+        None | Some("boot") => {
+            let mut volume = shell_state.volume()?;
+            if volume.size() < BYTES_PER_SECTOR {
+                return Err(FatError::PastEndOfDevice {
+                    sector: 0,
+                    sectors: volume.size() / BYTES_PER_SECTOR,
+                });
+            }
+            format_boot_sector(&volume.read(0, BYTES_PER_SECTOR)?)
+        }
+        Some("sectors") => {
+            let first_sector = expect_number(&args.expect(2, "sector")?, "Sector")?;
+            let count = match args.get(3) {
+                Some(count) => expect_number(&count, "Count")?,
+                None => 1,
+            };
+            let origin = match args.get(4) {
+                Some(origin) => parse_hex(&origin)?,
+                None => 0,
+            };
+
+            let mut volume = shell_state.volume()?;
+            let sectors = volume.size() / BYTES_PER_SECTOR;
+            if first_sector >= sectors {
+                return Err(FatError::PastEndOfDevice {
+                    sector: first_sector,
+                    sectors,
+                });
+            }
+            let start = first_sector * BYTES_PER_SECTOR;
+            let end = first_sector.saturating_add(count).min(sectors) * BYTES_PER_SECTOR;
+            format_listing(&disassemble(&volume.read(start, end - start)?, origin), None)
+        }
+        Some("file") => {
+            let filename = args.expect(2, "filename")?;
+            let origin = match args.get(3) {
+                Some(origin) => parse_hex(&origin)?,
+                None => 0,
+            };
+
+            let file = read_file_at_path(&mut shell_state.volume()?, shell_state.get_cwd(), &filename)?;
+            format_listing(&disassemble(&file, origin), None)
+        }
+        Some(other) => {
+            return Err(FatError::BadArgument(format!(
+                "Unknown disasm target {}, expected boot, sectors or file",
+                other
+            )))
+        }
+    };
+
+    for line in lines {
+        println!("{}", line);
+    }
+
+    Ok(shell_state)
+}
+
+fn parse_hex(number: &str) -> Result<u16> {
+    u16::from_str_radix(number.trim_start_matches("0x"), 16)
+        .map_err(|_| FatError::BadArgument(format!("Origin isn't hex: {}", number)))
+}
+
+/// A decoded ModR/M byte, with the memory operand already formatted
+struct ModRm {
+    mode: u8,
+    reg: u8,
+    rm: u8,
+    memory: String,
+}
+
+struct Decoder<'a> {
+    code: &'a [u8],
+    position: usize,
+    address: u16,
+    start: usize,
+    operand_32: bool,
+    address_32: bool,
+    segment: Option<&'static str>,
+    target: Option<u16>,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(code: &'a [u8], position: usize, address: u16) -> Self {
+        Decoder {
+            code,
+            position,
+            address,
+            start: position,
+            operand_32: false,
+            address_32: false,
+            segment: None,
+            target: None,
+        }
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        let byte = *self.code.get(self.position)?;
+        self.position += 1;
+        Some(byte)
+    }
+
+    fn word(&mut self) -> Option<u16> {
+        Some(self.byte()? as u16 | (self.byte()? as u16) << 8)
+    }
+
+    fn dword(&mut self) -> Option<u32> {
+        Some(self.word()? as u32 | (self.word()? as u32) << 16)
+    }
+
+    /// Word or dword immediate depending on the operand size
+    fn immediate(&mut self) -> Option<u32> {
+        if self.operand_32 {
+            self.dword()
+        } else {
+            self.word().map(|word| word as u32)
+        }
+    }
+
+    fn signed_byte(&mut self) -> Option<String> {
+        let value = self.byte()? as i8;
+        if value < 0 {
+            Some(format!("-0x{:x}", -(value as i16)))
+        } else {
+            Some(format!("0x{:x}", value))
+        }
+    }
+
+    fn register(&self, size: u8, index: u8) -> &'static str {
+        match size {
+            8 => REGISTERS_8[index as usize],
+            32 => REGISTERS_32[index as usize],
+            _ => REGISTERS_16[index as usize],
+        }
+    }
+
+    /// Size in bits of "v" operands
+    fn variable_size(&self) -> u8 {
+        if self.operand_32 {
+            32
+        } else {
+            16
+        }
+    }
+
+    /// Sets the branch target from a displacement relative to the end of the instruction
+    fn relative_target(&mut self, displacement: i32) -> String {
+        let length = (self.position - self.start) as i32;
+        let target = (self.address as i32 + length + displacement) as u16;
+        self.target = Some(target);
+        format!("0x{:x}", target)
+    }
+
+    fn modrm(&mut self) -> Option<ModRm> {
+        let byte = self.byte()?;
+        let mode = byte >> 6;
+        let reg = (byte >> 3) & 7;
+        let rm = byte & 7;
+
+        let expression = if mode == 3 {
+            String::new()
+        } else if self.address_32 {
+            self.memory_32(mode, rm)?
+        } else {
+            self.memory_16(mode, rm)?
+        };
+
+        let memory = match self.segment {
+            Some(segment) => format!("[{}:{}]", segment, expression),
+            None => format!("[{}]", expression),
+        };
+
+        Some(ModRm {
+            mode,
+            reg,
+            rm,
+            memory,
+        })
+    }
+
+    fn memory_16(&mut self, mode: u8, rm: u8) -> Option<String> {
+        Some(match (mode, rm) {
+            (0, 6) => format!("0x{:x}", self.word()?),
+            (0, _) => ADDRESSES_16[rm as usize].to_owned(),
+            (1, _) => {
+                let displacement = self.byte()? as i8 as i32;
+                format_displacement(ADDRESSES_16[rm as usize], displacement)
+            }
+            _ => {
+                let displacement = self.word()? as i16 as i32;
+                format_displacement(ADDRESSES_16[rm as usize], displacement)
+            }
+        })
+    }
+
+    fn memory_32(&mut self, mode: u8, rm: u8) -> Option<String> {
+        let mut base = if rm == 4 {
+            // SIB byte
+            let sib = self.byte()?;
+            let scale = 1 << (sib >> 6);
+            let index = (sib >> 3) & 7;
+            let base = sib & 7;
+
+            let mut parts = vec![];
+            if base == 5 && mode == 0 {
+                parts.push(format!("0x{:x}", self.dword()?));
+            } else {
+                parts.push(REGISTERS_32[base as usize].to_owned());
+            }
+            if index != 4 {
+                if scale == 1 {
+                    parts.push(REGISTERS_32[index as usize].to_owned());
+                } else {
+                    parts.push(format!("{}*{}", REGISTERS_32[index as usize], scale));
+                }
+            }
+            parts.join("+")
+        } else if rm == 5 && mode == 0 {
+            return Some(format!("0x{:x}", self.dword()?));
+        } else {
+            REGISTERS_32[rm as usize].to_owned()
+        };
+
+        match mode {
+            1 => base = format_displacement(&base, self.byte()? as i8 as i32),
+            2 => base = format_displacement(&base, self.dword()? as i32),
+            _ => {}
+        }
+        Some(base)
+    }
+
+    /// The r/m operand as a register or memory reference; `sized` adds `byte`/`word`/`dword`
+    fn rm_operand(&self, modrm: &ModRm, size: u8, sized: bool) -> String {
+        if modrm.mode == 3 {
+            self.register(size, modrm.rm).to_owned()
+        } else if sized {
+            format!("{} {}", size_name(size), modrm.memory)
+        } else {
+            modrm.memory.clone()
+        }
+    }
+
+    /// Direct memory operand of the A0-A3 moves
+    fn memory_offset(&mut self) -> Option<String> {
+        let offset = if self.address_32 {
+            self.dword()?
+        } else {
+            self.word()? as u32
+        };
+        Some(match self.segment {
+            Some(segment) => format!("[{}:0x{:x}]", segment, offset),
+            None => format!("[0x{:x}]", offset),
+        })
+    }
+
+    fn instruction(&mut self) -> Option<String> {
+        let mut prefixes = vec![];
+
+        loop {
+            let opcode = self.byte()?;
+            let text = match opcode {
+                0x26 | 0x2E | 0x36 | 0x3E | 0x64 | 0x65 => {
+                    self.segment = Some(match opcode {
+                        0x26 => "es",
+                        0x2E => "cs",
+                        0x36 => "ss",
+                        0x3E => "ds",
+                        0x64 => "fs",
+                        _ => "gs",
+                    });
+                    continue;
+                }
+                0x66 => {
+                    self.operand_32 = true;
+                    continue;
+                }
+                0x67 => {
+                    self.address_32 = true;
+                    continue;
+                }
+                0xF0 => {
+                    prefixes.push("lock");
+                    continue;
+                }
+                0xF2 => {
+                    prefixes.push("repne");
+                    continue;
+                }
+                0xF3 => {
+                    prefixes.push("rep");
+                    continue;
+                }
+                _ => self.opcode(opcode)?,
+            };
+
+            prefixes.push(&text);
+            return Some(prefixes.join(" ").replace("rep cmps", "repe cmps").replace("rep scas", "repe scas"));
+        }
+    }
+
+    fn opcode(&mut self, opcode: u8) -> Option<String> {
+        let v = self.variable_size();
+        let accumulator = self.register(v, 0);
+
+        Some(match opcode {
+            // ALU operations in the first quarter of the table
+            0x00..=0x3F if opcode & 7 < 6 => {
+                let name = ALU_OPS[(opcode >> 3) as usize];
+                match opcode & 7 {
+                    0..=3 => {
+                        let size = if opcode & 1 == 0 { 8 } else { v };
+                        let modrm = self.modrm()?;
+                        let rm = self.rm_operand(&modrm, size, false);
+                        let reg = self.register(size, modrm.reg);
+                        if opcode & 2 == 0 {
+                            format!("{} {},{}", name, rm, reg)
+                        } else {
+                            format!("{} {},{}", name, reg, rm)
+                        }
+                    }
+                    4 => format!("{} al,0x{:x}", name, self.byte()?),
+                    _ => format!("{} {},0x{:x}", name, accumulator, self.immediate()?),
+                }
+            }
+            0x06 | 0x0E | 0x16 | 0x1E => {
+                format!("push {}", SEGMENT_REGISTERS[(opcode >> 3) as usize])
+            }
+            0x07 | 0x17 | 0x1F => format!("pop {}", SEGMENT_REGISTERS[(opcode >> 3) as usize]),
+            0x0F => self.two_byte_opcode()?,
+            0x27 => "daa".to_owned(),
+            0x2F => "das".to_owned(),
+            0x37 => "aaa".to_owned(),
+            0x3F => "aas".to_owned(),
+            0x40..=0x47 => format!("inc {}", self.register(v, opcode & 7)),
+            0x48..=0x4F => format!("dec {}", self.register(v, opcode & 7)),
+            0x50..=0x57 => format!("push {}", self.register(v, opcode & 7)),
+            0x58..=0x5F => format!("pop {}", self.register(v, opcode & 7)),
+            0x60 => self.sized_name("pusha", "pushad"),
+            0x61 => self.sized_name("popa", "popad"),
+            0x62 => {
+                let modrm = self.modrm()?;
+                format!("bound {},{}", self.register(v, modrm.reg), modrm.memory)
+            }
+            0x63 => {
+                let modrm = self.modrm()?;
+                format!(
+                    "arpl {},{}",
+                    self.rm_operand(&modrm, 16, false),
+                    self.register(16, modrm.reg)
+                )
+            }
+            0x68 => format!("push {} 0x{:x}", size_name(v), self.immediate()?),
+            0x69 | 0x6B => {
+                let modrm = self.modrm()?;
+                let rm = self.rm_operand(&modrm, v, false);
+                let immediate = if opcode == 0x69 {
+                    format!("0x{:x}", self.immediate()?)
+                } else {
+                    self.signed_byte()?
+                };
+                format!("imul {},{},{}", self.register(v, modrm.reg), rm, immediate)
+            }
+            0x6A => format!("push byte {}", self.signed_byte()?),
+            0x6C => "insb".to_owned(),
+            0x6D => self.sized_name("insw", "insd"),
+            0x6E => "outsb".to_owned(),
+            0x6F => self.sized_name("outsw", "outsd"),
+            0x70..=0x7F => {
+                let displacement = self.byte()? as i8 as i32;
+                format!(
+                    "j{} short {}",
+                    CONDITIONS[(opcode & 0xF) as usize],
+                    self.relative_target(displacement)
+                )
+            }
+            0x80..=0x83 => {
+                let size = if opcode & 1 == 0 { 8 } else { v };
+                let modrm = self.modrm()?;
+                let rm = self.rm_operand(&modrm, size, true);
+                let immediate = match opcode {
+                    0x81 => format!("0x{:x}", self.immediate()?),
+                    0x83 => self.signed_byte()?,
+                    _ => format!("0x{:x}", self.byte()?),
+                };
+                format!("{} {},{}", ALU_OPS[modrm.reg as usize], rm, immediate)
+            }
+            0x84..=0x8B => {
+                let name = match opcode {
+                    0x84 | 0x85 => "test",
+                    0x86 | 0x87 => "xchg",
+                    _ => "mov",
+                };
+                let size = if opcode & 1 == 0 { 8 } else { v };
+                let modrm = self.modrm()?;
+                let rm = self.rm_operand(&modrm, size, false);
+                let reg = self.register(size, modrm.reg);
+                if opcode >= 0x8A {
+                    format!("{} {},{}", name, reg, rm)
+                } else {
+                    format!("{} {},{}", name, rm, reg)
+                }
+            }
+            0x8C => {
+                let modrm = self.modrm()?;
+                format!(
+                    "mov {},{}",
+                    self.rm_operand(&modrm, 16, false),
+                    SEGMENT_REGISTERS[modrm.reg as usize]
+                )
+            }
+            0x8D => {
+                let modrm = self.modrm()?;
+                format!("lea {},{}", self.register(v, modrm.reg), modrm.memory)
+            }
+            0x8E => {
+                let modrm = self.modrm()?;
+                format!(
+                    "mov {},{}",
+                    SEGMENT_REGISTERS[modrm.reg as usize],
+                    self.rm_operand(&modrm, 16, false)
+                )
+            }
+            0x8F => {
+                let modrm = self.modrm()?;
+                format!("pop {}", self.rm_operand(&modrm, v, true))
+            }
+            0x90 => "nop".to_owned(),
+            0x91..=0x97 => format!("xchg {},{}", self.register(v, opcode & 7), accumulator),
+            0x98 => self.sized_name("cbw", "cwde"),
+            0x99 => self.sized_name("cwd", "cdq"),
+            0x9A => {
+                let offset = self.immediate()?;
+                let segment = self.word()?;
+                format!("call 0x{:x}:0x{:x}", segment, offset)
+            }
+            0x9B => "wait".to_owned(),
+            0x9C => self.sized_name("pushf", "pushfd"),
+            0x9D => self.sized_name("popf", "popfd"),
+            0x9E => "sahf".to_owned(),
+            0x9F => "lahf".to_owned(),
+            0xA0 => format!("mov al,{}", self.memory_offset()?),
+            0xA1 => format!("mov {},{}", accumulator, self.memory_offset()?),
+            0xA2 => format!("mov {},al", self.memory_offset()?),
+            0xA3 => format!("mov {},{}", self.memory_offset()?, accumulator),
+            0xA4 => "movsb".to_owned(),
+            0xA5 => self.sized_name("movsw", "movsd"),
+            0xA6 => "cmpsb".to_owned(),
+            0xA7 => self.sized_name("cmpsw", "cmpsd"),
+            0xA8 => format!("test al,0x{:x}", self.byte()?),
+            0xA9 => format!("test {},0x{:x}", accumulator, self.immediate()?),
+            0xAA => "stosb".to_owned(),
+            0xAB => self.sized_name("stosw", "stosd"),
+            0xAC => "lodsb".to_owned(),
+            0xAD => self.sized_name("lodsw", "lodsd"),
+            0xAE => "scasb".to_owned(),
+            0xAF => self.sized_name("scasw", "scasd"),
+            0xB0..=0xB7 => format!("mov {},0x{:x}", REGISTERS_8[(opcode & 7) as usize], self.byte()?),
+            0xB8..=0xBF => format!("mov {},0x{:x}", self.register(v, opcode & 7), self.immediate()?),
+            0xC0 | 0xC1 | 0xD0..=0xD3 => {
+                let size = if opcode & 1 == 0 { 8 } else { v };
+                let modrm = self.modrm()?;
+                let rm = self.rm_operand(&modrm, size, true);
+                let count = match opcode {
+                    0xC0 | 0xC1 => format!("0x{:x}", self.byte()?),
+                    0xD0 | 0xD1 => "1".to_owned(),
+                    _ => "cl".to_owned(),
+                };
+                format!("{} {},{}", SHIFT_OPS[modrm.reg as usize], rm, count)
+            }
+            0xC2 => format!("ret 0x{:x}", self.word()?),
+            0xC3 => "ret".to_owned(),
+            0xC4 | 0xC5 => {
+                let modrm = self.modrm()?;
+                let name = if opcode == 0xC4 { "les" } else { "lds" };
+                format!("{} {},{}", name, self.register(v, modrm.reg), modrm.memory)
+            }
+            0xC6 | 0xC7 => {
+                let size = if opcode == 0xC6 { 8 } else { v };
+                let modrm = self.modrm()?;
+                let rm = self.rm_operand(&modrm, size, true);
+                let immediate = if size == 8 {
+                    self.byte()? as u32
+                } else {
+                    self.immediate()?
+                };
+                format!("mov {},0x{:x}", rm, immediate)
+            }
+            0xC8 => {
+                let size = self.word()?;
+                let level = self.byte()?;
+                format!("enter 0x{:x},0x{:x}", size, level)
+            }
+            0xC9 => "leave".to_owned(),
+            0xCA => format!("retf 0x{:x}", self.word()?),
+            0xCB => "retf".to_owned(),
+            0xCC => "int3".to_owned(),
+            0xCD => format!("int 0x{:x}", self.byte()?),
+            0xCE => "into".to_owned(),
+            0xCF => self.sized_name("iret", "iretd"),
+            0xD4 => format!("aam 0x{:x}", self.byte()?),
+            0xD5 => format!("aad 0x{:x}", self.byte()?),
+            0xD6 => "salc".to_owned(),
+            0xD7 => "xlatb".to_owned(),
+            0xD8..=0xDF => {
+                // x87 escape, only the length matters here
+                let modrm = self.modrm()?;
+                format!("esc 0x{:x},{}", ((opcode & 7) << 3) | modrm.reg, self.rm_operand(&modrm, 16, false))
+            }
+            0xE0..=0xE3 => {
+                let displacement = self.byte()? as i8 as i32;
+                let name = match opcode {
+                    0xE0 => "loopne",
+                    0xE1 => "loope",
+                    0xE2 => "loop",
+                    _ if self.address_32 => "jecxz",
+                    _ => "jcxz",
+                };
+                format!("{} {}", name, self.relative_target(displacement))
+            }
+            0xE4 => format!("in al,0x{:x}", self.byte()?),
+            0xE5 => format!("in {},0x{:x}", accumulator, self.byte()?),
+            0xE6 => format!("out 0x{:x},al", self.byte()?),
+            0xE7 => format!("out 0x{:x},{}", self.byte()?, accumulator),
+            0xE8 | 0xE9 => {
+                let displacement = if self.operand_32 {
+                    self.dword()? as i32
+                } else {
+                    self.word()? as i16 as i32
+                };
+                let name = if opcode == 0xE8 { "call" } else { "jmp" };
+                format!("{} {}", name, self.relative_target(displacement))
+            }
+            0xEA => {
+                let offset = self.immediate()?;
+                let segment = self.word()?;
+                format!("jmp 0x{:x}:0x{:x}", segment, offset)
+            }
+            0xEB => {
+                let displacement = self.byte()? as i8 as i32;
+                format!("jmp short {}", self.relative_target(displacement))
+            }
+            0xEC => "in al,dx".to_owned(),
+            0xED => format!("in {},dx", accumulator),
+            0xEE => "out dx,al".to_owned(),
+            0xEF => format!("out dx,{}", accumulator),
+            0xF1 => "int1".to_owned(),
+            0xF4 => "hlt".to_owned(),
+            0xF5 => "cmc".to_owned(),
+            0xF6 | 0xF7 => {
+                let size = if opcode == 0xF6 { 8 } else { v };
+                let modrm = self.modrm()?;
+                let rm = self.rm_operand(&modrm, size, true);
+                match modrm.reg {
+                    0 | 1 => {
+                        let immediate = if size == 8 {
+                            self.byte()? as u32
+                        } else {
+                            self.immediate()?
+                        };
+                        format!("test {},0x{:x}", rm, immediate)
+                    }
+                    reg => {
+                        let name = ["", "", "not", "neg", "mul", "imul", "div", "idiv"][reg as usize];
+                        format!("{} {}", name, rm)
+                    }
+                }
+            }
+            0xF8 => "clc".to_owned(),
+            0xF9 => "stc".to_owned(),
+            0xFA => "cli".to_owned(),
+            0xFB => "sti".to_owned(),
+            0xFC => "cld".to_owned(),
+            0xFD => "std".to_owned(),
+            0xFE => {
+                let modrm = self.modrm()?;
+                let name = match modrm.reg {
+                    0 => "inc",
+                    1 => "dec",
+                    _ => return None,
+                };
+                format!("{} {}", name, self.rm_operand(&modrm, 8, true))
+            }
+            0xFF => {
+                let modrm = self.modrm()?;
+                match modrm.reg {
+                    0 => format!("inc {}", self.rm_operand(&modrm, v, true)),
+                    1 => format!("dec {}", self.rm_operand(&modrm, v, true)),
+                    2 => format!("call {}", self.rm_operand(&modrm, v, false)),
+                    3 => format!("call far {}", modrm.memory),
+                    4 => format!("jmp {}", self.rm_operand(&modrm, v, false)),
+                    5 => format!("jmp far {}", modrm.memory),
+                    6 => format!("push {}", self.rm_operand(&modrm, v, true)),
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        })
+    }
+
+    /// 80386 instructions behind the 0x0F escape
+    fn two_byte_opcode(&mut self) -> Option<String> {
+        let opcode = self.byte()?;
+        let v = self.variable_size();
+
+        Some(match opcode {
+            0x00 => {
+                let modrm = self.modrm()?;
+                let name = ["sldt", "str", "lldt", "ltr", "verr", "verw", "", ""][modrm.reg as usize];
+                if name.is_empty() {
+                    return None;
+                }
+                format!("{} {}", name, self.rm_operand(&modrm, 16, false))
+            }
+            0x01 => {
+                let modrm = self.modrm()?;
+                match modrm.reg {
+                    0 => format!("sgdt {}", modrm.memory),
+                    1 => format!("sidt {}", modrm.memory),
+                    2 => format!("lgdt {}", modrm.memory),
+                    3 => format!("lidt {}", modrm.memory),
+                    4 => format!("smsw {}", self.rm_operand(&modrm, 16, false)),
+                    6 => format!("lmsw {}", self.rm_operand(&modrm, 16, false)),
+                    7 => format!("invlpg {}", modrm.memory),
+                    _ => return None,
+                }
+            }
+            0x06 => "clts".to_owned(),
+            0x08 => "invd".to_owned(),
+            0x09 => "wbinvd".to_owned(),
+            0x0B => "ud2".to_owned(),
+            0x20..=0x23 => {
+                let modrm = self.modrm()?;
+                let special = if opcode & 1 == 0 { "cr" } else { "dr" };
+                let special = format!("{}{}", special, modrm.reg);
+                let register = REGISTERS_32[modrm.rm as usize];
+                if opcode & 2 == 0 {
+                    format!("mov {},{}", register, special)
+                } else {
+                    format!("mov {},{}", special, register)
+                }
+            }
+            0x30 => "wrmsr".to_owned(),
+            0x31 => "rdtsc".to_owned(),
+            0x32 => "rdmsr".to_owned(),
+            0x80..=0x8F => {
+                let displacement = if self.operand_32 {
+                    self.dword()? as i32
+                } else {
+                    self.word()? as i16 as i32
+                };
+                format!(
+                    "j{} near {}",
+                    CONDITIONS[(opcode & 0xF) as usize],
+                    self.relative_target(displacement)
+                )
+            }
+            0x90..=0x9F => {
+                let modrm = self.modrm()?;
+                format!(
+                    "set{} {}",
+                    CONDITIONS[(opcode & 0xF) as usize],
+                    self.rm_operand(&modrm, 8, false)
+                )
+            }
+            0xA0 => "push fs".to_owned(),
+            0xA1 => "pop fs".to_owned(),
+            0xA2 => "cpuid".to_owned(),
+            0xA8 => "push gs".to_owned(),
+            0xA9 => "pop gs".to_owned(),
+            0xA3 | 0xAB | 0xB3 | 0xBB => {
+                let name = match opcode {
+                    0xA3 => "bt",
+                    0xAB => "bts",
+                    0xB3 => "btr",
+                    _ => "btc",
+                };
+                let modrm = self.modrm()?;
+                format!(
+                    "{} {},{}",
+                    name,
+                    self.rm_operand(&modrm, v, false),
+                    self.register(v, modrm.reg)
+                )
+            }
+            0xA4 | 0xA5 | 0xAC | 0xAD => {
+                let name = if opcode < 0xAC { "shld" } else { "shrd" };
+                let modrm = self.modrm()?;
+                let rm = self.rm_operand(&modrm, v, false);
+                let count = if opcode & 1 == 0 {
+                    format!("0x{:x}", self.byte()?)
+                } else {
+                    "cl".to_owned()
+                };
+                format!("{} {},{},{}", name, rm, self.register(v, modrm.reg), count)
+            }
+            0xAF => {
+                let modrm = self.modrm()?;
+                format!(
+                    "imul {},{}",
+                    self.register(v, modrm.reg),
+                    self.rm_operand(&modrm, v, false)
+                )
+            }
+            0xB2 | 0xB4 | 0xB5 => {
+                let name = match opcode {
+                    0xB2 => "lss",
+                    0xB4 => "lfs",
+                    _ => "lgs",
+                };
+                let modrm = self.modrm()?;
+                format!("{} {},{}", name, self.register(v, modrm.reg), modrm.memory)
+            }
+            0xB6 | 0xB7 | 0xBE | 0xBF => {
+                let name = if opcode < 0xBE { "movzx" } else { "movsx" };
+                let size = if opcode & 1 == 0 { 8 } else { 16 };
+                let modrm = self.modrm()?;
+                format!(
+                    "{} {},{}",
+                    name,
+                    self.register(v, modrm.reg),
+                    self.rm_operand(&modrm, size, true)
+                )
+            }
+            0xBA => {
+                let modrm = self.modrm()?;
+                let name = match modrm.reg {
+                    4 => "bt",
+                    5 => "bts",
+                    6 => "btr",
+                    7 => "btc",
+                    _ => return None,
+                };
+                let rm = self.rm_operand(&modrm, v, true);
+                format!("{} {},0x{:x}", name, rm, self.byte()?)
+            }
+            0xBC | 0xBD => {
+                let name = if opcode == 0xBC { "bsf" } else { "bsr" };
+                let modrm = self.modrm()?;
+                format!(
+                    "{} {},{}",
+                    name,
+                    self.register(v, modrm.reg),
+                    self.rm_operand(&modrm, v, false)
+                )
+            }
+            0xC8..=0xCF => format!("bswap {}", REGISTERS_32[(opcode & 7) as usize]),
+            _ => return None,
+        })
+    }
+
+    fn sized_name(&self, name_16: &str, name_32: &str) -> String {
+        if self.operand_32 {
+            name_32.to_owned()
+        } else {
+            name_16.to_owned()
+        }
+    }
+}
+
+fn size_name(size: u8) -> &'static str {
+    match size {
+        8 => "byte",
+        32 => "dword",
+        _ => "word",
+    }
+}
+
+fn format_displacement(base: &str, displacement: i32) -> String {
+    if displacement < 0 {
+        format!("{}-0x{:x}", base, -(displacement as i64))
+    } else {
+        format!("{}+0x{:x}", base, displacement)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(code: &[u8], origin: u16) -> Vec<String> {
+        disassemble(code, origin).into_iter().map(|instruction| instruction.text).collect()
+    }
+
+    #[test]
+    fn decodes_boot_sector_setup() {
+        let code = [
+            0xFA, // cli
+            0x31, 0xC0, // xor ax,ax
+            0x8E, 0xD8, // mov ds,ax
+            0xBC, 0x00, 0x7C, // mov sp,0x7c00
+            0xCD, 0x13, // int 0x13
+            0xF3, 0xA4, // rep movsb
+            0xEA, 0x00, 0x00, 0x60, 0x00, // jmp 0x60:0x0
+        ];
+        assert_eq!(
+            texts(&code, 0x7C00),
+            [
+                "cli",
+                "xor ax,ax",
+                "mov ds,ax",
+                "mov sp,0x7c00",
+                "int 0x13",
+                "rep movsb",
+                "jmp 0x60:0x0"
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_memory_operands() {
+        let code = [
+            0x8A, 0x46, 0xFC, // mov al,[bp-0x4]
+            0x26, 0x8B, 0x07, // mov ax,[es:bx]
+            0xC6, 0x06, 0x34, 0x12, 0x05, // mov byte [0x1234],0x5
+            0x83, 0xC4, 0xFE, // add sp,-0x2
+            0xD1, 0xE0, // shl ax,1
+            0x66, 0x31, 0xC0, // xor eax,eax
+        ];
+        assert_eq!(
+            texts(&code, 0),
+            [
+                "mov al,[bp-0x4]",
+                "mov ax,[es:bx]",
+                "mov byte [0x1234],0x5",
+                "add sp,-0x2",
+                "shl ax,1",
+                "xor eax,eax"
+            ]
+        );
+    }
+
+    #[test]
+    fn works_out_branch_targets_from_the_origin() {
+        let jump = decode(&[0x72, 0xFE], 0, 0x7C00);
+        assert_eq!((jump.text.as_str(), jump.target), ("jb short 0x7c00", Some(0x7C00)));
+        let call = decode(&[0x90, 0xE8, 0x10, 0x00], 1, 0x7C00);
+        assert_eq!((call.address, call.target), (0x7C01, Some(0x7C14)));
+        let back = decode(&[0xE2, 0xFA], 0, 0x7C00);
+        assert_eq!(back.target, Some(0x7BFC));
+    }
+
+    #[test]
+    fn cut_off_and_unknown_opcodes_become_bytes() {
+        assert_eq!(texts(&[0xB8, 0x01], 0), ["db 0xb8", "db 0x01"]);
+        assert_eq!(decode(&[0x0F], 0, 0).text, "db 0x0f");
+    }
+
+    #[test]
+    fn lists_padding_and_labels() {
+        let code = [0xEB, 0xFE, 0, 0, 0, 0, 0, 0xC3];
+        assert_eq!(
+            format_listing(&disassemble(&code, 0x100), None),
+            [
+                "loc_0100:",
+                "0100  EBFE              jmp short 0x100",
+                "0102  0000000000        times 5 db 0",
+                "0107  C3                ret"
+            ]
+        );
+    }
+}
@@ -0,0 +1,272 @@
+//! Several images open at once, one on each drive, and copying files between them.
+//!
+//! The shell starts on drive `A`, and `use B` opens another drive to put an image on. Drives can
+//! have longer names too, like `use BOOT`. Paths given to `cp` and `mv` can start with a drive,
+//! like `B:/SYS/KERNEL.BIN`, and are on the current drive when they don't.
+
+use crate::block_device::BlockDevice;
+use crate::directories::{
+    expand_glob, find_in_directory, find_path, has_wildcards, is_directory, resolve_directory, split_path,
+};
+use crate::fat_error::{FatError, Result};
+use crate::read_file::read_file;
+use crate::long_names::{add_named_entry, long_name_of, validate_long_name};
+use crate::remove_file::{delete_entry, remove_entry};
+use crate::root_dir_util::{directory_entry, display_name, entry_file_size, entry_first_cluster, write_directory_entry};
+use crate::shell_files::store_file;
+use crate::shell_parsing::Args;
+use crate::shell_state::ShellState;
+use crate::volume::Volume;
+
+/// A file read from one drive, to be written to another
+struct SourceFile {
+    path: String,
+    // Where its directory entry is, to remove it after a move
+    dir_cluster: usize,
+    offset: usize,
+    entry: Vec<u8>,
+    long_name: Option<String>,
+    data: Vec<u8>,
+}
+
+impl SourceFile {
+    /// The long name if it has one, so copies keep it
+    fn name(&self) -> String {
+        self.long_name.clone().unwrap_or_else(|| display_name(&self.entry))
+    }
+}
+
+/// `b`, `B` or `B:` as the drive name `B`
+pub fn parse_drive(text: &str) -> Result<String> {
+    let name = text.strip_suffix(':').unwrap_or(text);
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        return Err(FatError::BadArgument(format!("{} isn't a drive", text)));
+    }
+    Ok(name.to_ascii_uppercase())
+}
+
+/// Splits `B:/DIR/FILE` into the drive and the path on it, the current drive if there's no prefix
+pub fn split_drive<'a>(shell_state: &ShellState, path: &'a str) -> Result<(String, &'a str)> {
+    match path.split_once(':') {
+        Some((drive, rest)) => Ok((parse_drive(drive)?, rest)),
+        None => Ok((shell_state.get_drive().to_owned(), path)),
+    }
+}
+
+/// use [drive]
+pub fn use_drive(shell_state: ShellState, args: &Args) -> Result<ShellState> {
+    let Some(drive) = args.get(1) else {
+        let current = shell_state.get_drive().to_owned();
+        let mut drives = shell_state.open_drives();
+        if !drives.contains(&current) {
+            drives.push(current.clone());
+            drives.sort();
+        }
+        for drive in drives {
+            let marker = if drive == current { '*' } else { ' ' };
+            let image_filename = shell_state.drive_filename(&drive).unwrap_or("no image");
+            println!("{} {}: {}", marker, drive, image_filename);
+        }
+        return Ok(shell_state);
+    };
+
+    let shell_state = shell_state.use_drive(&parse_drive(&drive)?);
+    match shell_state.drive_filename(shell_state.get_drive()) {
+        Some(image_filename) => println!("Now on {}: ({})", shell_state.get_drive(), image_filename),
+        None => println!("Now on {}:, which has no image open", shell_state.get_drive()),
+    }
+    Ok(shell_state)
+}
+
+/// cp <file or pattern> <file or directory>
+///
+/// Copies keep the attributes and times of the originals.
+pub fn copy_file(mut shell_state: ShellState, args: &Args) -> Result<ShellState> {
+    let source = args.expect(1, "file to copy")?;
+    let target = args.expect(2, "where to copy it")?;
+    let (source_drive, source_path) = split_drive(&shell_state, &source)?;
+    let (target_drive, target_path) = split_drive(&shell_state, &target)?;
+    let several = has_wildcards(split_path(source_path).1);
+
+    let files = read_sources(&shell_state, &source_drive, source_path)?;
+    let (mut volume, cwd) = shell_state.drive_volume_mut(&target_drive)?;
+    for file in &files {
+        let (dir_cluster, name, shown) = target_location(&mut volume, cwd, target_path, &file.name(), several)?;
+        if source_drive == target_drive && find_in_directory(&mut volume, dir_cluster, &name)? == Some(file.offset) {
+            return Err(FatError::BadArgument(format!("Can't copy {} onto itself", file.path)));
+        }
+        store_file(&mut volume, dir_cluster, &name, &file.data, file.entry.clone())?;
+        println!("Copied {}:{} to {}:{}!", source_drive, file.path, target_drive, shown);
+    }
+
+    Ok(shell_state)
+}
+
+/// mv <file, directory or pattern> <name or directory>
+///
+/// On the same drive the entries move and the data stays put, so directories can move too.
+/// Between drives only files can, being copied and then removed.
+pub fn move_file(mut shell_state: ShellState, args: &Args) -> Result<ShellState> {
+    let source = args.expect(1, "file to move")?;
+    let target = args.expect(2, "where to move it")?;
+    let (source_drive, source_path) = split_drive(&shell_state, &source)?;
+    let (target_drive, target_path) = split_drive(&shell_state, &target)?;
+    let several = has_wildcards(split_path(source_path).1);
+
+    if source_drive == target_drive {
+        let (mut volume, cwd) = shell_state.drive_volume_mut(&source_drive)?;
+        for path in matching_paths(&mut volume, cwd, source_path)? {
+            let shown = relocate(&mut volume, cwd, &path, target_path, several)?;
+            println!("Moved {}:{} to {}:{}!", source_drive, path, target_drive, shown);
+        }
+        return Ok(shell_state);
+    }
+
+    // Find out the files can't be removed before copying any
+    shell_state.drive_volume_mut(&source_drive)?;
+    let files = read_sources(&shell_state, &source_drive, source_path)?;
+    let (mut volume, cwd) = shell_state.drive_volume_mut(&target_drive)?;
+    for file in &files {
+        let (dir_cluster, name, shown) = target_location(&mut volume, cwd, target_path, &file.name(), several)?;
+        store_file(&mut volume, dir_cluster, &name, &file.data, file.entry.clone())?;
+        println!("Moved {}:{} to {}:{}!", source_drive, file.path, target_drive, shown);
+    }
+    let (mut volume, _) = shell_state.drive_volume_mut(&source_drive)?;
+    for file in &files {
+        remove_entry(&mut volume, file.dir_cluster, file.offset)?;
+    }
+
+    Ok(shell_state)
+}
+
+/// The path, or every path matching it if its last part has wildcards
+fn matching_paths<D: BlockDevice>(volume: &mut Volume<D>, cwd: usize, path: &str) -> Result<Vec<String>> {
+    if has_wildcards(split_path(path).1) {
+        expand_glob(volume, cwd, path)
+    } else {
+        Ok(vec![path.to_owned()])
+    }
+}
+
+/// Reads the files a path or pattern names on `drive`
+///
+/// Directories a pattern matches are passed over, one named outright is refused.
+fn read_sources(shell_state: &ShellState, drive: &str, pattern: &str) -> Result<Vec<SourceFile>> {
+    let (mut volume, cwd) = shell_state.drive_volume(drive)?;
+    let mut files = vec![];
+    for path in matching_paths(&mut volume, cwd, pattern)? {
+        let (dir_cluster, offset) = find_path(&mut volume, cwd, &path)?;
+        let entry = directory_entry(&mut volume, offset)?;
+        if is_directory(&entry) {
+            if has_wildcards(split_path(pattern).1) {
+                continue;
+            }
+            return Err(FatError::IsADirectory(path));
+        }
+        let mut data = read_file(&mut volume, entry_first_cluster(&entry))?;
+        data.truncate(entry_file_size(&entry));
+        let long_name = long_name_of(&mut volume, dir_cluster, offset)?;
+        files.push(SourceFile {
+            path,
+            dir_cluster,
+            offset,
+            entry,
+            long_name,
+            data,
+        });
+    }
+    if files.is_empty() {
+        return Err(FatError::NotFound(pattern.to_owned()));
+    }
+    Ok(files)
+}
+
+/// Where an entry called `name` goes: into the directory `target_path` names, keeping its name, or
+/// else to the name it ends with. Several entries can only go into a directory.
+///
+/// Returns the directory's first cluster, the name in it and the path to show.
+fn target_location<D: BlockDevice>(
+    volume: &mut Volume<D>,
+    cwd: usize,
+    target_path: &str,
+    name: &str,
+    several: bool,
+) -> Result<(usize, String, String)> {
+    match resolve_directory(volume, cwd, target_path) {
+        Ok(dir_cluster) => {
+            let shown = match target_path {
+                "" => name.to_owned(),
+                _ if target_path.ends_with('/') => format!("{}{}", target_path, name),
+                _ => format!("{}/{}", target_path, name),
+            };
+            Ok((dir_cluster, name.to_owned(), shown))
+        }
+        Err(error) if several => Err(error),
+        Err(_) => {
+            let (dir_path, new_name) = split_path(target_path);
+            let dir_cluster = resolve_directory(volume, cwd, dir_path)?;
+            Ok((dir_cluster, new_name.to_owned(), target_path.to_owned()))
+        }
+    }
+}
+
+/// Moves an entry to another directory or name on the same volume, leaving its clusters where they are
+///
+/// A file already at the target is replaced, but not a directory. Returns the path to show.
+fn relocate<D: BlockDevice>(
+    volume: &mut Volume<D>,
+    cwd: usize,
+    path: &str,
+    target_path: &str,
+    several: bool,
+) -> Result<String> {
+    let (source_dir, offset) = find_path(volume, cwd, path)?;
+    let entry = directory_entry(volume, offset)?;
+    let name = display_name(&entry);
+    if name == "." || name == ".." {
+        return Err(FatError::BadArgument(format!("Can't move {}", path)));
+    }
+    let moving_directory = is_directory(&entry);
+    let name = long_name_of(volume, source_dir, offset)?.unwrap_or(name);
+
+    let (dir_cluster, new_name, shown) = target_location(volume, cwd, target_path, &name, several)?;
+    validate_long_name(&new_name)?;
+    if let Some(existing) = find_in_directory(volume, dir_cluster, &new_name)? {
+        if existing == offset {
+            return Ok(shown);
+        }
+        if moving_directory || is_directory(&directory_entry(volume, existing)?) {
+            return Err(FatError::AlreadyExists(shown));
+        }
+        remove_entry(volume, dir_cluster, existing)?;
+    }
+
+    let moved_cluster = entry_first_cluster(&entry);
+    if moving_directory {
+        // Going up from the target must never pass the directory itself
+        let mut cluster = dir_cluster;
+        for _ in 0..volume.bpb().cluster_count() {
+            if cluster == moved_cluster {
+                return Err(FatError::BadArgument(format!("Can't move {} into itself", path)));
+            }
+            if cluster == 0 {
+                break;
+            }
+            cluster = resolve_directory(volume, cluster, "..")?;
+        }
+    }
+
+    // The old name goes first, so a rename can keep its short name
+    delete_entry(volume, source_dir, offset)?;
+    add_named_entry(volume, dir_cluster, &new_name, entry)?;
+
+    // A directory's `..` points at the directory it's in
+    if moving_directory {
+        if let Some(dot_dot) = find_in_directory(volume, moved_cluster, "..")? {
+            let mut dot_dot_entry = directory_entry(volume, dot_dot)?;
+            dot_dot_entry[26..28].copy_from_slice(&(dir_cluster as u16).to_le_bytes());
+            write_directory_entry(volume, dot_dot, &dot_dot_entry)?;
+        }
+    }
+    Ok(shown)
+}
@@ -1,6 +1,8 @@
-use crate::shell_state::ShellState;
-
-/// Not implemented yet
-pub fn editfile(shell_state: ShellState, _args: Vec<&str>) -> ShellState {
-    shell_state
-}
+use crate::fat_error::Result;
+use crate::shell_parsing::Args;
+use crate::shell_state::ShellState;
+
+/// Not implemented yet
+pub fn editfile(shell_state: ShellState, _args: &Args) -> Result<ShellState> {
+    Ok(shell_state)
+}
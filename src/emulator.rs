@@ -0,0 +1,1407 @@
+use crate::bios_parameter_block::{
+    BYTES_PER_SECTOR, HEADS_PER_CYLINDER, HEADS_PER_CYLINDER_OFFSET, SECTORS_PER_TRACK,
+    SECTORS_PER_TRACK_OFFSET,
+};
+use crate::block_device::BlockDevice;
+use crate::boot_template::BOOT_ORIGIN;
+use crate::bootsector::parse_segment_offset;
+use crate::change_trace::record_read;
+use crate::disassembler::decode;
+use crate::fat_error::{self, FatError};
+use crate::shell_parsing::{expect_number, Args};
+use crate::shell_state::ShellState;
+use std::collections::VecDeque;
+
+// Bits of the flags register
+const CF: u16 = 1 << 0;
+const PF: u16 = 1 << 2;
+const AF: u16 = 1 << 4;
+const ZF: u16 = 1 << 6;
+const SF: u16 = 1 << 7;
+const TF: u16 = 1 << 8;
+const IF: u16 = 1 << 9;
+const DF: u16 = 1 << 10;
+const OF: u16 = 1 << 11;
+
+// Register indexes, in ModR/M order
+const AX: usize = 0;
+const CX: usize = 1;
+const DX: usize = 2;
+const BX: usize = 3;
+const SP: usize = 4;
+const BP: usize = 5;
+const SI: usize = 6;
+const DI: usize = 7;
+
+// Segment register indexes, in ModR/M order
+const ES: usize = 0;
+const CS: usize = 1;
+const SS: usize = 2;
+const DS: usize = 3;
+
+/// Every interrupt vector points into this segment until the guest replaces it
+const BIOS_SEGMENT: u16 = 0xF000;
+/// 1 MiB, addresses past it wrap around like a PC with A20 off
+const MEMORY_SIZE: usize = 0x100000;
+/// The key returned when the guest reads the keyboard and nothing was queued
+const ENTER_KEY: u16 = 0x1C0D;
+
+/// Why the emulator stopped
+pub enum Stop {
+    /// Reached the configured address
+    ReachedAddress,
+    /// The configured marker showed up in the screen output
+    FoundMarker,
+    /// Left the boot sector, the default success condition
+    LeftBootSector,
+    InstructionLimit,
+    Halted,
+    /// INT 18h or INT 19h, what boot sectors do when they give up
+    Rebooted,
+    DivideError,
+    Unsupported(String),
+}
+
+impl Stop {
+    pub fn is_success(&self) -> bool {
+        matches!(
+            self,
+            Stop::ReachedAddress | Stop::FoundMarker | Stop::LeftBootSector
+        )
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            Stop::ReachedAddress => "reached the target address".to_owned(),
+            Stop::FoundMarker => "printed the marker".to_owned(),
+            Stop::LeftBootSector => "jumped out of the boot sector".to_owned(),
+            Stop::InstructionLimit => "hit the instruction limit".to_owned(),
+            Stop::Halted => "halted".to_owned(),
+            Stop::Rebooted => "gave up and asked the BIOS to reboot".to_owned(),
+            Stop::DivideError => "divided by zero".to_owned(),
+            Stop::Unsupported(what) => format!("ran an unsupported {}", what),
+        }
+    }
+}
+
+/// Where a ModR/M operand lives
+#[derive(Clone, Copy)]
+enum Location {
+    Register(usize),
+    Memory(u16, u16),
+}
+
+/// A minimal 8086 (plus the common 80186 additions) with a BIOS for the boot disk
+pub struct Emulator {
+    registers: [u16; 8],
+    segments: [u16; 4],
+    ip: u16,
+    flags: u16,
+    memory: Vec<u8>,
+    /// The emulator writes to its own copy of the image
+    disk: Box<dyn BlockDevice>,
+    sectors_per_track: usize,
+    heads: usize,
+    /// Everything the guest printed through INT 10h
+    pub output: String,
+    keys: VecDeque<u16>,
+    pub instructions: u64,
+    segment_override: Option<usize>,
+}
+
+impl Emulator {
+    /// Sets up the machine the way a BIOS hands it to the boot sector
+    pub fn new(mut disk: Box<dyn BlockDevice>) -> fat_error::Result<Self> {
+        // Boot sector at 0000:7C00
+        record_read(0, BYTES_PER_SECTOR);
+        let mut boot_sector = vec![0; BYTES_PER_SECTOR];
+        disk.read_sectors(0, &mut boot_sector)?;
+
+        let geometry = |offset: usize, default: usize| {
+            let value = boot_sector[offset] as usize | (boot_sector[offset + 1] as usize) << 8;
+            if value == 0 {
+                default
+            } else {
+                value
+            }
+        };
+
+        let mut emulator = Emulator {
+            registers: [0; 8],
+            segments: [0; 4],
+            ip: BOOT_ORIGIN,
+            flags: 0x0002 | IF,
+            memory: vec![0; MEMORY_SIZE],
+            disk,
+            sectors_per_track: geometry(SECTORS_PER_TRACK_OFFSET, SECTORS_PER_TRACK),
+            heads: geometry(HEADS_PER_CYLINDER_OFFSET, HEADS_PER_CYLINDER),
+            output: String::new(),
+            keys: VecDeque::new(),
+            instructions: 0,
+            segment_override: None,
+        };
+
+        // Interrupt vector n points at F000:n, which holds an IRET
+        for vector in 0..256 {
+            emulator.write16(0, vector * 4, vector);
+            emulator.write16(0, vector * 4 + 2, BIOS_SEGMENT);
+            emulator.write8(BIOS_SEGMENT, vector, 0xCF);
+        }
+        // BIOS data area: 640 KiB of conventional memory
+        emulator.write16(0x40, 0x13, 640);
+
+        // Boot drive in DL, stack just below the boot sector
+        emulator.memory[BOOT_ORIGIN as usize..BOOT_ORIGIN as usize + BYTES_PER_SECTOR].copy_from_slice(&boot_sector);
+        emulator.registers[SP] = BOOT_ORIGIN;
+        Ok(emulator)
+    }
+
+    /// Whether the boot sector ends in 55 AA, without which a BIOS won't boot it
+    pub fn has_boot_signature(&self) -> bool {
+        let end = BOOT_ORIGIN as usize + BYTES_PER_SECTOR;
+        self.memory[end - 2..end] == [0x55, 0xAA]
+    }
+
+    /// Queues keys for INT 16h, as ASCII characters with no scan code
+    pub fn queue_keys(&mut self, keys: &str) {
+        self.keys.extend(keys.bytes().map(|key| key as u16));
+    }
+
+    pub fn code_segment(&self) -> u16 {
+        self.segments[CS]
+    }
+
+    pub fn instruction_pointer(&self) -> u16 {
+        self.ip
+    }
+
+    pub fn linear_ip(&self) -> usize {
+        linear(self.segments[CS], self.ip)
+    }
+
+    /// One trace line: address, decoded instruction and the registers before it runs
+    pub fn trace_line(&self) -> String {
+        let code: Vec<u8> = (0..15)
+            .map(|i| self.read8(self.segments[CS], self.ip.wrapping_add(i)))
+            .collect();
+        let instruction = decode(&code, 0, self.ip);
+        let hex: String = instruction
+            .bytes
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect();
+
+        format!(
+            "{:04X}:{:04X}  {:<12} {:<28} AX={:04X} BX={:04X} CX={:04X} DX={:04X} SI={:04X} DI={:04X} SP={:04X} DS={:04X} ES={:04X} FL={:04X}",
+            self.segments[CS],
+            self.ip,
+            hex,
+            instruction.text,
+            self.registers[AX],
+            self.registers[BX],
+            self.registers[CX],
+            self.registers[DX],
+            self.registers[SI],
+            self.registers[DI],
+            self.registers[SP],
+            self.segments[DS],
+            self.segments[ES],
+            self.flags
+        )
+    }
+
+    fn read8(&self, segment: u16, offset: u16) -> u8 {
+        self.memory[linear(segment, offset)]
+    }
+
+    fn read16(&self, segment: u16, offset: u16) -> u16 {
+        self.read8(segment, offset) as u16 | (self.read8(segment, offset.wrapping_add(1)) as u16) << 8
+    }
+
+    fn write8(&mut self, segment: u16, offset: u16, value: u8) {
+        self.memory[linear(segment, offset)] = value;
+    }
+
+    fn write16(&mut self, segment: u16, offset: u16, value: u16) {
+        self.write8(segment, offset, value as u8);
+        self.write8(segment, offset.wrapping_add(1), (value >> 8) as u8);
+    }
+
+    fn fetch8(&mut self) -> u8 {
+        let byte = self.read8(self.segments[CS], self.ip);
+        self.ip = self.ip.wrapping_add(1);
+        byte
+    }
+
+    fn fetch16(&mut self) -> u16 {
+        self.fetch8() as u16 | (self.fetch8() as u16) << 8
+    }
+
+    fn push(&mut self, value: u16) {
+        self.registers[SP] = self.registers[SP].wrapping_sub(2);
+        self.write16(self.segments[SS], self.registers[SP], value);
+    }
+
+    fn pop(&mut self) -> u16 {
+        let value = self.read16(self.segments[SS], self.registers[SP]);
+        self.registers[SP] = self.registers[SP].wrapping_add(2);
+        value
+    }
+
+    fn flag(&self, flag: u16) -> bool {
+        self.flags & flag != 0
+    }
+
+    fn set_flag(&mut self, flag: u16, on: bool) {
+        if on {
+            self.flags |= flag;
+        } else {
+            self.flags &= !flag;
+        }
+    }
+
+    fn register(&self, index: usize, word: bool) -> u16 {
+        if word {
+            self.registers[index]
+        } else if index < 4 {
+            self.registers[index] & 0xFF
+        } else {
+            self.registers[index - 4] >> 8
+        }
+    }
+
+    fn set_register(&mut self, index: usize, word: bool, value: u16) {
+        if word {
+            self.registers[index] = value;
+        } else if index < 4 {
+            self.registers[index] = (self.registers[index] & 0xFF00) | (value & 0xFF);
+        } else {
+            self.registers[index - 4] = (self.registers[index - 4] & 0x00FF) | (value & 0xFF) << 8;
+        }
+    }
+
+    /// Segment for data accesses, honouring a segment override prefix
+    fn data_segment(&self, default: usize) -> u16 {
+        self.segments[self.segment_override.unwrap_or(default)]
+    }
+
+    /// Decodes a ModR/M byte into the reg field and the r/m location
+    fn modrm(&mut self) -> (usize, Location) {
+        let byte = self.fetch8();
+        let mode = byte >> 6;
+        let reg = ((byte >> 3) & 7) as usize;
+        let rm = (byte & 7) as usize;
+
+        if mode == 3 {
+            return (reg, Location::Register(rm));
+        }
+
+        let (base, default_segment) = match rm {
+            0 => (self.registers[BX].wrapping_add(self.registers[SI]), DS),
+            1 => (self.registers[BX].wrapping_add(self.registers[DI]), DS),
+            2 => (self.registers[BP].wrapping_add(self.registers[SI]), SS),
+            3 => (self.registers[BP].wrapping_add(self.registers[DI]), SS),
+            4 => (self.registers[SI], DS),
+            5 => (self.registers[DI], DS),
+            6 if mode == 0 => (0, DS),
+            6 => (self.registers[BP], SS),
+            _ => (self.registers[BX], DS),
+        };
+        let displacement = match (mode, rm) {
+            (0, 6) => self.fetch16(),
+            (0, _) => 0,
+            (1, _) => self.fetch8() as i8 as u16,
+            _ => self.fetch16(),
+        };
+
+        let segment = self.data_segment(default_segment);
+        (reg, Location::Memory(segment, base.wrapping_add(displacement)))
+    }
+
+    fn get(&self, location: Location, word: bool) -> u16 {
+        match location {
+            Location::Register(index) => self.register(index, word),
+            Location::Memory(segment, offset) if word => self.read16(segment, offset),
+            Location::Memory(segment, offset) => self.read8(segment, offset) as u16,
+        }
+    }
+
+    fn set(&mut self, location: Location, word: bool, value: u16) {
+        match location {
+            Location::Register(index) => self.set_register(index, word, value),
+            Location::Memory(segment, offset) if word => self.write16(segment, offset, value),
+            Location::Memory(segment, offset) => self.write8(segment, offset, value as u8),
+        }
+    }
+
+    /// Far pointer stored at a memory operand, for LES/LDS and indirect far jumps
+    fn far_pointer(&self, location: Location) -> Result<(u16, u16), Stop> {
+        match location {
+            Location::Memory(segment, offset) => Ok((
+                self.read16(segment, offset.wrapping_add(2)),
+                self.read16(segment, offset),
+            )),
+            Location::Register(_) => Err(Stop::Unsupported("far pointer in a register".to_owned())),
+        }
+    }
+
+    fn set_sign_zero_parity(&mut self, result: u32, word: bool) {
+        let (mask, sign) = if word { (0xFFFF, 0x8000) } else { (0xFF, 0x80) };
+        self.set_flag(ZF, result & mask == 0);
+        self.set_flag(SF, result & sign != 0);
+        self.set_flag(PF, (result as u8).count_ones().is_multiple_of(2));
+    }
+
+    /// The eight ALU operations in ModR/M order: add or adc sbb and sub xor cmp
+    fn alu(&mut self, operation: usize, a: u16, b: u16, word: bool) -> u16 {
+        let (mask, sign) = if word {
+            (0xFFFFu32, 0x8000u32)
+        } else {
+            (0xFF, 0x80)
+        };
+        let (a, b) = (a as u32 & mask, b as u32 & mask);
+        let carry = self.flag(CF) as u32;
+
+        let result = match operation {
+            0 | 2 => {
+                let carry = if operation == 2 { carry } else { 0 };
+                let result = a + b + carry;
+                self.set_flag(CF, result > mask);
+                self.set_flag(OF, (a ^ result) & (b ^ result) & sign != 0);
+                self.set_flag(AF, (a ^ b ^ result) & 0x10 != 0);
+                result
+            }
+            3 | 5 | 7 => {
+                let carry = if operation == 3 { carry } else { 0 };
+                let result = a.wrapping_sub(b).wrapping_sub(carry);
+                self.set_flag(CF, b + carry > a);
+                self.set_flag(OF, (a ^ b) & (a ^ result) & sign != 0);
+                self.set_flag(AF, (a ^ b ^ result) & 0x10 != 0);
+                result
+            }
+            _ => {
+                let result = match operation {
+                    1 => a | b,
+                    4 => a & b,
+                    _ => a ^ b,
+                };
+                self.set_flag(CF, false);
+                self.set_flag(OF, false);
+                result
+            }
+        };
+
+        self.set_sign_zero_parity(result, word);
+        (result & mask) as u16
+    }
+
+    /// INC and DEC leave the carry flag alone
+    fn increment(&mut self, value: u16, word: bool, delta: i8) -> u16 {
+        let carry = self.flag(CF);
+        let result = if delta > 0 {
+            self.alu(0, value, 1, word)
+        } else {
+            self.alu(5, value, 1, word)
+        };
+        self.set_flag(CF, carry);
+        result
+    }
+
+    /// The eight shifts and rotates in ModR/M order
+    fn shift(&mut self, operation: usize, value: u16, count: u8, word: bool) -> u16 {
+        let count = count & 0x1F;
+        if count == 0 {
+            return value;
+        }
+
+        let bits = if word { 16 } else { 8 };
+        let mask: u32 = if word { 0xFFFF } else { 0xFF };
+        let sign: u32 = 1 << (bits - 1);
+        let original = value as u32 & mask;
+        let mut result = original;
+
+        for _ in 0..count {
+            let carry = self.flag(CF) as u32;
+            let (new_result, new_carry) = match operation {
+                0 => (((result << 1) | (result >> (bits - 1))) & mask, result & sign != 0),
+                1 => ((result >> 1) | ((result & 1) << (bits - 1)), result & 1 != 0),
+                2 => (((result << 1) | carry) & mask, result & sign != 0),
+                3 => ((result >> 1) | (carry << (bits - 1)), result & 1 != 0),
+                4 | 6 => ((result << 1) & mask, result & sign != 0),
+                5 => (result >> 1, result & 1 != 0),
+                _ => ((result >> 1) | (result & sign), result & 1 != 0),
+            };
+            result = new_result;
+            self.set_flag(CF, new_carry);
+        }
+
+        let overflow = match operation {
+            0 | 2 | 4 | 6 => ((result & sign) != 0) != self.flag(CF),
+            1 | 3 => (result ^ (result << 1)) & sign != 0,
+            5 => original & sign != 0,
+            _ => false,
+        };
+        self.set_flag(OF, overflow);
+        if operation >= 4 {
+            self.set_sign_zero_parity(result, word);
+        }
+
+        result as u16
+    }
+
+    fn condition(&self, code: u8) -> bool {
+        let result = match code >> 1 {
+            0 => self.flag(OF),
+            1 => self.flag(CF),
+            2 => self.flag(ZF),
+            3 => self.flag(CF) || self.flag(ZF),
+            4 => self.flag(SF),
+            5 => self.flag(PF),
+            6 => self.flag(SF) != self.flag(OF),
+            _ => self.flag(ZF) || self.flag(SF) != self.flag(OF),
+        };
+        if code & 1 == 1 {
+            !result
+        } else {
+            result
+        }
+    }
+
+    fn jump_relative(&mut self, displacement: u16) {
+        self.ip = self.ip.wrapping_add(displacement);
+    }
+
+    /// F6/F7 group: test not neg mul imul div idiv
+    fn group3(&mut self, operation: usize, location: Location, word: bool) -> Result<(), Stop> {
+        let value = self.get(location, word);
+        match operation {
+            0 | 1 => {
+                let immediate = if word {
+                    self.fetch16()
+                } else {
+                    self.fetch8() as u16
+                };
+                self.alu(4, value, immediate, word);
+            }
+            2 => self.set(location, word, !value),
+            3 => {
+                let result = self.alu(5, 0, value, word);
+                self.set(location, word, result);
+            }
+            4 if word => {
+                let result = self.registers[AX] as u32 * value as u32;
+                self.registers[AX] = result as u16;
+                self.registers[DX] = (result >> 16) as u16;
+                self.set_flag(CF, result >> 16 != 0);
+                self.set_flag(OF, result >> 16 != 0);
+            }
+            4 => {
+                let result = (self.registers[AX] & 0xFF) * value;
+                self.registers[AX] = result;
+                self.set_flag(CF, result >> 8 != 0);
+                self.set_flag(OF, result >> 8 != 0);
+            }
+            5 if word => {
+                let result = self.registers[AX] as i16 as i32 * value as i16 as i32;
+                self.registers[AX] = result as u16;
+                self.registers[DX] = (result >> 16) as u16;
+                let overflow = result != result as i16 as i32;
+                self.set_flag(CF, overflow);
+                self.set_flag(OF, overflow);
+            }
+            5 => {
+                let result = (self.registers[AX] as i8 as i16) * (value as i8 as i16);
+                self.registers[AX] = result as u16;
+                let overflow = result != result as i8 as i16;
+                self.set_flag(CF, overflow);
+                self.set_flag(OF, overflow);
+            }
+            6 if word => {
+                let dividend = (self.registers[DX] as u32) << 16 | self.registers[AX] as u32;
+                if value == 0 || dividend / value as u32 > 0xFFFF {
+                    return Err(Stop::DivideError);
+                }
+                self.registers[AX] = (dividend / value as u32) as u16;
+                self.registers[DX] = (dividend % value as u32) as u16;
+            }
+            6 => {
+                let dividend = self.registers[AX];
+                if value == 0 || dividend / value > 0xFF {
+                    return Err(Stop::DivideError);
+                }
+                self.registers[AX] = (dividend % value) << 8 | (dividend / value);
+            }
+            7 if word => {
+                let dividend = ((self.registers[DX] as u32) << 16 | self.registers[AX] as u32) as i32;
+                let divisor = value as i16 as i32;
+                if divisor == 0 || dividend / divisor != (dividend / divisor) as i16 as i32 {
+                    return Err(Stop::DivideError);
+                }
+                self.registers[AX] = (dividend / divisor) as u16;
+                self.registers[DX] = (dividend % divisor) as u16;
+            }
+            _ => {
+                let dividend = self.registers[AX] as i16;
+                let divisor = value as i8 as i16;
+                if divisor == 0 || dividend / divisor != (dividend / divisor) as i8 as i16 {
+                    return Err(Stop::DivideError);
+                }
+                let quotient = (dividend / divisor) as u8 as u16;
+                let remainder = (dividend % divisor) as u8 as u16;
+                self.registers[AX] = remainder << 8 | quotient;
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs one string instruction, repeating it for REP prefixes
+    fn string_operation(&mut self, opcode: u8, repeat: Option<u8>) {
+        let word = opcode & 1 == 1;
+        let size = if word { 2 } else { 1 };
+        let delta = if self.flag(DF) { 0u16.wrapping_sub(size) } else { size };
+
+        loop {
+            if repeat.is_some() && self.registers[CX] == 0 {
+                break;
+            }
+
+            let source = self.data_segment(DS);
+            match opcode {
+                // movs
+                0xA4 | 0xA5 => {
+                    let value = self.get(Location::Memory(source, self.registers[SI]), word);
+                    self.set(Location::Memory(self.segments[ES], self.registers[DI]), word, value);
+                    self.registers[SI] = self.registers[SI].wrapping_add(delta);
+                    self.registers[DI] = self.registers[DI].wrapping_add(delta);
+                }
+                // cmps
+                0xA6 | 0xA7 => {
+                    let a = self.get(Location::Memory(source, self.registers[SI]), word);
+                    let b = self.get(Location::Memory(self.segments[ES], self.registers[DI]), word);
+                    self.alu(7, a, b, word);
+                    self.registers[SI] = self.registers[SI].wrapping_add(delta);
+                    self.registers[DI] = self.registers[DI].wrapping_add(delta);
+                }
+                // stos
+                0xAA | 0xAB => {
+                    let value = self.register(AX, word);
+                    self.set(Location::Memory(self.segments[ES], self.registers[DI]), word, value);
+                    self.registers[DI] = self.registers[DI].wrapping_add(delta);
+                }
+                // lods
+                0xAC | 0xAD => {
+                    let value = self.get(Location::Memory(source, self.registers[SI]), word);
+                    self.set_register(AX, word, value);
+                    self.registers[SI] = self.registers[SI].wrapping_add(delta);
+                }
+                // scas
+                _ => {
+                    let a = self.register(AX, word);
+                    let b = self.get(Location::Memory(self.segments[ES], self.registers[DI]), word);
+                    self.alu(7, a, b, word);
+                    self.registers[DI] = self.registers[DI].wrapping_add(delta);
+                }
+            }
+
+            let Some(prefix) = repeat else { break };
+            self.registers[CX] = self.registers[CX].wrapping_sub(1);
+
+            // CMPS and SCAS also stop on the zero flag: F3 is REPE, F2 is REPNE
+            let compares = matches!(opcode, 0xA6 | 0xA7 | 0xAE | 0xAF);
+            if compares && self.flag(ZF) != (prefix == 0xF3) {
+                break;
+            }
+        }
+    }
+
+    /// Handles INT n, calling the built-in BIOS unless the guest hooked the vector
+    fn interrupt(&mut self, vector: u8) -> Result<(), Stop> {
+        let offset = self.read16(0, vector as u16 * 4);
+        let segment = self.read16(0, vector as u16 * 4 + 2);
+
+        if segment == BIOS_SEGMENT && offset == vector as u16 {
+            return self.bios(vector);
+        }
+
+        self.push(self.flags);
+        self.push(self.segments[CS]);
+        self.push(self.ip);
+        self.set_flag(IF, false);
+        self.set_flag(TF, false);
+        self.segments[CS] = segment;
+        self.ip = offset;
+        Ok(())
+    }
+
+    fn bios(&mut self, vector: u8) -> Result<(), Stop> {
+        let function = (self.registers[AX] >> 8) as u8;
+        match vector {
+            0x00 => return Err(Stop::DivideError),
+            0x10 => self.video_service(function),
+            0x11 => self.registers[AX] = 0x0021,
+            0x12 => self.registers[AX] = 640,
+            0x13 => self.disk_service(function),
+            0x16 => self.keyboard_service(function),
+            0x18 | 0x19 => return Err(Stop::Rebooted),
+            // Timer ticks since midnight, derived from the instruction count
+            0x1A if function == 0 => {
+                let ticks = self.instructions / 100_000;
+                self.registers[CX] = (ticks >> 16) as u16;
+                self.registers[DX] = ticks as u16;
+                self.registers[AX] &= 0xFF00;
+            }
+            // Anything else is unsupported, reported the way INT 15h does
+            _ => {
+                self.registers[AX] = (self.registers[AX] & 0x00FF) | 0x8600;
+                self.set_flag(CF, true);
+            }
+        }
+        Ok(())
+    }
+
+    fn video_service(&mut self, function: u8) {
+        let character = self.registers[AX] as u8;
+        match function {
+            // Teletype output
+            0x0E => self.output.push(character as char),
+            // Write character CX times
+            0x09 | 0x0A => {
+                for _ in 0..self.registers[CX] {
+                    self.output.push(character as char);
+                }
+            }
+            // Write string at ES:BP
+            0x13 => {
+                let with_attributes = character & 2 != 0;
+                for i in 0..self.registers[CX] {
+                    let index = if with_attributes { i * 2 } else { i };
+                    let offset = self.registers[BP].wrapping_add(index);
+                    let byte = self.read8(self.segments[ES], offset);
+                    self.output.push(byte as char);
+                }
+            }
+            // Get cursor position: always the top left
+            0x03 => {
+                self.registers[CX] = 0x0607;
+                self.registers[DX] = 0;
+            }
+            // Get video mode: 80 column text
+            0x0F => {
+                self.registers[AX] = 0x5003;
+                self.registers[BX] &= 0x00FF;
+            }
+            // Mode set, cursor moves, scrolling and colours don't affect the output
+            _ => {}
+        }
+    }
+
+    fn keyboard_service(&mut self, function: u8) {
+        match function {
+            // Wait for a key
+            0x00 | 0x10 => self.registers[AX] = self.keys.pop_front().unwrap_or(ENTER_KEY),
+            // Check for a key
+            0x01 | 0x11 => match self.keys.front() {
+                Some(&key) => {
+                    self.registers[AX] = key;
+                    self.set_flag(ZF, false);
+                }
+                None => self.set_flag(ZF, true),
+            },
+            // Shift flags
+            0x02 | 0x12 => self.registers[AX] &= 0xFF00,
+            _ => {}
+        }
+    }
+
+    fn disk_service(&mut self, function: u8) {
+        let drive = self.registers[DX] as u8;
+        let status = if drive != 0 && function != 0x00 {
+            // Only the boot floppy is attached
+            0x01
+        } else {
+            match function {
+                0x00 => 0x00,
+                0x02 | 0x03 => self.disk_read_write_chs(function == 0x03),
+                0x08 => {
+                    let cylinders = self.disk.sector_count() / (self.sectors_per_track * self.heads);
+                    let last_cylinder = cylinders.max(1) - 1;
+                    self.registers[BX] = (self.registers[BX] & 0xFF00) | 0x04;
+                    self.registers[CX] = ((last_cylinder as u16 & 0xFF) << 8)
+                        | ((last_cylinder as u16 >> 2) & 0xC0)
+                        | self.sectors_per_track as u16;
+                    self.registers[DX] = ((self.heads as u16 - 1) << 8) | 0x01;
+                    self.segments[ES] = 0;
+                    self.registers[DI] = 0;
+                    0x00
+                }
+                // Drive type: floppy without change line
+                0x15 => {
+                    self.set_flag(CF, false);
+                    self.registers[AX] = (self.registers[AX] & 0x00FF) | 0x0100;
+                    return;
+                }
+                // Extensions present, with the packet interface
+                0x41 if self.registers[BX] == 0x55AA => {
+                    self.registers[BX] = 0xAA55;
+                    self.registers[CX] = 0x0001;
+                    self.set_flag(CF, false);
+                    self.registers[AX] = (self.registers[AX] & 0x00FF) | 0x2100;
+                    return;
+                }
+                0x42 | 0x43 => self.disk_read_write_lba(function == 0x43),
+                _ => 0x01,
+            }
+        };
+
+        self.registers[AX] = (self.registers[AX] & 0x00FF) | (status as u16) << 8;
+        self.set_flag(CF, status != 0);
+    }
+
+    fn disk_read_write_chs(&mut self, write: bool) -> u8 {
+        let count = self.registers[AX] as u8 as usize;
+        let cylinder = (self.registers[CX] >> 8) as usize | ((self.registers[CX] as usize & 0xC0) << 2);
+        let sector = self.registers[CX] as usize & 0x3F;
+        let head = (self.registers[DX] >> 8) as usize;
+
+        if sector == 0 || sector > self.sectors_per_track || head >= self.heads {
+            return 0x04;
+        }
+
+        let lba = (cylinder * self.heads + head) * self.sectors_per_track + sector - 1;
+        let status = self.transfer(lba, count, self.segments[ES], self.registers[BX], write);
+        if status == 0 {
+            self.registers[AX] = count as u16;
+        }
+        status
+    }
+
+    /// INT 13h AH=42h/43h with the disk address packet at DS:SI
+    fn disk_read_write_lba(&mut self, write: bool) -> u8 {
+        let (segment, packet) = (self.segments[DS], self.registers[SI]);
+        let count = self.read16(segment, packet.wrapping_add(2)) as usize;
+        let offset = self.read16(segment, packet.wrapping_add(4));
+        let buffer_segment = self.read16(segment, packet.wrapping_add(6));
+        let lba = self.read16(segment, packet.wrapping_add(8)) as usize
+            | (self.read16(segment, packet.wrapping_add(10)) as usize) << 16;
+
+        self.transfer(lba, count, buffer_segment, offset, write)
+    }
+
+    /// Copies sectors between the disk and memory, returning the INT 13h status
+    fn transfer(&mut self, lba: usize, count: usize, segment: u16, offset: u16, write: bool) -> u8 {
+        if count == 0 || lba + count > self.disk.sector_count() {
+            return 0x04;
+        }
+        let mut sectors = vec![0; count * BYTES_PER_SECTOR];
+
+        // Writes only go to the emulator's copy of the disk
+        if write {
+            for (i, byte) in sectors.iter_mut().enumerate() {
+                *byte = self.read8(segment, offset.wrapping_add(i as u16));
+            }
+            return match self.disk.write_sectors(lba, &sectors) {
+                Ok(()) => 0x00,
+                // Controller failure
+                Err(_) => 0x20,
+            };
+        }
+
+        record_read(lba * BYTES_PER_SECTOR, sectors.len());
+        if self.disk.read_sectors(lba, &mut sectors).is_err() {
+            return 0x20;
+        }
+        for (i, &byte) in sectors.iter().enumerate() {
+            self.write8(segment, offset.wrapping_add(i as u16), byte);
+        }
+        0x00
+    }
+
+    /// Executes one instruction
+    pub fn step(&mut self) -> Result<(), Stop> {
+        self.instructions += 1;
+
+        // A far call or jump straight into a BIOS stub runs the service, then returns
+        // like `retf 2` so the result flags survive
+        if self.segments[CS] == BIOS_SEGMENT && self.ip < 0x100 {
+            let vector = self.ip as u8;
+            self.bios(vector)?;
+            self.ip = self.pop();
+            self.segments[CS] = self.pop();
+            let saved_flags = self.pop();
+            self.flags = (saved_flags & !(CF | ZF)) | (self.flags & (CF | ZF));
+            return Ok(());
+        }
+
+        self.segment_override = None;
+        let mut repeat = None;
+        let opcode = loop {
+            match self.fetch8() {
+                0x26 => self.segment_override = Some(ES),
+                0x2E => self.segment_override = Some(CS),
+                0x36 => self.segment_override = Some(SS),
+                0x3E => self.segment_override = Some(DS),
+                prefix @ (0xF2 | 0xF3) => repeat = Some(prefix),
+                0xF0 => {}
+                opcode => break opcode,
+            }
+        };
+
+        match opcode {
+            // ALU operations on r/m and registers
+            0x00..=0x3F if opcode & 7 < 6 => {
+                let operation = (opcode >> 3) as usize;
+                let word = opcode & 1 == 1;
+                match opcode & 7 {
+                    0..=3 => {
+                        let (reg, location) = self.modrm();
+                        let register = Location::Register(reg);
+                        let (destination, source) = if opcode & 2 == 0 {
+                            (location, register)
+                        } else {
+                            (register, location)
+                        };
+                        let result = self.alu(
+                            operation,
+                            self.get(destination, word),
+                            self.get(source, word),
+                            word,
+                        );
+                        if operation != 7 {
+                            self.set(destination, word, result);
+                        }
+                    }
+                    _ => {
+                        let immediate = if word {
+                            self.fetch16()
+                        } else {
+                            self.fetch8() as u16
+                        };
+                        let result = self.alu(operation, self.register(AX, word), immediate, word);
+                        if operation != 7 {
+                            self.set_register(AX, word, result);
+                        }
+                    }
+                }
+            }
+            0x06 | 0x0E | 0x16 | 0x1E => self.push(self.segments[(opcode >> 3) as usize]),
+            0x07 | 0x17 | 0x1F => {
+                let value = self.pop();
+                self.segments[(opcode >> 3) as usize] = value;
+            }
+            0x27 | 0x2F | 0x37 | 0x3F => return Err(Stop::Unsupported("BCD instruction".to_owned())),
+            0x40..=0x47 => {
+                let index = (opcode & 7) as usize;
+                self.registers[index] = self.increment(self.registers[index], true, 1);
+            }
+            0x48..=0x4F => {
+                let index = (opcode & 7) as usize;
+                self.registers[index] = self.increment(self.registers[index], true, -1);
+            }
+            0x50..=0x57 => {
+                // PUSH SP pushes the value after the decrement on the 8086
+                let index = (opcode & 7) as usize;
+                let value = if index == SP {
+                    self.registers[SP].wrapping_sub(2)
+                } else {
+                    self.registers[index]
+                };
+                self.push(value);
+            }
+            0x58..=0x5F => {
+                let value = self.pop();
+                self.registers[(opcode & 7) as usize] = value;
+            }
+            0x60 => {
+                let sp = self.registers[SP];
+                for index in 0..8 {
+                    let value = if index == SP { sp } else { self.registers[index] };
+                    self.push(value);
+                }
+            }
+            0x61 => {
+                for index in (0..8).rev() {
+                    let value = self.pop();
+                    if index != SP {
+                        self.registers[index] = value;
+                    }
+                }
+            }
+            0x68 => {
+                let value = self.fetch16();
+                self.push(value);
+            }
+            0x6A => {
+                let value = self.fetch8() as i8 as u16;
+                self.push(value);
+            }
+            0x69 | 0x6B => {
+                let (reg, location) = self.modrm();
+                let immediate = if opcode == 0x69 {
+                    self.fetch16() as i16 as i32
+                } else {
+                    self.fetch8() as i8 as i32
+                };
+                let result = self.get(location, true) as i16 as i32 * immediate;
+                self.registers[reg] = result as u16;
+                let overflow = result != result as i16 as i32;
+                self.set_flag(CF, overflow);
+                self.set_flag(OF, overflow);
+            }
+            0x70..=0x7F => {
+                let displacement = self.fetch8() as i8 as u16;
+                if self.condition(opcode & 0xF) {
+                    self.jump_relative(displacement);
+                }
+            }
+            0x80..=0x83 => {
+                let word = opcode & 1 == 1;
+                let (operation, location) = self.modrm();
+                let immediate = match opcode {
+                    0x81 => self.fetch16(),
+                    0x83 => self.fetch8() as i8 as u16,
+                    _ => self.fetch8() as u16,
+                };
+                let result = self.alu(operation, self.get(location, word), immediate, word);
+                if operation != 7 {
+                    self.set(location, word, result);
+                }
+            }
+            0x84 | 0x85 => {
+                let word = opcode & 1 == 1;
+                let (reg, location) = self.modrm();
+                self.alu(4, self.get(location, word), self.register(reg, word), word);
+            }
+            0x86 | 0x87 => {
+                let word = opcode & 1 == 1;
+                let (reg, location) = self.modrm();
+                let value = self.get(location, word);
+                self.set(location, word, self.register(reg, word));
+                self.set_register(reg, word, value);
+            }
+            0x88..=0x8B => {
+                let word = opcode & 1 == 1;
+                let (reg, location) = self.modrm();
+                if opcode & 2 == 0 {
+                    self.set(location, word, self.register(reg, word));
+                } else {
+                    let value = self.get(location, word);
+                    self.set_register(reg, word, value);
+                }
+            }
+            0x8C => {
+                let (reg, location) = self.modrm();
+                self.set(location, true, self.segments[reg & 3]);
+            }
+            0x8D => {
+                let (reg, location) = self.modrm();
+                match location {
+                    Location::Memory(_, offset) => self.registers[reg] = offset,
+                    Location::Register(_) => {
+                        return Err(Stop::Unsupported("LEA with a register operand".to_owned()))
+                    }
+                }
+            }
+            0x8E => {
+                let (reg, location) = self.modrm();
+                self.segments[reg & 3] = self.get(location, true);
+            }
+            0x8F => {
+                let (_, location) = self.modrm();
+                let value = self.pop();
+                self.set(location, true, value);
+            }
+            0x90 => {}
+            0x91..=0x97 => self.registers.swap(AX, (opcode & 7) as usize),
+            0x98 => self.registers[AX] = self.registers[AX] as u8 as i8 as i16 as u16,
+            0x99 => {
+                self.registers[DX] = if self.registers[AX] & 0x8000 != 0 { 0xFFFF } else { 0 };
+            }
+            0x9A => {
+                let offset = self.fetch16();
+                let segment = self.fetch16();
+                self.push(self.segments[CS]);
+                self.push(self.ip);
+                self.segments[CS] = segment;
+                self.ip = offset;
+            }
+            0x9B => {}
+            0x9C => self.push(self.flags),
+            0x9D => {
+                let value = self.pop();
+                self.flags = (value & 0x0FD5) | 0x0002;
+            }
+            0x9E => {
+                let ah = self.registers[AX] >> 8;
+                self.flags = (self.flags & 0xFF00) | (ah & 0xD5) | 0x0002;
+            }
+            0x9F => {
+                self.registers[AX] = (self.registers[AX] & 0x00FF) | (self.flags & 0xFF) << 8;
+            }
+            0xA0..=0xA3 => {
+                let word = opcode & 1 == 1;
+                let offset = self.fetch16();
+                let location = Location::Memory(self.data_segment(DS), offset);
+                if opcode & 2 == 0 {
+                    let value = self.get(location, word);
+                    self.set_register(AX, word, value);
+                } else {
+                    self.set(location, word, self.register(AX, word));
+                }
+            }
+            0xA4..=0xA7 | 0xAA..=0xAF => self.string_operation(opcode, repeat),
+            0xA8 | 0xA9 => {
+                let word = opcode & 1 == 1;
+                let immediate = if word {
+                    self.fetch16()
+                } else {
+                    self.fetch8() as u16
+                };
+                self.alu(4, self.register(AX, word), immediate, word);
+            }
+            0xB0..=0xB7 => {
+                let value = self.fetch8() as u16;
+                self.set_register((opcode & 7) as usize, false, value);
+            }
+            0xB8..=0xBF => self.registers[(opcode & 7) as usize] = self.fetch16(),
+            0xC0 | 0xC1 | 0xD0..=0xD3 => {
+                let word = opcode & 1 == 1;
+                let (operation, location) = self.modrm();
+                let count = match opcode {
+                    0xC0 | 0xC1 => self.fetch8(),
+                    0xD0 | 0xD1 => 1,
+                    _ => self.registers[CX] as u8,
+                };
+                let result = self.shift(operation, self.get(location, word), count, word);
+                self.set(location, word, result);
+            }
+            0xC2 => {
+                let bytes = self.fetch16();
+                self.ip = self.pop();
+                self.registers[SP] = self.registers[SP].wrapping_add(bytes);
+            }
+            0xC3 => self.ip = self.pop(),
+            0xC4 | 0xC5 => {
+                let (reg, location) = self.modrm();
+                let (segment, offset) = self.far_pointer(location)?;
+                self.registers[reg] = offset;
+                self.segments[if opcode == 0xC4 { ES } else { DS }] = segment;
+            }
+            0xC6 | 0xC7 => {
+                let word = opcode == 0xC7;
+                let (_, location) = self.modrm();
+                let immediate = if word {
+                    self.fetch16()
+                } else {
+                    self.fetch8() as u16
+                };
+                self.set(location, word, immediate);
+            }
+            0xCA | 0xCB => {
+                let bytes = if opcode == 0xCA { self.fetch16() } else { 0 };
+                self.ip = self.pop();
+                self.segments[CS] = self.pop();
+                self.registers[SP] = self.registers[SP].wrapping_add(bytes);
+            }
+            0xCC => self.interrupt(3)?,
+            0xCD => {
+                let vector = self.fetch8();
+                self.interrupt(vector)?;
+            }
+            0xCE => {
+                if self.flag(OF) {
+                    self.interrupt(4)?;
+                }
+            }
+            0xCF => {
+                self.ip = self.pop();
+                self.segments[CS] = self.pop();
+                let value = self.pop();
+                self.flags = (value & 0x0FD5) | 0x0002;
+            }
+            0xD7 => {
+                let offset = self.registers[BX].wrapping_add(self.registers[AX] & 0xFF);
+                let value = self.read8(self.data_segment(DS), offset) as u16;
+                self.set_register(AX, false, value);
+            }
+            0xE0..=0xE2 => {
+                let displacement = self.fetch8() as i8 as u16;
+                self.registers[CX] = self.registers[CX].wrapping_sub(1);
+                let taken = self.registers[CX] != 0
+                    && match opcode {
+                        0xE0 => !self.flag(ZF),
+                        0xE1 => self.flag(ZF),
+                        _ => true,
+                    };
+                if taken {
+                    self.jump_relative(displacement);
+                }
+            }
+            0xE3 => {
+                let displacement = self.fetch8() as i8 as u16;
+                if self.registers[CX] == 0 {
+                    self.jump_relative(displacement);
+                }
+            }
+            // No devices behind the ports: reads float high, writes vanish
+            0xE4 | 0xEC => {
+                if opcode == 0xE4 {
+                    self.fetch8();
+                }
+                self.set_register(AX, false, 0xFF);
+            }
+            0xE5 | 0xED => {
+                if opcode == 0xE5 {
+                    self.fetch8();
+                }
+                self.registers[AX] = 0xFFFF;
+            }
+            0xE6 | 0xE7 => {
+                self.fetch8();
+            }
+            0xEE | 0xEF => {}
+            0xE8 => {
+                let displacement = self.fetch16();
+                self.push(self.ip);
+                self.jump_relative(displacement);
+            }
+            0xE9 => {
+                let displacement = self.fetch16();
+                self.jump_relative(displacement);
+            }
+            0xEA => {
+                let offset = self.fetch16();
+                let segment = self.fetch16();
+                self.segments[CS] = segment;
+                self.ip = offset;
+            }
+            0xEB => {
+                let displacement = self.fetch8() as i8 as u16;
+                self.jump_relative(displacement);
+            }
+            0xF4 => return Err(Stop::Halted),
+            0xF5 => self.flags ^= CF,
+            0xF6 | 0xF7 => {
+                let (operation, location) = self.modrm();
+                self.group3(operation, location, opcode == 0xF7)?;
+            }
+            0xF8 => self.set_flag(CF, false),
+            0xF9 => self.set_flag(CF, true),
+            0xFA => self.set_flag(IF, false),
+            0xFB => self.set_flag(IF, true),
+            0xFC => self.set_flag(DF, false),
+            0xFD => self.set_flag(DF, true),
+            0xFE | 0xFF => {
+                let word = opcode == 0xFF;
+                let (operation, location) = self.modrm();
+                match operation {
+                    0 | 1 => {
+                        let delta = if operation == 0 { 1 } else { -1 };
+                        let result = self.increment(self.get(location, word), word, delta);
+                        self.set(location, word, result);
+                    }
+                    2 if word => {
+                        let target = self.get(location, true);
+                        self.push(self.ip);
+                        self.ip = target;
+                    }
+                    3 if word => {
+                        let (segment, offset) = self.far_pointer(location)?;
+                        self.push(self.segments[CS]);
+                        self.push(self.ip);
+                        self.segments[CS] = segment;
+                        self.ip = offset;
+                    }
+                    4 if word => self.ip = self.get(location, true),
+                    5 if word => {
+                        let (segment, offset) = self.far_pointer(location)?;
+                        self.segments[CS] = segment;
+                        self.ip = offset;
+                    }
+                    6 if word => {
+                        let value = self.get(location, true);
+                        self.push(value);
+                    }
+                    _ => return Err(Stop::Unsupported(format!("opcode {:02X} /{}", opcode, operation))),
+                }
+            }
+            0x66 | 0x67 | 0x0F | 0x64 | 0x65 => {
+                return Err(Stop::Unsupported(format!("80386 instruction (prefix {:02X})", opcode)))
+            }
+            _ => return Err(Stop::Unsupported(format!("opcode {:02X}", opcode))),
+        }
+
+        Ok(())
+    }
+}
+
+fn linear(segment: u16, offset: u16) -> usize {
+    (((segment as usize) << 4) + offset as usize) & (MEMORY_SIZE - 1)
+}
+
+/// boottest [until <segment:offset>] [marker <text>] [limit <instructions>] [keys <text>] [trace]
+pub fn boot_test(shell_state: ShellState, args: &Args) -> fat_error::Result<ShellState> {
+    let mut target = None;
+    let mut marker = None;
+    let mut limit: u64 = 1_000_000;
+    let mut keys = String::new();
+    let mut trace = false;
+
+    let mut argnum = 1;
+    while let Some(option) = args.get(argnum) {
+        match option.as_str() {
+            "until" => {
+                let (segment, offset) =
+                    parse_segment_offset(&args.expect(argnum + 1, "address")?)?;
+                target = Some(linear(segment, offset));
+                argnum += 1;
+            }
+            "marker" => {
+                marker = Some(args.expect(argnum + 1, "marker text")?);
+                argnum += 1;
+            }
+            "limit" => {
+                limit = expect_number(&args.expect(argnum + 1, "instruction limit")?, "Limit")? as u64;
+                argnum += 1;
+            }
+            "keys" => {
+                keys = args.expect(argnum + 1, "keys")?;
+                argnum += 1;
+            }
+            "trace" => trace = true,
+            other => println!("Ignoring unknown boottest option {}", other),
+        }
+        argnum += 1;
+    }
+
+    let no_signature = || FatError::CheckFailed("Boot test failed: sector 0 has no boot signature.".to_owned());
+    if shell_state.image().sector_count() == 0 {
+        return Err(no_signature());
+    }
+    let mut emulator = Emulator::new(Box::new(shell_state.image().clone()))?;
+    if !emulator.has_boot_signature() {
+        return Err(no_signature());
+    }
+    emulator.queue_keys(&keys);
+
+    let boot_sector = BOOT_ORIGIN as usize..BOOT_ORIGIN as usize + BYTES_PER_SECTOR;
+    let bios = linear(BIOS_SEGMENT, 0)..linear(BIOS_SEGMENT, 0x100);
+
+    let stop = loop {
+        if let Some(marker) = &marker {
+            if emulator.output.contains(marker.as_str()) {
+                break Stop::FoundMarker;
+            }
+        }
+        let ip = emulator.linear_ip();
+        match target {
+            Some(target) if ip == target => break Stop::ReachedAddress,
+            None if marker.is_none() && !boot_sector.contains(&ip) && !bios.contains(&ip) => {
+                break Stop::LeftBootSector
+            }
+            _ => {}
+        }
+        if emulator.instructions >= limit {
+            break Stop::InstructionLimit;
+        }
+
+        if trace {
+            println!("{}", emulator.trace_line());
+        }
+        if let Err(stop) = emulator.step() {
+            break stop;
+        }
+    };
+
+    if !emulator.output.is_empty() {
+        println!("Screen output:");
+        println!("-----------------------");
+        println!("{}", emulator.output.replace('\r', ""));
+        println!("-----------------------");
+    }
+
+    let verdict = if stop.is_success() { "passed" } else { "failed" };
+    let summary = format!(
+        "Boot test {}: {} at {:04X}:{:04X} after {} instructions.",
+        verdict,
+        stop.describe(),
+        emulator.code_segment(),
+        emulator.instruction_pointer(),
+        emulator.instructions
+    );
+    // Failing is an error so `fat12 <image> boottest` can gate a build
+    if !stop.is_success() {
+        return Err(FatError::CheckFailed(summary));
+    }
+    println!("{}", summary);
+
+    Ok(shell_state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bios_parameter_block::BiosParameterBlock;
+    use crate::boot_template::BootTemplate;
+    use crate::image_device::ImageDevice;
+    use crate::root_dir_util::build_directory_entry;
+    use crate::shell_files::store_file;
+    use crate::shell_images::format_device;
+
+    /// Prints OK through the BIOS and halts
+    const KERNEL: [u8; 11] = [
+        0xB4, 0x0E, // mov ah,0x0e
+        0xB0, b'O', // mov al,'O'
+        0xCD, 0x10, // int 0x10
+        0xB0, b'K', // mov al,'K'
+        0xCD, 0x10, // int 0x10
+        0xF4, // hlt
+    ];
+
+    /// A formatted 360K floppy with a generated boot sector that loads KERNEL.SYS, holding `kernel` if given
+    fn boot_floppy(kernel: Option<&[u8]>) -> ImageDevice {
+        let mut image = ImageDevice::from_bytes(vec![0; 720 * BYTES_PER_SECTOR]);
+        let bpb = BiosParameterBlock::for_sectors(720);
+        let mut volume = format_device(&mut image, &bpb).unwrap();
+        if let Some(kernel) = kernel {
+            store_file(&mut volume, 0, "KERNEL.SYS", kernel, build_directory_entry("", 0, 0, false)).unwrap();
+        }
+        volume.write(0, &BootTemplate::new("KERNEL.SYS").generate(&bpb.to_bytes())).unwrap();
+        image
+    }
+
+    fn run(emulator: &mut Emulator) -> Stop {
+        while emulator.instructions < 1_000_000 {
+            if let Err(stop) = emulator.step() {
+                return stop;
+            }
+        }
+        Stop::InstructionLimit
+    }
+
+    #[test]
+    fn generated_boot_sector_runs_the_kernel() {
+        // Nops in front make it span several clusters, so the whole chain has to be loaded
+        let mut kernel = vec![0x90; 2500];
+        kernel.extend_from_slice(&KERNEL);
+        let mut emulator = Emulator::new(Box::new(boot_floppy(Some(&kernel)))).unwrap();
+        assert!(emulator.has_boot_signature());
+        let stop = run(&mut emulator);
+        assert!(matches!(stop, Stop::Halted), "{}", stop.describe());
+        assert_eq!(emulator.output, "OK");
+        assert_eq!((emulator.code_segment(), emulator.instruction_pointer()), (0x1000, kernel.len() as u16));
+    }
+
+    #[test]
+    fn generated_boot_sector_without_the_kernel_gives_up() {
+        let mut emulator = Emulator::new(Box::new(boot_floppy(None))).unwrap();
+        let stop = run(&mut emulator);
+        assert!(matches!(stop, Stop::Rebooted), "{}", stop.describe());
+        assert!(emulator.output.starts_with("Non-system disk"));
+    }
+
+    #[test]
+    fn blank_disk_has_no_boot_signature() {
+        let emulator = Emulator::new(Box::new(vec![0u8; 720 * BYTES_PER_SECTOR])).unwrap();
+        assert!(!emulator.has_boot_signature());
+    }
+}
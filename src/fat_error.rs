@@ -0,0 +1,121 @@
+use alloc::string::String;
+use core::fmt;
+#[cfg(feature = "std")]
+use std::io;
+
+/// Everything that can go wrong while working on an image
+#[derive(Debug)]
+pub enum FatError {
+    /// A host file couldn't be read or written
+    #[cfg(feature = "std")]
+    Io { context: String, source: io::Error },
+    NoImageOpen,
+    /// A drive prefix like `B:` names a drive that has no image open
+    NoSuchDrive(String),
+    MissingArgument(String),
+    BadArgument(String),
+    UnknownCommand(String),
+    NotFound(String),
+    /// Closing or replacing the image would lose changes that haven't been saved
+    UnsavedChanges(String),
+    AlreadyExists(String),
+    IsADirectory(String),
+    NotADirectory(String),
+    DirectoryNotEmpty(String),
+    DiskFull { needed: usize, free: usize },
+    RootDirectoryFull,
+    InvalidName(String),
+    CorruptFat(String),
+    InvalidBootSector(String),
+    /// Something tried to write to a device that can only be read
+    ReadOnly(String),
+    /// Another session has the image open for writing
+    Locked { image: String, holder: String },
+    PastEndOfDevice { sector: usize, sectors: usize },
+    Manifest { filename: String, line: usize, message: String },
+    /// A check like `fsck` or `boottest` ran and found a problem
+    CheckFailed(String),
+    /// A bug, caught so the shell keeps running
+    Internal(String),
+}
+
+pub type Result<T> = core::result::Result<T, FatError>;
+
+#[cfg(feature = "std")]
+impl FatError {
+    /// Wraps an I/O error with what was being done, e.g. "Can't read kernel.bin"
+    pub fn io(context: impl Into<String>) -> impl FnOnce(io::Error) -> FatError {
+        let context = context.into();
+        move |source| FatError::Io { context, source }
+    }
+}
+
+/// For `std::io` traits, like the ones implemented by files in the image
+#[cfg(feature = "std")]
+impl From<FatError> for io::Error {
+    fn from(error: FatError) -> Self {
+        match error {
+            FatError::Io { source, .. } => source,
+            FatError::NotFound(_) => io::Error::new(io::ErrorKind::NotFound, error),
+            FatError::DiskFull { .. } => io::Error::new(io::ErrorKind::StorageFull, error),
+            FatError::ReadOnly(_) => io::Error::new(io::ErrorKind::PermissionDenied, error),
+            _ => io::Error::other(error),
+        }
+    }
+}
+
+impl fmt::Display for FatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            #[cfg(feature = "std")]
+            FatError::Io { context, source } => write!(f, "{}: {}", context, source),
+            FatError::NoImageOpen => write!(f, "No image is open, use open or new first"),
+            FatError::NoSuchDrive(drive) => write!(f, "No image is open on {}:", drive),
+            FatError::MissingArgument(what) => write!(f, "No {} provided", what),
+            FatError::BadArgument(message) => write!(f, "{}", message),
+            FatError::UnknownCommand(name) => write!(f, "Unknown command {}", name),
+            FatError::NotFound(path) => write!(f, "{} not found", path),
+            FatError::UnsavedChanges(filename) => write!(
+                f,
+                "{} has unsaved changes, save it first or add -f to discard them",
+                filename
+            ),
+            FatError::AlreadyExists(path) => write!(f, "{} already exists", path),
+            FatError::IsADirectory(path) => write!(f, "{} is a directory", path),
+            FatError::NotADirectory(path) => write!(f, "{} isn't a directory", path),
+            FatError::DirectoryNotEmpty(path) => write!(f, "{} isn't empty", path),
+            FatError::DiskFull { needed, free } => write!(
+                f,
+                "The disk is full: {} clusters are needed but only {} are free",
+                needed, free
+            ),
+            FatError::RootDirectoryFull => write!(f, "The root directory is full"),
+            FatError::InvalidName(name) => write!(f, "{} isn't a valid file name", name),
+            FatError::CorruptFat(message) => write!(f, "The file system is corrupt: {}", message),
+            FatError::InvalidBootSector(message) => write!(f, "{}", message),
+            FatError::ReadOnly(what) => write!(f, "{} is read only", what),
+            FatError::Locked { image, holder } if holder.is_empty() => {
+                write!(f, "{} is open for writing in another session", image)
+            }
+            FatError::Locked { image, holder } => write!(f, "{} is open for writing by {}", image, holder),
+            FatError::PastEndOfDevice { sector, sectors } => write!(
+                f,
+                "Sector {} is past the end of the device, which has {} sectors",
+                sector, sectors
+            ),
+            FatError::Manifest { filename, line, message } => write!(f, "{}:{}: {}", filename, line, message),
+            FatError::CheckFailed(message) => write!(f, "{}", message),
+            FatError::Internal(message) => write!(f, "Internal error: {}", message),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for FatError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FatError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
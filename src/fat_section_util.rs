@@ -1,40 +1,108 @@
-use crate::bios_parameter_block::{BYTES_PER_ENTRY, BYTES_PER_SECTOR, RESERVED_SECTORS};
-
-pub fn get_fat_entry(bytes: &Vec<u8>, entry_num: usize) -> usize {
-    // Realistically should return a u12 but that doesn't exist
-    let fat_start = RESERVED_SECTORS * BYTES_PER_SECTOR;
-
-    let entry_start_byte = fat_start + (entry_num as f64 * BYTES_PER_ENTRY) as usize;
-    let untrimmed_bytes = &bytes[entry_start_byte..entry_start_byte + 2];
-
-    if entry_num % 2 == 0 {
-        // If entry is even
-        // xxxx xxxx xxxx 0000
-        ((untrimmed_bytes[0] as usize) << 4) + (untrimmed_bytes[1] >> 4) as usize
-    } else {
-        // if entry is odd
-        // 0000 xxxx xxxx xxxx
-        (((untrimmed_bytes[0] & 0b00001111) as usize) << 8) + untrimmed_bytes[1] as usize
-    }
-}
-
-pub fn write_to_fat(mut bytes: Vec<u8>, entry_num: usize, last_entry_num: usize) -> Vec<u8> {
-    let fat_start = RESERVED_SECTORS * BYTES_PER_SECTOR;
-    let entry_start = fat_start + (last_entry_num as f64 * BYTES_PER_ENTRY) as usize;
-    let mut new_bytes = bytes[entry_start..entry_start + 2].to_vec();
-
-    if last_entry_num % 2 == 0 {
-        // If the entry is even
-        // xxxx xxxx xxxx 0000
-        new_bytes[0] = (entry_num >> 4) as u8;
-        new_bytes[1] = (entry_num as u8) << 4 + (new_bytes[1] & 0b00001111);
-    } else {
-        // if the entry is odd
-        // 0000 xxxx xxxx xxxx
-        new_bytes[0] = (entry_num >> 8) as u8 + (new_bytes[0] & 0b11110000);
-        new_bytes[1] = entry_num as u8;
-    }
-
-    bytes.splice(entry_start..entry_start + 2, new_bytes);
-    bytes
-}
+use crate::bios_parameter_block::BYTES_PER_ENTRY;
+use crate::block_device::BlockDevice;
+use crate::fat_error::{FatError, Result};
+use crate::volume::Volume;
+use alloc::format;
+use alloc::vec::Vec;
+
+/// Any entry at or above this value marks the end of a cluster chain
+pub const END_OF_CHAIN: usize = 0xFF8;
+
+pub fn get_fat_entry<D: BlockDevice>(volume: &mut Volume<D>, entry_num: usize) -> Result<usize> {
+    // Realistically should return a u12 but that doesn't exist
+    let bpb = volume.bpb();
+    let fat_start = bpb.fat_start(0) * bpb.bytes_per_sector;
+
+    let entry_start_byte = fat_start + (entry_num as f64 * BYTES_PER_ENTRY) as usize;
+    let pair = volume.read(entry_start_byte, 2)?;
+    let pair = pair[0] as usize | (pair[1] as usize) << 8;
+
+    if entry_num.is_multiple_of(2) {
+        // If entry is even, it's the low 12 bits of the little endian pair
+        Ok(pair & 0xFFF)
+    } else {
+        // if entry is odd, it's the high 12 bits
+        Ok(pair >> 4)
+    }
+}
+
+/// Follows one link of a chain, rejecting free, reserved and out of range entries
+pub fn next_cluster<D: BlockDevice>(volume: &mut Volume<D>, cluster: usize) -> Result<usize> {
+    let next = get_fat_entry(volume, cluster)?;
+    let fat_entries = volume.bpb().fat_entries();
+    if next >= END_OF_CHAIN || (2..fat_entries).contains(&next) {
+        Ok(next)
+    } else {
+        Err(FatError::CorruptFat(format!("cluster {} links to {:#05x}", cluster, next)))
+    }
+}
+
+/// Every cluster of the chain starting at `first_cluster`, in order
+pub fn cluster_chain<D: BlockDevice>(volume: &mut Volume<D>, first_cluster: usize) -> Result<Vec<usize>> {
+    let fat_entries = volume.bpb().fat_entries();
+    let mut chain = Vec::new();
+    let mut cluster = first_cluster;
+    while (2..END_OF_CHAIN).contains(&cluster) {
+        if cluster >= fat_entries {
+            return Err(FatError::CorruptFat(format!("cluster {} is past the end of the FAT", cluster)));
+        }
+        // A chain can't be longer than the disk, so anything longer has a loop
+        if chain.len() >= fat_entries {
+            return Err(FatError::CorruptFat(format!("the chain starting at cluster {} loops", first_cluster)));
+        }
+        chain.push(cluster);
+        cluster = next_cluster(volume, cluster)?;
+    }
+    Ok(chain)
+}
+
+/// Sets FAT entry `last_entry_num` to `entry_num` in every copy of the FAT
+pub fn write_to_fat<D: BlockDevice>(volume: &mut Volume<D>, entry_num: usize, last_entry_num: usize) -> Result<()> {
+    let bpb = volume.bpb().clone();
+    for fat_index in 0..bpb.number_fats {
+        let fat_start = bpb.fat_start(fat_index) * bpb.bytes_per_sector;
+        let entry_start = fat_start + (last_entry_num as f64 * BYTES_PER_ENTRY) as usize;
+        let old_pair = volume.read(entry_start, 2)?;
+        let old_pair = old_pair[0] as usize | (old_pair[1] as usize) << 8;
+
+        let new_pair = if last_entry_num.is_multiple_of(2) {
+            // If the entry is even, keep the high nibble that belongs to the next entry
+            (old_pair & 0xF000) | (entry_num & 0xFFF)
+        } else {
+            // if the entry is odd, keep the low nibble that belongs to the previous entry
+            (old_pair & 0x000F) | ((entry_num & 0xFFF) << 4)
+        };
+
+        volume.write(entry_start, &(new_pair as u16).to_le_bytes())?;
+    }
+    Ok(())
+}
+
+/// Marks every cluster of a chain as free
+pub fn free_chain<D: BlockDevice>(volume: &mut Volume<D>, first_entry: usize) -> Result<()> {
+    let cluster_count = volume.bpb().cluster_count();
+    let mut entry_num = first_entry;
+
+    // A chain can't be longer than the disk, so stop there if the FAT has a loop
+    for _ in 0..cluster_count {
+        if !(2..END_OF_CHAIN).contains(&entry_num) {
+            break;
+        }
+        let next_entry = get_fat_entry(volume, entry_num)?;
+        write_to_fat(volume, 0, entry_num)?;
+        entry_num = next_entry;
+    }
+    Ok(())
+}
+
+/// Number of clusters that aren't in use
+pub fn count_free_clusters<D: BlockDevice>(volume: &mut Volume<D>) -> Result<usize> {
+    let fat_entries = volume.bpb().fat_entries();
+    let mut free = 0;
+    for entry_num in 2..fat_entries {
+        if get_fat_entry(volume, entry_num)? == 0 {
+            free += 1;
+        }
+    }
+    Ok(free)
+}
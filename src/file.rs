@@ -0,0 +1,478 @@
+//! Open files inside the image, read and written a piece at a time.
+//!
+//! A `File` keeps its place and its cluster chain, growing the chain as it's written past its end
+//! and giving clusters back when it's cut short. Its directory entry gets the new size, first
+//! cluster and modification time when it's closed or flushed. With the `std` feature files are also
+//! `std::io` readers, writers and seekers, so `std::io::copy` can stream them to and from the host.
+
+use crate::block_device::BlockDevice;
+use crate::directories::{find_in_directory, find_path, is_directory, resolve_directory, split_path};
+use crate::fat_error::{FatError, Result};
+use crate::fat_section_util::{cluster_chain, count_free_clusters, free_chain, write_to_fat};
+use crate::long_names::{add_named_entry, validate_long_name};
+use crate::new_file::{get_cluster_from_entry, get_next_free_cluster};
+use crate::root_dir_util::{
+    build_directory_entry, directory_entry, entry_file_size, entry_first_cluster, set_entry_time,
+    write_directory_entry, ATTR_ARCHIVE,
+};
+use crate::timestamps::CivilTime;
+use crate::volume::Volume;
+use alloc::borrow::ToOwned;
+use alloc::string::String;
+use alloc::vec::Vec;
+use alloc::{format, vec};
+#[cfg(feature = "std")]
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// A file opened from a volume, which it borrows until it's closed
+///
+/// Dropping a file closes it too, but only `close` says whether updating its entry worked.
+pub struct File<'a, D: BlockDevice> {
+    volume: &'a mut Volume<D>,
+    path: String,
+    // Byte offset of the file's directory entry on the volume
+    entry_offset: usize,
+    chain: Vec<usize>,
+    size: usize,
+    position: usize,
+    writable: bool,
+    // Written or resized since the entry was last updated
+    modified: bool,
+    modified_time: Option<CivilTime>,
+}
+
+impl<D: BlockDevice> Volume<D> {
+    /// Opens a file by path, absolute or relative to the directory at `cwd`, for writing as well as
+    /// reading if `writable`
+    pub fn open_file(&mut self, cwd: usize, path: &str, writable: bool) -> Result<File<'_, D>> {
+        File::open(self, cwd, path, writable)
+    }
+
+    /// Opens a file for writing, emptying it if it's there already and making it if not
+    pub fn create_file(&mut self, cwd: usize, path: &str) -> Result<File<'_, D>> {
+        File::create(self, cwd, path)
+    }
+}
+
+impl<'a, D: BlockDevice> File<'a, D> {
+    pub fn open(volume: &'a mut Volume<D>, cwd: usize, path: &str, writable: bool) -> Result<Self> {
+        let (_, entry_offset) = find_path(volume, cwd, path)?;
+        let entry = directory_entry(volume, entry_offset)?;
+        if is_directory(&entry) {
+            return Err(FatError::IsADirectory(path.to_owned()));
+        }
+
+        let chain = cluster_chain(volume, entry_first_cluster(&entry))?;
+        let size = entry_file_size(&entry);
+        if chain.len() * volume.bpb().bytes_per_cluster() < size {
+            return Err(FatError::CorruptFat(format!("{} is shorter than its directory entry says", path)));
+        }
+
+        Ok(File {
+            volume,
+            path: path.to_owned(),
+            entry_offset,
+            chain,
+            size,
+            position: 0,
+            writable,
+            modified: false,
+            modified_time: None,
+        })
+    }
+
+    /// Opens a file for writing, emptying it if it's there already and making it if not
+    pub fn create(volume: &'a mut Volume<D>, cwd: usize, path: &str) -> Result<Self> {
+        let (dir_path, name) = split_path(path);
+        let dir_cluster = resolve_directory(volume, cwd, dir_path)?;
+        if find_in_directory(volume, dir_cluster, name)?.is_some() {
+            let mut file = Self::open(volume, cwd, path, true)?;
+            file.set_len(0)?;
+            return Ok(file);
+        }
+
+        validate_long_name(name)?;
+        // The name is filled in with the short name it's stored under
+        let mut entry = build_directory_entry("", 0, 0, false);
+        if let Some(time) = now() {
+            set_entry_time(&mut entry, &time);
+        }
+        add_named_entry(volume, dir_cluster, name, entry)?;
+        Self::open(volume, cwd, path, true)
+    }
+
+    /// Size in bytes
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    /// Where the next read or write starts
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Moves to `position`, which can be past the end; writing there fills the gap with zeros
+    pub fn set_position(&mut self, position: usize) {
+        self.position = position;
+    }
+
+    /// The time the entry gets when it's updated, rather than now (which needs `std`)
+    pub fn set_modified(&mut self, time: CivilTime) {
+        self.modified_time = Some(time);
+    }
+
+    /// Reads from the current position into `buffer`, returning how many bytes were read (0 at the end)
+    pub fn read_bytes(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        let length = buffer.len().min(self.size.saturating_sub(self.position));
+        let mut done = 0;
+        for (offset, piece) in self.pieces(self.position, length) {
+            let bytes = self.volume.read(offset, piece)?;
+            buffer[done..done + piece].copy_from_slice(&bytes);
+            done += piece;
+        }
+        self.position += length;
+        Ok(length)
+    }
+
+    /// Writes all of `data` at the current position, growing the file if it goes past the end
+    pub fn write_bytes(&mut self, data: &[u8]) -> Result<usize> {
+        self.check_writable()?;
+        if self.position > self.size {
+            self.set_len(self.position)?;
+        }
+        let end = self.position + data.len();
+        self.reserve(end)?;
+
+        let mut done = 0;
+        for (offset, piece) in self.pieces(self.position, data.len()) {
+            self.volume.write(offset, &data[done..done + piece])?;
+            done += piece;
+        }
+        self.position = end;
+        self.size = self.size.max(end);
+        self.modified = true;
+        Ok(data.len())
+    }
+
+    /// Cuts the file short, freeing the clusters it no longer needs, or grows it with zeros
+    pub fn set_len(&mut self, size: usize) -> Result<()> {
+        self.check_writable()?;
+        if size > self.size {
+            // The end of the last cluster can hold anything, so the new part is cleared
+            self.reserve(size)?;
+            for (offset, piece) in self.pieces(self.size, size - self.size) {
+                self.volume.write(offset, &vec![0; piece])?;
+            }
+            self.size = size;
+        } else {
+            let needed = size.div_ceil(self.volume.bpb().bytes_per_cluster());
+            if needed < self.chain.len() {
+                free_chain(self.volume, self.chain[needed])?;
+                if needed > 0 {
+                    write_to_fat(self.volume, 0xFFF, self.chain[needed - 1])?;
+                }
+                self.chain.truncate(needed);
+            }
+            self.size = size;
+        }
+        self.modified = true;
+        Ok(())
+    }
+
+    /// Updates the directory entry and flushes the volume
+    pub fn sync(&mut self) -> Result<()> {
+        self.update_entry()?;
+        self.volume.flush()
+    }
+
+    /// Updates the directory entry, reporting what went wrong
+    pub fn close(mut self) -> Result<()> {
+        self.update_entry()
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.writable {
+            Ok(())
+        } else {
+            Err(FatError::ReadOnly(self.path.clone()))
+        }
+    }
+
+    /// Grows the chain to hold `size` bytes, checking there's room for all of it first
+    fn reserve(&mut self, size: usize) -> Result<()> {
+        let needed = size.div_ceil(self.volume.bpb().bytes_per_cluster());
+        if needed <= self.chain.len() {
+            return Ok(());
+        }
+        let more = needed - self.chain.len();
+        let free = count_free_clusters(self.volume)?;
+        if more > free {
+            return Err(FatError::DiskFull { needed: more, free });
+        }
+
+        for _ in 0..more {
+            let last = self.chain.last().copied().unwrap_or(0);
+            // Free clusters come after the last one if they can, keeping the file in one piece
+            let cluster = match get_next_free_cluster(self.volume, last)? {
+                0 => get_next_free_cluster(self.volume, 0)?,
+                cluster => cluster,
+            };
+            if last != 0 {
+                write_to_fat(self.volume, cluster, last)?;
+            }
+            write_to_fat(self.volume, 0xFFF, cluster)?;
+            self.chain.push(cluster);
+        }
+        Ok(())
+    }
+
+    /// Splits `length` bytes of the file from `start` into (volume offset, length) pieces,
+    /// one for each run of clusters that follow each other on the disk
+    fn pieces(&self, start: usize, length: usize) -> Vec<(usize, usize)> {
+        let cluster_size = self.volume.bpb().bytes_per_cluster();
+        let mut pieces: Vec<(usize, usize)> = vec![];
+        let mut position = start;
+        while position < start + length {
+            let within = position % cluster_size;
+            let piece = (cluster_size - within).min(start + length - position);
+            let offset = get_cluster_from_entry(self.volume, self.chain[position / cluster_size]) + within;
+            match pieces.last_mut() {
+                Some((last_offset, last_length)) if *last_offset + *last_length == offset => *last_length += piece,
+                _ => pieces.push((offset, piece)),
+            }
+            position += piece;
+        }
+        pieces
+    }
+
+    /// Writes the size, first cluster and modification time into the directory entry if they changed
+    fn update_entry(&mut self) -> Result<()> {
+        if !self.modified {
+            return Ok(());
+        }
+        let mut entry = directory_entry(self.volume, self.entry_offset)?;
+        let first_cluster = self.chain.first().copied().unwrap_or(0);
+        entry[26..28].copy_from_slice(&(first_cluster as u16).to_le_bytes());
+        entry[28..32].copy_from_slice(&(self.size as u32).to_le_bytes());
+        // Backup programs look for the archive bit to tell what changed
+        entry[11] |= ATTR_ARCHIVE;
+
+        if let Some(time) = self.modified_time.clone().or_else(now) {
+            let (date, time) = (time.dos_date().to_le_bytes(), time.dos_time().to_le_bytes());
+            // Bytes 18-19: Last access date
+            entry[18..20].copy_from_slice(&date);
+            // Bytes 22-25: Modification time and date
+            entry[22..24].copy_from_slice(&time);
+            entry[24..26].copy_from_slice(&date);
+        }
+
+        write_directory_entry(self.volume, self.entry_offset, &entry)?;
+        self.modified = false;
+        Ok(())
+    }
+}
+
+/// The time to stamp entries with, when there's a clock to ask
+fn now() -> Option<CivilTime> {
+    #[cfg(feature = "std")]
+    return Some(CivilTime::now());
+    #[cfg(not(feature = "std"))]
+    None
+}
+
+impl<D: BlockDevice> Drop for File<'_, D> {
+    fn drop(&mut self) {
+        // Nowhere to report a failure from here, `close` is for that
+        let _ = self.update_entry();
+    }
+}
+
+#[cfg(feature = "std")]
+impl<D: BlockDevice> Read for File<'_, D> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        Ok(self.read_bytes(buffer)?)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<D: BlockDevice> Write for File<'_, D> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        Ok(self.write_bytes(data)?)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(self.sync()?)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<D: BlockDevice> Seek for File<'_, D> {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        let position = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => (self.size as u64).checked_add_signed(offset),
+            SeekFrom::Current(offset) => (self.position as u64).checked_add_signed(offset),
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Can't seek before the start of the file"))?;
+        self.position = position as usize;
+        Ok(position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bios_parameter_block::BiosParameterBlock;
+    use crate::long_names::named_entries;
+    #[cfg(feature = "std")]
+    use std::io::{Read, Seek, SeekFrom};
+
+    /// An empty 360K floppy held in memory
+    fn empty_volume() -> Volume<Vec<u8>> {
+        let bpb = BiosParameterBlock::for_sectors(720);
+        let mut volume = Volume::mount(vec![0; 720 * 512]).unwrap();
+        volume.set_bpb(bpb.clone()).unwrap();
+        write_to_fat(&mut volume, 0xF00 | bpb.media as usize, 0).unwrap();
+        write_to_fat(&mut volume, 0xFFF, 1).unwrap();
+        volume
+    }
+
+    #[test]
+    fn creates_files_with_long_names() {
+        let mut volume = empty_volume();
+        let mut file = volume.create_file(0, "release notes.txt").unwrap();
+        file.write_bytes(b"hello").unwrap();
+        file.close().unwrap();
+
+        let entries = named_entries(&mut volume, 0).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].long_name.as_deref(), Some("release notes.txt"));
+        assert_eq!(&entries[0].entry.bytes[0..11], b"RELEAS~1TXT");
+
+        let mut file = volume.open_file(0, "release notes.txt", false).unwrap();
+        let mut contents = [0; 5];
+        assert_eq!(file.read_bytes(&mut contents).unwrap(), 5);
+        assert_eq!(&contents, b"hello");
+    }
+
+    /// Bytes that differ from one position to the next, so a piece in the wrong place shows
+    fn pattern(length: usize) -> Vec<u8> {
+        (0..length).map(|index| (index % 251) as u8).collect()
+    }
+
+    fn read_all(volume: &mut Volume<Vec<u8>>, path: &str) -> Vec<u8> {
+        let mut file = volume.open_file(0, path, false).unwrap();
+        let mut contents = vec![0; file.len()];
+        assert_eq!(file.read_bytes(&mut contents).unwrap(), contents.len());
+        contents
+    }
+
+    #[test]
+    fn writes_and_reads_across_clusters() {
+        let mut volume = empty_volume();
+        let cluster = volume.bpb().bytes_per_cluster();
+        let data = pattern(cluster * 5 / 2);
+        let mut file = volume.create_file(0, "DATA.BIN").unwrap();
+        // In pieces that don't line up with the clusters
+        for piece in data.chunks(cluster / 3) {
+            file.write_bytes(piece).unwrap();
+        }
+        assert_eq!(file.chain.len(), 3);
+        file.close().unwrap();
+
+        assert_eq!(read_all(&mut volume, "DATA.BIN"), data);
+        let mut file = volume.open_file(0, "DATA.BIN", false).unwrap();
+        file.set_position(cluster - 2);
+        let mut middle = [0; 4];
+        file.read_bytes(&mut middle).unwrap();
+        assert_eq!(middle[..], data[cluster - 2..cluster + 2]);
+    }
+
+    #[test]
+    fn writing_past_the_end_fills_the_gap_with_zeros() {
+        let mut volume = empty_volume();
+        let cluster = volume.bpb().bytes_per_cluster();
+        let mut file = volume.create_file(0, "GAP.BIN").unwrap();
+        file.write_bytes(b"a").unwrap();
+        file.set_position(cluster + 10);
+        file.write_bytes(b"b").unwrap();
+        assert_eq!(file.len(), cluster + 11);
+        assert_eq!(file.chain.len(), 2);
+        file.close().unwrap();
+
+        let contents = read_all(&mut volume, "GAP.BIN");
+        assert_eq!(contents[0], b'a');
+        assert!(contents[1..cluster + 10].iter().all(|&byte| byte == 0));
+        assert_eq!(contents[cluster + 10], b'b');
+    }
+
+    #[test]
+    fn truncating_frees_clusters_and_growing_clears_them() {
+        let mut volume = empty_volume();
+        let cluster = volume.bpb().bytes_per_cluster();
+        let free = count_free_clusters(&mut volume).unwrap();
+        let mut file = volume.create_file(0, "CUT.BIN").unwrap();
+        file.write_bytes(&pattern(cluster * 3)).unwrap();
+        file.set_len(cluster + 1).unwrap();
+        assert_eq!(file.chain.len(), 2);
+        file.close().unwrap();
+        assert_eq!(count_free_clusters(&mut volume).unwrap(), free - 2);
+        assert_eq!(read_all(&mut volume, "CUT.BIN"), pattern(cluster + 1));
+
+        // What was past the cut is still on the disk, but mustn't show up again
+        let mut file = volume.open_file(0, "CUT.BIN", true).unwrap();
+        file.set_len(10).unwrap();
+        file.set_len(cluster * 2).unwrap();
+        file.close().unwrap();
+        let contents = read_all(&mut volume, "CUT.BIN");
+        assert_eq!(contents[..10], pattern(10)[..]);
+        assert!(contents[10..].iter().all(|&byte| byte == 0));
+
+        let mut file = volume.open_file(0, "CUT.BIN", true).unwrap();
+        file.set_len(0).unwrap();
+        assert!(file.chain.is_empty());
+        file.close().unwrap();
+        assert_eq!(count_free_clusters(&mut volume).unwrap(), free);
+        let (_, offset) = find_path(&mut volume, 0, "CUT.BIN").unwrap();
+        assert_eq!(entry_first_cluster(&directory_entry(&mut volume, offset).unwrap()), 0);
+    }
+
+    #[test]
+    fn files_opened_to_read_refuse_writes() {
+        let mut volume = empty_volume();
+        volume.create_file(0, "RO.BIN").unwrap().close().unwrap();
+        let mut file = volume.open_file(0, "RO.BIN", false).unwrap();
+        assert!(file.write_bytes(b"no").is_err());
+        assert!(file.set_len(5).is_err());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn seeks_from_the_start_end_and_current_position() {
+        let mut volume = empty_volume();
+        let cluster = volume.bpb().bytes_per_cluster();
+        let data = pattern(cluster * 2);
+        let mut file = volume.create_file(0, "SEEK.BIN").unwrap();
+        file.write_bytes(&data).unwrap();
+
+        let mut byte = [0; 1];
+        assert_eq!(file.seek(SeekFrom::End(-1)).unwrap(), (cluster * 2 - 1) as u64);
+        file.read_exact(&mut byte).unwrap();
+        assert_eq!(byte[0], data[cluster * 2 - 1]);
+
+        file.seek(SeekFrom::Start(cluster as u64 - 1)).unwrap();
+        assert_eq!(file.seek(SeekFrom::Current(1)).unwrap(), cluster as u64);
+        file.read_exact(&mut byte).unwrap();
+        assert_eq!(byte[0], data[cluster]);
+
+        assert!(file.seek(SeekFrom::Current(-(cluster as i64) * 3)).is_err());
+        assert_eq!(file.position(), cluster + 1);
+        // Past the end reads nothing
+        file.seek(SeekFrom::End(5)).unwrap();
+        assert_eq!(file.read(&mut byte).unwrap(), 0);
+    }
+}
@@ -0,0 +1,188 @@
+use crate::bios_parameter_block::{BiosParameterBlock, BPB_END};
+use crate::block_device::BlockDevice;
+use crate::directories::{directory_slots, is_directory};
+use crate::fat_error::{FatError, Result};
+use crate::fat_section_util::{get_fat_entry, END_OF_CHAIN};
+use crate::root_dir_util::{directory_entry, display_name, entry_file_size, entry_first_cluster, is_listed};
+use crate::shell_parsing::Args;
+use crate::shell_state::ShellState;
+use crate::volume::Volume;
+
+/// FAT entry of a cluster marked as bad
+const BAD_CLUSTER: usize = 0xFF7;
+
+/// fsck
+///
+/// Checks the FATs and the directory tree without changing anything.
+pub fn check_image(shell_state: ShellState, _args: &Args) -> Result<ShellState> {
+    let mut volume = shell_state.volume()?;
+    let mut problems = vec![];
+
+    let bpb = match BiosParameterBlock::parse(&volume.read(0, BPB_END)?) {
+        Some(bpb) => bpb,
+        None => {
+            return Err(FatError::InvalidBootSector(
+                "The boot sector doesn't have a valid BPB".to_owned(),
+            ))
+        }
+    };
+
+    let image_sectors = volume.size() / bpb.bytes_per_sector;
+    if image_sectors < bpb.sectors() {
+        problems.push(format!(
+            "The BPB says {} sectors but the image only has {}",
+            bpb.sectors(),
+            image_sectors
+        ));
+    }
+    if bpb.fat_type() != "FAT12" {
+        problems.push(format!("{} clusters is too many for FAT12", bpb.cluster_count()));
+    }
+
+    let fat_size = bpb.sectors_per_fat * bpb.bytes_per_sector;
+    let first_fat = volume.read(bpb.fat_start(0) * bpb.bytes_per_sector, fat_size)?;
+    for fat_index in 1..bpb.number_fats {
+        if volume.read(bpb.fat_start(fat_index) * bpb.bytes_per_sector, fat_size)? != first_fat {
+            problems.push(format!("FAT{} doesn't match FAT1", fat_index + 1));
+        }
+    }
+    let media_entry = get_fat_entry(&mut volume, 0)?;
+    if media_entry & 0xFF != bpb.media as usize {
+        problems.push(format!(
+            "FAT entry 0 is {:03X} but the media descriptor is {:02X}",
+            media_entry, bpb.media
+        ));
+    }
+
+    // Which file owns each cluster
+    let mut owners: Vec<Option<String>> = vec![None; bpb.fat_entries()];
+    check_directory(&mut volume, &bpb, 0, 0, "", &mut owners, &mut problems)?;
+
+    let mut lost_clusters = 0;
+    for (cluster, owner) in owners.iter().enumerate().skip(2) {
+        let entry = get_fat_entry(&mut volume, cluster)?;
+        if entry != 0 && entry != BAD_CLUSTER && owner.is_none() {
+            lost_clusters += 1;
+        }
+    }
+    if lost_clusters > 0 {
+        problems.push(format!("{} clusters are in use but don't belong to any file", lost_clusters));
+    }
+
+    let used_clusters = owners.iter().filter(|owner| owner.is_some()).count();
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    println!(
+        "{} of {} clusters used, {} problems found",
+        used_clusters,
+        bpb.cluster_count(),
+        problems.len()
+    );
+    if !problems.is_empty() {
+        return Err(FatError::CheckFailed(format!("The image has {} problems", problems.len())));
+    }
+
+    Ok(shell_state)
+}
+
+/// Checks every entry of a directory and the directories below it
+fn check_directory<D: BlockDevice>(
+    volume: &mut Volume<D>,
+    bpb: &BiosParameterBlock,
+    dir_cluster: usize,
+    parent_cluster: usize,
+    dir_path: &str,
+    owners: &mut Vec<Option<String>>,
+    problems: &mut Vec<String>,
+) -> Result<()> {
+    for offset in directory_slots(volume, dir_cluster)? {
+        let entry = directory_entry(volume, offset)?;
+        if entry[0] == 0 {
+            break;
+        }
+        if !is_listed(&entry) {
+            continue;
+        }
+
+        let name = display_name(&entry);
+        let path = format!("{}/{}", dir_path, name);
+        let first_cluster = entry_first_cluster(&entry);
+
+        if name == "." || name == ".." {
+            let expected = if name == "." { dir_cluster } else { parent_cluster };
+            if dir_cluster == 0 {
+                problems.push(format!("The root directory has a {} entry", name));
+            } else if first_cluster != expected {
+                problems.push(format!("{} points at cluster {} instead of {}", path, first_cluster, expected));
+            }
+            continue;
+        }
+
+        let chain_length = claim_chain(volume, bpb, first_cluster, &path, owners, problems)?;
+
+        if is_directory(&entry) {
+            if first_cluster == 0 {
+                problems.push(format!("{} is a directory without a cluster", path));
+            } else if chain_length > 0 {
+                check_directory(volume, bpb, first_cluster, dir_cluster, &path, owners, problems)?;
+            }
+        } else {
+            let expected_length = entry_file_size(&entry).div_ceil(bpb.bytes_per_cluster());
+            if chain_length != expected_length {
+                problems.push(format!(
+                    "{} is {} bytes but has {} clusters instead of {}",
+                    path,
+                    entry_file_size(&entry),
+                    chain_length,
+                    expected_length
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Marks the clusters of a chain as owned by `path`, returning how many it has
+fn claim_chain<D: BlockDevice>(
+    volume: &mut Volume<D>,
+    bpb: &BiosParameterBlock,
+    first_cluster: usize,
+    path: &str,
+    owners: &mut [Option<String>],
+    problems: &mut Vec<String>,
+) -> Result<usize> {
+    let mut cluster = first_cluster;
+    let mut length = 0;
+
+    while cluster != 0 && cluster < END_OF_CHAIN {
+        if cluster < 2 || cluster >= bpb.fat_entries() {
+            problems.push(format!("{} points at cluster {}, which isn't on the disk", path, cluster));
+            break;
+        }
+        if let Some(owner) = &owners[cluster] {
+            if owner == path {
+                problems.push(format!("{} has a loop at cluster {}", path, cluster));
+            } else {
+                problems.push(format!("{} and {} share cluster {}", path, owner, cluster));
+            }
+            break;
+        }
+
+        owners[cluster] = Some(path.to_owned());
+        length += 1;
+
+        let next_cluster = get_fat_entry(volume, cluster)?;
+        if next_cluster == 0 {
+            problems.push(format!("{} uses cluster {}, which is marked free", path, cluster));
+            break;
+        }
+        if next_cluster == BAD_CLUSTER {
+            problems.push(format!("{} uses cluster {}, which is marked bad", path, cluster));
+            break;
+        }
+        cluster = next_cluster;
+    }
+
+    Ok(length)
+}
@@ -0,0 +1,201 @@
+use crate::block_device::BlockDevice;
+use crate::fat_error::{FatError, Result};
+use crate::fat_section_util::cluster_chain;
+use crate::read_file::find_file_entry;
+use crate::root_dir_util::entry_first_cluster;
+use crate::shell_parsing::{expect_number, Args};
+use crate::shell_state::ShellState;
+use crate::volume::Volume;
+
+const BYTES_PER_LINE: usize = 16;
+
+/// Formats bytes like `xxd`, collapsing repeated lines into `*`
+pub fn format_hex(bytes: &[u8], start_offset: usize) -> Vec<String> {
+    let mut lines = vec![];
+    let mut previous: Option<&[u8]> = None;
+    let mut collapsed = false;
+
+    for (line_index, line) in bytes.chunks(BYTES_PER_LINE).enumerate() {
+        if previous == Some(line) && line.len() == BYTES_PER_LINE {
+            if !collapsed {
+                lines.push("*".to_owned());
+                collapsed = true;
+            }
+            continue;
+        }
+        previous = Some(line);
+        collapsed = false;
+
+        let hex: Vec<String> = line.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let ascii: String = line
+            .iter()
+            .map(|&byte| {
+                if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+
+        lines.push(format!(
+            "{:08x}: {:<47}  |{}|",
+            start_offset + line_index * BYTES_PER_LINE,
+            hex.join(" "),
+            ascii
+        ));
+    }
+
+    lines
+}
+
+fn print_sectors<D: BlockDevice>(volume: &mut Volume<D>, first_sector: usize, count: usize) -> Result<()> {
+    let bpb = volume.bpb().clone();
+    let sector_size = bpb.bytes_per_sector;
+
+    for lba in first_sector..first_sector + count {
+        let start = lba * sector_size;
+        if start + sector_size > volume.size() {
+            println!("; LBA {} is past the end of the image", lba);
+            break;
+        }
+
+        println!("; LBA {} ({})", lba, bpb.sector_region(lba));
+        for line in format_hex(&volume.read(start, sector_size)?, start) {
+            println!("{}", line);
+        }
+    }
+    Ok(())
+}
+
+/// hexdump <lba> [count]
+/// hexdump file <name>
+pub fn hexdump(shell_state: ShellState, args: &Args) -> Result<ShellState> {
+    let target = args.expect(1, "sector or file")?;
+    let mut volume = shell_state.volume()?;
+
+    if target == "file" {
+        let filename = args.expect(2, "filename")?;
+        let file_entry = find_file_entry(&mut volume, shell_state.get_cwd(), &filename)?;
+
+        let bpb = volume.bpb().clone();
+        for cluster in cluster_chain(&mut volume, entry_first_cluster(&file_entry))? {
+            print_sectors(&mut volume, bpb.cluster_start(cluster), bpb.sectors_per_cluster)?;
+        }
+    } else {
+        let first_sector = expect_number(&target, "Sector")?;
+        let count = match args.get(2) {
+            Some(count) => expect_number(&count, "Count")?,
+            None => 1,
+        };
+        print_sectors(&mut volume, first_sector, count)?;
+    }
+
+    Ok(shell_state)
+}
+
+/// peek <offset> [length]
+pub fn peek(shell_state: ShellState, args: &Args) -> Result<ShellState> {
+    let offset = expect_number(&args.expect(1, "offset")?, "Offset")?;
+    let length = match args.get(2) {
+        Some(length) => expect_number(&length, "Length")?,
+        None => BYTES_PER_LINE,
+    };
+
+    let mut volume = shell_state.volume()?;
+    let end = (offset + length).min(volume.size());
+    if offset >= end {
+        return Err(FatError::BadArgument("Offset is past the end of the image".to_owned()));
+    }
+
+    let sector_size = volume.bpb().bytes_per_sector;
+    println!(
+        "; offset 0x{:x} is in LBA {} ({})",
+        offset,
+        offset / sector_size,
+        volume.bpb().sector_region(offset / sector_size)
+    );
+    for line in format_hex(&volume.read(offset, end - offset)?, offset) {
+        println!("{}", line);
+    }
+
+    Ok(shell_state)
+}
+
+/// poke <offset> <hex bytes>...
+/// poke lba <lba> <hex bytes>...
+/// poke file <host file> <lba> [count]
+pub fn poke(mut shell_state: ShellState, args: &Args) -> Result<ShellState> {
+    let first = args.expect(1, "offset")?;
+    let mut volume = shell_state.volume_mut()?;
+    let sector_size = volume.bpb().bytes_per_sector;
+
+    let (offset, data) = match first.as_str() {
+        "file" => {
+            let host_filename = args.expect(2, "host file")?;
+            let lba = expect_number(&args.expect(3, "sector")?, "Sector")?;
+            let mut data =
+                std::fs::read(&host_filename).map_err(FatError::io(format!("Can't read {}", host_filename)))?;
+
+            let sectors = data.len().div_ceil(sector_size);
+            let count = match args.get(4) {
+                Some(count) => expect_number(&count, "Count")?,
+                None => sectors,
+            };
+            if sectors > count {
+                return Err(FatError::BadArgument(format!(
+                    "{} needs {} sectors but only {} were given",
+                    host_filename, sectors, count
+                )));
+            }
+
+            // Zero the rest of the range
+            data.resize(count * sector_size, 0);
+            (lba * sector_size, data)
+        }
+        "lba" => {
+            let lba = expect_number(&args.expect(2, "sector")?, "Sector")?;
+            (lba * sector_size, parse_hex_bytes(args.rest(3))?)
+        }
+        _ => (
+            expect_number(&first, "Offset")?,
+            parse_hex_bytes(args.rest(2))?,
+        ),
+    };
+
+    if data.is_empty() {
+        return Err(FatError::MissingArgument("bytes to write".to_owned()));
+    }
+    if offset + data.len() > volume.size() {
+        return Err(FatError::BadArgument("Write goes past the end of the image".to_owned()));
+    }
+
+    volume.write(offset, &data)?;
+
+    let first_sector = offset / sector_size;
+    let last_sector = (offset + data.len() - 1) / sector_size;
+    println!(
+        "Wrote {} bytes at 0x{:x} (LBA {}-{}, {})",
+        data.len(),
+        offset,
+        first_sector,
+        last_sector,
+        volume.bpb().sector_region(first_sector)
+    );
+
+    Ok(shell_state)
+}
+
+/// Parses arguments like `eb 3c 90` or `eb3c90` into bytes
+fn parse_hex_bytes(args: &[String]) -> Result<Vec<u8>> {
+    let digits: String = args.concat();
+    let not_hex = || FatError::BadArgument(format!("{} isn't a list of hex bytes", digits));
+    if !digits.len().is_multiple_of(2) || !digits.is_ascii() {
+        return Err(not_hex());
+    }
+
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).map_err(|_| not_hex()))
+        .collect()
+}
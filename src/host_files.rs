@@ -0,0 +1,305 @@
+//! The shell commands that work on the host side, like an FTP client's `lcd`, `lls` and `mput`.
+//!
+//! The shell keeps its own host directory, which `lcd` changes. Host paths given to any command
+//! start from it, and `get` and `mget` put files there when they aren't told where. `import` brings
+//! in a whole tree.
+
+use crate::block_device::BlockDevice;
+use crate::directories::{
+    create_directory, expand_glob, find_in_directory, glob_matches, has_wildcards, is_directory, resolve_directory,
+    split_path,
+};
+use crate::fat_error::{FatError, Result};
+use crate::root_dir_util::{
+    build_directory_entry, directory_entry, entry_first_cluster, set_entry_time, write_directory_entry,
+};
+use crate::shell_files::{newfile, save_file_to_os, store_file};
+use crate::shell_parsing::Args;
+use crate::shell_state::ShellState;
+use crate::timestamps::CivilTime;
+use crate::volume::Volume;
+use std::fs::{self, Metadata};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// What `import` did, for its report
+#[derive(Default)]
+struct Import {
+    files: usize,
+    directories: usize,
+    // Host files left out, with why
+    skipped: Vec<(PathBuf, String)>,
+}
+
+/// lcd [host directory]
+///
+/// Without a directory, goes to the home directory.
+pub fn change_host_directory(shell_state: ShellState, args: &Args) -> Result<ShellState> {
+    let dir = match args.get(1) {
+        Some(dir) => dir,
+        None => std::env::var("HOME")
+            .or_else(|_| std::env::var("USERPROFILE"))
+            .map_err(|_| FatError::MissingArgument("host directory, there's no home directory".to_owned()))?,
+    };
+    let host_cwd = Path::new(&dir)
+        .canonicalize()
+        .map_err(FatError::io(format!("Can't go to {}", dir)))?;
+    if !host_cwd.is_dir() {
+        return Err(FatError::NotADirectory(dir));
+    }
+
+    println!("Local directory now {}", host_cwd.display());
+    Ok(shell_state.set_host_cwd(host_cwd))
+}
+
+/// lpwd
+pub fn print_host_directory(shell_state: ShellState, _args: &Args) -> Result<ShellState> {
+    println!("Local directory is {}", shell_state.get_host_cwd().display());
+    Ok(shell_state)
+}
+
+/// lls [host directory, file or pattern]
+pub fn list_host_directory(shell_state: ShellState, args: &Args) -> Result<ShellState> {
+    let path = args.get(1).unwrap_or_else(|| shell_state.host_path("."));
+    let paths = if Path::new(&path).is_dir() {
+        let mut paths = vec![];
+        for entry in std::fs::read_dir(&path).map_err(FatError::io(format!("Can't read {}", path)))? {
+            let entry = entry.map_err(FatError::io(format!("Can't read {}", path)))?;
+            // Hidden files only when asked for, as with tab completion
+            if !entry.file_name().to_string_lossy().starts_with('.') {
+                paths.push(entry.path());
+            }
+        }
+        paths.sort();
+        paths
+    } else {
+        host_glob(&path)?
+    };
+
+    let names: Vec<String> = paths
+        .iter()
+        .map(|path| path.file_name().unwrap_or_default().to_string_lossy().into_owned())
+        .collect();
+    let width = names.iter().map(String::len).max().unwrap_or_default().max(12);
+    let shown = Path::new(&path).canonicalize().unwrap_or_else(|_| PathBuf::from(&path));
+    println!("Listing files in {}:", shown.display());
+    println!("-----------------------");
+    for (path, name) in paths.iter().zip(&names) {
+        match path.metadata() {
+            Ok(metadata) if metadata.is_dir() => println!("{:<width$} <DIR>", name, width = width),
+            Ok(metadata) => println!("{:<width$} {:>10}", name, metadata.len(), width = width),
+            Err(_) => println!("{:<width$} ?", name, width = width),
+        }
+    }
+    println!("-----------------------");
+    Ok(shell_state)
+}
+
+/// mput <host files or patterns...>
+///
+/// Each file goes into the current directory of the image under its own name.
+pub fn put_files(mut shell_state: ShellState, args: &Args) -> Result<ShellState> {
+    for pattern in args.rest(1) {
+        let paths = if has_wildcards(&host_file_name(pattern)) {
+            host_glob(pattern)?.into_iter().filter(|path| !path.is_dir()).collect()
+        } else {
+            vec![PathBuf::from(pattern)]
+        };
+        for path in paths {
+            shell_state = newfile(shell_state, &Args::new(&["put", &path.to_string_lossy()]))?;
+        }
+    }
+    Ok(shell_state)
+}
+
+/// mget <files or patterns...>
+///
+/// Each file goes into the host directory under its own name, directories a pattern matches are
+/// passed over.
+pub fn get_files(mut shell_state: ShellState, args: &Args) -> Result<ShellState> {
+    for pattern in args.rest(1) {
+        let paths = if has_wildcards(split_path(pattern).1) {
+            let cwd = shell_state.get_cwd();
+            let mut volume = shell_state.volume()?;
+            let mut files = vec![];
+            for path in expand_glob(&mut volume, cwd, pattern)? {
+                if resolve_directory(&mut volume, cwd, &path).is_err() {
+                    files.push(path);
+                }
+            }
+            files
+        } else {
+            vec![pattern.clone()]
+        };
+        for path in paths {
+            shell_state = save_file_to_os(shell_state, &Args::new(&["get", &path]))?;
+        }
+    }
+    Ok(shell_state)
+}
+
+/// import <host directory> [directory]
+///
+/// Mirrors everything below a host directory into a directory of the image, the current one by
+/// default. Directories that aren't there yet are made and files that are get replaced. Names that
+/// don't fit 8.3 get long names, and everything keeps its modification time. Files that don't fit
+/// in the image are left out and listed at the end, rather than stopping the rest.
+pub fn import_tree(mut shell_state: ShellState, args: &Args) -> Result<ShellState> {
+    let host_dir = args.expect(1, "host directory")?;
+    if !Path::new(&host_dir).is_dir() {
+        return Err(FatError::NotADirectory(host_dir));
+    }
+    let target = args.get(2).unwrap_or_default();
+    let cwd = shell_state.get_cwd();
+    let mut volume = shell_state.volume_mut()?;
+    let dir_cluster = match resolve_directory(&mut volume, cwd, &target) {
+        Ok(dir_cluster) => dir_cluster,
+        Err(FatError::NotFound(_)) => create_directory(&mut volume, cwd, &target)?,
+        Err(error) => return Err(error),
+    };
+
+    let mut import = Import::default();
+    import_directory(&mut volume, Path::new(&host_dir), dir_cluster, &mut import)?;
+
+    println!(
+        "Imported {} files and {} directories from {}!",
+        import.files, import.directories, host_dir
+    );
+    if !import.skipped.is_empty() {
+        println!("Skipped {} files:", import.skipped.len());
+        for (host_path, reason) in &import.skipped {
+            println!("  {}: {}", host_path.display(), reason);
+        }
+    }
+    Ok(shell_state)
+}
+
+/// Imports the contents of `host_dir` into the directory at `dir_cluster`, in name order
+fn import_directory<D: BlockDevice>(
+    volume: &mut Volume<D>,
+    host_dir: &Path,
+    dir_cluster: usize,
+    import: &mut Import,
+) -> Result<()> {
+    let read_error = |path: &Path| FatError::io(format!("Can't read {}", path.display()));
+    let mut host_paths = fs::read_dir(host_dir)
+        .and_then(|entries| entries.map(|entry| entry.map(|entry| entry.path())).collect::<std::io::Result<Vec<_>>>())
+        .map_err(read_error(host_dir))?;
+    host_paths.sort();
+
+    for host_path in host_paths {
+        let name = host_file_name(&host_path.to_string_lossy());
+        // Following links, so a linked file or directory is imported like any other
+        let metadata = fs::metadata(&host_path).map_err(read_error(&host_path))?;
+        if metadata.is_dir() {
+            match import_subdirectory(volume, dir_cluster, &name, &metadata) {
+                Ok(cluster) => {
+                    import.directories += 1;
+                    import_directory(volume, &host_path, cluster, import)?;
+                }
+                Err(error) if can_skip(&error) => skip_tree(&host_path, &error.to_string(), import),
+                Err(error) => return Err(error),
+            }
+        } else if metadata.is_file() {
+            let data = fs::read(&host_path).map_err(read_error(&host_path))?;
+            let mut entry = build_directory_entry("", 0, 0, false);
+            if let Some(time) = modified_time(&metadata) {
+                set_entry_time(&mut entry, &time);
+            }
+            match store_file(volume, dir_cluster, &name, &data, entry) {
+                Ok(()) => import.files += 1,
+                Err(error) if can_skip(&error) => import.skipped.push((host_path, error.to_string())),
+                Err(error) => return Err(error),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The first cluster of the directory `name`, made with the host directory's time if it isn't there
+fn import_subdirectory<D: BlockDevice>(
+    volume: &mut Volume<D>,
+    parent: usize,
+    name: &str,
+    metadata: &Metadata,
+) -> Result<usize> {
+    if let Some(offset) = find_in_directory(volume, parent, name)? {
+        let entry = directory_entry(volume, offset)?;
+        if !is_directory(&entry) {
+            return Err(FatError::AlreadyExists(name.to_owned()));
+        }
+        return Ok(entry_first_cluster(&entry));
+    }
+
+    let cluster = create_directory(volume, parent, name)?;
+    if let (Some(time), Some(offset)) = (modified_time(metadata), find_in_directory(volume, parent, name)?) {
+        let mut entry = directory_entry(volume, offset)?;
+        set_entry_time(&mut entry, &time);
+        write_directory_entry(volume, offset, &entry)?;
+    }
+    Ok(cluster)
+}
+
+/// Whether `import` can leave out what failed with `error` and carry on with the rest
+fn can_skip(error: &FatError) -> bool {
+    matches!(
+        error,
+        FatError::DiskFull { .. }
+            | FatError::RootDirectoryFull
+            | FatError::InvalidName(_)
+            | FatError::AlreadyExists(_)
+            | FatError::IsADirectory(_)
+    )
+}
+
+/// Lists every file below a directory that couldn't be made as skipped
+fn skip_tree(host_path: &Path, reason: &str, import: &mut Import) {
+    if !host_path.is_dir() {
+        import.skipped.push((host_path.to_owned(), reason.to_owned()));
+        return;
+    }
+    let mut host_paths: Vec<PathBuf> = match fs::read_dir(host_path) {
+        Ok(entries) => entries.flatten().map(|entry| entry.path()).collect(),
+        Err(_) => vec![],
+    };
+    host_paths.sort();
+    for host_path in host_paths {
+        skip_tree(&host_path, reason, import);
+    }
+}
+
+fn modified_time(metadata: &Metadata) -> Option<CivilTime> {
+    let since_epoch = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some(CivilTime::from_unix(since_epoch.as_secs()))
+}
+
+fn host_file_name(path: &str) -> String {
+    Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// The host files and directories a pattern like `docs/*.txt` matches, in order
+///
+/// Only the last part of the path can have wildcards, which match as they do in the image.
+fn host_glob(pattern: &str) -> Result<Vec<PathBuf>> {
+    let name_pattern = host_file_name(pattern);
+    let dir = Path::new(pattern).parent().unwrap_or(Path::new(""));
+    let listed = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    let entries = std::fs::read_dir(listed).map_err(FatError::io(format!("Can't read {}", listed.display())))?;
+
+    let mut paths: Vec<PathBuf> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let hidden = name.starts_with('.') && !name_pattern.starts_with('.');
+            (glob_matches(&name_pattern, &name) && !hidden).then(|| dir.join(name))
+        })
+        .collect();
+    if paths.is_empty() {
+        return Err(FatError::NotFound(pattern.to_owned()));
+    }
+    paths.sort();
+    Ok(paths)
+}
@@ -0,0 +1,390 @@
+//! The image open on a drive, as the shell's commands work on it.
+//!
+//! The image file stays on the host and is read a few sectors at a time through a cache rather
+//! than loaded whole. Sectors that commands write are kept in memory until the image is saved, so
+//! unsaved changes can still be dropped. Every command works on a copy of the shell's state so a
+//! failed one changes nothing, and copying an image only copies the sectors that changed: the
+//! copies share the file and its cache.
+
+use crate::bios_parameter_block::BYTES_PER_SECTOR;
+use crate::block_device::{sector_range, Access, BlockDevice, FileDevice};
+use crate::change_trace::{record_read, record_write};
+use crate::fat_error::{FatError, Result};
+use crate::sector_cache::{CacheMode, CachedDevice};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
+use std::rc::{Rc, Weak};
+
+/// Sectors of the image file kept in memory unless `cache` says otherwise, enough for the FATs and
+/// root directory of most images
+pub const IMAGE_CACHE_SECTORS: usize = 256;
+
+/// All-zero blocks this size aren't written when the whole image is, so they stay holes in sparse files
+const SPARSE_BLOCK_SIZE: usize = 4096;
+
+enum Contents {
+    File {
+        device: CachedDevice<FileDevice>,
+        // In bytes, which can end part way through a sector
+        length: u64,
+    },
+    /// An image made in memory, like one being built from a manifest
+    Memory(Vec<u8>),
+}
+
+/// The image as it was opened or last saved
+struct Saved {
+    contents: Contents,
+    sector_count: usize,
+    // Sectors of the file that saving in place has written over since, as they were, for the
+    // copies of the image from before
+    overwritten: BTreeMap<usize, Rc<[u8]>>,
+    // The saved image this one was saved over, and the sectors they differ in
+    replaced: Option<(Weak<RefCell<Saved>>, BTreeSet<usize>)>,
+}
+
+impl Saved {
+    fn open(filename: &str, capacity: usize, mode: CacheMode) -> Result<Self> {
+        let device = FileDevice::open(filename, false)?;
+        let length = std::fs::metadata(filename)
+            .map_err(FatError::io(format!("Can't read the size of {}", filename)))?
+            .len();
+        Ok(Saved {
+            sector_count: device.sector_count(),
+            contents: Contents::File {
+                device: CachedDevice::new(device, capacity, mode),
+                length,
+            },
+            overwritten: BTreeMap::new(),
+            replaced: None,
+        })
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        Saved {
+            sector_count: bytes.len() / BYTES_PER_SECTOR,
+            contents: Contents::Memory(bytes),
+            overwritten: BTreeMap::new(),
+            replaced: None,
+        }
+    }
+
+    /// Sectors past the end read as zeros, like the rest of an image that's been made longer
+    fn read(&mut self, first_sector: usize, buffer: &mut [u8]) -> Result<()> {
+        let stored = self.sector_count.saturating_sub(first_sector) * BYTES_PER_SECTOR;
+        let (inside, past_end) = buffer.split_at_mut(stored.min(buffer.len()));
+        past_end.fill(0);
+        if inside.is_empty() {
+            return Ok(());
+        }
+        match &mut self.contents {
+            Contents::File { device, .. } => device.read_sectors(first_sector, inside)?,
+            Contents::Memory(bytes) => bytes.read_sectors(first_sector, inside)?,
+        }
+
+        let last_sector = first_sector + inside.len() / BYTES_PER_SECTOR;
+        for (&sector, data) in self.overwritten.range(first_sector..last_sector) {
+            let start = (sector - first_sector) * BYTES_PER_SECTOR;
+            inside[start..start + BYTES_PER_SECTOR].copy_from_slice(data);
+        }
+        Ok(())
+    }
+}
+
+/// An image whose changes wait in memory until they're saved
+#[derive(Clone)]
+pub struct ImageDevice {
+    saved: Rc<RefCell<Saved>>,
+    sector_count: usize,
+    // The sectors that differ from the saved image, by number
+    changed: BTreeMap<usize, Rc<[u8]>>,
+}
+
+impl Default for ImageDevice {
+    fn default() -> Self {
+        Self::from_bytes(vec![])
+    }
+}
+
+impl ImageDevice {
+    /// Opens an image file, which is only read until the image is saved
+    pub fn open(filename: &str) -> Result<Self> {
+        Ok(Self::from_saved(Saved::open(filename, IMAGE_CACHE_SECTORS, CacheMode::WriteBack)?))
+    }
+
+    /// An image held in memory, with no file behind it
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        Self::from_saved(Saved::from_bytes(bytes))
+    }
+
+    fn from_saved(saved: Saved) -> Self {
+        ImageDevice {
+            sector_count: saved.sector_count,
+            saved: Rc::new(RefCell::new(saved)),
+            changed: BTreeMap::new(),
+        }
+    }
+
+    /// Whether anything differs from the image as it was opened or last saved
+    pub fn is_modified(&self) -> bool {
+        !self.changed.is_empty() || self.sector_count != self.saved.borrow().sector_count
+    }
+
+    /// Sector 0, or nothing for an image too small to have one
+    pub fn boot_sector(&self) -> Result<Vec<u8>> {
+        let mut boot_sector = vec![0; BYTES_PER_SECTOR.min(self.sector_count * BYTES_PER_SECTOR)];
+        self.read(0, &mut boot_sector)?;
+        Ok(boot_sector)
+    }
+
+    /// The whole image, for images small enough to hold, like one built from a manifest
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut bytes = vec![0; self.sector_count * BYTES_PER_SECTOR];
+        self.read(0, &mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Whether the image holds exactly `bytes`, compared a sector at a time
+    pub fn matches(&self, bytes: &[u8]) -> Result<bool> {
+        if bytes.len() != self.sector_count * BYTES_PER_SECTOR {
+            return Ok(false);
+        }
+        let mut sector = vec![0; BYTES_PER_SECTOR];
+        for (number, expected) in bytes.chunks(BYTES_PER_SECTOR).enumerate() {
+            self.read(number, &mut sector)?;
+            if sector != expected {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Replaces the whole image with `bytes`, which can be a different size
+    pub fn overwrite(&mut self, bytes: &[u8]) -> Result<()> {
+        let sector_count = bytes.len() / BYTES_PER_SECTOR;
+        self.changed.retain(|&sector, _| sector < sector_count);
+        self.sector_count = sector_count;
+        self.write(0, &bytes[..sector_count * BYTES_PER_SECTOR])
+    }
+
+    /// Sectors whose contents differ between two versions of an image
+    ///
+    /// Versions that share a saved image can only differ in the sectors either of them changed,
+    /// and saving only changes the sectors that were. Anything else is compared sector by sector.
+    pub fn changed_sectors(before: &ImageDevice, after: &ImageDevice) -> Result<BTreeSet<usize>> {
+        let sector_count = before.sector_count.max(after.sector_count);
+        let resized = before.sector_count.min(after.sector_count)..sector_count;
+        let mut candidates: BTreeSet<usize> = before.changed.keys().chain(after.changed.keys()).copied().collect();
+        match saved_since(&before.saved, &after.saved) {
+            Some(saved) => candidates.extend(saved.into_iter().chain(resized)),
+            None => candidates.extend(0..sector_count),
+        }
+
+        let mut changed = BTreeSet::new();
+        let (mut old, mut new) = (vec![0; BYTES_PER_SECTOR], vec![0; BYTES_PER_SECTOR]);
+        for sector in candidates {
+            if sector >= before.sector_count || sector >= after.sector_count {
+                changed.insert(sector);
+                continue;
+            }
+            before.read(sector, &mut old)?;
+            after.read(sector, &mut new)?;
+            if old != new {
+                changed.insert(sector);
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Whether the changes can be written into `filename` in place: it has to be the same size
+    /// as the image, and still the size it was when the image was read from it
+    pub fn can_save_in_place(&self, filename: &str) -> bool {
+        let saved = self.saved.borrow();
+        let Contents::File { length, .. } = saved.contents else {
+            return false;
+        };
+        let file_length = std::fs::metadata(filename).map(|metadata| metadata.len()).ok();
+        file_length == Some(length) && length == (self.sector_count * BYTES_PER_SECTOR) as u64
+    }
+
+    /// Writes the changed sectors into `filename` in place, the image is read from it from then on
+    ///
+    /// Copies of the image from before still see what they did, the sectors written over are
+    /// kept for them.
+    pub fn save_in_place(&mut self, filename: &str) -> Result<()> {
+        let mut overwritten = BTreeMap::new();
+        for &sector in self.changed.keys() {
+            let mut old = vec![0; BYTES_PER_SECTOR];
+            self.saved.borrow_mut().read(sector, &mut old)?;
+            overwritten.insert(sector, Rc::from(old));
+        }
+        self.saved.borrow_mut().overwritten.extend(overwritten);
+
+        // Writing back a cache that holds every changed sector puts neighbouring ones in one write,
+        // and flushing it syncs the file
+        let mode = self.with_cache(|cache| cache.mode()).unwrap_or(CacheMode::WriteBack);
+        let mut device = CachedDevice::new(FileDevice::open(filename, true)?, self.changed.len(), mode);
+        for (&sector, data) in &self.changed {
+            device.write_sectors(sector, data)?;
+        }
+        device.flush()?;
+        self.reopen(filename)
+    }
+
+    /// Writes the whole image into `file`
+    ///
+    /// Blocks of zeros are skipped over, so a mostly empty new image doesn't take up its whole size.
+    /// Bytes after the last whole sector of the file it was read from aren't part of the image, so
+    /// they aren't written.
+    pub fn write_to(&self, file: &mut File) -> Result<()> {
+        let length = self.sector_count * BYTES_PER_SECTOR;
+        let mut block = vec![0; SPARSE_BLOCK_SIZE];
+        for start in (0..length).step_by(SPARSE_BLOCK_SIZE) {
+            let block = &mut block[..SPARSE_BLOCK_SIZE.min(length - start)];
+            self.read(start / BYTES_PER_SECTOR, block)?;
+            if block.iter().any(|&byte| byte != 0) {
+                file.seek(SeekFrom::Start(start as u64))
+                    .and_then(|_| file.write_all(block))
+                    .map_err(FatError::io(format!("Can't write sector {}", start / BYTES_PER_SECTOR)))?;
+            }
+        }
+        // The file only reaches its full length through the last block written, so a hole at the end needs this
+        file.set_len(length as u64)
+            .map_err(FatError::io("Can't set the length of the image"))
+    }
+
+    /// Reads the image from `filename` from now on, once everything in it has been saved there
+    pub fn reopen(&mut self, filename: &str) -> Result<()> {
+        let (capacity, mode) = self
+            .with_cache(|cache| (cache.capacity(), cache.mode()))
+            .unwrap_or((IMAGE_CACHE_SECTORS, CacheMode::WriteBack));
+        let mut saved = Saved::open(filename, capacity, mode)?;
+        let resized = saved.sector_count.min(self.sector_count)..saved.sector_count.max(self.sector_count);
+        let written = self.changed.keys().copied().chain(resized).collect();
+        saved.replaced = Some((Rc::downgrade(&self.saved), written));
+        *self = Self::from_saved(saved);
+        Ok(())
+    }
+
+    /// Runs `f` on the cache the image file is read through, `None` for an image made in memory
+    ///
+    /// Its mode is how saving in place writes the file. Copies of the image share the cache, and
+    /// its statistics start over when the image is saved.
+    pub fn with_cache<T>(&self, f: impl FnOnce(&mut CachedDevice<FileDevice>) -> T) -> Option<T> {
+        match &mut self.saved.borrow_mut().contents {
+            Contents::File { device, .. } => Some(f(device)),
+            Contents::Memory(_) => None,
+        }
+    }
+
+    /// Fills `buffer`, a whole number of sectors, starting at `first_sector`
+    ///
+    /// Reading doesn't change the image, so it can be done through a shared reference.
+    pub fn read(&self, first_sector: usize, buffer: &mut [u8]) -> Result<()> {
+        sector_range(self, first_sector, buffer.len())?;
+        let mut saved = self.saved.borrow_mut();
+        let sectors = buffer.len() / BYTES_PER_SECTOR;
+        // Runs of sectors that haven't changed are read from the saved image in one go
+        let mut run_start = 0;
+        for number in 0..=sectors {
+            let changed = self.changed.get(&(first_sector + number)).filter(|_| number < sectors);
+            if number < sectors && changed.is_none() {
+                continue;
+            }
+            if run_start < number {
+                saved.read(
+                    first_sector + run_start,
+                    &mut buffer[run_start * BYTES_PER_SECTOR..number * BYTES_PER_SECTOR],
+                )?;
+            }
+            if let Some(data) = changed {
+                buffer[number * BYTES_PER_SECTOR..(number + 1) * BYTES_PER_SECTOR].copy_from_slice(data);
+            }
+            run_start = number + 1;
+        }
+        Ok(())
+    }
+
+    /// Sectors written back the way they were saved are forgotten, so `is_modified` stays exact
+    fn write(&mut self, first_sector: usize, data: &[u8]) -> Result<()> {
+        sector_range(self, first_sector, data.len())?;
+        let mut saved = vec![0; BYTES_PER_SECTOR];
+        for (number, sector) in data.chunks(BYTES_PER_SECTOR).enumerate() {
+            self.saved.borrow_mut().read(first_sector + number, &mut saved)?;
+            if sector == saved.as_slice() {
+                self.changed.remove(&(first_sector + number));
+            } else {
+                self.changed.insert(first_sector + number, Rc::from(sector));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The sectors saving has changed going from the saved image `before` to `after`, if it's one
+/// that was saved over `before`, or `before` itself
+fn saved_since(before: &Rc<RefCell<Saved>>, after: &Rc<RefCell<Saved>>) -> Option<BTreeSet<usize>> {
+    let mut written = BTreeSet::new();
+    let mut saved = Rc::clone(after);
+    while !Rc::ptr_eq(&saved, before) {
+        let (replaced, sectors) = saved.borrow().replaced.clone()?;
+        written.extend(sectors);
+        saved = replaced.upgrade()?;
+    }
+    Some(written)
+}
+
+impl BlockDevice for ImageDevice {
+    fn sector_size(&self) -> usize {
+        BYTES_PER_SECTOR
+    }
+
+    fn sector_count(&self) -> usize {
+        self.sector_count
+    }
+
+    fn read_sectors(&mut self, first_sector: usize, buffer: &mut [u8]) -> Result<()> {
+        self.read(first_sector, buffer)
+    }
+
+    fn write_sectors(&mut self, first_sector: usize, data: &[u8]) -> Result<()> {
+        self.write(first_sector, data)
+    }
+
+    fn observe(&mut self, access: Access, offset: usize, length: usize) {
+        record_access(access, offset, length)
+    }
+}
+
+/// An image that's only being looked at, which refuses writes
+impl BlockDevice for &ImageDevice {
+    fn sector_size(&self) -> usize {
+        BYTES_PER_SECTOR
+    }
+
+    fn sector_count(&self) -> usize {
+        self.sector_count
+    }
+
+    fn read_sectors(&mut self, first_sector: usize, buffer: &mut [u8]) -> Result<()> {
+        self.read(first_sector, buffer)
+    }
+
+    fn write_sectors(&mut self, _first_sector: usize, _data: &[u8]) -> Result<()> {
+        Err(FatError::ReadOnly("The image".to_owned()))
+    }
+
+    fn observe(&mut self, access: Access, offset: usize, length: usize) {
+        record_access(access, offset, length)
+    }
+}
+
+/// What volumes do with the image goes into the trace of the command running, if it's traced
+fn record_access(access: Access, offset: usize, length: usize) {
+    match access {
+        Access::Read => record_read(offset, length),
+        Access::Write => record_write(offset, length),
+    }
+}
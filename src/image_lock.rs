@@ -0,0 +1,190 @@
+//! Keeps two sessions from changing the same image at once.
+//!
+//! A session that opens an image for writing holds a lock on `<image>.lock` until it's done with
+//! the image, and writes who it is into that file so anyone else trying gets told who to ask.
+//! The file is removed again when the lock is let go.
+//! The lock is on a file next to the image rather than the image itself, because `save -a`
+//! replaces the image with a new file. It's advisory: only programs that look for it respect it.
+
+use crate::fat_error::{FatError, Result};
+use crate::timestamps::CivilTime;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+pub struct ImageLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl ImageLock {
+    /// Takes the lock for `image_filename`, failing with who holds it if it's busy
+    pub fn acquire(image_filename: &str) -> Result<Self> {
+        let path = lock_path(image_filename);
+        loop {
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)
+                .map_err(FatError::io(format!("Can't open the lock file {}", path.display())))?;
+
+            match file.try_lock() {
+                Ok(()) => {}
+                Err(TryLockError::WouldBlock) => {
+                    let mut holder = String::new();
+                    // Whoever has it may not have said who they are yet
+                    let _ = file.read_to_string(&mut holder);
+                    return Err(FatError::Locked {
+                        image: image_filename.to_owned(),
+                        holder: holder.trim().to_owned(),
+                    });
+                }
+                Err(TryLockError::Error(error)) => {
+                    return Err(FatError::io(format!("Can't lock {}", path.display()))(error));
+                }
+            }
+
+            // The holder removes the file as it lets go, so the one just locked may be gone already,
+            // and someone else could lock a new file of the same name
+            if !is_same_file(&file, &path) {
+                continue;
+            }
+            file.set_len(0)
+                .and_then(|_| writeln!(file, "{}", holder_description()))
+                .map_err(FatError::io(format!("Can't write the lock file {}", path.display())))?;
+            return Ok(ImageLock { file, path });
+        }
+    }
+
+    /// Whether this is the lock for `image_filename`, so opening it again doesn't wait on itself
+    pub fn covers(&self, image_filename: &str) -> bool {
+        let other = lock_path(image_filename);
+        match (self.path.canonicalize(), other.canonicalize()) {
+            (Ok(path), Ok(other)) => path == other,
+            _ => self.path == other,
+        }
+    }
+}
+
+impl Drop for ImageLock {
+    fn drop(&mut self) {
+        // Removed while it's still locked, `acquire` notices if it locked the file just before
+        let _ = std::fs::remove_file(&self.path);
+        let _ = self.file.unlock();
+    }
+}
+
+/// Whether `path` still names the open `file`
+#[cfg(unix)]
+fn is_same_file(file: &File, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (file.metadata(), std::fs::metadata(path)) {
+        (Ok(open), Ok(named)) => open.dev() == named.dev() && open.ino() == named.ino(),
+        _ => false,
+    }
+}
+
+/// Elsewhere an open file can't be removed, so it's always still there
+#[cfg(not(unix))]
+fn is_same_file(_file: &File, _path: &Path) -> bool {
+    true
+}
+
+fn lock_path(image_filename: &str) -> PathBuf {
+    let mut path = Path::new(image_filename).as_os_str().to_owned();
+    path.push(".lock");
+    PathBuf::from(path)
+}
+
+/// `fat12 (pid 1234, sam@buildhost) since 2024-05-01 09:30:00 UTC`
+fn holder_description() -> String {
+    let program = std::env::args()
+        .next()
+        .and_then(|program| Path::new(&program).file_name().map(|name| name.to_string_lossy().into_owned()))
+        .unwrap_or_else(|| "fat12".to_owned());
+    let user = std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| "someone".to_owned());
+    let host = std::fs::read_to_string("/etc/hostname")
+        .ok()
+        .map(|host| host.trim().to_owned())
+        .filter(|host| !host.is_empty())
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .or_else(|| std::env::var("COMPUTERNAME").ok())
+        .unwrap_or_else(|| "an unknown host".to_owned());
+    let now = CivilTime::now();
+    format!(
+        "{} (pid {}, {}@{}) since {:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        program,
+        std::process::id(),
+        user,
+        host,
+        now.year,
+        now.month,
+        now.day,
+        now.hour,
+        now.minute,
+        now.second
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shell_state::ShellState;
+
+    /// An empty directory of its own under the system's temporary one, with a blank image in it
+    fn temp_image(name: &str) -> (PathBuf, String) {
+        let dir = std::env::temp_dir().join(format!("fat12-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let image = dir.join("a.img").to_string_lossy().into_owned();
+        std::fs::write(&image, [0; 4096]).unwrap();
+        (dir, image)
+    }
+
+    #[test]
+    fn a_second_lock_fails_naming_the_holder() {
+        let (dir, image) = temp_image("lock-held");
+        let _held = ImageLock::acquire(&image).unwrap();
+
+        match ImageLock::acquire(&image) {
+            Err(FatError::Locked { image: locked, holder }) => {
+                assert_eq!(locked, image);
+                assert!(holder.contains(&format!("pid {}", std::process::id())), "{}", holder);
+            }
+            other => panic!("expected the image to be locked, got {:?}", other.map(|_| ())),
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn letting_go_removes_the_lock_file() {
+        let (dir, image) = temp_image("lock-dropped");
+        let lock = ImageLock::acquire(&image).unwrap();
+        assert!(lock_path(&image).exists());
+        assert!(lock.covers(&image));
+
+        drop(lock);
+        assert!(!lock_path(&image).exists());
+        // Free for the next session
+        drop(ImageLock::acquire(&image).unwrap());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn only_opening_for_writing_locks() {
+        let (dir, image) = temp_image("lock-read-only");
+        let shell_state = ShellState::new().load_file(image.clone(), true).unwrap();
+        assert!(!lock_path(&image).exists());
+        drop(shell_state);
+
+        let shell_state = ShellState::new().load_file(image.clone(), false).unwrap();
+        assert!(lock_path(&image).exists());
+        drop(shell_state);
+        assert!(!lock_path(&image).exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,70 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+// The file system, which only needs `core` and `alloc` so it can also be built into a kernel
+pub mod bios_parameter_block;
+pub mod block_device;
+pub mod directories;
+pub mod fat_error;
+pub mod fat_section_util;
+pub mod file;
+pub mod long_names;
+pub mod new_file;
+pub mod read_dir;
+pub mod read_file;
+pub mod remove_file;
+pub mod root_dir_util;
+pub mod sector_cache;
+pub mod timestamps;
+pub mod volume;
+
+// The shell and the tools around it, which need the host
+#[cfg(feature = "std")]
+pub mod boot_template;
+#[cfg(feature = "std")]
+pub mod bootsector;
+#[cfg(feature = "std")]
+pub mod change_trace;
+#[cfg(feature = "std")]
+pub mod commands;
+#[cfg(feature = "std")]
+pub mod completion;
+#[cfg(feature = "std")]
+pub mod disassembler;
+#[cfg(feature = "std")]
+pub mod drives;
+#[cfg(feature = "std")]
+pub mod edit_file;
+#[cfg(feature = "std")]
+pub mod emulator;
+#[cfg(feature = "std")]
+pub mod fsck;
+#[cfg(feature = "std")]
+pub mod hexdump;
+#[cfg(feature = "std")]
+pub mod host_files;
+#[cfg(feature = "std")]
+pub mod image_device;
+#[cfg(feature = "std")]
+pub mod image_lock;
+#[cfg(feature = "std")]
+pub mod line_editor;
+#[cfg(feature = "std")]
+pub mod manifest;
+#[cfg(feature = "std")]
+pub mod scripts;
+#[cfg(feature = "std")]
+pub mod shell;
+#[cfg(feature = "std")]
+pub mod shell_files;
+#[cfg(feature = "std")]
+pub mod shell_images;
+#[cfg(feature = "std")]
+pub mod shell_parsing;
+#[cfg(feature = "std")]
+pub mod shell_state;
+#[cfg(feature = "std")]
+pub mod undo;
+#[cfg(feature = "std")]
+pub mod volume_info;
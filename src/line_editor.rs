@@ -0,0 +1,452 @@
+//! A small line editor for the interactive shell, so it doesn't need any crates.
+//!
+//! Emacs style keys: arrows, Home/End, Ctrl-A/E/B/F, Ctrl-K/U/W, Up/Down through history,
+//! Ctrl-R to search it and Tab to complete. The terminal is switched to raw mode with `stty`
+//! only while a line is being read.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+/// Lines kept in the history file
+const HISTORY_LIMIT: usize = 1000;
+
+const CTRL_A: u8 = 0x01;
+const CTRL_B: u8 = 0x02;
+const CTRL_C: u8 = 0x03;
+const CTRL_D: u8 = 0x04;
+const CTRL_E: u8 = 0x05;
+const CTRL_F: u8 = 0x06;
+const CTRL_G: u8 = 0x07;
+const BACKSPACE: u8 = 0x08;
+const TAB: u8 = 0x09;
+const CTRL_K: u8 = 0x0B;
+const CTRL_L: u8 = 0x0C;
+const ENTER: u8 = 0x0D;
+const CTRL_N: u8 = 0x0E;
+const CTRL_P: u8 = 0x10;
+const CTRL_R: u8 = 0x12;
+const CTRL_U: u8 = 0x15;
+const CTRL_W: u8 = 0x17;
+const ESCAPE: u8 = 0x1B;
+const DELETE: u8 = 0x7F;
+
+/// What Tab offers for the word before the cursor
+pub struct Completion {
+    /// Where the word being completed starts, in characters
+    pub start: usize,
+    /// Whole replacements for the word, directories ending in `/`
+    pub candidates: Vec<String>,
+}
+
+/// A key press, with escape sequences already decoded
+#[derive(PartialEq)]
+enum Key {
+    Char(char),
+    Control(u8),
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    Delete,
+    Escape,
+}
+
+pub struct LineEditor {
+    history: Vec<String>,
+    history_path: Option<PathBuf>,
+}
+
+impl LineEditor {
+    /// Loads the history kept in `history_path`, if there is one
+    pub fn new(history_path: Option<PathBuf>) -> Self {
+        let mut history: Vec<String> = history_path
+            .as_ref()
+            .and_then(|path| File::open(path).ok())
+            .map(|file| BufReader::new(file).lines().map_while(|line| line.ok()).collect())
+            .unwrap_or_default();
+        let excess = history.len().saturating_sub(HISTORY_LIMIT);
+        history.drain(..excess);
+
+        LineEditor { history, history_path }
+    }
+
+    /// `~/.fat12_history`, so every user keeps their own
+    pub fn default_history_path() -> Option<PathBuf> {
+        std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".fat12_history"))
+    }
+
+    /// Reads one line, returning None at the end of input (Ctrl-D on an empty line)
+    pub fn read_line(
+        &mut self,
+        prompt: &str,
+        complete: &mut dyn FnMut(&str) -> Completion,
+    ) -> io::Result<Option<String>> {
+        let raw_mode = RawMode::enable()?;
+        let line = Editor::new(prompt, &self.history).run(complete);
+        drop(raw_mode);
+
+        let line = line?;
+        if let Some(line) = &line {
+            self.add_history(line);
+        }
+        Ok(line)
+    }
+
+    /// Remembers a line, skipping blanks and repeats of the last one
+    fn add_history(&mut self, line: &str) {
+        let line = line.trim();
+        if line.is_empty() || self.history.last().map(String::as_str) == Some(line) {
+            return;
+        }
+        self.history.push(line.to_owned());
+
+        let path = match &self.history_path {
+            Some(path) => path,
+            None => return,
+        };
+        // Rewrite the file once it's grown well past the limit, otherwise just append
+        let result = if self.history.len() > HISTORY_LIMIT + HISTORY_LIMIT / 10 {
+            let excess = self.history.len() - HISTORY_LIMIT;
+            self.history.drain(..excess);
+            std::fs::write(path, self.history.join("\n") + "\n")
+        } else {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{}", line))
+        };
+        // Losing history isn't worth interrupting the shell for
+        let _ = result;
+    }
+}
+
+/// Puts the terminal in raw mode, putting it back how it was when dropped
+struct RawMode {
+    saved: String,
+}
+
+impl RawMode {
+    fn enable() -> io::Result<Self> {
+        let saved = stty(&["-g"])?;
+        // min 0 time 1: reads give up after a tenth of a second, so a lone Escape can be told apart
+        stty(&["raw", "-echo", "min", "0", "time", "1"])?;
+        Ok(RawMode {
+            saved: saved.trim().to_owned(),
+        })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = stty(&[&self.saved]);
+    }
+}
+
+fn stty(args: &[&str]) -> io::Result<String> {
+    let output = Command::new("stty").args(args).stdin(Stdio::inherit()).output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(String::from_utf8_lossy(&output.stderr).trim().to_owned()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// The state of the line while it's being edited
+struct Editor<'a> {
+    prompt: &'a str,
+    history: &'a [String],
+    line: Vec<char>,
+    cursor: usize,
+    /// Which history entry is shown, history.len() being the line being typed
+    history_index: usize,
+    /// The line being typed, kept while browsing history
+    draft: Vec<char>,
+    out: io::Stdout,
+}
+
+impl<'a> Editor<'a> {
+    fn new(prompt: &'a str, history: &'a [String]) -> Self {
+        Editor {
+            prompt,
+            history,
+            line: vec![],
+            cursor: 0,
+            history_index: history.len(),
+            draft: vec![],
+            out: io::stdout(),
+        }
+    }
+
+    fn run(mut self, complete: &mut dyn FnMut(&str) -> Completion) -> io::Result<Option<String>> {
+        self.redraw()?;
+        let mut last_key_was_tab = false;
+
+        loop {
+            let key = read_key()?;
+            let is_tab = key == Key::Control(TAB);
+
+            match key {
+                Key::Control(ENTER) | Key::Char('\n') => {
+                    write!(self.out, "\r\n")?;
+                    return Ok(Some(self.text()));
+                }
+                Key::Control(CTRL_D) if self.line.is_empty() => {
+                    write!(self.out, "\r\n")?;
+                    return Ok(None);
+                }
+                Key::Control(CTRL_C) => {
+                    // Throw the line away and start again, like a shell
+                    write!(self.out, "^C\r\n")?;
+                    return Ok(Some(String::new()));
+                }
+                Key::Char(c) => {
+                    self.line.insert(self.cursor, c);
+                    self.cursor += 1;
+                }
+                Key::Control(BACKSPACE) | Key::Control(DELETE) if self.cursor > 0 => {
+                    self.cursor -= 1;
+                    self.line.remove(self.cursor);
+                }
+                Key::Delete | Key::Control(CTRL_D) if self.cursor < self.line.len() => {
+                    self.line.remove(self.cursor);
+                }
+                Key::Left | Key::Control(CTRL_B) => self.cursor = self.cursor.saturating_sub(1),
+                Key::Right | Key::Control(CTRL_F) => self.cursor = (self.cursor + 1).min(self.line.len()),
+                Key::Home | Key::Control(CTRL_A) => self.cursor = 0,
+                Key::End | Key::Control(CTRL_E) => self.cursor = self.line.len(),
+                Key::Control(CTRL_K) => self.line.truncate(self.cursor),
+                Key::Control(CTRL_U) => {
+                    self.line.drain(..self.cursor);
+                    self.cursor = 0;
+                }
+                Key::Control(CTRL_W) => {
+                    let mut start = self.cursor;
+                    while start > 0 && self.line[start - 1].is_whitespace() {
+                        start -= 1;
+                    }
+                    while start > 0 && !self.line[start - 1].is_whitespace() {
+                        start -= 1;
+                    }
+                    self.line.drain(start..self.cursor);
+                    self.cursor = start;
+                }
+                Key::Up | Key::Control(CTRL_P) => self.show_history(self.history_index.checked_sub(1)),
+                Key::Down | Key::Control(CTRL_N) => self.show_history(Some(self.history_index + 1)),
+                Key::Control(CTRL_L) => write!(self.out, "\x1b[H\x1b[2J")?,
+                Key::Control(CTRL_R) => {
+                    if let Some(line) = self.search()? {
+                        return Ok(Some(line));
+                    }
+                }
+                Key::Control(TAB) => self.complete(complete, last_key_was_tab)?,
+                _ => {}
+            }
+
+            last_key_was_tab = is_tab;
+            self.redraw()?;
+        }
+    }
+
+    fn text(&self) -> String {
+        self.line.iter().collect()
+    }
+
+    fn set_line(&mut self, line: Vec<char>) {
+        self.cursor = line.len();
+        self.line = line;
+    }
+
+    fn show_history(&mut self, index: Option<usize>) {
+        let index = match index {
+            Some(index) if index <= self.history.len() => index,
+            _ => return,
+        };
+        if self.history_index == self.history.len() {
+            self.draft = self.line.clone();
+        }
+        self.history_index = index;
+
+        let line = match self.history.get(index) {
+            Some(line) => line.chars().collect(),
+            None => self.draft.clone(),
+        };
+        self.set_line(line);
+    }
+
+    fn redraw(&mut self) -> io::Result<()> {
+        write!(self.out, "\r{}{}\x1b[K", self.prompt, self.text())?;
+        let after_cursor = self.line.len() - self.cursor;
+        if after_cursor > 0 {
+            write!(self.out, "\x1b[{}D", after_cursor)?;
+        }
+        self.out.flush()
+    }
+
+    /// Completes the word before the cursor as far as every candidate agrees,
+    /// listing them all on a second Tab
+    fn complete(&mut self, complete: &mut dyn FnMut(&str) -> Completion, list: bool) -> io::Result<()> {
+        let before_cursor: String = self.line[..self.cursor].iter().collect();
+        let completion = complete(&before_cursor);
+        let start = completion.start.min(self.cursor);
+        let word: String = self.line[start..self.cursor].iter().collect();
+
+        let replacement = match completion.candidates.as_slice() {
+            [] => return Ok(()),
+            [only] if only.ends_with('/') => only.clone(),
+            [only] => format!("{} ", only),
+            candidates => {
+                let prefix = common_prefix(candidates);
+                if prefix.chars().count() <= word.chars().count() && list {
+                    write!(self.out, "\r\n{}\r\n", candidates.join("  "))?;
+                }
+                prefix
+            }
+        };
+        if replacement.chars().count() < word.chars().count() {
+            return Ok(());
+        }
+
+        self.line.splice(start..self.cursor, replacement.chars());
+        self.cursor = start + replacement.chars().count();
+        Ok(())
+    }
+
+    /// Ctrl-R: searches back through history as the query is typed
+    ///
+    /// Enter runs the match, other editing keys keep it on the line to change it first
+    /// and Ctrl-G or Ctrl-C go back to the line as it was.
+    fn search(&mut self) -> io::Result<Option<String>> {
+        let mut query = String::new();
+        let mut found = self.history.len();
+
+        loop {
+            let shown = self.history.get(found).map(String::as_str).unwrap_or_default();
+            write!(self.out, "\r(reverse-i-search)'{}': {}\x1b[K", query, shown)?;
+            self.out.flush()?;
+
+            // Search from the current match down, or from just below it on another Ctrl-R
+            let mut from = found;
+            match read_key()? {
+                Key::Char(c) => query.push(c),
+                Key::Control(BACKSPACE) | Key::Control(DELETE) => {
+                    query.pop();
+                    from = self.history.len();
+                }
+                Key::Control(CTRL_R) => from = found.saturating_sub(1),
+                Key::Control(ENTER) => {
+                    let line = shown.to_owned();
+                    write!(self.out, "\r{}{}\x1b[K\r\n", self.prompt, line)?;
+                    return Ok(Some(line));
+                }
+                Key::Control(CTRL_G) | Key::Control(CTRL_C) => return Ok(None),
+                _ => {
+                    if found < self.history.len() {
+                        self.history_index = found;
+                        self.set_line(shown.chars().collect());
+                    }
+                    return Ok(None);
+                }
+            }
+
+            if let Some(index) = self.history[..(from + 1).min(self.history.len())]
+                .iter()
+                .rposition(|line| line.contains(query.as_str()))
+            {
+                found = index;
+            }
+        }
+    }
+}
+
+/// Reads one key press, decoding UTF-8 and the common VT100 escape sequences
+fn read_key() -> io::Result<Key> {
+    let byte = loop {
+        if let Some(byte) = read_byte()? {
+            break byte;
+        }
+    };
+
+    match byte {
+        ESCAPE => read_escape_sequence(),
+        0x00..=0x1F | DELETE => Ok(Key::Control(byte)),
+        0x20..=0x7E => Ok(Key::Char(byte as char)),
+        _ => {
+            // The leading byte says how many continuation bytes follow
+            let length = match byte {
+                0xC0..=0xDF => 2,
+                0xE0..=0xEF => 3,
+                _ => 4,
+            };
+            let mut bytes = vec![byte];
+            while bytes.len() < length {
+                match read_byte()? {
+                    Some(byte) => bytes.push(byte),
+                    None => break,
+                }
+            }
+            Ok(std::str::from_utf8(&bytes)
+                .ok()
+                .and_then(|text| text.chars().next())
+                .map_or(Key::Control(0), Key::Char))
+        }
+    }
+}
+
+fn read_escape_sequence() -> io::Result<Key> {
+    let kind = match read_byte()? {
+        Some(kind @ b'[') | Some(kind @ b'O') => kind,
+        _ => return Ok(Key::Escape),
+    };
+
+    let mut parameter = String::new();
+    loop {
+        let byte = match read_byte()? {
+            Some(byte) => byte,
+            None => return Ok(Key::Escape),
+        };
+        if byte.is_ascii_digit() || byte == b';' {
+            parameter.push(byte as char);
+            continue;
+        }
+
+        return Ok(match (kind, byte, parameter.as_str()) {
+            (_, b'A', _) => Key::Up,
+            (_, b'B', _) => Key::Down,
+            (_, b'C', _) => Key::Right,
+            (_, b'D', _) => Key::Left,
+            (_, b'H', _) | (b'[', b'~', "1") | (b'[', b'~', "7") => Key::Home,
+            (_, b'F', _) | (b'[', b'~', "4") | (b'[', b'~', "8") => Key::End,
+            (b'[', b'~', "3") => Key::Delete,
+            _ => Key::Escape,
+        });
+    }
+}
+
+/// One byte of input, or None when the read timed out
+fn read_byte() -> io::Result<Option<u8>> {
+    let mut byte = [0];
+    match io::stdin().read(&mut byte)? {
+        0 => Ok(None),
+        _ => Ok(Some(byte[0])),
+    }
+}
+
+fn common_prefix(candidates: &[String]) -> String {
+    let first = &candidates[0];
+    let mut length = first.len();
+    for candidate in &candidates[1..] {
+        length = first
+            .char_indices()
+            .zip(candidate.chars())
+            .take_while(|((_, a), b)| a == b)
+            .map(|((index, a), _)| index + a.len_utf8())
+            .last()
+            .unwrap_or(0)
+            .min(length);
+    }
+    first[..length].to_owned()
+}
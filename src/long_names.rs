@@ -0,0 +1,402 @@
+//! Long file names, kept in extra directory slots in front of the 8.3 entry they belong to.
+//!
+//! A name that doesn't fit the 8.3 form is stored under a short name made up from it, like
+//! `MYNOTE~1.TXT` for `my notes.txt`, which is all that programs without long name support see.
+//! Each extra slot holds 13 UTF-16 characters of the long name, the slot with the end coming first,
+//! and a checksum of the short name so a slot left behind by an older program is noticed.
+
+use crate::block_device::BlockDevice;
+use crate::directories::{add_directory_entry, directory_slots, find_in_directory, grow_directory};
+use crate::fat_error::{FatError, Result};
+use crate::read_dir::{read_dir, DirEntry, EntryKind};
+use crate::root_dir_util::{directory_entry, to_short_name, validate_short_name, write_directory_entry, DELETED_ENTRY};
+use crate::volume::Volume;
+use alloc::borrow::ToOwned;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Attribute bits of the extra entries that hold a long file name
+pub const ATTR_LONG_NAME: u8 = 0x0F;
+/// Set in the sequence number of the slot holding the end of a long name, which is stored first
+const LAST_LONG_ENTRY: u8 = 0x40;
+/// In UTF-16 units
+pub const MAX_LONG_NAME: usize = 255;
+/// Where the 13 characters of each slot are, two bytes each
+const CHAR_OFFSETS: [usize; 13] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+/// A file or directory together with the long name in front of it, if it has one
+#[derive(Clone, Debug)]
+pub struct NamedEntry {
+    pub entry: DirEntry,
+    pub long_name: Option<String>,
+    /// Byte offsets of the slots holding the long name
+    pub long_name_slots: Vec<usize>,
+}
+
+/// Whether a name needs a long name to be stored, not fitting the 8.3 form
+pub fn needs_long_name(name: &str) -> bool {
+    name != "." && name != ".." && validate_short_name(name).is_err()
+}
+
+/// Checks that a name can be stored, either as it is or as a long name
+pub fn validate_long_name(name: &str) -> Result<()> {
+    let invalid = || FatError::InvalidName(name.to_owned());
+    if !needs_long_name(name) {
+        return Ok(());
+    }
+    if name.trim().is_empty() || name.encode_utf16().count() > MAX_LONG_NAME {
+        return Err(invalid());
+    }
+    if name.chars().any(|c| c < ' ' || "\"*/:<>?\\|".contains(c)) {
+        return Err(invalid());
+    }
+    // Trailing dots and spaces are dropped when names are looked up, so a name ending in them can't be found
+    if name.ends_with(['.', ' ']) {
+        return Err(invalid());
+    }
+    Ok(())
+}
+
+/// The 8.3 name to store `name` under in a directory, in the padded on-disk form
+///
+/// Names that fit are only uppercased. Others are boiled down to the characters 8.3 names allow,
+/// with a `~1` style tail numbered so it's unique in the directory.
+pub fn short_name_for<D: BlockDevice>(volume: &mut Volume<D>, dir_cluster: usize, name: &str) -> Result<[u8; 11]> {
+    if !needs_long_name(name) {
+        return Ok(to_short_name(name));
+    }
+    validate_long_name(name)?;
+
+    let trimmed = name.trim_start_matches('.');
+    let (base, extension) = match trimmed.rfind('.') {
+        Some(dot) => (&trimmed[..dot], &trimmed[dot + 1..]),
+        None => (trimmed, ""),
+    };
+    let base = match short_name_chars(base) {
+        base if base.is_empty() => "_".to_owned(),
+        base => base,
+    };
+    let extension: String = short_name_chars(extension).chars().take(3).collect();
+
+    for number in 1..1_000_000 {
+        let tail = format!("~{}", number);
+        let kept: String = base.chars().take(8 - tail.len()).collect();
+        let candidate = match extension.as_str() {
+            "" => format!("{}{}", kept, tail),
+            _ => format!("{}{}.{}", kept, tail, extension),
+        };
+        if find_in_directory(volume, dir_cluster, &candidate)?.is_none() {
+            return Ok(to_short_name(&candidate));
+        }
+    }
+    Err(FatError::BadArgument(format!("There's no short name left for {}", name)))
+}
+
+/// Adds an entry called `name` to a directory, filling in its short name and writing slots for
+/// the long one if it needs them. The rest of `entry` is kept as it is.
+///
+/// Returns the byte offset of the 8.3 entry.
+pub fn add_named_entry<D: BlockDevice>(
+    volume: &mut Volume<D>,
+    dir_cluster: usize,
+    name: &str,
+    mut entry: Vec<u8>,
+) -> Result<usize> {
+    let short_name = short_name_for(volume, dir_cluster, name)?;
+    entry[0..11].copy_from_slice(&short_name);
+    if !needs_long_name(name) {
+        return add_directory_entry(volume, dir_cluster, entry);
+    }
+
+    let mut slots = long_name_slots(name, &short_name);
+    slots.push(entry);
+    // The long name has to be in the slots right before its entry
+    let offsets = loop {
+        let offsets = directory_slots(volume, dir_cluster)?;
+        if let Some(start) = free_run(volume, &offsets, slots.len())? {
+            break offsets[start..start + slots.len()].to_vec();
+        }
+        if dir_cluster == 0 {
+            return Err(FatError::RootDirectoryFull);
+        }
+        grow_directory(volume, dir_cluster)?;
+    };
+
+    for (offset, slot) in offsets.iter().zip(&slots) {
+        write_directory_entry(volume, *offset, slot)?;
+    }
+    Ok(offsets[offsets.len() - 1])
+}
+
+/// The files and directories in a directory, with their long names
+pub fn named_entries<D: BlockDevice>(volume: &mut Volume<D>, dir_cluster: usize) -> Result<Vec<NamedEntry>> {
+    let mut named = vec![];
+    let mut parts: Vec<DirEntry> = vec![];
+    for entry in read_dir(volume, dir_cluster)?.include_long_names().include_deleted() {
+        let entry = entry?;
+        match entry.kind() {
+            EntryKind::LongName => {
+                if entry.bytes[0] & LAST_LONG_ENTRY != 0 {
+                    parts.clear();
+                }
+                parts.push(entry);
+            }
+            EntryKind::Deleted => parts.clear(),
+            _ => {
+                let long_name = assemble_long_name(&parts, &entry.bytes);
+                let long_name_slots = match long_name {
+                    Some(_) => parts.iter().map(|part| part.offset).collect(),
+                    None => vec![],
+                };
+                parts.clear();
+                named.push(NamedEntry {
+                    entry,
+                    long_name,
+                    long_name_slots,
+                });
+            }
+        }
+    }
+    Ok(named)
+}
+
+/// The entry with this long name, ignoring case, as a byte offset
+pub fn find_long_name<D: BlockDevice>(volume: &mut Volume<D>, dir_cluster: usize, name: &str) -> Result<Option<usize>> {
+    let name = name.to_lowercase();
+    Ok(named_entries(volume, dir_cluster)?
+        .into_iter()
+        .find(|named| named.long_name.as_ref().is_some_and(|long_name| long_name.to_lowercase() == name))
+        .map(|named| named.entry.offset))
+}
+
+/// Marks the slots holding the long name of the entry at `offset` as deleted
+pub fn delete_long_name<D: BlockDevice>(volume: &mut Volume<D>, dir_cluster: usize, offset: usize) -> Result<()> {
+    let Some(named) = named_entries(volume, dir_cluster)?
+        .into_iter()
+        .find(|named| named.entry.offset == offset)
+    else {
+        return Ok(());
+    };
+    for slot in named.long_name_slots {
+        let mut entry = directory_entry(volume, slot)?;
+        entry[0] = DELETED_ENTRY;
+        write_directory_entry(volume, slot, &entry)?;
+    }
+    Ok(())
+}
+
+/// The long name of the entry at `offset`, if it has one
+pub fn long_name_of<D: BlockDevice>(
+    volume: &mut Volume<D>,
+    dir_cluster: usize,
+    offset: usize,
+) -> Result<Option<String>> {
+    Ok(named_entries(volume, dir_cluster)?
+        .into_iter()
+        .find(|named| named.entry.offset == offset)
+        .and_then(|named| named.long_name))
+}
+
+/// Keeps the characters 8.3 names allow, uppercased, and replaces the rest with `_`
+///
+/// Spaces and dots are left out altogether.
+fn short_name_chars(part: &str) -> String {
+    part.chars()
+        .filter(|&c| c != ' ' && c != '.')
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || "!#$%&'()-@^_`{}~".contains(c) {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Ties long name slots to their short name
+fn checksum(short_name: &[u8]) -> u8 {
+    short_name[..11]
+        .iter()
+        .fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+/// The slots holding `name` for the entry with `short_name`, in the order they're stored
+fn long_name_slots(name: &str, short_name: &[u8]) -> Vec<Vec<u8>> {
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    // A name that doesn't fill its last slot ends with a 0, and the rest is padding
+    if !units.len().is_multiple_of(CHAR_OFFSETS.len()) {
+        units.push(0);
+        units.resize(units.len().div_ceil(CHAR_OFFSETS.len()) * CHAR_OFFSETS.len(), 0xFFFF);
+    }
+    let count = units.len() / CHAR_OFFSETS.len();
+    let checksum = checksum(short_name);
+
+    (0..count)
+        .rev()
+        .map(|index| {
+            let mut slot = vec![0; 32];
+            slot[0] = (index + 1) as u8;
+            if index == count - 1 {
+                slot[0] |= LAST_LONG_ENTRY;
+            }
+            slot[11] = ATTR_LONG_NAME;
+            slot[13] = checksum;
+            for (i, &offset) in CHAR_OFFSETS.iter().enumerate() {
+                let unit = units[index * CHAR_OFFSETS.len() + i];
+                slot[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+            }
+            slot
+        })
+        .collect()
+}
+
+/// The long name in `parts` (as stored, end first), if they all belong to `short_entry`
+fn assemble_long_name(parts: &[DirEntry], short_entry: &[u8]) -> Option<String> {
+    let first = parts.first()?;
+    if first.bytes[0] & LAST_LONG_ENTRY == 0 || (first.bytes[0] & !LAST_LONG_ENTRY) as usize != parts.len() {
+        return None;
+    }
+    let checksum = checksum(short_entry);
+
+    let mut units = vec![];
+    for (index, part) in parts.iter().rev().enumerate() {
+        if (part.bytes[0] & !LAST_LONG_ENTRY) as usize != index + 1 || part.bytes[13] != checksum {
+            return None;
+        }
+        units.extend(
+            CHAR_OFFSETS
+                .iter()
+                .map(|&offset| u16::from_le_bytes([part.bytes[offset], part.bytes[offset + 1]])),
+        );
+    }
+    let end = units.iter().position(|&unit| unit == 0).unwrap_or(units.len());
+    String::from_utf16(&units[..end]).ok()
+}
+
+/// Where `count` free slots in a row start, as an index into `offsets`
+fn free_run<D: BlockDevice>(volume: &mut Volume<D>, offsets: &[usize], count: usize) -> Result<Option<usize>> {
+    let mut run = 0;
+    for (index, &offset) in offsets.iter().enumerate() {
+        if matches!(directory_entry(volume, offset)?[0], 0 | DELETED_ENTRY) {
+            run += 1;
+            if run == count {
+                return Ok(Some(index + 1 - count));
+            }
+        } else {
+            run = 0;
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bios_parameter_block::BiosParameterBlock;
+    use crate::root_dir_util::build_directory_entry;
+
+    /// A 360K floppy held in memory, with nothing in its root directory
+    fn volume() -> Volume<Vec<u8>> {
+        let mut volume = Volume::mount(vec![0; 720 * 512]).unwrap();
+        volume.set_bpb(BiosParameterBlock::for_sectors(720)).unwrap();
+        volume
+    }
+
+    /// Adds an empty file called `name` to the root directory, returning the offset of its entry
+    fn add(volume: &mut Volume<Vec<u8>>, name: &str) -> usize {
+        add_named_entry(volume, 0, name, build_directory_entry("", 0, 0, false)).unwrap()
+    }
+
+    /// The UTF-16 units of a slot, in name order
+    fn slot_units(slot: &[u8]) -> Vec<u16> {
+        CHAR_OFFSETS
+            .iter()
+            .map(|&offset| u16::from_le_bytes([slot[offset], slot[offset + 1]]))
+            .collect()
+    }
+
+    #[test]
+    fn checksums_the_padded_short_name() {
+        assert_eq!(checksum(b"README  TXT"), 0x73);
+        assert_eq!(checksum(b"RELEAS~1TXT"), 0x3E);
+    }
+
+    #[test]
+    fn slots_are_stored_end_first() {
+        let slots = long_name_slots("release notes.txt", b"RELEAS~1TXT");
+        assert_eq!(slots.len(), 2);
+        assert_eq!((slots[0][0], slots[1][0]), (LAST_LONG_ENTRY | 2, 1));
+        for slot in &slots {
+            assert_eq!((slot[11], slot[13]), (ATTR_LONG_NAME, 0x3E));
+        }
+
+        let first: Vec<u16> = "release notes".encode_utf16().collect();
+        assert_eq!(slot_units(&slots[1]), first);
+        // The end of the name, a 0 after it, then padding
+        let mut last: Vec<u16> = ".txt".encode_utf16().collect();
+        last.push(0);
+        last.resize(13, 0xFFFF);
+        assert_eq!(slot_units(&slots[0]), last);
+    }
+
+    #[test]
+    fn name_filling_its_slots_has_no_end_marker() {
+        let slots = long_name_slots("thirteen char", b"THIRTE~1   ");
+        assert_eq!(slots.len(), 1);
+        assert_eq!(slots[0][0], LAST_LONG_ENTRY | 1);
+        assert_eq!(slot_units(&slots[0]), "thirteen char".encode_utf16().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn adds_and_finds_long_names() {
+        let mut volume = volume();
+        let first = add(&mut volume, "release notes.txt");
+        let second = add(&mut volume, "release plan.txt");
+        add(&mut volume, "SHORT.TXT");
+
+        let entries = named_entries(&mut volume, 0).unwrap();
+        let names: Vec<(&[u8], Option<&str>)> = entries
+            .iter()
+            .map(|named| (&named.entry.bytes[0..11], named.long_name.as_deref()))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                (&b"RELEAS~1TXT"[..], Some("release notes.txt")),
+                (&b"RELEAS~2TXT"[..], Some("release plan.txt")),
+                (&b"SHORT   TXT"[..], None)
+            ]
+        );
+        // The slots come right before their entry
+        assert_eq!(entries[0].long_name_slots, [first - 64, first - 32]);
+
+        assert_eq!(find_long_name(&mut volume, 0, "RELEASE PLAN.TXT").unwrap(), Some(second));
+        assert_eq!(find_long_name(&mut volume, 0, "release.txt").unwrap(), None);
+    }
+
+    #[test]
+    fn slots_for_another_short_name_are_ignored() {
+        let mut volume = volume();
+        let offset = add(&mut volume, "release notes.txt");
+        // Like a program without long name support renaming the file
+        let mut entry = directory_entry(&mut volume, offset).unwrap();
+        entry[0..11].copy_from_slice(b"RENAMED TXT");
+        write_directory_entry(&mut volume, offset, &entry).unwrap();
+
+        assert_eq!(long_name_of(&mut volume, 0, offset).unwrap(), None);
+    }
+
+    #[test]
+    fn deleting_a_long_name_frees_its_slots() {
+        let mut volume = volume();
+        let offset = add(&mut volume, "release notes.txt");
+        delete_long_name(&mut volume, 0, offset).unwrap();
+
+        assert_eq!(long_name_of(&mut volume, 0, offset).unwrap(), None);
+        for slot in [offset - 64, offset - 32] {
+            assert_eq!(directory_entry(&mut volume, slot).unwrap()[0], DELETED_ENTRY);
+        }
+    }
+}
@@ -0,0 +1,538 @@
+//! Builds an image from a checked in description of the disk:
+//!
+//! ```text
+//! [image]
+//! size = 1440K
+//! label = MY OS
+//! timestamp = 2024-01-01 00:00
+//!
+//! [boot]
+//! generate = KERNEL.BIN
+//! load = 1000:0000
+//!
+//! [reserved]
+//! file = stage2.bin
+//!
+//! [dir /SYSTEM]
+//! attributes = hidden
+//!
+//! [file /KERNEL.BIN]
+//! source = build/kernel.bin
+//! attributes = system, readonly
+//! cluster = 2
+//! ```
+//!
+//! The image is always rebuilt from scratch, so the same manifest and inputs give the same bytes.
+
+use crate::bios_parameter_block::{BiosParameterBlock, BYTES_PER_SECTOR};
+use crate::block_device::BlockDevice;
+use crate::bootsector::{edit_bootsector, generate_bootsector};
+use crate::change_trace::record_write;
+use crate::directories::{add_directory_entry, find_path, resolve_directory, split_path};
+use crate::fat_error::{FatError, Result};
+use crate::new_file::{find_free_run, write_file_data_at};
+use crate::root_dir_util::{
+    build_directory_entry, directory_entry, set_entry_time, validate_short_name, write_directory_entry, ATTR_ARCHIVE,
+    ATTR_HIDDEN, ATTR_READ_ONLY, ATTR_SYSTEM,
+};
+use crate::shell_files::{make_directory, newfile};
+use crate::shell_images::{confirm_discard, format_device};
+use crate::shell_parsing::{parse_number, Args};
+use crate::shell_state::ShellState;
+use crate::timestamps::CivilTime;
+use crate::volume::Volume;
+use crate::volume_info::set_volume_label;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// One `[kind argument]` section and its `key = value` lines
+struct Section {
+    kind: String,
+    argument: String,
+    line: usize,
+    // (key, value, line)
+    values: Vec<(String, String, usize)>,
+}
+
+impl Section {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.values
+            .iter()
+            .find(|(value_key, _, _)| value_key == key)
+            .map(|(_, value, _)| value.as_str())
+    }
+}
+
+/// Everything needed to report errors against the manifest
+struct Manifest {
+    filename: String,
+    base_dir: PathBuf,
+    text: String,
+    sections: Vec<Section>,
+}
+
+impl Manifest {
+    fn section(&self, kind: &str) -> Option<&Section> {
+        self.sections.iter().find(|section| section.kind == kind)
+    }
+
+    /// Paths in the manifest are relative to the manifest itself
+    fn host_path(&self, path: &str) -> PathBuf {
+        self.base_dir.join(path)
+    }
+
+    fn error(&self, line: usize, message: &str) -> FatError {
+        FatError::Manifest {
+            filename: self.filename.clone(),
+            line,
+            message: message.to_owned(),
+        }
+    }
+
+    /// Parses a section value, reporting the line it came from when it's malformed
+    fn parse<T>(&self, section: &Section, key: &str, parser: impl Fn(&str) -> Option<T>) -> Result<Option<T>> {
+        let (_, value, line) = match section.values.iter().find(|(value_key, _, _)| value_key == key) {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        match parser(value) {
+            Some(parsed) => Ok(Some(parsed)),
+            None => Err(self.error(*line, &format!("bad value for {}: {}", key, value))),
+        }
+    }
+}
+
+/// build [-f] <manifest> [image]
+///
+/// With an image the result is written to it, otherwise it replaces the open image.
+pub fn build_manifest(mut shell_state: ShellState, args: &Args) -> Result<ShellState> {
+    let manifest_filename = args.expect(1, "manifest")?;
+    let image_filename = args.get(2);
+    // Without an image to write to, the result replaces the open one
+    if image_filename.is_none() {
+        shell_state.require_image()?;
+    }
+
+    // Build before touching the image so a bad manifest doesn't leave an empty file behind
+    let manifest = read_manifest(&manifest_filename)?;
+    let bytes = build_image(&manifest)?;
+
+    if let Some(image_filename) = &image_filename {
+        shell_state = confirm_discard(shell_state, args.flag("-f"))?;
+        if !Path::new(image_filename).exists() {
+            File::create(image_filename).map_err(FatError::io(format!("Can't create {}", image_filename)))?;
+        }
+        shell_state = shell_state.load_file(image_filename.clone(), false)?;
+    }
+
+    if shell_state.image().matches(&bytes)? {
+        println!("Image is already up to date with {}!", manifest_filename);
+        return Ok(shell_state.set_cwd(0));
+    }
+
+    // Every sector is written, whether or not it changes
+    record_write(0, bytes.len());
+    shell_state.image_mut()?.overwrite(&bytes)?;
+    if image_filename.is_some() {
+        shell_state = shell_state.save_file()?;
+    }
+    println!("Built image from {}!", manifest_filename);
+    Ok(shell_state.set_cwd(0))
+}
+
+fn read_manifest(manifest_filename: &str) -> Result<Manifest> {
+    let text = std::fs::read_to_string(manifest_filename)
+        .map_err(FatError::io(format!("Can't read {}", manifest_filename)))?;
+    let mut manifest = Manifest {
+        filename: manifest_filename.to_owned(),
+        base_dir: Path::new(manifest_filename)
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default(),
+        text: String::new(),
+        sections: vec![],
+    };
+
+    for (line_index, line) in text.lines().enumerate() {
+        let line_number = line_index + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+
+        if let Some(header) = line.strip_prefix('[') {
+            let header = header
+                .strip_suffix(']')
+                .ok_or_else(|| manifest.error(line_number, "section header is missing ]"))?;
+            let mut parts = header.trim().splitn(2, char::is_whitespace);
+            let kind = parts.next().unwrap_or_default().to_owned();
+            let argument = parts.next().unwrap_or_default().trim().to_owned();
+
+            let needs_argument = kind == "file" || kind == "dir";
+            if !["image", "boot", "reserved", "file", "dir"].contains(&kind.as_str()) {
+                return Err(manifest.error(line_number, &format!("unknown section {}", kind)));
+            }
+            if needs_argument == argument.is_empty() {
+                return Err(manifest.error(line_number, &format!("bad section header [{}]", header)));
+            }
+
+            manifest.sections.push(Section {
+                kind,
+                argument,
+                line: line_number,
+                values: vec![],
+            });
+            continue;
+        }
+
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) => (key.trim().to_owned(), value.trim().to_owned()),
+            None => return Err(manifest.error(line_number, "expected key = value")),
+        };
+        match manifest.sections.last_mut() {
+            Some(section) => section.values.push((key, value, line_number)),
+            None => return Err(manifest.error(line_number, "key outside of a section")),
+        }
+    }
+
+    manifest.text = text;
+    check_keys(&manifest)?;
+    Ok(manifest)
+}
+
+/// Rejects keys that would otherwise be silently ignored
+fn check_keys(manifest: &Manifest) -> Result<()> {
+    for section in &manifest.sections {
+        let allowed: &[&str] = match section.kind.as_str() {
+            "image" => &[
+                "size", "sectors", "oem", "label", "serial", "timestamp", "sectors_per_cluster",
+                "root_entries", "reserved_sectors", "fats", "sectors_per_fat", "sectors_per_track",
+                "heads", "media", "drive",
+            ],
+            "boot" => &["generate", "load", "jump", "file"],
+            "reserved" => &["file"],
+            "dir" => &["attributes", "modified"],
+            _ => &["source", "attributes", "modified", "cluster", "contiguous"],
+        };
+        for (key, _, line) in &section.values {
+            if !allowed.contains(&key.as_str()) {
+                return Err(manifest.error(*line, &format!("unknown key {} in [{}]", key, section.kind)));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn build_image(manifest: &Manifest) -> Result<Vec<u8>> {
+    let empty_section = Section {
+        kind: "image".to_owned(),
+        argument: String::new(),
+        line: 0,
+        values: vec![],
+    };
+    let image = manifest.section("image").unwrap_or(&empty_section);
+
+    let reserved_payload = match manifest.section("reserved") {
+        Some(section) => {
+            let source = section
+                .get("file")
+                .ok_or_else(|| manifest.error(section.line, "[reserved] needs a file"))?;
+            Some(read_source(manifest, section, source)?)
+        }
+        None => None,
+    };
+
+    let bpb = layout(manifest, image, reserved_payload.as_deref())?;
+    let mut shell_state = ShellState::new().set_bytes(vec![0; bpb.sectors() * bpb.bytes_per_sector]);
+    let mut volume = format_device(shell_state.image_mut()?, &bpb)?;
+    if let Some(label) = image.get("label") {
+        set_volume_label(&mut volume, label)?;
+    }
+
+    if let Some(boot) = manifest.section("boot") {
+        shell_state = install_boot_sector(manifest, boot, shell_state, &bpb)?;
+    }
+
+    if let Some(payload) = reserved_payload {
+        // The reserved sectors after the boot sector, e.g. for a second stage loader
+        shell_state.volume_mut()?.write(bpb.bytes_per_sector, &payload)?;
+    }
+
+    let default_time = manifest.parse(image, "timestamp", CivilTime::parse)?;
+    let entries: Vec<&Section> = manifest
+        .sections
+        .iter()
+        .filter(|section| section.kind == "file" || section.kind == "dir")
+        .collect();
+
+    // Files pinned to a cluster go in first so nothing else takes their place
+    let mut pinned_clusters = vec![];
+    for section in entries.iter().filter(|section| section.get("cluster").is_some()) {
+        let cluster = manifest.parse(section, "cluster", parse_number)?.unwrap_or_default();
+        let data = read_source(manifest, section, source_of(manifest, section)?)?;
+        write_file_data_at(&mut shell_state.volume_mut()?, &data, cluster)
+            .map_err(|error| manifest.error(section.line, &error.to_string()))?;
+        pinned_clusters.push((section.argument.clone(), cluster, data.len()));
+    }
+
+    for section in entries {
+        let path = section.argument.as_str();
+        shell_state = make_parent_directories(shell_state, path, default_time.as_ref())
+            .and_then(|shell_state| {
+                if section.kind == "file" {
+                    add_file(manifest, section, shell_state, &pinned_clusters)
+                } else if resolve_directory(&mut shell_state.volume()?, 0, path).is_err() {
+                    make_directory(shell_state, &Args::new(&["mkdir", path]))
+                } else {
+                    Ok(shell_state)
+                }
+            })
+            .map_err(|error| match error {
+                FatError::Manifest { .. } => error,
+                _ => manifest.error(section.line, &error.to_string()),
+            })?;
+
+        let time = manifest
+            .parse(section, "modified", CivilTime::parse)?
+            .or_else(|| default_time.clone())
+            .or_else(|| source_time(manifest, section))
+            .unwrap_or_else(|| CivilTime::from_unix(0));
+        let attributes = manifest
+            .parse(section, "attributes", parse_attributes)?
+            .unwrap_or_default();
+        set_entry_details(&mut shell_state.volume_mut()?, path, attributes, &time)?;
+    }
+
+    shell_state.image().to_bytes()
+}
+
+/// Works out the BPB from the image section, starting from the standard layout for the size
+fn layout(manifest: &Manifest, image: &Section, reserved_payload: Option<&[u8]>) -> Result<BiosParameterBlock> {
+    let total_sectors = match manifest.parse(image, "sectors", parse_number)? {
+        Some(sectors) => sectors,
+        None => manifest.parse(image, "size", parse_size)?.unwrap_or(1_474_560) / BYTES_PER_SECTOR,
+    };
+    let mut bpb = BiosParameterBlock::for_sectors(total_sectors);
+    bpb.volume_label = *b"NO NAME    ";
+
+    // The serial would normally come from the clock, derive it from the manifest to keep builds repeatable
+    bpb.serial_number = manifest
+        .parse(image, "serial", |serial| u32::from_str_radix(&serial.replace('-', ""), 16).ok())?
+        .unwrap_or_else(|| fnv1a(manifest.text.as_bytes()));
+
+    if let Some(oem) = image.get("oem") {
+        if oem.len() > 8 {
+            return Err(manifest.error(image.line, "oem is longer than 8 characters"));
+        }
+        bpb.oem = *b"        ";
+        bpb.oem[..oem.len()].copy_from_slice(oem.as_bytes());
+    }
+
+    let number = |key: &str| manifest.parse(image, key, parse_number);
+    let mut changes_layout = false;
+    if let Some(sectors_per_cluster) = number("sectors_per_cluster")? {
+        bpb.sectors_per_cluster = sectors_per_cluster;
+        changes_layout = true;
+    }
+    if let Some(root_entries) = number("root_entries")? {
+        bpb.root_entries = root_entries;
+        changes_layout = true;
+    }
+    if let Some(fats) = number("fats")? {
+        bpb.number_fats = fats;
+        changes_layout = true;
+    }
+    match (number("reserved_sectors")?, reserved_payload) {
+        (Some(reserved_sectors), _) => {
+            bpb.reserved_sectors = reserved_sectors;
+            changes_layout = true;
+        }
+        (None, Some(payload)) => {
+            bpb.reserved_sectors = 1 + payload.len().div_ceil(bpb.bytes_per_sector);
+            changes_layout = true;
+        }
+        (None, None) => {}
+    }
+    if let Some(payload) = reserved_payload {
+        if payload.len() > (bpb.reserved_sectors - 1) * bpb.bytes_per_sector {
+            return Err(manifest.error(image.line, "the [reserved] file doesn't fit in the reserved sectors"));
+        }
+    }
+
+    match number("sectors_per_fat")? {
+        Some(sectors_per_fat) => bpb.sectors_per_fat = sectors_per_fat,
+        None if changes_layout => bpb.fit_fat(),
+        None => {}
+    }
+    if let Some(sectors_per_track) = number("sectors_per_track")? {
+        bpb.sectors_per_track = sectors_per_track;
+    }
+    if let Some(heads) = number("heads")? {
+        bpb.heads_per_cylinder = heads;
+    }
+    if let Some(media) = number("media")? {
+        bpb.media = media as u8;
+    }
+    if let Some(drive) = number("drive")? {
+        bpb.drive_number = drive as u8;
+    }
+
+    if bpb.data_start() >= bpb.sectors() {
+        return Err(manifest.error(image.line, "the image is too small for its layout"));
+    }
+    Ok(bpb)
+}
+
+fn install_boot_sector(
+    manifest: &Manifest,
+    boot: &Section,
+    mut shell_state: ShellState,
+    bpb: &BiosParameterBlock,
+) -> Result<ShellState> {
+    match (boot.get("generate"), boot.get("file")) {
+        (Some(filename), None) => {
+            let load = boot.get("load").unwrap_or("1000:0000");
+            let mut args = vec!["genboot", filename, load];
+            if let Some(jump) = boot.get("jump") {
+                args.push(jump);
+            }
+            generate_bootsector(shell_state, &Args::new(&args))
+                .map_err(|error| manifest.error(boot.line, &error.to_string()))
+        }
+        (None, Some(source)) => {
+            let host_path = manifest.host_path(source);
+            shell_state = edit_bootsector(shell_state, &Args::new(&["editboot", &host_path.to_string_lossy()]))
+                .map_err(|error| manifest.error(boot.line, &error.to_string()))?;
+            // The boot code is the file's, the layout is the manifest's
+            shell_state.volume_mut()?.set_bpb(bpb.clone())?;
+            Ok(shell_state)
+        }
+        _ => Err(manifest.error(boot.line, "[boot] needs either generate or file")),
+    }
+}
+
+/// Creates the directories above `path` that the manifest didn't list itself
+fn make_parent_directories(mut shell_state: ShellState, path: &str, time: Option<&CivilTime>) -> Result<ShellState> {
+    let (parent, _) = split_path(path);
+    let mut partial = String::new();
+
+    for component in parent.split('/').filter(|component| !component.is_empty()) {
+        partial = format!("{}/{}", partial, component);
+        if resolve_directory(&mut shell_state.volume()?, 0, &partial).is_err() {
+            shell_state = make_directory(shell_state, &Args::new(&["mkdir", &partial]))?;
+            let time = time.cloned().unwrap_or_else(|| CivilTime::from_unix(0));
+            set_entry_details(&mut shell_state.volume_mut()?, &partial, 0, &time)?;
+        }
+    }
+    Ok(shell_state)
+}
+
+fn add_file(
+    manifest: &Manifest,
+    section: &Section,
+    mut shell_state: ShellState,
+    pinned_clusters: &[(String, usize, usize)],
+) -> Result<ShellState> {
+    let path = section.argument.as_str();
+    let source = source_of(manifest, section)?;
+
+    let pinned = pinned_clusters.iter().find(|(pinned_path, _, _)| pinned_path == path);
+    let contiguous = manifest
+        .parse(section, "contiguous", |value| match value {
+            "true" | "yes" => Some(true),
+            "false" | "no" => Some(false),
+            _ => None,
+        })?
+        .unwrap_or(false);
+
+    let (first_cluster, size) = match pinned {
+        Some(&(_, cluster, size)) => (cluster, size),
+        None if contiguous => {
+            let data = read_source(manifest, section, source)?;
+            let mut volume = shell_state.volume_mut()?;
+            let clusters = data.len().div_ceil(volume.bpb().bytes_per_cluster());
+            if clusters == 0 {
+                (0, 0)
+            } else {
+                let cluster = find_free_run(&mut volume, clusters)?
+                    .ok_or_else(|| manifest.error(section.line, "no run of free clusters is long enough"))?;
+                write_file_data_at(&mut volume, &data, cluster)?;
+                (cluster, data.len())
+            }
+        }
+        None => {
+            let host_path = manifest.host_path(source);
+            return newfile(shell_state, &Args::new(&["newfile", &host_path.to_string_lossy(), path]));
+        }
+    };
+
+    let (dir_path, name) = split_path(path);
+    validate_short_name(name)?;
+    let mut volume = shell_state.volume_mut()?;
+    let dir_cluster = resolve_directory(&mut volume, 0, dir_path)?;
+    add_directory_entry(&mut volume, dir_cluster, build_directory_entry(name, first_cluster, size, false))?;
+    Ok(shell_state)
+}
+
+fn set_entry_details<D: BlockDevice>(
+    volume: &mut Volume<D>,
+    path: &str,
+    attributes: u8,
+    time: &CivilTime,
+) -> Result<()> {
+    let (_, offset) = find_path(volume, 0, path)?;
+    let mut entry = directory_entry(volume, offset)?;
+    entry[11] |= attributes;
+    set_entry_time(&mut entry, time);
+    write_directory_entry(volume, offset, &entry)
+}
+
+fn source_of<'a>(manifest: &Manifest, section: &'a Section) -> Result<&'a str> {
+    section
+        .get("source")
+        .ok_or_else(|| manifest.error(section.line, &format!("{} needs a source", section.argument)))
+}
+
+fn read_source(manifest: &Manifest, section: &Section, source: &str) -> Result<Vec<u8>> {
+    std::fs::read(manifest.host_path(source))
+        .map_err(|error| manifest.error(section.line, &format!("can't read {}: {}", source, error)))
+}
+
+/// Modification time of a file's source, in UTC
+fn source_time(manifest: &Manifest, section: &Section) -> Option<CivilTime> {
+    let source = section.get("source")?;
+    let modified = std::fs::metadata(manifest.host_path(source)).ok()?.modified().ok()?;
+    Some(CivilTime::from_unix(modified.duration_since(UNIX_EPOCH).ok()?.as_secs()))
+}
+
+/// Parses `readonly, hidden, system, archive`
+fn parse_attributes(attributes: &str) -> Option<u8> {
+    attributes
+        .split(',')
+        .map(|attribute| match attribute.trim() {
+            "readonly" => Some(ATTR_READ_ONLY),
+            "hidden" => Some(ATTR_HIDDEN),
+            "system" => Some(ATTR_SYSTEM),
+            "archive" => Some(ATTR_ARCHIVE),
+            _ => None,
+        })
+        .try_fold(0, |all, attribute| attribute.map(|attribute| all | attribute))
+}
+
+/// Parses a size in bytes, with an optional K or M suffix
+fn parse_size(size: &str) -> Option<usize> {
+    let size = size.to_ascii_uppercase();
+    if let Some(kilobytes) = size.strip_suffix('K') {
+        parse_number(kilobytes).map(|kilobytes| kilobytes * 1024)
+    } else if let Some(megabytes) = size.strip_suffix('M') {
+        parse_number(megabytes).map(|megabytes| megabytes * 1024 * 1024)
+    } else {
+        parse_number(&size)
+    }
+}
+
+/// 32 bit FNV-1a hash
+fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |hash, &byte| (hash ^ byte as u32).wrapping_mul(0x0100_0193))
+}
@@ -1,122 +1,139 @@
-use crate::bios_parameter_block::{
-    BITS_PER_FAT_ENTRY, BYTES_PER_DIRECTORY_ENTRY, BYTES_PER_SECTOR, NUMBER_FATS, RESERVED_SECTORS,
-    ROOT_ENTRIES, SECTORS_PER_FAT,
-};
-use crate::fat_section_util::{get_fat_entry, write_to_fat};
-use crate::root_dir_util::append_to_root_dir;
-use crate::shell_parsing::get_arg;
-use crate::shell_state::ShellState;
-
-pub fn newfile(mut shell_state: ShellState, args: Vec<&str>) -> ShellState {
-    // newfile image.bin testfile.txt
-    // Get cmdline args
-    let newfile: String = get_arg(&args, 1).expect("No new file provided!");
-
-    let newfile_bytes = std::fs::read(newfile.clone()).expect("Can't read the image file!");
-
-    let filename_extension = get_arg(&args, 2).expect("No new file name provided!");
-
-    // newfile_bytes.len (ceildiv) BYTES_PER_SECTOR
-    let newfile_sectors = (newfile_bytes.len() + BYTES_PER_SECTOR - 1) / BYTES_PER_SECTOR;
-
-    let mut last_fat_entry: usize = 0;
-    let mut sectors_stored = 0;
-    let mut first_entry: usize = 0;
-    // Is there more data left?
-    while sectors_stored < newfile_sectors {
-        //  Yes: get next free cluster
-        let next_free_cluster = get_next_free_cluster(&shell_state.bytes, last_fat_entry);
-
-        if sectors_stored == 0 {
-            first_entry = next_free_cluster;
-        }
-
-        if next_free_cluster == 0 {
-            // You're screwed (no free space)
-            println!("You're screwed!");
-            break;
-        } else {
-            //  put data at that cluster
-            let cluster_byte = get_cluster_from_entry(next_free_cluster);
-
-            let sector = get_cluster_from_new_file(&newfile_bytes, sectors_stored);
-
-            shell_state.bytes = write_cluster(shell_state.bytes, cluster_byte, sector);
-
-            // set last FAT entry to new cluster index
-            if last_fat_entry != 0 && last_fat_entry != next_free_cluster {
-                shell_state.bytes =
-                    write_to_fat(shell_state.bytes, next_free_cluster, last_fat_entry);
-            }
-
-            // update last entry and keep looping
-            last_fat_entry = next_free_cluster;
-            sectors_stored += 1;
-        }
-    }
-    //  No: set last FAT entry to EOF
-    shell_state.bytes = write_to_fat(shell_state.bytes, 0xFFF, last_fat_entry);
-
-    // Write to the root directory
-    shell_state.bytes =
-        append_to_root_dir(shell_state.bytes, filename_extension, first_entry, false);
-
-    println!("Wrote new file to FAT12 Image!");
-
-    shell_state
-}
-
-/// Gets a specific cluster from the new file and pads with 0s
-fn get_cluster_from_new_file(new_file: &Vec<u8>, cluster_num: usize) -> Vec<u8> {
-    let cluster_byte = cluster_num * BYTES_PER_SECTOR;
-    let cluster_byte_end = cluster_byte + BYTES_PER_SECTOR;
-
-    if new_file.len() < cluster_byte {
-        // If cluster byte exceeds file length
-        return vec![0; BYTES_PER_SECTOR];
-    } else if new_file.len() < cluster_byte_end {
-        // If cluster byte is within sector-aligned file
-        let mut zeros = vec![0; cluster_byte_end - new_file.len()];
-        let mut custom = new_file[cluster_byte..].to_vec();
-        custom.append(&mut zeros);
-        return custom;
-    } else {
-        // If cluster byte is within file
-        return new_file[cluster_byte..cluster_byte_end].to_vec();
-    }
-}
-
-/// cluster_byte is the byte index of the start of the cluster
-fn write_cluster(mut bytes: Vec<u8>, cluster_byte: usize, cluster_to_write: Vec<u8>) -> Vec<u8> {
-    bytes.splice(
-        cluster_byte..cluster_byte + BYTES_PER_SECTOR,
-        cluster_to_write,
-    );
-    bytes
-}
-
-/// Returns first byte of the cluster
-pub fn get_cluster_from_entry(entry: usize) -> usize {
-    (RESERVED_SECTORS + (SECTORS_PER_FAT * NUMBER_FATS) + entry) * BYTES_PER_SECTOR
-        + ROOT_ENTRIES * BYTES_PER_DIRECTORY_ENTRY
-}
-
-pub fn get_next_free_cluster(bytes: &Vec<u8>, current_entry: usize) -> usize {
-    // Look through the FAT for first 0 entry
-    let fat_start = RESERVED_SECTORS * BYTES_PER_SECTOR;
-    let fat_end = fat_start + SECTORS_PER_FAT * BYTES_PER_SECTOR;
-
-    let num_entries = (fat_end - fat_start) * 8 / BITS_PER_FAT_ENTRY;
-
-    for entry_index in 0..num_entries {
-        if get_fat_entry(&bytes, entry_index) == 0
-            && entry_index != 0
-            && entry_index != current_entry
-        {
-            // We found the next free cluster!
-            return entry_index;
-        }
-    }
-    // We should implement crashing because no free entry was found
-    0
-}
+use crate::block_device::BlockDevice;
+use crate::fat_error::{FatError, Result};
+use crate::fat_section_util::{count_free_clusters, get_fat_entry, write_to_fat};
+use crate::volume::Volume;
+use alloc::vec::Vec;
+use alloc::{format, vec};
+
+/// Stores data in a new cluster chain, returning the first cluster (0 for empty data)
+pub fn write_file_data<D: BlockDevice>(volume: &mut Volume<D>, data: &[u8]) -> Result<usize> {
+    // data.len (ceildiv) bytes per cluster
+    let cluster_size = volume.bpb().bytes_per_cluster();
+    let newfile_clusters = data.len().div_ceil(cluster_size);
+
+    // Check up front so a full disk doesn't leave half a chain behind
+    let free_clusters = count_free_clusters(volume)?;
+    if newfile_clusters > free_clusters {
+        return Err(FatError::DiskFull { needed: newfile_clusters, free: free_clusters });
+    }
+
+    let mut last_fat_entry: usize = 0;
+    let mut first_entry: usize = 0;
+    // Is there more data left?
+    for clusters_stored in 0..newfile_clusters {
+        //  Yes: get next free cluster
+        let next_free_cluster = get_next_free_cluster(volume, last_fat_entry)?;
+
+        if clusters_stored == 0 {
+            first_entry = next_free_cluster;
+        }
+
+        //  put data at that cluster
+        let cluster_byte = get_cluster_from_entry(volume, next_free_cluster);
+        let cluster = get_cluster_from_new_file(data, clusters_stored, cluster_size);
+        volume.write(cluster_byte, &cluster)?;
+
+        // set last FAT entry to new cluster index
+        if last_fat_entry != 0 {
+            write_to_fat(volume, next_free_cluster, last_fat_entry)?;
+        }
+        // Mark the new cluster as used straight away so it isn't handed out twice
+        write_to_fat(volume, 0xFFF, next_free_cluster)?;
+
+        // update last entry and keep looping
+        last_fat_entry = next_free_cluster;
+    }
+    //  No: the last FAT entry is already EOF (empty files don't own a cluster)
+
+    Ok(first_entry)
+}
+
+/// Stores data in consecutive clusters starting at `first_cluster`, which must all be free
+pub fn write_file_data_at<D: BlockDevice>(volume: &mut Volume<D>, data: &[u8], first_cluster: usize) -> Result<()> {
+    let bpb = volume.bpb().clone();
+    let cluster_size = bpb.bytes_per_cluster();
+    let clusters = data.len().div_ceil(cluster_size);
+
+    if first_cluster < 2 || first_cluster + clusters > bpb.fat_entries() {
+        return Err(FatError::BadArgument(format!(
+            "Clusters {} to {} aren't on the disk",
+            first_cluster,
+            first_cluster + clusters
+        )));
+    }
+    for cluster in first_cluster..first_cluster + clusters {
+        if get_fat_entry(volume, cluster)? != 0 {
+            return Err(FatError::BadArgument(format!("Cluster {} is already in use", cluster)));
+        }
+    }
+
+    for cluster_index in 0..clusters {
+        let cluster = first_cluster + cluster_index;
+        let cluster_byte = get_cluster_from_entry(volume, cluster);
+        volume.write(cluster_byte, &get_cluster_from_new_file(data, cluster_index, cluster_size))?;
+
+        let next_entry = if cluster_index + 1 == clusters { 0xFFF } else { cluster + 1 };
+        write_to_fat(volume, next_entry, cluster)?;
+    }
+    Ok(())
+}
+
+/// First cluster of the earliest run of `clusters` free clusters in a row
+pub fn find_free_run<D: BlockDevice>(volume: &mut Volume<D>, clusters: usize) -> Result<Option<usize>> {
+    let fat_entries = volume.bpb().fat_entries();
+    let mut run_start = 2;
+
+    for cluster in 2..fat_entries {
+        if get_fat_entry(volume, cluster)? != 0 {
+            run_start = cluster + 1;
+        } else if cluster + 1 - run_start == clusters {
+            return Ok(Some(run_start));
+        }
+    }
+    Ok(None)
+}
+
+/// Gets a specific cluster from the new file and pads with 0s
+fn get_cluster_from_new_file(new_file: &[u8], cluster_num: usize, cluster_size: usize) -> Vec<u8> {
+    let cluster_byte = cluster_num * cluster_size;
+    let cluster_byte_end = cluster_byte + cluster_size;
+
+    if new_file.len() < cluster_byte {
+        // If cluster byte exceeds file length
+        vec![0; cluster_size]
+    } else if new_file.len() < cluster_byte_end {
+        // If cluster byte is within sector-aligned file
+        let mut zeros = vec![0; cluster_byte_end - new_file.len()];
+        let mut custom = new_file[cluster_byte..].to_vec();
+        custom.append(&mut zeros);
+        custom
+    } else {
+        // If cluster byte is within file
+        new_file[cluster_byte..cluster_byte_end].to_vec()
+    }
+}
+
+/// Returns first byte of the cluster
+///
+/// Data clusters are numbered from 2, the first two FAT entries are reserved.
+pub fn get_cluster_from_entry<D: BlockDevice>(volume: &Volume<D>, entry: usize) -> usize {
+    let bpb = volume.bpb();
+    bpb.cluster_start(entry) * bpb.bytes_per_sector
+}
+
+/// First free cluster after `current_entry`, or 0 when the disk is full
+///
+/// Clusters are handed out lowest first, so the ones before the last cluster given out are taken.
+pub fn get_next_free_cluster<D: BlockDevice>(volume: &mut Volume<D>, current_entry: usize) -> Result<usize> {
+    // Look through the FAT for first 0 entry, stopping at the last cluster on the disk
+    let num_entries = volume.bpb().fat_entries();
+
+    for entry_index in (current_entry + 1).max(2)..num_entries {
+        if get_fat_entry(volume, entry_index)? == 0 {
+            // We found the next free cluster!
+            return Ok(entry_index);
+        }
+    }
+    // We should implement crashing because no free entry was found
+    Ok(0)
+}
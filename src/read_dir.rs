@@ -0,0 +1,438 @@
+//! Going through directories without handling raw 32 byte slots.
+//!
+//! `read_dir` yields the entries of one directory, leaving out the slots that don't name a file
+//! unless it's asked to include them. `walk` goes through a whole tree below a directory, in
+//! depth-first or breadth-first order, handing each entry to a visitor.
+
+use crate::block_device::BlockDevice;
+use crate::directories::{directory_slots, resolve_directory};
+use crate::fat_error::Result;
+use crate::long_names::ATTR_LONG_NAME;
+use crate::root_dir_util::{
+    directory_entry, display_name, entry_file_size, entry_first_cluster, ATTR_DIRECTORY, ATTR_VOLUME_LABEL,
+    DELETED_ENTRY,
+};
+use crate::volume::Volume;
+use alloc::collections::{BTreeSet, VecDeque};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// What a directory slot holds
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    VolumeLabel,
+    /// Part of a long file name, kept in front of the 8.3 entry it belongs to
+    LongName,
+    Deleted,
+    /// Never used, which also means nothing is stored after it
+    Free,
+}
+
+/// One 32 byte slot of a directory
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    /// Byte offset of the slot on the volume
+    pub offset: usize,
+    pub bytes: Vec<u8>,
+}
+
+impl DirEntry {
+    pub fn kind(&self) -> EntryKind {
+        let attributes = self.attributes();
+        if self.bytes[0] == 0 {
+            EntryKind::Free
+        } else if self.bytes[0] == DELETED_ENTRY {
+            EntryKind::Deleted
+        } else if attributes & 0x3F == ATTR_LONG_NAME {
+            EntryKind::LongName
+        } else if attributes & ATTR_VOLUME_LABEL != 0 {
+            EntryKind::VolumeLabel
+        } else if attributes & ATTR_DIRECTORY != 0 {
+            EntryKind::Directory
+        } else {
+            EntryKind::File
+        }
+    }
+
+    /// `NAME.EXT`
+    pub fn name(&self) -> String {
+        display_name(&self.bytes)
+    }
+
+    pub fn attributes(&self) -> u8 {
+        self.bytes[11]
+    }
+
+    pub fn first_cluster(&self) -> usize {
+        entry_first_cluster(&self.bytes)
+    }
+
+    pub fn size(&self) -> usize {
+        entry_file_size(&self.bytes)
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.kind() == EntryKind::Directory
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.kind() == EntryKind::File
+    }
+
+    /// Whether it's the `.` or `..` entry at the start of a subdirectory
+    pub fn is_dot(&self) -> bool {
+        self.is_directory() && self.bytes[0] == b'.'
+    }
+}
+
+/// The entries of a directory, in the order they're stored
+///
+/// Only files and directories, `.` and `..` included, come out unless more is asked for.
+pub struct ReadDir<'a, D: BlockDevice> {
+    volume: &'a mut Volume<D>,
+    slots: vec::IntoIter<usize>,
+    free: bool,
+    deleted: bool,
+    long_names: bool,
+    volume_labels: bool,
+    dots: bool,
+    finished: bool,
+}
+
+/// The entries of the directory starting at `dir_cluster`, 0 being the root
+pub fn read_dir<D: BlockDevice>(volume: &mut Volume<D>, dir_cluster: usize) -> Result<ReadDir<'_, D>> {
+    let slots = directory_slots(volume, dir_cluster)?;
+    Ok(ReadDir {
+        volume,
+        slots: slots.into_iter(),
+        free: false,
+        deleted: false,
+        long_names: false,
+        volume_labels: false,
+        dots: true,
+        finished: false,
+    })
+}
+
+impl<D: BlockDevice> Volume<D> {
+    /// The entries of a directory by path, absolute or relative to the directory at `cwd`
+    pub fn read_dir(&mut self, cwd: usize, path: &str) -> Result<ReadDir<'_, D>> {
+        let dir_cluster = resolve_directory(self, cwd, path)?;
+        read_dir(self, dir_cluster)
+    }
+}
+
+impl<D: BlockDevice> ReadDir<'_, D> {
+    /// Also yields unused slots, and carries on past the first one to whatever is left after it
+    pub fn include_free(mut self) -> Self {
+        self.free = true;
+        self
+    }
+
+    pub fn include_deleted(mut self) -> Self {
+        self.deleted = true;
+        self
+    }
+
+    pub fn include_long_names(mut self) -> Self {
+        self.long_names = true;
+        self
+    }
+
+    pub fn include_volume_labels(mut self) -> Self {
+        self.volume_labels = true;
+        self
+    }
+
+    /// Leaves out `.` and `..`
+    pub fn without_dots(mut self) -> Self {
+        self.dots = false;
+        self
+    }
+}
+
+impl<D: BlockDevice> Iterator for ReadDir<'_, D> {
+    type Item = Result<DirEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.finished {
+            let offset = self.slots.next()?;
+            let entry = match directory_entry(self.volume, offset) {
+                Ok(bytes) => DirEntry { offset, bytes },
+                Err(error) => {
+                    self.finished = true;
+                    return Some(Err(error));
+                }
+            };
+
+            let wanted = match entry.kind() {
+                EntryKind::Free if !self.free => {
+                    self.finished = true;
+                    false
+                }
+                EntryKind::Deleted => self.deleted,
+                EntryKind::LongName => self.long_names,
+                EntryKind::VolumeLabel => self.volume_labels,
+                EntryKind::Directory if entry.is_dot() => self.dots,
+                _ => true,
+            };
+            if wanted {
+                return Some(Ok(entry));
+            }
+        }
+        None
+    }
+}
+
+/// The order `walk` goes through a tree in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WalkOrder {
+    /// Everything below a directory comes straight after it
+    DepthFirst,
+    /// Everything at one depth comes before anything deeper
+    BreadthFirst,
+}
+
+/// What a visitor wants `walk` to do next
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Visit {
+    Continue,
+    /// Don't go into the directory just visited (the same as `Continue` for files)
+    SkipDirectory,
+    Stop,
+}
+
+/// A file or directory found by `walk`
+#[derive(Clone, Debug)]
+pub struct WalkEntry {
+    /// `/DIR/NAME.EXT`, starting with the path the walk was given
+    pub path: String,
+    /// 0 for the entries of the directory the walk started from
+    pub depth: usize,
+    pub entry: DirEntry,
+}
+
+/// Visits every file and directory below the directory at `dir_cluster`, except `.` and `..`
+///
+/// Paths are `dir_path` followed by `/` and the names, so an empty `dir_path` gives paths from the
+/// root. The visitor gets the volume too, to read what it finds. A directory that turns up again
+/// below itself isn't gone into twice.
+pub fn walk<D: BlockDevice>(
+    volume: &mut Volume<D>,
+    dir_cluster: usize,
+    dir_path: &str,
+    order: WalkOrder,
+    mut visitor: impl FnMut(&mut Volume<D>, &WalkEntry) -> Result<Visit>,
+) -> Result<()> {
+    let mut visited = BTreeSet::new();
+    visited.insert(dir_cluster);
+    match order {
+        WalkOrder::DepthFirst => {
+            walk_depth_first(volume, dir_cluster, dir_path, 0, &mut visited, &mut visitor)?;
+        }
+        WalkOrder::BreadthFirst => {
+            let mut queue = VecDeque::new();
+            queue.push_back((dir_cluster, String::from(dir_path), 0));
+            while let Some((cluster, path, depth)) = queue.pop_front() {
+                for walk_entry in walk_entries(volume, cluster, &path, depth)? {
+                    match visitor(volume, &walk_entry)? {
+                        Visit::Stop => return Ok(()),
+                        Visit::SkipDirectory => {}
+                        Visit::Continue => {
+                            if let Some(cluster) = subdirectory(&walk_entry, &mut visited) {
+                                queue.push_back((cluster, walk_entry.path, depth + 1));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns false once the visitor has asked to stop
+fn walk_depth_first<D: BlockDevice>(
+    volume: &mut Volume<D>,
+    dir_cluster: usize,
+    dir_path: &str,
+    depth: usize,
+    visited: &mut BTreeSet<usize>,
+    visitor: &mut impl FnMut(&mut Volume<D>, &WalkEntry) -> Result<Visit>,
+) -> Result<bool> {
+    for walk_entry in walk_entries(volume, dir_cluster, dir_path, depth)? {
+        match visitor(volume, &walk_entry)? {
+            Visit::Stop => return Ok(false),
+            Visit::SkipDirectory => {}
+            Visit::Continue => {
+                if let Some(cluster) = subdirectory(&walk_entry, visited) {
+                    if !walk_depth_first(volume, cluster, &walk_entry.path, depth + 1, visited, visitor)? {
+                        return Ok(false);
+                    }
+                }
+            }
+        }
+    }
+    Ok(true)
+}
+
+/// Reads a whole directory up front, so the visitor is free to use the volume
+fn walk_entries<D: BlockDevice>(
+    volume: &mut Volume<D>,
+    dir_cluster: usize,
+    dir_path: &str,
+    depth: usize,
+) -> Result<Vec<WalkEntry>> {
+    read_dir(volume, dir_cluster)?
+        .without_dots()
+        .map(|entry| {
+            let entry = entry?;
+            Ok(WalkEntry {
+                path: format!("{}/{}", dir_path, entry.name()),
+                depth,
+                entry,
+            })
+        })
+        .collect()
+}
+
+/// The first cluster of a directory that hasn't been gone into yet
+fn subdirectory(walk_entry: &WalkEntry, visited: &mut BTreeSet<usize>) -> Option<usize> {
+    let cluster = walk_entry.entry.first_cluster();
+    // A directory without a cluster would read as the root
+    if walk_entry.entry.is_directory() && cluster != 0 && visited.insert(cluster) {
+        Some(cluster)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bios_parameter_block::BiosParameterBlock;
+    use crate::directories::{add_directory_entry, create_directory};
+    use crate::fat_section_util::write_to_fat;
+    use crate::long_names::add_named_entry;
+    use crate::root_dir_util::build_directory_entry;
+
+    /// A 360K floppy held in memory with a small tree on it:
+    ///
+    /// `/A/C/E.TXT`, `/A/C/LOOP` (another name for `/A`), `/A/D.TXT`, `/release notes.txt` and `/B.TXT`
+    fn volume() -> Volume<Vec<u8>> {
+        let bpb = BiosParameterBlock::for_sectors(720);
+        let mut volume = Volume::mount(vec![0; 720 * 512]).unwrap();
+        volume.set_bpb(bpb.clone()).unwrap();
+        write_to_fat(&mut volume, 0xF00 | bpb.media as usize, 0).unwrap();
+        write_to_fat(&mut volume, 0xFFF, 1).unwrap();
+
+        let a = create_directory(&mut volume, 0, "A").unwrap();
+        let c = create_directory(&mut volume, 0, "/A/C").unwrap();
+        add_file(&mut volume, c, "E.TXT");
+        add_directory_entry(&mut volume, c, build_directory_entry("LOOP", a, 0, true)).unwrap();
+        add_file(&mut volume, a, "D.TXT");
+        add_file(&mut volume, 0, "release notes.txt");
+        add_file(&mut volume, 0, "B.TXT");
+        volume
+    }
+
+    fn add_file(volume: &mut Volume<Vec<u8>>, dir_cluster: usize, name: &str) {
+        add_named_entry(volume, dir_cluster, name, build_directory_entry("", 0, 0, false)).unwrap();
+    }
+
+    fn names(entries: ReadDir<'_, Vec<u8>>) -> Vec<String> {
+        entries.map(|entry| entry.unwrap().name()).collect()
+    }
+
+    fn walk_paths(volume: &mut Volume<Vec<u8>>, order: WalkOrder) -> Vec<(String, usize)> {
+        let mut paths = vec![];
+        walk(volume, 0, "", order, |_, walk_entry| {
+            paths.push((walk_entry.path.clone(), walk_entry.depth));
+            Ok(Visit::Continue)
+        })
+        .unwrap();
+        paths
+    }
+
+    #[test]
+    fn reads_files_and_directories() {
+        let mut volume = volume();
+        assert_eq!(names(read_dir(&mut volume, 0).unwrap()), ["A", "RELEAS~1.TXT", "B.TXT"]);
+        assert_eq!(names(volume.read_dir(0, "/A").unwrap()), [".", "..", "C", "D.TXT"]);
+        assert_eq!(names(volume.read_dir(0, "/A").unwrap().without_dots()), ["C", "D.TXT"]);
+    }
+
+    #[test]
+    fn long_names_come_before_their_entry() {
+        let mut volume = volume();
+        let kinds: Vec<EntryKind> = read_dir(&mut volume, 0)
+            .unwrap()
+            .include_long_names()
+            .map(|entry| entry.unwrap().kind())
+            .collect();
+        assert_eq!(
+            kinds,
+            [EntryKind::Directory, EntryKind::LongName, EntryKind::LongName, EntryKind::File, EntryKind::File]
+        );
+    }
+
+    #[test]
+    fn free_slots_end_the_directory_unless_asked_for() {
+        let mut volume = volume();
+        let slots = directory_slots(&mut volume, 0).unwrap().len();
+        let entries = read_dir(&mut volume, 0).unwrap().include_long_names().include_free();
+        assert_eq!(entries.count(), slots);
+    }
+
+    #[test]
+    fn walks_depth_first() {
+        let mut volume = volume();
+        let paths = walk_paths(&mut volume, WalkOrder::DepthFirst);
+        let expected = [
+            ("/A", 0),
+            ("/A/C", 1),
+            ("/A/C/E.TXT", 2),
+            ("/A/C/LOOP", 2),
+            ("/A/D.TXT", 1),
+            ("/RELEAS~1.TXT", 0),
+            ("/B.TXT", 0),
+        ];
+        assert_eq!(paths, expected.map(|(path, depth)| (String::from(path), depth)));
+    }
+
+    #[test]
+    fn walks_breadth_first() {
+        let mut volume = volume();
+        let paths = walk_paths(&mut volume, WalkOrder::BreadthFirst);
+        let expected = [
+            ("/A", 0),
+            ("/RELEAS~1.TXT", 0),
+            ("/B.TXT", 0),
+            ("/A/C", 1),
+            ("/A/D.TXT", 1),
+            ("/A/C/E.TXT", 2),
+            ("/A/C/LOOP", 2),
+        ];
+        assert_eq!(paths, expected.map(|(path, depth)| (String::from(path), depth)));
+    }
+
+    #[test]
+    fn skips_and_stops_when_asked() {
+        let mut volume = volume();
+        let mut paths = vec![];
+        walk(&mut volume, 0, "", WalkOrder::DepthFirst, |_, walk_entry| {
+            paths.push(walk_entry.path.clone());
+            Ok(match walk_entry.path.as_str() {
+                "/A/C" => Visit::SkipDirectory,
+                "/RELEAS~1.TXT" => Visit::Stop,
+                _ => Visit::Continue,
+            })
+        })
+        .unwrap();
+        assert_eq!(paths, ["/A", "/A/C", "/A/D.TXT", "/RELEAS~1.TXT"]);
+    }
+}
@@ -1,34 +1,49 @@
-use crate::bios_parameter_block::BYTES_PER_SECTOR;
-use crate::fat_section_util::get_fat_entry;
-use crate::new_file::get_cluster_from_entry;
-use crate::shell_state::ShellState;
-
-pub fn read_file(shell_state: &ShellState, fat_entry: usize) -> Vec<u8> {
-    let mut current_fat_entry = fat_entry;
-    let file = vec![];
-
-    loop {
-        let mut cluster = get_cluster(&shell_state.bytes, current_fat_entry);
-        file.append(&mut cluster);
-        current_fat_entry = get_fat_entry(&shell_state.bytes, current_fat_entry);
-        if current_fat_entry == 0xFFF {
-            break;
-        }
-    }
-
-    file
-}
-
-pub fn save_file_to_os(shell_state: ShellState, args: Vec<&str>) -> ShellState {
-    let filename = get_arg(&args, 1).expect("Missing filename!");
-
-
-    let file = read_file(&shell_state, )
-
-    shell_state
-}
-
-fn get_cluster(bytes: &Vec<u8>, fat_entry: usize) -> Vec<u8> {
-    let cluster_byte = get_cluster_from_entry(fat_entry);
-    bytes[cluster_byte..cluster_byte + BYTES_PER_SECTOR].to_vec()
-}
+use crate::block_device::BlockDevice;
+use crate::directories::{find_path, is_directory};
+use crate::fat_error::{FatError, Result};
+use crate::fat_section_util::cluster_chain;
+use crate::new_file::get_cluster_from_entry;
+use crate::root_dir_util::{directory_entry, entry_file_size, entry_first_cluster};
+use crate::volume::Volume;
+use alloc::borrow::ToOwned;
+use alloc::vec::Vec;
+use alloc::{format, vec};
+
+/// Every cluster of a chain, one after the other
+pub fn read_file<D: BlockDevice>(volume: &mut Volume<D>, fat_entry: usize) -> Result<Vec<u8>> {
+    let mut file = vec![];
+
+    // Empty files don't have a cluster chain
+    for cluster in cluster_chain(volume, fat_entry)? {
+        file.append(&mut get_cluster(volume, cluster)?);
+    }
+
+    Ok(file)
+}
+
+/// Returns the 32 byte entry of a file, relative to the directory at `cwd`
+pub fn find_file_entry<D: BlockDevice>(volume: &mut Volume<D>, cwd: usize, path: &str) -> Result<Vec<u8>> {
+    let (_, offset) = find_path(volume, cwd, path)?;
+    let entry = directory_entry(volume, offset)?;
+    if is_directory(&entry) {
+        return Err(FatError::IsADirectory(path.to_owned()));
+    }
+    Ok(entry)
+}
+
+/// Reads a whole file by path, cut down to its stored size
+pub fn read_file_at_path<D: BlockDevice>(volume: &mut Volume<D>, cwd: usize, path: &str) -> Result<Vec<u8>> {
+    let entry = find_file_entry(volume, cwd, path)?;
+    let mut file = read_file(volume, entry_first_cluster(&entry))?;
+    if file.len() < entry_file_size(&entry) {
+        return Err(FatError::CorruptFat(format!("{} is shorter than its directory entry says", path)));
+    }
+    file.truncate(entry_file_size(&entry));
+    Ok(file)
+}
+
+fn get_cluster<D: BlockDevice>(volume: &mut Volume<D>, fat_entry: usize) -> Result<Vec<u8>> {
+    let cluster_byte = get_cluster_from_entry(volume, fat_entry);
+    let cluster_size = volume.bpb().bytes_per_cluster();
+    volume.read(cluster_byte, cluster_size)
+}
@@ -0,0 +1,21 @@
+use crate::block_device::BlockDevice;
+use crate::fat_error::Result;
+use crate::fat_section_util::free_chain;
+use crate::long_names::delete_long_name;
+use crate::root_dir_util::{directory_entry, entry_first_cluster, write_directory_entry, DELETED_ENTRY};
+use crate::volume::Volume;
+
+/// Frees the clusters of the entry at `offset` in the directory at `dir_cluster` and marks it as deleted
+pub fn remove_entry<D: BlockDevice>(volume: &mut Volume<D>, dir_cluster: usize, offset: usize) -> Result<()> {
+    let entry = directory_entry(volume, offset)?;
+    free_chain(volume, entry_first_cluster(&entry))?;
+    delete_entry(volume, dir_cluster, offset)
+}
+
+/// Marks the entry at `offset` as deleted, along with its long name, leaving its clusters alone
+pub fn delete_entry<D: BlockDevice>(volume: &mut Volume<D>, dir_cluster: usize, offset: usize) -> Result<()> {
+    delete_long_name(volume, dir_cluster, offset)?;
+    let mut entry = directory_entry(volume, offset)?;
+    entry[0] = DELETED_ENTRY;
+    write_directory_entry(volume, offset, &entry)
+}
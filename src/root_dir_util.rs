@@ -1,73 +1,177 @@
-use crate::bios_parameter_block::{
-    BYTES_PER_DIRECTORY_ENTRY, BYTES_PER_SECTOR, NUMBER_FATS, RESERVED_SECTORS, ROOT_ENTRIES,
-    SECTORS_PER_FAT,
-};
-use crate::shell_state::ShellState;
-
-pub fn append_to_root_dir(
-    mut bytes: Vec<u8>,
-    newfilename: String,
-    first_entry: usize,
-    is_subdir: bool,
-) -> Vec<u8> {
-    let root_start = (RESERVED_SECTORS + NUMBER_FATS * SECTORS_PER_FAT) * BYTES_PER_SECTOR;
-    let root_entry = get_first_free_root_entry(&bytes);
-    println!("First free root entry: {}", root_entry);
-
-    let entry_start = root_start + BYTES_PER_DIRECTORY_ENTRY * root_entry;
-
-    // Make sure filename is 11 letters long
-    let mut truncated_filename = newfilename.clone();
-    truncated_filename.truncate(11);
-    let truncated_filename = format!("{:11}", truncated_filename).into_bytes();
-
-    // I would make this a constant but it won't compile
-    let mut entry_to_add = vec![0; 32];
-
-    // Bytes 0-10: Filename and extension
-    entry_to_add.splice(0..11, truncated_filename);
-
-    // Byte 11: File attributes
-    if is_subdir {
-        entry_to_add[11] = 0b00001000;
-    } else {
-        entry_to_add[11] = 0b00000000;
-    }
-
-    // Bytes 26-27: First cluster
-    entry_to_add[26] = (first_entry >> 8) as u8;
-    entry_to_add[27] = first_entry as u8;
-
-    // Bytes 28-32: File size
-    // TODO
-
-    bytes.splice(
-        entry_start..entry_start + BYTES_PER_DIRECTORY_ENTRY,
-        entry_to_add,
-    );
-    bytes
-}
-
-/// Returns 32 bit entry
-pub fn read_root_entry(bytes: &Vec<u8>, root_entry: usize) -> Vec<u8> {
-    let root_start = (RESERVED_SECTORS + NUMBER_FATS * SECTORS_PER_FAT) * BYTES_PER_SECTOR;
-    let entry_start = root_start + BYTES_PER_DIRECTORY_ENTRY * root_entry;
-
-    bytes[entry_start..entry_start + BYTES_PER_DIRECTORY_ENTRY].to_vec()
-}
-
-pub fn list_root_directory(shell_state: ShellState, _args: Vec<&str>) -> ShellState {
-    println!("Listing files in the root directory:");
-    println!("-----------------------");
-
-    for root_entry_index in 0..ROOT_ENTRIES {
-        let root_entry = read_root_entry(&shell_state.bytes, root_entry_index);
-        if root_entry[0] != 0 {
-            let filename = String::from_utf8(root_entry[0..12].to_vec()).unwrap();
-            println!("{}", filename);
-        }
-    }
-
-    println!("-----------------------");
-    shell_state
-}
+use crate::bios_parameter_block::BYTES_PER_DIRECTORY_ENTRY;
+use crate::block_device::BlockDevice;
+use crate::fat_error::{FatError, Result};
+use crate::timestamps::CivilTime;
+use crate::volume::Volume;
+use alloc::borrow::ToOwned;
+use alloc::string::String;
+use alloc::vec::Vec;
+use alloc::{format, vec};
+
+/// First byte of a directory entry that was deleted
+pub const DELETED_ENTRY: u8 = 0xE5;
+
+pub const ATTR_READ_ONLY: u8 = 0b00000001;
+pub const ATTR_HIDDEN: u8 = 0b00000010;
+pub const ATTR_SYSTEM: u8 = 0b00000100;
+/// Attribute bit of the entry holding the volume label
+pub const ATTR_VOLUME_LABEL: u8 = 0b00001000;
+/// Attribute bit of a subdirectory entry
+pub const ATTR_DIRECTORY: u8 = 0b00010000;
+pub const ATTR_ARCHIVE: u8 = 0b00100000;
+
+/// Builds a 32 byte directory entry
+pub fn build_directory_entry(
+    filename: &str,
+    first_entry: usize,
+    file_size: usize,
+    is_subdir: bool,
+) -> Vec<u8> {
+    // I would make this a constant but it won't compile
+    let mut entry_to_add = vec![0; 32];
+
+    // Bytes 0-10: Filename and extension
+    entry_to_add.splice(0..11, to_short_name(filename));
+
+    // Byte 11: File attributes
+    if is_subdir {
+        entry_to_add[11] = ATTR_DIRECTORY;
+    } else {
+        entry_to_add[11] = 0b00000000;
+    }
+
+    // Bytes 26-27: First cluster (little endian)
+    entry_to_add[26] = first_entry as u8;
+    entry_to_add[27] = (first_entry >> 8) as u8;
+
+    // Bytes 28-31: File size (little endian)
+    entry_to_add.splice(28..32, (file_size as u32).to_le_bytes().to_vec());
+
+    entry_to_add
+}
+
+/// Sets the creation, last access and modification times of an entry
+pub fn set_entry_time(entry: &mut [u8], time: &CivilTime) {
+    let (date, time) = (time.dos_date().to_le_bytes(), time.dos_time().to_le_bytes());
+
+    // Bytes 14-17: Creation time and date
+    entry[14..16].copy_from_slice(&time);
+    entry[16..18].copy_from_slice(&date);
+    // Bytes 18-19: Last access date
+    entry[18..20].copy_from_slice(&date);
+    // Bytes 22-25: Modification time and date
+    entry[22..24].copy_from_slice(&time);
+    entry[24..26].copy_from_slice(&date);
+}
+
+/// Checks that a name fits the 8.3 form without being cut short
+pub fn validate_short_name(filename: &str) -> Result<()> {
+    let invalid = || FatError::InvalidName(filename.to_owned());
+    let (name, extension) = match filename.rfind('.') {
+        Some(dot) => (&filename[..dot], &filename[dot + 1..]),
+        None => (filename, ""),
+    };
+
+    if name.is_empty() || name.len() > 8 || extension.len() > 3 {
+        return Err(invalid());
+    }
+    let is_valid_char = |c: char| c.is_ascii_alphanumeric() || "!#$%&'()-@^_`{}~".contains(c);
+    if !name.chars().chain(extension.chars()).all(is_valid_char) {
+        return Err(invalid());
+    }
+    Ok(())
+}
+
+/// Converts `name.ext` into the space padded 11 byte form stored on disk
+///
+/// Names already in the padded form (`KERNEL  BIN`) are passed through.
+pub fn to_short_name(filename: &str) -> [u8; 11] {
+    let mut short_name = [b' '; 11];
+    let upper = filename.to_ascii_uppercase();
+
+    // "." and ".." are the only names made of dots
+    if upper == "." || upper == ".." {
+        short_name[..upper.len()].copy_from_slice(upper.as_bytes());
+        return short_name;
+    }
+
+    let (name, extension) = match upper.rfind('.') {
+        Some(dot) if dot > 0 => (&upper[..dot], &upper[dot + 1..]),
+        _ if upper.len() > 8 => (&upper[..8], &upper[8..]),
+        _ => (&upper[..], ""),
+    };
+
+    for (i, byte) in name.bytes().take(8).enumerate() {
+        short_name[i] = byte;
+    }
+    for (i, byte) in extension.trim().bytes().take(3).enumerate() {
+        short_name[8 + i] = byte;
+    }
+    short_name
+}
+
+/// Converts the 11 byte on-disk name into `NAME.EXT`
+pub fn display_name(entry: &[u8]) -> String {
+    let name = String::from_utf8_lossy(&entry[0..8]).trim_end().to_owned();
+    let extension = String::from_utf8_lossy(&entry[8..11]).trim_end().to_owned();
+
+    if extension.is_empty() {
+        name
+    } else {
+        format!("{}.{}", name, extension)
+    }
+}
+
+/// Whether `ls` should show an entry (not free, deleted or the volume label)
+pub fn is_listed(entry: &[u8]) -> bool {
+    entry[0] != 0 && entry[0] != DELETED_ENTRY && entry[11] & ATTR_VOLUME_LABEL == 0
+}
+
+/// First cluster of a directory entry
+pub fn entry_first_cluster(entry: &[u8]) -> usize {
+    entry[26] as usize | (entry[27] as usize) << 8
+}
+
+/// File size of a directory entry
+pub fn entry_file_size(entry: &[u8]) -> usize {
+    u32::from_le_bytes([entry[28], entry[29], entry[30], entry[31]]) as usize
+}
+
+/// Byte offset of a root entry on the volume
+pub fn root_entry_start<D: BlockDevice>(volume: &Volume<D>, root_entry: usize) -> usize {
+    let bpb = volume.bpb();
+    bpb.root_start() * bpb.bytes_per_sector + BYTES_PER_DIRECTORY_ENTRY * root_entry
+}
+
+/// Number of entries the root directory can hold
+pub fn root_entry_count<D: BlockDevice>(volume: &Volume<D>) -> usize {
+    volume.bpb().root_entries
+}
+
+/// The 32 byte directory entry at `offset` on the volume
+pub fn directory_entry<D: BlockDevice>(volume: &mut Volume<D>, offset: usize) -> Result<Vec<u8>> {
+    volume.read(offset, BYTES_PER_DIRECTORY_ENTRY)
+}
+
+/// Overwrites the 32 byte directory entry at `offset` on the volume
+pub fn write_directory_entry<D: BlockDevice>(volume: &mut Volume<D>, offset: usize, entry: &[u8]) -> Result<()> {
+    volume.write(offset, &entry[..BYTES_PER_DIRECTORY_ENTRY])
+}
+
+/// Returns 32 bit entry
+pub fn read_root_entry<D: BlockDevice>(volume: &mut Volume<D>, root_entry: usize) -> Result<Vec<u8>> {
+    let entry_start = root_entry_start(volume, root_entry);
+
+    directory_entry(volume, entry_start)
+}
+
+/// Index of the first unused or deleted root entry
+pub fn get_first_free_root_entry<D: BlockDevice>(volume: &mut Volume<D>) -> Result<Option<usize>> {
+    for root_entry_index in 0..root_entry_count(volume) {
+        let root_entry = read_root_entry(volume, root_entry_index)?;
+        if root_entry[0] == 0 || root_entry[0] == DELETED_ENTRY {
+            return Ok(Some(root_entry_index));
+        }
+    }
+    Ok(None)
+}
@@ -0,0 +1,130 @@
+use crate::commands::run_line;
+use crate::fat_error::{FatError, Result};
+use crate::shell_parsing::{split_commands, Args};
+use crate::shell_state::ShellState;
+use std::path::{Path, PathBuf};
+
+/// What happened when a script was run
+pub struct ScriptResult {
+    pub shell_state: ShellState,
+    pub lines_run: usize,
+    pub failures: usize,
+}
+
+/// source [-k] <script>
+///
+/// Stops at the first failing line unless `-k` (keep going) is given. A script with a failing line
+/// fails as a whole, so `&&` and an outer script stop there, and like any failed command it leaves
+/// the image as it was before: what the lines before the failure did is taken back too, even with `-k`.
+pub fn source(shell_state: ShellState, args: &Args) -> Result<ShellState> {
+    let keep_going = args.flag("-k");
+    let script_filename = args.expect(1, "script")?;
+    if shell_state.is_running_script(&script_path(&script_filename)) {
+        return Err(FatError::BadArgument(format!(
+            "{} is already running, a script can't source itself",
+            script_filename
+        )));
+    }
+
+    let result = run_script(shell_state, &script_filename, keep_going);
+    if result.failures > 0 {
+        return Err(FatError::CheckFailed(format!(
+            "{} failed on {} of {} lines, nothing it did was kept",
+            script_filename, result.failures, result.lines_run
+        )));
+    }
+    println!("Ran {} lines of {}", result.lines_run, script_filename);
+    Ok(result.shell_state)
+}
+
+/// record <script>
+///
+/// Writes the commands typed so far this session as a script `source` can replay.
+pub fn record(shell_state: ShellState, args: &Args) -> Result<ShellState> {
+    let script_filename = args.expect(1, "script filename")?;
+
+    let mut script = String::from("# Recorded shell session, replay with `source`\n");
+    for line in shell_state.get_history() {
+        script.push_str(line);
+        script.push('\n');
+    }
+
+    std::fs::write(&script_filename, script).map_err(FatError::io(format!("Can't write {}", script_filename)))?;
+    println!(
+        "Recorded {} commands to {}!",
+        shell_state.get_history().len(),
+        script_filename
+    );
+    Ok(shell_state)
+}
+
+/// Runs every command of a script file, reporting failures with their line number
+///
+/// Nobody is asked anything while it runs, so commands that would lose unsaved changes fail instead.
+pub fn run_script(shell_state: ShellState, script_filename: &str, keep_going: bool) -> ScriptResult {
+    let interactive = shell_state.is_interactive();
+    let shell_state = shell_state.set_interactive(false).start_script(script_path(script_filename));
+    let mut result = run_script_lines(shell_state, script_filename, keep_going);
+    result.shell_state = result.shell_state.set_interactive(interactive).finish_script();
+    result
+}
+
+/// The script's path as it's told apart from others, the same however it was named
+fn script_path(script_filename: &str) -> PathBuf {
+    Path::new(script_filename)
+        .canonicalize()
+        .unwrap_or_else(|_| PathBuf::from(script_filename))
+}
+
+fn run_script_lines(mut shell_state: ShellState, script_filename: &str, keep_going: bool) -> ScriptResult {
+    let script = match std::fs::read_to_string(script_filename) {
+        Ok(script) => script,
+        Err(error) => {
+            eprintln!("{}: can't read the script: {}", script_filename, error);
+            return ScriptResult {
+                shell_state,
+                lines_run: 0,
+                failures: 1,
+            };
+        }
+    };
+
+    let mut lines_run = 0;
+    let mut failures = 0;
+
+    for (line_index, line) in script.lines().enumerate() {
+        let report = |error: FatError| eprintln!("{}:{}: {}", script_filename, line_index + 1, error);
+
+        let succeeded = match split_commands(line) {
+            Ok(commands) if commands.is_empty() => continue,
+            Ok(commands) if commands.len() == 1 && commands[0].name() == "exit" => break,
+            Ok(commands) => {
+                println!("> {}", line.trim());
+                lines_run += 1;
+
+                // A failed command leaves the image as it was before that command
+                let (new_state, succeeded) = run_line(shell_state, &commands, report);
+                shell_state = new_state;
+                succeeded
+            }
+            Err(error) => {
+                lines_run += 1;
+                report(error);
+                false
+            }
+        };
+
+        if !succeeded {
+            failures += 1;
+            if !keep_going {
+                break;
+            }
+        }
+    }
+
+    ScriptResult {
+        shell_state,
+        lines_run,
+        failures,
+    }
+}
@@ -0,0 +1,347 @@
+//! A cache of recently used sectors in front of a slower block device.
+//!
+//! Working with files reads the FAT and directory sectors over and over, so keeping them saves
+//! going back to the floppy drive or the file on the network each time. In write-back mode writes
+//! stay in the cache as well until they're flushed, the way the shell's changes wait for `save`.
+
+use crate::block_device::{sector_range, Access, BlockDevice};
+use crate::fat_error::Result;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+/// When writes reach the device underneath
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Every write goes to the device straight away, the cache only saves reads
+    WriteThrough,
+    /// Writes wait in the cache until they're flushed or the sector is evicted
+    WriteBack,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Sectors read from the cache
+    pub hits: usize,
+    /// Sectors that had to be read from the device
+    pub misses: usize,
+    /// Sectors written to the device
+    pub writes: usize,
+    /// Sectors dropped to make room for others
+    pub evictions: usize,
+}
+
+impl CacheStats {
+    /// The share of sectors read that were in the cache, from 0 to 1
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            reads => self.hits as f64 / reads as f64,
+        }
+    }
+}
+
+struct CachedSector {
+    data: Vec<u8>,
+    // Written since it was last read from or written to the device
+    dirty: bool,
+    last_used: u64,
+}
+
+/// A block device with the least recently used sectors cached in memory
+///
+/// Dirty sectors are written back when they're evicted and by `flush`, which also flushes the
+/// device underneath. Dropping the cache without flushing it loses them.
+pub struct CachedDevice<D: BlockDevice> {
+    device: D,
+    capacity: usize,
+    mode: CacheMode,
+    sectors: BTreeMap<usize, CachedSector>,
+    // Counts accesses, to tell which sector was used least recently
+    clock: u64,
+    stats: CacheStats,
+}
+
+impl<D: BlockDevice> CachedDevice<D> {
+    /// Caches up to `capacity` sectors of `device`, none at all for 0
+    pub fn new(device: D, capacity: usize, mode: CacheMode) -> Self {
+        CachedDevice {
+            device,
+            capacity,
+            mode,
+            sectors: BTreeMap::new(),
+            clock: 0,
+            stats: CacheStats::default(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Resizes the cache, evicting the least recently used sectors that no longer fit
+    pub fn set_capacity(&mut self, capacity: usize) -> Result<()> {
+        self.capacity = capacity;
+        while self.sectors.len() > capacity {
+            self.evict()?;
+        }
+        Ok(())
+    }
+
+    pub fn mode(&self) -> CacheMode {
+        self.mode
+    }
+
+    /// Switching to write-through writes back everything that's waiting first
+    pub fn set_mode(&mut self, mode: CacheMode) -> Result<()> {
+        if mode == CacheMode::WriteThrough {
+            self.write_back()?;
+        }
+        self.mode = mode;
+        Ok(())
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    /// Number of sectors written to the cache but not yet to the device
+    pub fn dirty_sectors(&self) -> usize {
+        self.sectors.values().filter(|sector| sector.dirty).count()
+    }
+
+    /// Writes every dirty sector to the device, without flushing the device itself
+    ///
+    /// Runs of neighbouring sectors go in one write.
+    pub fn write_back(&mut self) -> Result<()> {
+        let dirty: Vec<usize> = self
+            .sectors
+            .iter()
+            .filter(|(_, sector)| sector.dirty)
+            .map(|(&number, _)| number)
+            .collect();
+
+        let mut run_start = 0;
+        while run_start < dirty.len() {
+            let mut run_end = run_start + 1;
+            while run_end < dirty.len() && dirty[run_end] == dirty[run_end - 1] + 1 {
+                run_end += 1;
+            }
+
+            let mut data = Vec::with_capacity((run_end - run_start) * self.device.sector_size());
+            for number in &dirty[run_start..run_end] {
+                data.extend_from_slice(&self.sectors[number].data);
+            }
+            self.device.write_sectors(dirty[run_start], &data)?;
+            self.stats.writes += run_end - run_start;
+            for number in &dirty[run_start..run_end] {
+                if let Some(sector) = self.sectors.get_mut(number) {
+                    sector.dirty = false;
+                }
+            }
+            run_start = run_end;
+        }
+        Ok(())
+    }
+
+    /// Forgets every cached sector, including changes that haven't been written back
+    pub fn discard(&mut self) {
+        self.sectors.clear();
+    }
+
+    /// Flushes the cache and hands back the device
+    pub fn into_inner(mut self) -> Result<D> {
+        self.flush()?;
+        Ok(self.device)
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    /// Drops the least recently used sector, writing it back first if it's dirty
+    fn evict(&mut self) -> Result<()> {
+        let oldest = self
+            .sectors
+            .iter()
+            .min_by_key(|(_, sector)| sector.last_used)
+            .map(|(&number, _)| number);
+        if let Some(number) = oldest {
+            if self.sectors[&number].dirty {
+                self.device.write_sectors(number, &self.sectors[&number].data)?;
+                self.stats.writes += 1;
+            }
+            self.sectors.remove(&number);
+            self.stats.evictions += 1;
+        }
+        Ok(())
+    }
+
+    /// Keeps a sector, making room for it if the cache is full
+    fn insert(&mut self, number: usize, data: &[u8], dirty: bool) -> Result<()> {
+        if self.capacity == 0 {
+            return Ok(());
+        }
+        if !self.sectors.contains_key(&number) && self.sectors.len() >= self.capacity {
+            self.evict()?;
+        }
+        let last_used = self.tick();
+        let sector = self.sectors.entry(number).or_insert_with(|| CachedSector {
+            data: Vec::new(),
+            dirty: false,
+            last_used,
+        });
+        sector.data.clear();
+        sector.data.extend_from_slice(data);
+        sector.dirty |= dirty;
+        sector.last_used = last_used;
+        Ok(())
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for CachedDevice<D> {
+    fn sector_size(&self) -> usize {
+        self.device.sector_size()
+    }
+
+    fn sector_count(&self) -> usize {
+        self.device.sector_count()
+    }
+
+    fn read_sectors(&mut self, first_sector: usize, buffer: &mut [u8]) -> Result<()> {
+        sector_range(self, first_sector, buffer.len())?;
+        let sector_size = self.sector_size();
+        let count = buffer.len() / sector_size;
+
+        let mut index = 0;
+        while index < count {
+            let number = first_sector + index;
+            let chunk = index * sector_size..(index + 1) * sector_size;
+            let last_used = self.tick();
+            if let Some(sector) = self.sectors.get_mut(&number) {
+                buffer[chunk].copy_from_slice(&sector.data);
+                sector.last_used = last_used;
+                self.stats.hits += 1;
+                index += 1;
+                continue;
+            }
+
+            // Read the whole run of sectors that aren't cached at once
+            let mut run_end = index + 1;
+            while run_end < count && !self.sectors.contains_key(&(first_sector + run_end)) {
+                run_end += 1;
+            }
+            let run = index * sector_size..run_end * sector_size;
+            self.device.read_sectors(number, &mut buffer[run])?;
+            self.stats.misses += run_end - index;
+            for missed in index..run_end {
+                self.insert(
+                    first_sector + missed,
+                    &buffer[missed * sector_size..(missed + 1) * sector_size],
+                    false,
+                )?;
+            }
+            index = run_end;
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, first_sector: usize, data: &[u8]) -> Result<()> {
+        sector_range(self, first_sector, data.len())?;
+        let sector_size = self.sector_size();
+        let write_back = self.mode == CacheMode::WriteBack && self.capacity > 0;
+
+        if !write_back {
+            self.device.write_sectors(first_sector, data)?;
+            self.stats.writes += data.len() / sector_size;
+        }
+        for (index, sector) in data.chunks(sector_size).enumerate() {
+            self.insert(first_sector + index, sector, write_back)?;
+        }
+        Ok(())
+    }
+
+    /// Writes back the dirty sectors and flushes the device, so everything written is stored
+    fn flush(&mut self) -> Result<()> {
+        self.write_back()?;
+        self.device.flush()
+    }
+
+    fn observe(&mut self, access: Access, offset: usize, length: usize) {
+        self.device.observe(access, offset, length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bios_parameter_block::BYTES_PER_SECTOR;
+    use alloc::vec;
+
+    fn read_sector(cache: &mut CachedDevice<&mut Vec<u8>>, number: usize) -> Vec<u8> {
+        let mut buffer = vec![0; BYTES_PER_SECTOR];
+        cache.read_sectors(number, &mut buffer).unwrap();
+        buffer
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_sector() {
+        let mut disk = vec![0; 8 * BYTES_PER_SECTOR];
+        let mut cache = CachedDevice::new(&mut disk, 2, CacheMode::WriteThrough);
+        read_sector(&mut cache, 0);
+        read_sector(&mut cache, 1);
+        // Sector 0 is now used more recently than sector 1
+        read_sector(&mut cache, 0);
+        read_sector(&mut cache, 2);
+        assert_eq!(cache.stats().evictions, 1);
+
+        read_sector(&mut cache, 0);
+        read_sector(&mut cache, 1);
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (2, 4));
+    }
+
+    #[test]
+    fn write_back_waits_for_flush() {
+        let mut disk = vec![0; 8 * BYTES_PER_SECTOR];
+        let mut cache = CachedDevice::new(&mut disk, 4, CacheMode::WriteBack);
+        cache.write_sectors(3, &[0xAA; 2 * BYTES_PER_SECTOR]).unwrap();
+        assert_eq!(cache.dirty_sectors(), 2);
+        assert_eq!(cache.stats().writes, 0);
+        // Reads see the waiting write
+        assert_eq!(read_sector(&mut cache, 4), vec![0xAA; BYTES_PER_SECTOR]);
+
+        cache.flush().unwrap();
+        assert_eq!(cache.dirty_sectors(), 0);
+        assert_eq!(cache.stats().writes, 2);
+        cache.into_inner().unwrap();
+        assert!(disk[3 * BYTES_PER_SECTOR..5 * BYTES_PER_SECTOR].iter().all(|&byte| byte == 0xAA));
+    }
+
+    #[test]
+    fn evicting_a_dirty_sector_writes_it_back() {
+        let mut disk = vec![0; 8 * BYTES_PER_SECTOR];
+        let mut cache = CachedDevice::new(&mut disk, 1, CacheMode::WriteBack);
+        cache.write_sectors(0, &[0x55; BYTES_PER_SECTOR]).unwrap();
+        read_sector(&mut cache, 1);
+        assert_eq!(cache.stats().writes, 1);
+        assert_eq!(cache.dirty_sectors(), 0);
+
+        // Nothing is left waiting, so forgetting the cache keeps the write
+        cache.discard();
+        drop(cache);
+        assert_eq!(&disk[0..BYTES_PER_SECTOR], &[0x55; BYTES_PER_SECTOR][..]);
+    }
+
+    #[test]
+    fn write_through_writes_straight_away() {
+        let mut disk = vec![0; 8 * BYTES_PER_SECTOR];
+        let mut cache = CachedDevice::new(&mut disk, 2, CacheMode::WriteThrough);
+        cache.write_sectors(5, &[0x11; BYTES_PER_SECTOR]).unwrap();
+        assert_eq!(cache.dirty_sectors(), 0);
+        assert_eq!(read_sector(&mut cache, 5), vec![0x11; BYTES_PER_SECTOR]);
+        assert_eq!(cache.stats().hits, 1);
+        drop(cache);
+        assert_eq!(&disk[5 * BYTES_PER_SECTOR..6 * BYTES_PER_SECTOR], &[0x11; BYTES_PER_SECTOR][..]);
+    }
+}
@@ -0,0 +1,109 @@
+use crate::commands::run_line;
+use crate::completion::complete;
+use crate::line_editor::LineEditor;
+use crate::shell_images::{confirm_discard_all, discarded_message};
+use crate::shell_parsing::split_commands;
+use crate::shell_state::ShellState;
+use std::io;
+use std::io::*;
+
+/// The interactive shell, reading commands from stdin until `exit`
+///
+/// Quitting with unsaved changes asks whether to save them first, `exit -f` discards them.
+///
+/// Commands added with `commands::register` beforehand are available too.
+/// On a terminal lines are read with the line editor, otherwise (e.g. piped in) as they come.
+pub fn run_shell() {
+    // Stores the state of the shell (cwd, image bytes, etc)
+    let mut shell_state = ShellState::new().set_interactive(io::stdin().is_terminal());
+    let mut editor = if shell_state.is_interactive() {
+        Some(LineEditor::new(LineEditor::default_history_path()))
+    } else {
+        None
+    };
+
+    loop {
+        let input = match read_input(editor.as_mut(), &shell_state) {
+            Some(input) => input,
+            // Nobody is left to ask when a pipe runs out
+            None if !shell_state.is_interactive() => {
+                for image_filename in shell_state.modified_images() {
+                    println!("{}", discarded_message(&image_filename));
+                }
+                break;
+            }
+            None if can_exit(&shell_state, false) => break,
+            None => continue,
+        };
+        let commands = match split_commands(&input) {
+            Ok(commands) => commands,
+            Err(error) => {
+                println!("Error: {}", error);
+                continue;
+            }
+        };
+        if commands.len() == 1 && commands[0].name() == "exit" {
+            let force = commands[0].words.iter().any(|word| word.text == "-f");
+            if can_exit(&shell_state, force) {
+                break;
+            }
+            continue;
+        }
+        if commands.is_empty() {
+            continue;
+        }
+
+        // Each command on the line runs in turn, a failed one leaves the image as it was
+        let (new_state, succeeded) = run_line(shell_state, &commands, |error| println!("Error: {}", error));
+        // Recording the recorder would make the script overwrite itself on replay
+        let is_record = commands.iter().any(|command| command.name() == "record");
+        shell_state = if succeeded && !is_record {
+            new_state.add_history(input.trim().to_owned())
+        } else {
+            new_state
+        };
+    }
+    println!("Finished!");
+}
+
+/// Whether the shell can quit without losing changes, after asking if need be
+fn can_exit(shell_state: &ShellState, force: bool) -> bool {
+    match confirm_discard_all(shell_state.clone(), force) {
+        Ok(_) => true,
+        Err(error) => {
+            println!("Error: {}", error);
+            false
+        }
+    }
+}
+
+/// The next line typed, None at the end of input
+fn read_input(editor: Option<&mut LineEditor>, shell_state: &ShellState) -> Option<String> {
+    // The drive is only worth showing once there's more than one
+    let drive = if shell_state.has_other_drives() {
+        format!("{}:", shell_state.get_drive())
+    } else {
+        String::new()
+    };
+    let prompt = if shell_state.is_dry_run() {
+        format!("(dry run) {}> ", drive)
+    } else {
+        format!("{}> ", drive)
+    };
+    if let Some(editor) = editor {
+        match editor.read_line(&prompt, &mut |line| complete(shell_state, line)) {
+            Ok(line) => return line,
+            // Without a working stty, fall back to plain lines
+            Err(error) => println!("Line editing is off: {}", error),
+        }
+    }
+
+    print!("{}", prompt);
+    io::stdout().flush().unwrap();
+
+    let mut input = String::new();
+    match io::stdin().read_line(&mut input).expect("Couldn't read user input!") {
+        0 => None,
+        _ => Some(input),
+    }
+}
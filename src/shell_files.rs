@@ -0,0 +1,225 @@
+//! The shell commands that work with files and directories in the image
+
+use crate::block_device::BlockDevice;
+use crate::directories::{
+    create_directory, directory_slots, expand_glob, find_in_directory, find_path, glob_matches, has_wildcards,
+    is_directory, resolve_directory, split_path,
+};
+use crate::fat_error::{FatError, Result};
+use crate::fat_section_util::{cluster_chain, count_free_clusters, free_chain};
+use crate::long_names::{add_named_entry, named_entries, validate_long_name, NamedEntry};
+use crate::new_file::write_file_data;
+use crate::remove_file::remove_entry;
+use crate::root_dir_util::{
+    build_directory_entry, directory_entry, display_name, entry_first_cluster, is_listed,
+};
+use crate::shell_parsing::Args;
+use crate::shell_state::ShellState;
+use crate::volume::Volume;
+use std::io;
+use std::path::Path;
+
+/// ls [directory, file or pattern]
+pub fn list_directory(shell_state: ShellState, args: &Args) -> Result<ShellState> {
+    let cwd = shell_state.get_cwd();
+    let mut volume = shell_state.volume()?;
+    let path = args.get(1).unwrap_or_default();
+    // `ls DIR/*.BIN` lists the matching part of DIR, and `ls DIR/FILE` just that file
+    let is_file = match find_path(&mut volume, cwd, &path) {
+        Ok((_, offset)) => !is_directory(&directory_entry(&mut volume, offset)?),
+        Err(_) => false,
+    };
+    let (dir_path, pattern) = match split_path(&path) {
+        (dir_path, name) if has_wildcards(name) || is_file => (dir_path, Some(name)),
+        _ => (path.as_str(), None),
+    };
+    let dir_cluster = resolve_directory(&mut volume, cwd, dir_path)?;
+    if pattern.is_some() {
+        // Matching nothing is an error, like DOS' "File not found"
+        expand_glob(&mut volume, cwd, &path)?;
+    }
+
+    if dir_cluster == 0 {
+        println!("Listing files in the root directory:");
+    } else {
+        println!("Listing files in current directory:");
+    }
+    println!("-----------------------");
+
+    for NamedEntry { entry, long_name, .. } in named_entries(&mut volume, dir_cluster)? {
+        let name = entry.name();
+        let matches = |name: &str| pattern.is_none_or(|pattern| glob_matches(pattern, name));
+        if !matches(&name) && !long_name.as_deref().is_some_and(matches) {
+            continue;
+        }
+        let shown = if entry.is_directory() {
+            format!("{:<12} <DIR>", name)
+        } else {
+            format!("{:<12} {:>10}", name, entry.size())
+        };
+        match long_name {
+            Some(long_name) => println!("{:<23}  {}", shown, long_name),
+            None => println!("{}", shown),
+        }
+    }
+
+    println!("-----------------------");
+    Ok(shell_state)
+}
+
+/// cd [directory]
+pub fn change_directory(shell_state: ShellState, args: &Args) -> Result<ShellState> {
+    let dirname = args.get(1).unwrap_or_else(|| "/".to_owned());
+    let cluster = resolve_directory(&mut shell_state.volume()?, shell_state.get_cwd(), &dirname)?;
+
+    println!("Changed directory to {}!", dirname);
+    Ok(shell_state.set_cwd(cluster))
+}
+
+/// mkdir <directory>
+pub fn make_directory(mut shell_state: ShellState, args: &Args) -> Result<ShellState> {
+    let path = args.expect(1, "directory name")?;
+    let cwd = shell_state.get_cwd();
+    create_directory(&mut shell_state.volume_mut()?, cwd, &path)?;
+
+    println!("Made directory!");
+    Ok(shell_state)
+}
+
+/// newfile <host file> [name or directory]
+pub fn newfile(mut shell_state: ShellState, args: &Args) -> Result<ShellState> {
+    // newfile testfile.txt TEST.TXT
+    // Get cmdline args
+    let newfile: String = args.expect(1, "new file")?;
+
+    let newfile_bytes = std::fs::read(&newfile).map_err(FatError::io(format!("Can't read {}", newfile)))?;
+
+    let host_name = Path::new(&newfile)
+        .file_name()
+        .ok_or_else(|| FatError::BadArgument(format!("{} isn't a file", newfile)))?
+        .to_string_lossy()
+        .into_owned();
+    let target = args.get(2).unwrap_or_else(|| host_name.clone());
+
+    // A directory target keeps the host file's name, like cp
+    let cwd = shell_state.get_cwd();
+    let mut volume = shell_state.volume_mut()?;
+    let (dir_cluster, filename_extension) = match resolve_directory(&mut volume, cwd, &target) {
+        Ok(dir_cluster) => (dir_cluster, host_name),
+        Err(_) => {
+            let (dir_path, name) = split_path(&target);
+            (resolve_directory(&mut volume, cwd, dir_path)?, name.to_owned())
+        }
+    };
+    store_file(
+        &mut volume,
+        dir_cluster,
+        &filename_extension,
+        &newfile_bytes,
+        build_directory_entry(&filename_extension, 0, 0, false),
+    )?;
+
+    println!("Wrote new file to FAT12 Image!");
+
+    Ok(shell_state)
+}
+
+/// Stores `data` as the file `name` in a directory, replacing a file of that name
+///
+/// The entry is `entry` with the name, first cluster and size filled in, so a copy can keep the
+/// attributes and times of the original. Names that don't fit 8.3 get a long name.
+pub fn store_file<D: BlockDevice>(
+    volume: &mut Volume<D>,
+    dir_cluster: usize,
+    name: &str,
+    data: &[u8],
+    mut entry: Vec<u8>,
+) -> Result<()> {
+    validate_long_name(name)?;
+
+    // Replace a file that's already there
+    if let Some(offset) = find_in_directory(volume, dir_cluster, name)? {
+        let old_entry = directory_entry(volume, offset)?;
+        if is_directory(&old_entry) {
+            return Err(FatError::IsADirectory(name.to_owned()));
+        }
+        // Make sure the new copy fits before giving up the old one
+        let old_clusters = cluster_chain(volume, entry_first_cluster(&old_entry))?;
+        let needed = data.len().div_ceil(volume.bpb().bytes_per_cluster());
+        let free = count_free_clusters(volume)? + old_clusters.len();
+        if needed > free {
+            return Err(FatError::DiskFull { needed, free });
+        }
+        remove_entry(volume, dir_cluster, offset)?;
+    }
+
+    let first_cluster = write_file_data(volume, data)?;
+
+    // Write to the directory
+    entry[26..28].copy_from_slice(&(first_cluster as u16).to_le_bytes());
+    entry[28..32].copy_from_slice(&(data.len() as u32).to_le_bytes());
+    if let Err(error) = add_named_entry(volume, dir_cluster, name, entry) {
+        // Without an entry nothing would ever free the clusters
+        free_chain(volume, first_cluster)?;
+        return Err(error);
+    }
+    Ok(())
+}
+
+/// get <file> [host file or directory]
+pub fn save_file_to_os(shell_state: ShellState, args: &Args) -> Result<ShellState> {
+    let filename = args.expect(1, "filename")?;
+    let name = split_path(&filename).1;
+    // A host directory keeps the file's name, so `get *.TXT out` works
+    let host_filename = match args.get(2) {
+        Some(host_path) if Path::new(&host_path).is_dir() => {
+            Path::new(&host_path).join(name).to_string_lossy().into_owned()
+        }
+        Some(host_path) => host_path,
+        None => shell_state.host_path(name),
+    };
+
+    {
+        let mut volume = shell_state.volume()?;
+        let mut file = volume.open_file(shell_state.get_cwd(), &filename, false)?;
+        let mut host_file =
+            std::fs::File::create(&host_filename).map_err(FatError::io(format!("Can't write {}", host_filename)))?;
+        io::copy(&mut file, &mut host_file).map_err(FatError::io(format!("Can't write {}", host_filename)))?;
+    }
+    println!("Saved {} to {}!", filename, host_filename);
+
+    Ok(shell_state)
+}
+
+/// rm <file or empty directory>
+pub fn remove_file(mut shell_state: ShellState, args: &Args) -> Result<ShellState> {
+    let path = args.expect(1, "file")?;
+    let cwd = shell_state.get_cwd();
+    let mut volume = shell_state.volume_mut()?;
+    let (parent, offset) = find_path(&mut volume, cwd, &path)?;
+    let entry = directory_entry(&mut volume, offset)?;
+
+    let name = display_name(&entry);
+    if name == "." || name == ".." {
+        return Err(FatError::BadArgument(format!("Can't remove {}", path)));
+    }
+
+    if is_directory(&entry) {
+        let dir_cluster = entry_first_cluster(&entry);
+        if dir_cluster == cwd {
+            return Err(FatError::BadArgument("Can't remove the current directory".to_owned()));
+        }
+
+        for slot in directory_slots(&mut volume, dir_cluster)? {
+            let dir_entry = directory_entry(&mut volume, slot)?;
+            if is_listed(&dir_entry) && dir_entry[0] != b'.' {
+                return Err(FatError::DirectoryNotEmpty(path));
+            }
+        }
+    }
+
+    remove_entry(&mut volume, parent, offset)?;
+    println!("Removed {}!", path);
+
+    Ok(shell_state)
+}
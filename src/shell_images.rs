@@ -1,32 +1,281 @@
-use crate::shell_parsing::get_arg;
-use crate::shell_state::ShellState;
-use std::fs::File;
-
-pub fn open_image(shell_state: ShellState, args: Vec<&str>) -> ShellState {
-    let image_filename = get_arg(&args, 1).expect("Couldn't open image file!");
-    shell_state.open_file(image_filename)
-}
-
-pub fn create_new_image(shell_state: ShellState, args: Vec<&str>) -> ShellState {
-    // Get cmdline args
-    let filename = get_arg(&args, 1).expect("No filename provided!");
-    let size_str = get_arg(&args, 2).expect("No size provided!");
-
-    // Parse size string
-    let size_in_mb: usize = size_str.parse().expect("Size isn't an integer!");
-
-    // Create the file
-    let file = File::create(&filename).expect("Can't create file!");
-
-    // Setting the length to longer than the file is just fills it with 0's
-    file.set_len(((2 as usize).pow(20) * size_in_mb) as u64)
-        .expect("Couldn't set file length");
-
-    println!("Created file!");
-
-    shell_state.open_file(filename)
-}
-
-pub fn close_image(_shell_state: ShellState, _args: Vec<&str>) -> ShellState {
-    ShellState::new()
-}
+use crate::bios_parameter_block::{BiosParameterBlock, BPB_END, BYTES_PER_SECTOR};
+use crate::block_device::BlockDevice;
+use crate::fat_error::{FatError, Result};
+use crate::fat_section_util::write_to_fat;
+use crate::sector_cache::CacheMode;
+use crate::shell_parsing::{expect_number, Args};
+use crate::shell_state::ShellState;
+use crate::timestamps::CivilTime;
+use crate::volume::Volume;
+use crate::volume_info::set_volume_label;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+
+/// Boot code of a formatted disk that has no system on it: hand back to the BIOS
+const NOT_BOOTABLE_CODE: [u8; 5] = [
+    0xCD, 0x18, // int 18h
+    0xF4, // hlt
+    0xEB, 0xFD, // jmp short hlt
+];
+
+/// open [-f] [-r] <image>
+pub fn open_image(shell_state: ShellState, args: &Args) -> Result<ShellState> {
+    let image_filename = args.expect(1, "image file")?;
+    let shell_state = confirm_discard(shell_state, args.flag("-f"))?;
+    shell_state.open_file(image_filename, args.flag("-r"))
+}
+
+/// new [-f] <image> <size in MB>
+pub fn create_new_image(shell_state: ShellState, args: &Args) -> Result<ShellState> {
+    // Get cmdline args
+    let filename = args.expect(1, "filename")?;
+    let size_str = args.expect(2, "size")?;
+
+    // Parse size string
+    let size_in_mb: usize = size_str
+        .parse()
+        .map_err(|_| FatError::BadArgument(format!("Size {} isn't a whole number of MB", size_str)))?;
+
+    let shell_state = confirm_discard(shell_state, args.flag("-f"))?;
+
+    // Make sure nobody else is using the file before emptying it
+    if let Some(drive) = shell_state.other_drive_with(&filename) {
+        return Err(FatError::Locked {
+            image: filename,
+            holder: format!("drive {}: of this session", drive),
+        });
+    }
+    let lock = shell_state.lock_image(&filename)?;
+
+    // Create the file
+    let file = File::create(&filename).map_err(FatError::io(format!("Can't create {}", filename)))?;
+
+    // Setting the length to longer than the file is just fills it with 0's
+    file.set_len((2_usize.pow(20) * size_in_mb) as u64)
+        .map_err(FatError::io(format!("Can't resize {}", filename)))?;
+
+    println!("Created file!");
+
+    let shell_state = shell_state.load_locked(filename, Some(lock))?;
+    println!("Opened image file!");
+    Ok(shell_state)
+}
+
+/// close [-f]
+///
+/// Only the current drive's image is closed, the drive stays current.
+pub fn close_image(shell_state: ShellState, args: &Args) -> Result<ShellState> {
+    let shell_state = confirm_discard(shell_state, args.flag("-f"))?;
+    Ok(shell_state.close_image())
+}
+
+/// save [-a] [-b] [as <image>]
+///
+/// Saving to the image's own file only writes the sectors that changed, unless `-a` asks for the
+/// whole image to be written to a new file that replaces it, which a crash can't leave half saved.
+/// Saving as another file always does that. `-b` keeps the previous contents of the file as `<image>.bak`.
+pub fn save_image(shell_state: ShellState, args: &Args) -> Result<ShellState> {
+    let save_as = match args.get(1) {
+        None => None,
+        Some(word) if word == "as" => Some(args.expect(2, "filename to save as")?),
+        Some(word) => return Err(FatError::BadArgument(format!("Expected save as <image>, not save {}", word))),
+    };
+
+    println!("Saving file...");
+    let shell_state = match save_as {
+        Some(filename) => shell_state.save_file_as(filename, args.flag("-b"))?,
+        None if args.flag("-a") => {
+            let filename = shell_state.get_image_filename().to_owned();
+            shell_state.save_file_as(filename, args.flag("-b"))?
+        }
+        None => shell_state.save_changes(args.flag("-b"))?,
+    };
+    println!("File saved to {}!", shell_state.get_image_filename());
+    Ok(shell_state)
+}
+
+/// cache [sectors] [through|back]
+///
+/// Shows how many sectors of the image file are kept in memory and how often reads found them
+/// there, after setting the number of sectors or whether saving in place writes each changed
+/// sector as it goes (`through`) or gathers them to write neighbouring ones together (`back`).
+pub fn cache(shell_state: ShellState, args: &Args) -> Result<ShellState> {
+    let mut capacity = None;
+    let mut mode = None;
+    for setting in args.rest(1) {
+        match setting.as_str() {
+            "through" => mode = Some(CacheMode::WriteThrough),
+            "back" => mode = Some(CacheMode::WriteBack),
+            sectors => capacity = Some(expect_number(sectors, "Cache size")?),
+        }
+    }
+
+    let settings = shell_state.image().with_cache(|cache| -> Result<_> {
+        if let Some(capacity) = capacity {
+            cache.set_capacity(capacity)?;
+        }
+        if let Some(mode) = mode {
+            cache.set_mode(mode)?;
+        }
+        Ok((cache.capacity(), cache.mode(), cache.stats()))
+    });
+    let Some(settings) = settings else {
+        return Err(FatError::BadArgument(format!(
+            "{} is held in memory, there's no file to cache",
+            shell_state.get_image_filename()
+        )));
+    };
+    let (capacity, mode, stats) = settings?;
+
+    let writes = match mode {
+        CacheMode::WriteThrough => "through",
+        CacheMode::WriteBack => "back",
+    };
+    println!("Caching up to {} sectors, saving writes {}", capacity, writes);
+    println!(
+        "Since opening or saving: {} hits, {} misses ({:.0}% hits), {} evicted",
+        stats.hits,
+        stats.misses,
+        stats.hit_rate() * 100.0,
+        stats.evictions
+    );
+    Ok(shell_state)
+}
+
+/// Makes sure it's fine to drop the open image, before closing it or opening another
+///
+/// With unsaved changes someone at the terminal is asked whether to save them first,
+/// while scripts are refused unless `force` says to discard them.
+pub fn confirm_discard(shell_state: ShellState, force: bool) -> Result<ShellState> {
+    // Nothing is really dropped in a dry run
+    if force || !shell_state.is_modified() || shell_state.is_dry_run() {
+        return Ok(shell_state);
+    }
+    let filename = shell_state.get_image_filename().to_owned();
+    if !shell_state.is_interactive() {
+        return Err(FatError::UnsavedChanges(filename));
+    }
+
+    print!("{} has unsaved changes. Save them first? [y]es, [n]o, [c]ancel: ", filename);
+    io::stdout().flush().map_err(FatError::io("Can't write the question"))?;
+    let mut answer = String::new();
+    io::stdin()
+        .read_line(&mut answer)
+        .map_err(FatError::io("Can't read the answer"))?;
+
+    match answer.trim().to_ascii_lowercase().as_str() {
+        "y" | "yes" => {
+            let shell_state = shell_state.save_file()?;
+            println!("File saved!");
+            Ok(shell_state)
+        }
+        "n" | "no" => Ok(shell_state),
+        _ => Err(FatError::UnsavedChanges(filename)),
+    }
+}
+
+/// What's said about an image whose changes were dropped without asking, when nobody is left to ask
+pub fn discarded_message(filename: &str) -> String {
+    format!("Unsaved changes to {} were discarded", filename)
+}
+
+/// `confirm_discard` for the image on every drive, for quitting
+pub fn confirm_discard_all(shell_state: ShellState, force: bool) -> Result<ShellState> {
+    let current = shell_state.get_drive().to_owned();
+    let mut shell_state = shell_state;
+    for drive in shell_state.open_drives() {
+        shell_state = confirm_discard(shell_state.use_drive(&drive), force)?;
+    }
+    Ok(shell_state.use_drive(&current))
+}
+
+/// format [label]
+///
+/// Writes a fresh boot sector, FATs and root directory sized to the image.
+/// The data area is left alone, like a quick format.
+pub fn format_image(mut shell_state: ShellState, args: &Args) -> Result<ShellState> {
+    let total_sectors = shell_state.image().sector_count();
+    let mut bpb = BiosParameterBlock::for_sectors(total_sectors);
+    bpb.serial_number = CivilTime::now().volume_serial();
+    bpb.volume_label = *b"NO NAME    ";
+
+    let mut volume = format_device(shell_state.image_mut()?, &bpb)?;
+    if let Some(label) = args.get(1) {
+        set_volume_label(&mut volume, &label)?;
+    }
+
+    println!(
+        "Formatted {} sectors as {} with {} clusters!",
+        total_sectors,
+        bpb.fat_type(),
+        bpb.cluster_count()
+    );
+
+    Ok(shell_state.set_cwd(0))
+}
+
+/// Lays out an empty file system described by `bpb` over the start of the device, mounting it
+pub fn format_device<D: BlockDevice>(device: D, bpb: &BiosParameterBlock) -> Result<Volume<D>> {
+    let system_area = bpb.data_start() * bpb.bytes_per_sector;
+    if system_area >= device.sector_count() * device.sector_size() {
+        return Err(FatError::BadArgument("The image is too small to format".to_owned()));
+    }
+
+    let mut system_sectors = vec![0; system_area.div_ceil(device.sector_size()) * device.sector_size()];
+    // jmp short over the BPB, then nop
+    system_sectors[0..3].copy_from_slice(&[0xEB, (BPB_END - 2) as u8, 0x90]);
+    bpb.write_to(&mut system_sectors);
+    system_sectors[BPB_END..BPB_END + NOT_BOOTABLE_CODE.len()].copy_from_slice(&NOT_BOOTABLE_CODE);
+    system_sectors[BYTES_PER_SECTOR - 2..BYTES_PER_SECTOR].copy_from_slice(&[0x55, 0xAA]);
+    let mut volume = Volume::mount(device)?;
+    volume.write(0, &system_sectors)?;
+    // Mounting found whatever BPB the device had before
+    volume.set_bpb(bpb.clone())?;
+
+    // The first two FAT entries are reserved: the media descriptor and an end of chain marker
+    write_to_fat(&mut volume, 0xF00 | bpb.media as usize, 0)?;
+    write_to_fat(&mut volume, 0xFFF, 1)?;
+    Ok(volume)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_lock::ImageLock;
+    use std::path::PathBuf;
+
+    /// An empty directory of its own under the system's temporary one
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("fat12-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn new_leaves_a_locked_image_alone() {
+        let dir = temp_dir("new-locked");
+        let image = dir.join("a.img").to_string_lossy().into_owned();
+        std::fs::write(&image, [0xAB; 4096]).unwrap();
+        let _held = ImageLock::acquire(&image).unwrap();
+
+        let result = create_new_image(ShellState::new(), &Args::new(&["new", &image, "1"]));
+        assert!(matches!(result, Err(FatError::Locked { .. })));
+        assert_eq!(std::fs::read(&image).unwrap(), [0xAB; 4096]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn new_refuses_an_image_open_on_another_drive() {
+        let dir = temp_dir("new-other-drive");
+        let image = dir.join("a.img").to_string_lossy().into_owned();
+        std::fs::write(&image, [0xAB; 4096]).unwrap();
+        let shell_state = ShellState::new().load_file(image.clone(), true).unwrap().use_drive("B");
+
+        let result = create_new_image(shell_state, &Args::new(&["new", &image, "1"]));
+        assert!(matches!(result, Err(FatError::Locked { .. })));
+        assert_eq!(std::fs::read(&image).unwrap(), [0xAB; 4096]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -1,12 +1,388 @@
-use std::io::*;
-
-pub fn get_arg(args: &Vec<&str>, argnum: usize) -> Result<String> {
-  if args.len() > argnum {
-    return Ok(args[argnum].to_owned());
-  } else {
-    return Err(std::io::Error::new(
-      ErrorKind::InvalidInput,
-      "Not enough arguments!",
-    ));
-  }
-}
+use crate::fat_error::{FatError, Result};
+
+/// A flag a command accepts, like `-k` or `-o <file>`
+#[derive(Clone, Copy)]
+pub struct Flag {
+  pub name: &'static str,
+  /// What the flag's value is called in help, for flags that take one
+  pub value: Option<&'static str>,
+  pub help: &'static str,
+}
+
+/// How many positional arguments a command takes and which flags it knows
+#[derive(Clone, Copy)]
+pub struct ArgSpec {
+  pub min: usize,
+  /// None for any number
+  pub max: Option<usize>,
+  pub flags: &'static [Flag],
+  /// Positional arguments naming files on the host rather than in the image, for tab completion
+  /// and so they're taken relative to the shell's host directory
+  pub host_paths: &'static [usize],
+  /// The first of the arguments that are all host paths, for commands taking any number of them
+  pub host_paths_from: Option<usize>,
+}
+
+impl ArgSpec {
+  pub const fn none() -> Self {
+    Self::between(0, 0)
+  }
+
+  pub const fn exactly(count: usize) -> Self {
+    Self::between(count, count)
+  }
+
+  pub const fn between(min: usize, max: usize) -> Self {
+    ArgSpec {
+      min,
+      max: Some(max),
+      flags: &[],
+      host_paths: &[],
+      host_paths_from: None,
+    }
+  }
+
+  pub const fn at_least(min: usize) -> Self {
+    ArgSpec {
+      min,
+      max: None,
+      flags: &[],
+      host_paths: &[],
+      host_paths_from: None,
+    }
+  }
+
+  pub const fn with_flags(mut self, flags: &'static [Flag]) -> Self {
+    self.flags = flags;
+    self
+  }
+
+  pub const fn with_host_paths(mut self, host_paths: &'static [usize]) -> Self {
+    self.host_paths = host_paths;
+    self
+  }
+
+  pub const fn with_host_paths_from(mut self, argnum: usize) -> Self {
+    self.host_paths_from = Some(argnum);
+    self
+  }
+
+  pub fn is_host_path(&self, argnum: usize) -> bool {
+    self.host_paths.contains(&argnum) || self.host_paths_from.is_some_and(|from| argnum >= from)
+  }
+
+  fn find_flag(&self, name: &str) -> Option<&Flag> {
+    self.flags.iter().find(|flag| flag.name == name)
+  }
+}
+
+/// A command line split into its positional arguments and flags
+///
+/// Positional arguments are numbered from 1, argument 0 is the command's name.
+/// Anything starting with `-` is a flag unless it comes after `--`.
+pub struct Args {
+  words: Vec<String>,
+  flags: Vec<(String, Option<String>)>,
+}
+
+impl Args {
+  /// Arguments with no flags, for calling a command directly
+  pub fn new(words: &[&str]) -> Self {
+    Args {
+      words: words.iter().map(|word| word.to_string()).collect(),
+      flags: vec![],
+    }
+  }
+
+  /// Splits out the flags and checks everything against the command's spec
+  pub fn parse(words: &[&str], spec: &ArgSpec) -> Result<Self> {
+    let mut args = Args::new(&words[..1.min(words.len())]);
+    let mut rest = words.iter().skip(1);
+    let mut flags_done = false;
+
+    while let Some(&word) = rest.next() {
+      if flags_done || !word.starts_with('-') || word.len() == 1 {
+        args.words.push(word.to_owned());
+        continue;
+      }
+      if word == "--" {
+        flags_done = true;
+        continue;
+      }
+
+      let flag = spec
+        .find_flag(word)
+        .ok_or_else(|| FatError::BadArgument(format!("Unknown flag {} for {}", word, args.name())))?;
+      let value = match flag.value {
+        Some(what) => Some(
+          rest
+            .next()
+            .map(|value| value.to_string())
+            .ok_or_else(|| FatError::MissingArgument(format!("{} for {}", what, word)))?,
+        ),
+        None => None,
+      };
+      args.flags.push((word.to_owned(), value));
+    }
+
+    let count = args.words.len().saturating_sub(1);
+    if count < spec.min || spec.max.is_some_and(|max| count > max) {
+      let expected = match spec.max {
+        Some(max) if max == spec.min => format!("{}", max),
+        Some(max) if spec.min == 0 => format!("at most {}", max),
+        Some(max) => format!("{} to {}", spec.min, max),
+        None => format!("at least {}", spec.min),
+      };
+      return Err(FatError::BadArgument(format!(
+        "{} takes {} arguments but was given {}, see help {}",
+        args.name(),
+        expected,
+        count,
+        args.name()
+      )));
+    }
+    Ok(args)
+  }
+
+  /// Rewrites the host paths among the arguments, e.g. to start from another directory
+  pub fn map_host_paths(mut self, spec: &ArgSpec, map: impl Fn(&str) -> String) -> Self {
+    for argnum in 1..self.words.len() {
+      if spec.is_host_path(argnum) {
+        self.words[argnum] = map(&self.words[argnum]);
+      }
+    }
+    self
+  }
+
+  pub fn name(&self) -> &str {
+    self.words.first().map(String::as_str).unwrap_or_default()
+  }
+
+  /// Argument `argnum`, for optional arguments
+  pub fn get(&self, argnum: usize) -> Option<String> {
+    self.words.get(argnum).cloned()
+  }
+
+  /// Argument `argnum`, or an error naming what's missing
+  pub fn expect(&self, argnum: usize, what: &str) -> Result<String> {
+    self.get(argnum).ok_or_else(|| FatError::MissingArgument(what.to_owned()))
+  }
+
+  /// Arguments from `argnum` on
+  pub fn rest(&self, argnum: usize) -> &[String] {
+    self.words.get(argnum..).unwrap_or_default()
+  }
+
+  /// Whether a flag like `-k` was given
+  pub fn flag(&self, name: &str) -> bool {
+    self.flags.iter().any(|(flag, _)| flag == name)
+  }
+
+  /// The value given to a flag like `-o <file>`
+  pub fn flag_value(&self, name: &str) -> Option<&str> {
+    self.flags
+      .iter()
+      .find(|(flag, _)| flag == name)
+      .and_then(|(_, value)| value.as_deref())
+  }
+}
+
+/// One word of a command line, with its quotes and escapes removed
+pub struct Word {
+  pub text: String,
+  /// Whether it has a `*` or `?` that wasn't quoted, so it can match several names
+  pub is_pattern: bool,
+}
+
+impl Word {
+  /// A word that came from somewhere already split up, like the program's arguments
+  pub fn new(text: &str) -> Self {
+    Word {
+      text: text.to_owned(),
+      is_pattern: text.contains(['*', '?']),
+    }
+  }
+}
+
+/// When a command on a line runs
+#[derive(Clone, Copy, PartialEq)]
+pub enum Chain {
+  /// The first command, or one after `;`
+  Always,
+  /// After `&&`, only when the command before it succeeded
+  IfSucceeded,
+}
+
+pub struct CommandLine {
+  pub chain: Chain,
+  pub words: Vec<Word>,
+}
+
+impl CommandLine {
+  pub fn name(&self) -> &str {
+    self.words.first().map(|word| word.text.as_str()).unwrap_or_default()
+  }
+}
+
+/// Splits a line into commands separated by `;` and `&&`, and each command into words
+///
+/// Words are separated by whitespace. `'...'` is taken literally, `"..."` allows `\"` and `\\`,
+/// and a backslash outside quotes escapes the next character. A `#` starting a word begins a comment.
+pub fn split_commands(line: &str) -> Result<Vec<CommandLine>> {
+  let mut commands = vec![];
+  let mut words: Vec<Word> = vec![];
+  let mut chain = Chain::Always;
+  // None between words, so an empty "" still counts as a word
+  let mut word: Option<Word> = None;
+  let mut chars = line.chars().peekable();
+
+  fn current(word: &mut Option<Word>) -> &mut Word {
+    word.get_or_insert_with(|| Word::new(""))
+  }
+
+  while let Some(c) = chars.next() {
+    match c {
+      c if c.is_whitespace() => words.extend(word.take()),
+      '#' if word.is_none() => break,
+      '\'' => {
+        let text = &mut current(&mut word).text;
+        loop {
+          match chars.next() {
+            Some('\'') => break,
+            Some(c) => text.push(c),
+            None => return Err(FatError::BadArgument("Unterminated ' quote".to_owned())),
+          }
+        }
+      }
+      '"' => {
+        let text = &mut current(&mut word).text;
+        loop {
+          match chars.next() {
+            Some('"') => break,
+            Some('\\') if matches!(chars.peek(), Some('"') | Some('\\')) => text.extend(chars.next()),
+            Some(c) => text.push(c),
+            None => return Err(FatError::BadArgument("Unterminated \" quote".to_owned())),
+          }
+        }
+      }
+      '\\' => match chars.next() {
+        Some(c) => current(&mut word).text.push(c),
+        None => return Err(FatError::BadArgument("Nothing to escape after \\".to_owned())),
+      },
+      ';' | '&' => {
+        if c == '&' && chars.next_if_eq(&'&').is_none() {
+          return Err(FatError::BadArgument("Expected && to chain commands".to_owned()));
+        }
+        words.extend(word.take());
+        let next_chain = if c == '&' { Chain::IfSucceeded } else { Chain::Always };
+        if words.is_empty() {
+          // `;` on its own is harmless, `&&` needs a command on both sides
+          if c == '&' || chain == Chain::IfSucceeded {
+            return Err(FatError::BadArgument("Missing command before &&".to_owned()));
+          }
+        } else {
+          commands.push(CommandLine {
+            chain,
+            words: std::mem::take(&mut words),
+          });
+        }
+        chain = next_chain;
+      }
+      '*' | '?' => {
+        let word = current(&mut word);
+        word.text.push(c);
+        word.is_pattern = true;
+      }
+      c => current(&mut word).text.push(c),
+    }
+  }
+
+  words.extend(word.take());
+  if words.is_empty() {
+    if chain == Chain::IfSucceeded {
+      return Err(FatError::BadArgument("Missing command after &&".to_owned()));
+    }
+  } else {
+    commands.push(CommandLine { chain, words });
+  }
+  Ok(commands)
+}
+
+/// Parses a decimal number, or a hex one with a 0x prefix
+pub fn parse_number(number: &str) -> Option<usize> {
+  match number.strip_prefix("0x").or_else(|| number.strip_prefix("0X")) {
+    Some(hex) => usize::from_str_radix(hex, 16).ok(),
+    None => number.parse().ok(),
+  }
+}
+
+/// Same as parse_number, with an error naming what the number was for
+pub fn expect_number(number: &str, what: &str) -> Result<usize> {
+  parse_number(number).ok_or_else(|| FatError::BadArgument(format!("{} isn't a number: {}", what, number)))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::bios_parameter_block::BiosParameterBlock;
+  use crate::directories::expand_glob;
+  use crate::volume::Volume;
+
+  /// The words of each command on the line
+  fn split(line: &str) -> Vec<Vec<String>> {
+    let commands = split_commands(line).unwrap();
+    commands
+      .iter()
+      .map(|command| command.words.iter().map(|word| word.text.clone()).collect())
+      .collect()
+  }
+
+  #[test]
+  fn quotes_nest_inside_each_other() {
+    assert_eq!(split(r#"put "it's here" 'say "hi"'"#), [["put", "it's here", "say \"hi\""]]);
+    assert_eq!(split(r#"put "a \"b\" \\ \c""#), [["put", r#"a "b" \ \c"#]]);
+    // Quoted parts run on into the rest of the word
+    assert_eq!(split(r#"put a"b c"'d e'f"#), [["put", "ab cd ef"]]);
+  }
+
+  #[test]
+  fn trailing_backslash_is_an_error() {
+    assert!(split_commands(r"put a\").is_err());
+    assert_eq!(split(r"put a\ b\;c"), [["put", "a b;c"]]);
+  }
+
+  #[test]
+  fn empty_quotes_are_a_word() {
+    assert_eq!(split(r#"label "" ''"#), [["label", "", ""]]);
+    assert!(split_commands("label 'unterminated").is_err());
+  }
+
+  #[test]
+  fn splits_on_semicolons_and_ands() {
+    let commands = split_commands("a;b&&c").unwrap();
+    let names: Vec<&str> = commands.iter().map(CommandLine::name).collect();
+    assert_eq!(names, ["a", "b", "c"]);
+    let chains: Vec<bool> = commands.iter().map(|command| command.chain == Chain::IfSucceeded).collect();
+    assert_eq!(chains, [false, false, true]);
+
+    assert_eq!(split("a ; ; b # c; d").len(), 2);
+    assert!(split_commands("a && && b").is_err());
+    assert!(split_commands("a &").is_err());
+    assert!(split_commands("a &&").is_err());
+  }
+
+  #[test]
+  fn only_unquoted_wildcards_make_patterns() {
+    let commands = split_commands(r#"rm *.TXT '*.BIN' "?" \*"#).unwrap();
+    let patterns: Vec<bool> = commands[0].words.iter().map(|word| word.is_pattern).collect();
+    assert_eq!(patterns, [false, true, false, false, false]);
+  }
+
+  #[test]
+  fn pattern_matching_nothing_is_not_found() {
+    let mut volume = Volume::mount(vec![0; 720 * 512]).unwrap();
+    volume.set_bpb(BiosParameterBlock::for_sectors(720)).unwrap();
+    let error = expand_glob(&mut volume, 0, "*.TXT").unwrap_err();
+    assert!(matches!(error, FatError::NotFound(pattern) if pattern == "*.TXT"));
+  }
+}
@@ -1,67 +1,572 @@
-use std::fs::OpenOptions;
-use std::io::Write;
-
-pub struct ShellState {
-  image_filename: String,
-  pub bytes: Vec<u8>,
-  cwd_fat_entry: usize,
-  is_image_file_open: bool,
-  is_root: bool,
-}
-
-impl ShellState {
-  pub fn new() -> Self {
-    ShellState {
-      image_filename: String::default(),
-      bytes: vec![],
-      cwd_fat_entry: 0,
-      is_image_file_open: false,
-      is_root: true,
-    }
-  }
-
-  pub fn set_bytes(mut self, bytes: Vec<u8>) -> Self {
-    self.bytes = bytes;
-    self
-  }
-
-  pub fn set_cwd(mut self, cwd: usize) -> Self {
-    self.cwd_fat_entry = cwd;
-    self
-  }
-
-  pub fn get_cwd(&self) -> usize {
-    self.cwd_fat_entry
-  }
-
-  pub fn open_file(mut self, filename: String) -> Self {
-    // Read file as bytes
-    self = self.set_bytes(std::fs::read(&filename).expect("Can't read the image file!"));
-
-    println!("Opened image file!");
-
-    // Set image filename
-    self.image_filename = filename;
-
-    // Set flag
-    self.is_image_file_open = true;
-
-    self
-  }
-
-  pub fn save_file(self) -> Self {
-    // Opening a file with truncate will replace contents
-    let mut file = OpenOptions::new()
-      .write(true)
-      .truncate(true)
-      .open(&self.image_filename)
-      .expect("Can't open the image file!");
-    file.write(&self.bytes).expect("Can't write data to file!");
-
-    self
-  }
-
-  pub fn is_root(&self) -> bool {
-    self.is_root
-  }
-}
+use crate::fat_error::{FatError, Result};
+use crate::image_device::ImageDevice;
+use crate::image_lock::ImageLock;
+use crate::undo::UndoHistory;
+use crate::volume::Volume;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// The drive the shell starts on
+pub const FIRST_DRIVE: &str = "A";
+
+/// An image open on a drive other than the current one, as it was left when `use` switched away
+#[derive(Clone)]
+struct ParkedDrive {
+  image_filename: String,
+  image: ImageDevice,
+  cwd_fat_entry: usize,
+  read_only: bool,
+  lock: Option<Rc<ImageLock>>,
+  undo_history: UndoHistory,
+}
+
+/// Everything a command works with: the open images and the settings of the session
+///
+/// The image fields are the current drive's, the images open on other drives wait in `other_drives`.
+#[derive(Clone)]
+pub struct ShellState {
+  // Drive letter or alias of the image below, like `A`
+  drive: String,
+  other_drives: BTreeMap<String, ParkedDrive>,
+  image_filename: String,
+  // Read from the file as it's needed, holding the changes that haven't been saved
+  image: ImageDevice,
+  cwd_fat_entry: usize,
+  is_image_file_open: bool,
+  // Commands that would change the image are refused, and it isn't locked
+  read_only: bool,
+  // Held while the image is open for writing, shared by the copies commands work on
+  lock: Option<Rc<ImageLock>>,
+  is_root: bool,
+  // Where relative host paths start from, empty for the process's working directory, see `lcd`
+  host_cwd: PathBuf,
+  // Whether someone is at a terminal to answer questions, rather than a script or pipe
+  interactive: bool,
+  // Commands run against a scratch copy that's thrown away, see `dryrun`
+  dry_run: bool,
+  // Log the sectors each command reads and writes, see `trace`
+  tracing: bool,
+  // Command lines typed this session, for `record`
+  history: Vec<String>,
+  // Scripts being run, outermost first, so one that sources itself is stopped rather than recursing forever
+  scripts: Vec<PathBuf>,
+  undo_history: UndoHistory,
+}
+
+impl Default for ShellState {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl ShellState {
+  pub fn new() -> Self {
+    ShellState {
+      drive: FIRST_DRIVE.to_owned(),
+      other_drives: BTreeMap::new(),
+      image_filename: String::default(),
+      image: ImageDevice::default(),
+      cwd_fat_entry: 0,
+      is_image_file_open: false,
+      read_only: false,
+      lock: None,
+      is_root: true,
+      host_cwd: PathBuf::new(),
+      interactive: false,
+      dry_run: false,
+      tracing: false,
+      history: vec![],
+      scripts: vec![],
+      undo_history: UndoHistory::new(),
+    }
+  }
+
+  /// Works on an image held in memory rather than one read from a file
+  pub fn set_bytes(mut self, bytes: Vec<u8>) -> Self {
+    self.image = ImageDevice::from_bytes(bytes);
+    self
+  }
+
+  pub fn image(&self) -> &ImageDevice {
+    &self.image
+  }
+
+  /// The image to change, refused if it was opened read only, except in a dry run
+  pub fn image_mut(&mut self) -> Result<&mut ImageDevice> {
+    if self.read_only && !self.dry_run {
+      return Err(FatError::ReadOnly(self.image_filename.clone()));
+    }
+    Ok(&mut self.image)
+  }
+
+  /// The image as a volume that can only be read
+  pub fn volume(&self) -> Result<Volume<&ImageDevice>> {
+    Volume::mount(&self.image)
+  }
+
+  /// The image as a volume, whose changes wait in `image` until it's saved
+  pub fn volume_mut(&mut self) -> Result<Volume<&mut ImageDevice>> {
+    Volume::mount(self.image_mut()?)
+  }
+
+  /// Cluster 0 means the root directory
+  pub fn set_cwd(mut self, cwd: usize) -> Self {
+    self.cwd_fat_entry = cwd;
+    self.is_root = cwd == 0;
+    self
+  }
+
+  pub fn get_cwd(&self) -> usize {
+    self.cwd_fat_entry
+  }
+
+  pub fn open_file(self, filename: String, read_only: bool) -> Result<Self> {
+    let shell_state = self.load_file(filename, read_only)?;
+    if read_only {
+      println!("Opened image file read only!");
+    } else {
+      println!("Opened image file!");
+    }
+    Ok(shell_state)
+  }
+
+  /// Same as open_file without the chatter, for the command line
+  ///
+  /// Opening for writing locks the image, failing if another session has it.
+  pub fn load_file(self, filename: String, read_only: bool) -> Result<Self> {
+    let lock = if read_only { None } else { Some(self.lock_image(&filename)?) };
+    self.load_locked(filename, lock)
+  }
+
+  /// The lock for writing `filename`, which this drive may hold already
+  ///
+  /// Fails if another drive of this session has it open for writing, or another session holds it.
+  pub fn lock_image(&self, filename: &str) -> Result<Rc<ImageLock>> {
+    // Two drives writing the same file would overwrite each other's changes
+    let other_drive = self
+      .other_drives
+      .iter()
+      .find(|(_, parked)| parked.lock.as_ref().is_some_and(|lock| lock.covers(filename)));
+    if let Some((drive, _)) = other_drive {
+      return Err(FatError::Locked {
+        image: filename.to_owned(),
+        holder: format!("drive {}: of this session", drive),
+      });
+    }
+
+    match &self.lock {
+      // Opening the same image again shouldn't wait for ourselves
+      Some(lock) if lock.covers(filename) => Ok(Rc::clone(lock)),
+      _ => Ok(Rc::new(ImageLock::acquire(filename)?)),
+    }
+  }
+
+  /// The drive other than the current one that has `filename` open, read only or not
+  pub fn other_drive_with(&self, filename: &str) -> Option<&str> {
+    self
+      .other_drives
+      .iter()
+      .find(|(_, parked)| same_file(&parked.image_filename, filename))
+      .map(|(drive, _)| drive.as_str())
+  }
+
+  /// Opens `filename`, for writing when `lock` is the lock for it and read only without one
+  pub fn load_locked(mut self, filename: String, lock: Option<Rc<ImageLock>>) -> Result<Self> {
+    let read_only = lock.is_none();
+    self.lock = lock;
+
+    self.image = ImageDevice::open(&filename)?;
+
+    // Set image filename
+    self.image_filename = filename;
+
+    // Set flags
+    self.is_image_file_open = true;
+    self.read_only = read_only;
+
+    // Changes to the last image don't apply to this one
+    self.undo_history.clear();
+
+    Ok(self)
+  }
+
+  /// Writes the image back to the file it came from
+  pub fn save_file(self) -> Result<Self> {
+    self.save_changes(false)
+  }
+
+  /// Writes just the sectors that changed since the image was opened or saved, in place
+  ///
+  /// That's much less to write to a big image, but a crash part way can leave some of the changes
+  /// on disk and not others, which `save_file_as` never does. It's used instead when the file
+  /// doesn't look like the one that was loaded any more.
+  /// With `backup` the old image is kept as `<filename>.bak` first.
+  pub fn save_changes(mut self, backup: bool) -> Result<Self> {
+    let filename = self.image_filename.clone();
+    if self.read_only {
+      return Err(FatError::ReadOnly(filename));
+    }
+    if !self.image.can_save_in_place(&filename) {
+      return self.save_file_as(filename, backup);
+    }
+
+    if backup {
+      back_up(&filename)?;
+    }
+    self.image.save_in_place(&filename)?;
+    Ok(self)
+  }
+
+  /// Writes the image to `filename`, which it's then saved to from then on
+  ///
+  /// The image goes to a temporary file next to it that's renamed over the old one,
+  /// so a crash part way leaves either the old image or the new one, never half of each.
+  /// With `backup` the old image is kept as `<filename>.bak` first.
+  pub fn save_file_as(mut self, filename: String, backup: bool) -> Result<Self> {
+    if self.read_only {
+      return Err(FatError::ReadOnly(self.image_filename));
+    }
+    // The new file is the one being worked on from now, so it's locked before it's written
+    let lock = match &self.lock {
+      Some(lock) if lock.covers(&filename) => Rc::clone(lock),
+      _ => Rc::new(ImageLock::acquire(&filename)?),
+    };
+
+    let temp_filename = format!("{}.tmp", filename);
+    let written = write_and_sync(&temp_filename, &self.image, Path::new(&filename));
+    if written.is_err() {
+      let _ = std::fs::remove_file(&temp_filename);
+      return written.map(|_| self);
+    }
+
+    if backup && Path::new(&filename).exists() {
+      back_up(&filename)?;
+    }
+    std::fs::rename(&temp_filename, &filename).map_err(FatError::io(format!("Can't replace {}", filename)))?;
+    sync_directory(&filename)?;
+
+    self.image.reopen(&filename)?;
+    self.image_filename = filename;
+    self.lock = Some(lock);
+    Ok(self)
+  }
+
+  /// Forgets the image on the current drive, keeping the session's settings
+  pub fn close_image(mut self) -> Self {
+    self.image_filename = String::default();
+    self.image = ImageDevice::default();
+    self.is_image_file_open = false;
+    self.read_only = false;
+    self.lock = None;
+    // Changes to the image go with it, but how many to keep is a setting of the session
+    self.undo_history.clear();
+    self.set_cwd(0)
+  }
+
+  pub fn get_drive(&self) -> &str {
+    &self.drive
+  }
+
+  /// Drives with an image open, the current one included, in order
+  pub fn open_drives(&self) -> Vec<String> {
+    let mut drives: Vec<String> = self.other_drives.keys().cloned().collect();
+    if self.is_image_file_open {
+      drives.push(self.drive.clone());
+      drives.sort();
+    }
+    drives
+  }
+
+  /// Whether more than one drive is in play, so it's worth showing which is current
+  pub fn has_other_drives(&self) -> bool {
+    !self.other_drives.is_empty() || self.drive != FIRST_DRIVE
+  }
+
+  /// Makes `drive` current, keeping the image on the one being left until it's used again
+  ///
+  /// A drive that nothing has been opened on starts out empty, ready for `open` or `new`.
+  pub fn use_drive(mut self, drive: &str) -> Self {
+    if drive == self.drive {
+      return self;
+    }
+    let mut empty_history = self.undo_history.clone();
+    empty_history.clear();
+
+    let leaving = ParkedDrive {
+      image_filename: std::mem::take(&mut self.image_filename),
+      image: std::mem::take(&mut self.image),
+      cwd_fat_entry: self.cwd_fat_entry,
+      read_only: self.read_only,
+      lock: self.lock.take(),
+      undo_history: std::mem::replace(&mut self.undo_history, empty_history),
+    };
+    if self.is_image_file_open {
+      self.other_drives.insert(self.drive.clone(), leaving);
+    }
+
+    self.drive = drive.to_owned();
+    match self.other_drives.remove(drive) {
+      Some(parked) => {
+        self.image_filename = parked.image_filename;
+        self.image = parked.image;
+        self.is_image_file_open = true;
+        self.read_only = parked.read_only;
+        self.lock = parked.lock;
+        self.undo_history = parked.undo_history;
+        self.set_cwd(parked.cwd_fat_entry)
+      }
+      None => self.close_image(),
+    }
+  }
+
+  /// The image on `drive` as a volume that can only be read, with the directory that's current on it
+  pub fn drive_volume(&self, drive: &str) -> Result<(Volume<&ImageDevice>, usize)> {
+    if drive == self.drive {
+      self.require_image()?;
+      return Ok((self.volume()?, self.cwd_fat_entry));
+    }
+    let parked = self
+      .other_drives
+      .get(drive)
+      .ok_or_else(|| FatError::NoSuchDrive(drive.to_owned()))?;
+    Ok((Volume::mount(&parked.image)?, parked.cwd_fat_entry))
+  }
+
+  /// The image on `drive` as a volume to change, refused if it was opened read only, except in a dry run
+  pub fn drive_volume_mut(&mut self, drive: &str) -> Result<(Volume<&mut ImageDevice>, usize)> {
+    if drive == self.drive {
+      self.require_image()?;
+      let cwd = self.cwd_fat_entry;
+      return Ok((self.volume_mut()?, cwd));
+    }
+    let parked = self
+      .other_drives
+      .get_mut(drive)
+      .ok_or_else(|| FatError::NoSuchDrive(drive.to_owned()))?;
+    if parked.read_only && !self.dry_run {
+      return Err(FatError::ReadOnly(parked.image_filename.clone()));
+    }
+    Ok((Volume::mount(&mut parked.image)?, parked.cwd_fat_entry))
+  }
+
+  /// The image file open on `drive`, if any
+  pub fn drive_filename(&self, drive: &str) -> Option<&str> {
+    self.drive_image(drive).map(|(image_filename, _, _)| image_filename)
+  }
+
+  /// Image files with unsaved changes, on any drive
+  pub fn modified_images(&self) -> Vec<String> {
+    let mut modified: Vec<String> = self
+      .other_drives
+      .values()
+      .filter(|parked| parked.image.is_modified())
+      .map(|parked| parked.image_filename.clone())
+      .collect();
+    if self.is_modified() {
+      modified.insert(0, self.image_filename.clone());
+    }
+    modified
+  }
+
+  pub fn get_image_filename(&self) -> &str {
+    &self.image_filename
+  }
+
+  /// Whether the image has changed since it was opened or last saved
+  pub fn is_modified(&self) -> bool {
+    self.is_image_file_open && self.image.is_modified()
+  }
+
+  pub fn is_read_only(&self) -> bool {
+    self.read_only
+  }
+
+  pub fn set_host_cwd(mut self, host_cwd: PathBuf) -> Self {
+    self.host_cwd = host_cwd;
+    self
+  }
+
+  /// The host directory relative paths start from, as an absolute path where that can be found
+  pub fn get_host_cwd(&self) -> PathBuf {
+    match self.host_cwd.as_os_str().is_empty() {
+      true => std::env::current_dir().unwrap_or_default(),
+      false => self.host_cwd.clone(),
+    }
+  }
+
+  /// A host path as the process has to see it, relative ones starting from the shell's host directory
+  pub fn host_path(&self, path: &str) -> String {
+    self.host_cwd.join(path).to_string_lossy().into_owned()
+  }
+
+  pub fn set_interactive(mut self, interactive: bool) -> Self {
+    self.interactive = interactive;
+    self
+  }
+
+  pub fn is_interactive(&self) -> bool {
+    self.interactive
+  }
+
+  pub fn set_dry_run(mut self, dry_run: bool) -> Self {
+    self.dry_run = dry_run;
+    self
+  }
+
+  pub fn is_dry_run(&self) -> bool {
+    self.dry_run
+  }
+
+  pub fn set_tracing(mut self, tracing: bool) -> Self {
+    self.tracing = tracing;
+    self
+  }
+
+  pub fn is_tracing(&self) -> bool {
+    self.tracing
+  }
+
+  /// Fails unless an image has been opened
+  pub fn require_image(&self) -> Result<()> {
+    if self.is_image_file_open {
+      Ok(())
+    } else {
+      Err(FatError::NoImageOpen)
+    }
+  }
+
+  pub fn is_root(&self) -> bool {
+    self.is_root
+  }
+
+  pub fn add_history(mut self, line: String) -> Self {
+    self.history.push(line);
+    self
+  }
+
+  pub fn set_history(mut self, history: Vec<String>) -> Self {
+    self.history = history;
+    self
+  }
+
+  pub fn get_history(&self) -> &[String] {
+    &self.history
+  }
+
+  /// Notes that a script has started, until `finish_script`
+  pub fn start_script(mut self, script: PathBuf) -> Self {
+    self.scripts.push(script);
+    self
+  }
+
+  pub fn finish_script(mut self) -> Self {
+    self.scripts.pop();
+    self
+  }
+
+  /// Whether the script is being run already, by the one running now or one of those that sourced it
+  pub fn is_running_script(&self, script: &Path) -> bool {
+    self.scripts.iter().any(|running| running == script)
+  }
+
+  pub fn get_undo_history(&self) -> &UndoHistory {
+    &self.undo_history
+  }
+
+  pub fn undo_history_mut(&mut self) -> &mut UndoHistory {
+    &mut self.undo_history
+  }
+
+  pub fn set_undo_history(mut self, undo_history: UndoHistory) -> Self {
+    self.undo_history = undo_history;
+    self
+  }
+
+  /// Records what `command` changed on each drive since the session was `before`, so it can be undone
+  ///
+  /// Drives whose history the command has seen to itself, like `undo` or a script's, are left alone.
+  pub fn record_changes(&mut self, command: &str, before: &ShellState) -> Result<()> {
+    for drive in self.open_drives() {
+      let Some((image_filename, image, undo_history)) = before.drive_image(&drive) else {
+        continue;
+      };
+      let unchanged_image = self.drive_image(&drive).is_some_and(|(new_filename, _, new_history)| {
+        new_filename == image_filename && new_history.generation() == undo_history.generation()
+      });
+      if !unchanged_image {
+        continue;
+      }
+      if drive == self.drive {
+        self.undo_history.record(command.to_owned(), image, &self.image)?;
+      } else if let Some(parked) = self.other_drives.get_mut(&drive) {
+        parked.undo_history.record(command.to_owned(), image, &parked.image)?;
+      }
+    }
+    Ok(())
+  }
+
+  /// The file, contents and undo history of the image on a drive
+  fn drive_image(&self, drive: &str) -> Option<(&str, &ImageDevice, &UndoHistory)> {
+    if drive == self.drive {
+      self
+        .is_image_file_open
+        .then_some((self.image_filename.as_str(), &self.image, &self.undo_history))
+    } else {
+      let parked = self.other_drives.get(drive)?;
+      Some((parked.image_filename.as_str(), &parked.image, &parked.undo_history))
+    }
+  }
+}
+
+/// Whether two paths name the same file, going by the paths alone for files that aren't there
+fn same_file(first: &str, second: &str) -> bool {
+  match (Path::new(first).canonicalize(), Path::new(second).canonicalize()) {
+    (Ok(first), Ok(second)) => first == second,
+    _ => first == second,
+  }
+}
+
+/// Copies `filename` to `<filename>.bak`
+fn back_up(filename: &str) -> Result<()> {
+  std::fs::copy(filename, format!("{}.bak", filename)).map_err(FatError::io(format!("Can't back up {}", filename)))?;
+  Ok(())
+}
+
+/// Writes `image` to `filename` and waits for it to reach the disk
+///
+/// The file gets the permissions of `like` when that exists, so replacing it doesn't change them.
+fn write_and_sync(filename: &str, image: &ImageDevice, like: &Path) -> Result<()> {
+  let mut file = OpenOptions::new()
+    .write(true)
+    .create(true)
+    .truncate(true)
+    .open(filename)
+    .map_err(FatError::io(format!("Can't create {}", filename)))?;
+  image.write_to(&mut file)?;
+
+  if let Ok(metadata) = std::fs::metadata(like) {
+    file
+      .set_permissions(metadata.permissions())
+      .map_err(FatError::io(format!("Can't set the permissions of {}", filename)))?;
+  }
+  // Synced last, so the permissions reach the disk with the contents
+  file.sync_all().map_err(FatError::io(format!("Can't write {}", filename)))
+}
+
+/// Waits for the directory holding `filename` to reach the disk, so a file renamed into it stays
+/// renamed after a crash
+fn sync_directory(filename: &str) -> Result<()> {
+  let dir = match Path::new(filename).parent() {
+    Some(dir) if !dir.as_os_str().is_empty() => dir,
+    _ => Path::new("."),
+  };
+  // Directories can't be opened as files everywhere, where they can't their entries are kept some other way
+  match File::open(dir) {
+    Ok(dir_file) => dir_file
+      .sync_all()
+      .map_err(FatError::io(format!("Can't sync {}", dir.display()))),
+    Err(_) => Ok(()),
+  }
+}
@@ -0,0 +1,110 @@
+use alloc::vec;
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A broken down UTC time
+#[derive(Clone)]
+pub struct CivilTime {
+    pub year: usize,
+    pub month: usize,
+    pub day: usize,
+    pub hour: usize,
+    pub minute: usize,
+    pub second: usize,
+    pub hundredths: usize,
+}
+
+impl CivilTime {
+    #[cfg(feature = "std")]
+    pub fn now() -> Self {
+        // A clock set before 1970 just reads as the epoch
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut time = Self::from_unix(since_epoch.as_secs());
+        time.hundredths = since_epoch.subsec_millis() as usize / 10;
+        time
+    }
+
+    /// Converts seconds since the UNIX epoch into a calendar date
+    pub fn from_unix(seconds: u64) -> Self {
+        let days = (seconds / 86400) as i64;
+        let time_of_day = (seconds % 86400) as usize;
+
+        // Days to civil date, counting in 400 year eras that start on March 1st
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let day_of_era = z.rem_euclid(146_097);
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+        let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        CivilTime {
+            year: year as usize,
+            month: month as usize,
+            day: day as usize,
+            hour: time_of_day / 3600,
+            minute: time_of_day / 60 % 60,
+            second: time_of_day % 60,
+            hundredths: 0,
+        }
+    }
+
+    /// Parses `YYYY-MM-DD`, optionally followed by `HH:MM[:SS]`
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut parts = text.trim().splitn(2, [' ', 'T']);
+        let date: Vec<usize> = parts.next()?.split('-').map(|part| part.parse().ok()).collect::<Option<_>>()?;
+        let time: Vec<usize> = match parts.next() {
+            Some(time) => time.trim().split(':').map(|part| part.parse().ok()).collect::<Option<_>>()?,
+            None => vec![0, 0],
+        };
+        if date.len() != 3 || time.len() < 2 || time.len() > 3 {
+            return None;
+        }
+
+        let parsed = CivilTime {
+            year: date[0],
+            month: date[1],
+            day: date[2],
+            hour: time[0],
+            minute: time[1],
+            second: time.get(2).copied().unwrap_or(0),
+            hundredths: 0,
+        };
+        let valid = (1..=12).contains(&parsed.month)
+            && (1..=31).contains(&parsed.day)
+            && parsed.hour < 24
+            && parsed.minute < 60
+            && parsed.second < 60;
+        if valid {
+            Some(parsed)
+        } else {
+            None
+        }
+    }
+
+    /// Date in the packed form directory entries use, clamped to the 1980 epoch
+    pub fn dos_date(&self) -> u16 {
+        if self.year < 1980 {
+            return (1 << 5) | 1;
+        }
+        (((self.year - 1980).min(127) << 9) | (self.month << 5) | self.day) as u16
+    }
+
+    /// Time in the packed form directory entries use, to 2 second precision
+    pub fn dos_time(&self) -> u16 {
+        if self.year < 1980 {
+            return 0;
+        }
+        ((self.hour << 11) | (self.minute << 5) | (self.second / 2)) as u16
+    }
+
+    /// Volume serial number the way DOS `FORMAT` derives it from the current time
+    pub fn volume_serial(&self) -> u32 {
+        let low = ((self.month << 8 | self.day) + (self.second << 8 | self.hundredths)) & 0xFFFF;
+        let high = ((self.hour << 8 | self.minute) + self.year) & 0xFFFF;
+        (high << 16 | low) as u32
+    }
+}
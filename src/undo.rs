@@ -0,0 +1,310 @@
+use crate::bios_parameter_block::BYTES_PER_SECTOR;
+use crate::block_device::BlockDevice;
+use crate::fat_error::{FatError, Result};
+use crate::image_device::ImageDevice;
+use crate::shell_parsing::{expect_number, Args};
+use crate::shell_state::ShellState;
+use crate::volume::Volume;
+use std::collections::VecDeque;
+
+/// How many changes are kept to undo unless `undo -depth` says otherwise
+pub const DEFAULT_UNDO_DEPTH: usize = 100;
+
+/// One sector a command changed, with its contents before and after
+#[derive(Clone)]
+struct SectorDelta {
+    sector: usize,
+    before: Vec<u8>,
+    after: Vec<u8>,
+}
+
+/// Everything one command changed, which is undone and redone as a whole
+#[derive(Clone)]
+pub struct Change {
+    /// The command line that made the change, to say what's being undone
+    pub command: String,
+    deltas: Vec<SectorDelta>,
+}
+
+impl Change {
+    pub fn sector_count(&self) -> usize {
+        self.deltas.len()
+    }
+
+    /// The command and how much it changed, e.g. `put kernel.bin (5 sectors)`
+    pub fn describe(&self) -> String {
+        format!("{} ({} sectors)", self.command, self.sector_count())
+    }
+
+    fn revert(&self, device: &mut impl BlockDevice) -> Result<()> {
+        let mut volume = Volume::mount(device)?;
+        for delta in &self.deltas {
+            volume.write(delta.sector * BYTES_PER_SECTOR, &delta.before)?;
+        }
+        Ok(())
+    }
+
+    fn reapply(&self, device: &mut impl BlockDevice) -> Result<()> {
+        let mut volume = Volume::mount(device)?;
+        for delta in &self.deltas {
+            volume.write(delta.sector * BYTES_PER_SECTOR, &delta.after)?;
+        }
+        Ok(())
+    }
+}
+
+/// The changes made to the open image, oldest first, so they can be undone and redone
+///
+/// Only the sectors a command changed are kept, so a long history of small edits
+/// costs little even on a large image.
+#[derive(Clone)]
+pub struct UndoHistory {
+    undo: VecDeque<Change>,
+    redo: Vec<Change>,
+    depth: usize,
+    // Counts every change to the history, so a command that ran others (like `source`)
+    // can tell they were recorded already
+    generation: usize,
+}
+
+impl Default for UndoHistory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UndoHistory {
+    pub fn new() -> Self {
+        UndoHistory {
+            undo: VecDeque::new(),
+            redo: vec![],
+            depth: DEFAULT_UNDO_DEPTH,
+            generation: 0,
+        }
+    }
+
+    pub fn generation(&self) -> usize {
+        self.generation
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Keeps at most `depth` changes, forgetting the oldest ones
+    pub fn set_depth(&mut self, depth: usize) {
+        self.depth = depth;
+        while self.undo.len() > depth {
+            self.undo.pop_front();
+        }
+        self.generation += 1;
+    }
+
+    /// Forgets everything, for when a different image is opened
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.generation += 1;
+    }
+
+    /// Records what `command` changed going from `before` to `after`
+    ///
+    /// A new change can't be followed by the ones undone before it, so those can't be redone any more.
+    /// Images of different sizes can't be compared sector by sector, so they clear the history instead.
+    pub fn record(&mut self, command: String, before: &ImageDevice, after: &ImageDevice) -> Result<()> {
+        if before.sector_count() != after.sector_count() {
+            self.clear();
+            return Ok(());
+        }
+
+        let mut deltas = vec![];
+        for sector in ImageDevice::changed_sectors(before, after)? {
+            let mut delta = SectorDelta {
+                sector,
+                before: vec![0; BYTES_PER_SECTOR],
+                after: vec![0; BYTES_PER_SECTOR],
+            };
+            before.read(sector, &mut delta.before)?;
+            after.read(sector, &mut delta.after)?;
+            deltas.push(delta);
+        }
+        if deltas.is_empty() {
+            return Ok(());
+        }
+
+        self.redo.clear();
+        self.undo.push_back(Change { command, deltas });
+        if self.undo.len() > self.depth {
+            self.undo.pop_front();
+        }
+        self.generation += 1;
+        Ok(())
+    }
+
+    /// Puts back the sectors of the last change, returning it, or `None` when there's nothing to undo
+    pub fn undo(&mut self, device: &mut impl BlockDevice) -> Result<Option<&Change>> {
+        let Some(change) = self.undo.pop_back() else {
+            return Ok(None);
+        };
+        change.revert(device)?;
+        self.redo.push(change);
+        self.generation += 1;
+        Ok(self.redo.last())
+    }
+
+    /// Makes the last undone change again, returning it, or `None` when there's nothing to redo
+    pub fn redo(&mut self, device: &mut impl BlockDevice) -> Result<Option<&Change>> {
+        let Some(change) = self.redo.pop() else {
+            return Ok(None);
+        };
+        change.reapply(device)?;
+        self.undo.push_back(change);
+        self.generation += 1;
+        Ok(self.undo.back())
+    }
+
+    /// The changes that can be undone, oldest first
+    pub fn undoable(&self) -> impl Iterator<Item = &Change> {
+        self.undo.iter()
+    }
+
+    /// The changes that can be redone, the next one to redo first
+    pub fn redoable(&self) -> impl Iterator<Item = &Change> {
+        self.redo.iter().rev()
+    }
+}
+
+/// undo [count]
+/// undo -l
+/// undo -depth <changes>
+pub fn undo(mut shell_state: ShellState, args: &Args) -> Result<ShellState> {
+    if let Some(depth) = args.flag_value("-depth") {
+        let depth = expect_number(depth, "Undo depth")?;
+        shell_state.undo_history_mut().set_depth(depth);
+        println!("Keeping the last {} changes to undo!", depth);
+        return Ok(shell_state);
+    }
+    if args.flag("-l") {
+        list_changes(shell_state.get_undo_history());
+        return Ok(shell_state);
+    }
+
+    shell_state.require_image()?;
+    let count = parse_count(args)?;
+    for _ in 0..count {
+        let mut image = std::mem::take(shell_state.image_mut()?);
+        let change = shell_state.undo_history_mut().undo(&mut image).map(|change| change.cloned());
+        *shell_state.image_mut()? = image;
+        match change? {
+            Some(change) => println!("Undid {}", change.describe()),
+            None => return Err(FatError::BadArgument("Nothing left to undo".to_owned())),
+        }
+    }
+    Ok(shell_state)
+}
+
+/// redo [count]
+pub fn redo(mut shell_state: ShellState, args: &Args) -> Result<ShellState> {
+    let count = parse_count(args)?;
+    for _ in 0..count {
+        let mut image = std::mem::take(shell_state.image_mut()?);
+        let change = shell_state.undo_history_mut().redo(&mut image).map(|change| change.cloned());
+        *shell_state.image_mut()? = image;
+        match change? {
+            Some(change) => println!("Redid {}", change.describe()),
+            None => return Err(FatError::BadArgument("Nothing left to redo".to_owned())),
+        }
+    }
+    Ok(shell_state)
+}
+
+fn parse_count(args: &Args) -> Result<usize> {
+    match args.get(1) {
+        Some(count) => expect_number(&count, "Count"),
+        None => Ok(1),
+    }
+}
+
+fn list_changes(history: &UndoHistory) {
+    println!("Changes to undo, last one first (keeping {}):", history.depth());
+    for (index, change) in history.undoable().collect::<Vec<_>>().iter().rev().enumerate() {
+        println!("{:>4}  {}", index + 1, change.describe());
+    }
+    println!("Changes to redo, next one first:");
+    for (index, change) in history.redoable().enumerate() {
+        println!("{:>4}  {}", index + 1, change.describe());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A copy of `image` with `data` written at `offset`
+    fn written(image: &ImageDevice, offset: usize, data: &[u8]) -> ImageDevice {
+        let mut image = image.clone();
+        Volume::mount(&mut image).unwrap().write(offset, data).unwrap();
+        image
+    }
+
+    #[test]
+    fn undo_and_redo_round_trip() {
+        let original = ImageDevice::from_bytes(vec![0; 8 * BYTES_PER_SECTOR]);
+        // Spans the end of sector 2 and the start of sector 3
+        let changed = written(&original, 3 * BYTES_PER_SECTOR - 2, b"abcd");
+        let mut history = UndoHistory::new();
+        history.record("poke".to_owned(), &original, &changed).unwrap();
+
+        let mut image = changed.clone();
+        let undone = history.undo(&mut image).unwrap().unwrap();
+        assert_eq!(undone.describe(), "poke (2 sectors)");
+        assert_eq!(image.to_bytes().unwrap(), original.to_bytes().unwrap());
+        assert!(history.undo(&mut image).unwrap().is_none());
+
+        history.redo(&mut image).unwrap().unwrap();
+        assert_eq!(image.to_bytes().unwrap(), changed.to_bytes().unwrap());
+        assert!(history.redo(&mut image).unwrap().is_none());
+    }
+
+    #[test]
+    fn new_change_forgets_what_was_undone() {
+        let original = ImageDevice::from_bytes(vec![0; 8 * BYTES_PER_SECTOR]);
+        let first = written(&original, 0, b"first");
+        let mut history = UndoHistory::new();
+        history.record("first".to_owned(), &original, &first).unwrap();
+
+        let mut image = first.clone();
+        history.undo(&mut image).unwrap();
+        let second = written(&image, BYTES_PER_SECTOR, b"second");
+        history.record("second".to_owned(), &image, &second).unwrap();
+
+        assert_eq!(history.redoable().count(), 0);
+        let commands: Vec<&str> = history.undoable().map(|change| change.command.as_str()).collect();
+        assert_eq!(commands, ["second"]);
+    }
+
+    #[test]
+    fn keeps_only_the_last_changes() {
+        let mut image = ImageDevice::from_bytes(vec![0; 8 * BYTES_PER_SECTOR]);
+        let mut history = UndoHistory::new();
+        history.set_depth(2);
+        for sector in 0..3 {
+            let next = written(&image, sector * BYTES_PER_SECTOR, b"x");
+            history.record(format!("poke {}", sector), &image, &next).unwrap();
+            image = next;
+        }
+
+        let commands: Vec<&str> = history.undoable().map(|change| change.command.as_str()).collect();
+        assert_eq!(commands, ["poke 1", "poke 2"]);
+    }
+
+    #[test]
+    fn unchanged_image_records_nothing() {
+        let image = ImageDevice::from_bytes(vec![0; 8 * BYTES_PER_SECTOR]);
+        let rewritten = written(&image, 0, &[0; 4]);
+        let mut history = UndoHistory::new();
+        history.record("poke".to_owned(), &image, &rewritten).unwrap();
+        assert_eq!(history.undoable().count(), 0);
+        assert_eq!(history.generation(), 0);
+    }
+}
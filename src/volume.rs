@@ -0,0 +1,89 @@
+//! A FAT12 file system on a block device.
+//!
+//! Everything that works with files, directories and the FAT goes through a mounted `Volume`.
+//! It addresses the disk by byte offset, like the image file's layout, and turns that into whole
+//! sector reads and writes on the device.
+
+use crate::bios_parameter_block::{BiosParameterBlock, OEM_OFFSET};
+use crate::block_device::{Access, BlockDevice};
+use crate::fat_error::Result;
+use alloc::vec;
+use alloc::vec::Vec;
+
+pub struct Volume<D: BlockDevice> {
+    device: D,
+    bpb: BiosParameterBlock,
+}
+
+impl<D: BlockDevice> Volume<D> {
+    /// Reads the BPB from the boot sector, using the default one for disks that haven't been formatted
+    pub fn mount(mut device: D) -> Result<Self> {
+        let mut boot_sector = vec![0; device.sector_size()];
+        device.read_sectors(0, &mut boot_sector)?;
+        let bpb = BiosParameterBlock::from_image(&boot_sector);
+        Ok(Volume { device, bpb })
+    }
+
+    pub fn bpb(&self) -> &BiosParameterBlock {
+        &self.bpb
+    }
+
+    /// Writes a new BPB into the boot sector, which changes the layout of everything else
+    pub fn set_bpb(&mut self, bpb: BiosParameterBlock) -> Result<()> {
+        self.write(OEM_OFFSET, &bpb.to_bytes())?;
+        self.bpb = bpb;
+        Ok(())
+    }
+
+    /// Size of the device in bytes
+    pub fn size(&self) -> usize {
+        self.device.sector_count() * self.device.sector_size()
+    }
+
+    /// The `length` bytes at `offset`
+    pub fn read(&mut self, offset: usize, length: usize) -> Result<Vec<u8>> {
+        if length == 0 {
+            return Ok(vec![]);
+        }
+        self.device.observe(Access::Read, offset, length);
+
+        let sector_size = self.device.sector_size();
+        let first_sector = offset / sector_size;
+        let mut sectors = vec![0; ((offset + length).div_ceil(sector_size) - first_sector) * sector_size];
+        self.device.read_sectors(first_sector, &mut sectors)?;
+
+        let start = offset - first_sector * sector_size;
+        Ok(sectors[start..start + length].to_vec())
+    }
+
+    /// Writes `data` at `offset`, keeping the rest of the sectors it lands in
+    pub fn write(&mut self, offset: usize, data: &[u8]) -> Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.device.observe(Access::Write, offset, data.len());
+
+        let sector_size = self.device.sector_size();
+        let first_sector = offset / sector_size;
+        let end = offset + data.len();
+        let mut sectors = vec![0; (end.div_ceil(sector_size) - first_sector) * sector_size];
+        // Sectors that are only partly written have to be read first
+        if !offset.is_multiple_of(sector_size) || !end.is_multiple_of(sector_size) {
+            self.device.read_sectors(first_sector, &mut sectors)?;
+        }
+
+        let start = offset - first_sector * sector_size;
+        sectors[start..start + data.len()].copy_from_slice(data);
+        self.device.write_sectors(first_sector, &sectors)
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.device.flush()
+    }
+
+    /// Flushes the device and hands it back
+    pub fn unmount(mut self) -> Result<D> {
+        self.flush()?;
+        Ok(self.device)
+    }
+}
@@ -0,0 +1,295 @@
+use crate::bios_parameter_block::{BiosParameterBlock, BPB_END, BYTES_PER_DIRECTORY_ENTRY};
+use crate::block_device::BlockDevice;
+use crate::fat_error::{FatError, Result};
+use crate::long_names::ATTR_LONG_NAME;
+use crate::root_dir_util::{
+    get_first_free_root_entry, read_root_entry, root_entry_count, root_entry_start, write_directory_entry,
+    ATTR_VOLUME_LABEL, DELETED_ENTRY,
+};
+use crate::shell_parsing::{expect_number, Args};
+use crate::shell_state::ShellState;
+use crate::timestamps::CivilTime;
+use crate::volume::Volume;
+use std::convert::TryFrom;
+
+/// info
+pub fn info(shell_state: ShellState, _args: &Args) -> Result<ShellState> {
+    let bpb = match BiosParameterBlock::parse(&shell_state.image().boot_sector()?) {
+        Some(bpb) => bpb,
+        None => {
+            println!("No valid BPB, using the defaults:");
+            BiosParameterBlock::default()
+        }
+    };
+
+    println!("OEM name:            \"{}\"", String::from_utf8_lossy(&bpb.oem));
+    println!("Bytes per sector:    {}", bpb.bytes_per_sector);
+    println!("Sectors per cluster: {}", bpb.sectors_per_cluster);
+    println!("Reserved sectors:    {}", bpb.reserved_sectors);
+    println!("Number of FATs:      {}", bpb.number_fats);
+    println!("Root entries:        {}", bpb.root_entries);
+    println!("Total sectors:       {}", bpb.total_sectors);
+    println!("Media descriptor:    0x{:02x}", bpb.media);
+    println!("Sectors per FAT:     {}", bpb.sectors_per_fat);
+    println!("Sectors per track:   {}", bpb.sectors_per_track);
+    println!("Heads:               {}", bpb.heads_per_cylinder);
+    println!("Hidden sectors:      {}", bpb.hidden_sectors);
+    println!("Total sectors (big): {}", bpb.total_sectors_big);
+    println!("Drive number:        0x{:02x}", bpb.drive_number);
+    println!("Boot signature:      0x{:02x}", bpb.ext_boot_signature);
+    println!(
+        "Serial number:       {:04X}-{:04X}",
+        bpb.serial_number >> 16,
+        bpb.serial_number & 0xFFFF
+    );
+    println!("Volume label:        \"{}\"", String::from_utf8_lossy(&bpb.volume_label));
+    println!("File system:         \"{}\"", String::from_utf8_lossy(&bpb.file_system));
+    if let Some(label) = root_volume_label(&mut shell_state.volume()?)? {
+        println!("Root dir label:      \"{}\"", String::from_utf8_lossy(&label));
+    }
+    println!("-----------------------");
+    println!("FAT start:           sector {}", bpb.fat_start(0));
+    println!("Root start:          sector {} ({} sectors)", bpb.root_start(), bpb.root_sectors());
+    println!("Data start:          sector {}", bpb.data_start());
+    println!("Cluster count:       {}", bpb.cluster_count());
+    println!("FAT type:            {}", bpb.fat_type());
+
+    let image_sectors = shell_state.volume()?.size() / bpb.bytes_per_sector;
+    if image_sectors != bpb.sectors() {
+        println!(
+            "Warning: the BPB says {} sectors but the image has {}!",
+            bpb.sectors(),
+            image_sectors
+        );
+    }
+
+    Ok(shell_state)
+}
+
+/// setbpb <field> <value>
+///
+/// `oem`, `label` and `fs` take text, `serial` takes hex or `time`, everything else is a number.
+pub fn set_bpb(mut shell_state: ShellState, args: &Args) -> Result<ShellState> {
+    let field = args.expect(1, "field")?;
+    // Text fields may contain spaces
+    let value = args.rest(2).join(" ");
+    if value.is_empty() {
+        return Err(FatError::MissingArgument("value".to_owned()));
+    }
+
+    let mut bpb = BiosParameterBlock::from_image(&shell_state.image().boot_sector()?);
+    let mut label = None;
+
+    match field.as_str() {
+        "oem" => bpb.oem = text_field(&value, false)?,
+        "label" => {
+            bpb.volume_label = text_field(&value, true)?;
+            label = Some(bpb.volume_label);
+        }
+        "fs" => bpb.file_system = text_field(&value, false)?,
+        "serial" => {
+            bpb.serial_number = if value == "time" {
+                CivilTime::now().volume_serial()
+            } else {
+                u32::from_str_radix(&value.replace('-', ""), 16)
+                    .map_err(|_| FatError::BadArgument(format!("Serial {} isn't hex", value)))?
+            }
+        }
+        "bytes_per_sector" => bpb.bytes_per_sector = field_number::<u16>(&field, &value)? as usize,
+        "sectors_per_cluster" => bpb.sectors_per_cluster = field_number::<u8>(&field, &value)? as usize,
+        "reserved_sectors" => bpb.reserved_sectors = field_number::<u16>(&field, &value)? as usize,
+        "fats" => bpb.number_fats = field_number::<u8>(&field, &value)? as usize,
+        "root_entries" => bpb.root_entries = field_number::<u16>(&field, &value)? as usize,
+        "total_sectors" => bpb.total_sectors = field_number::<u16>(&field, &value)? as usize,
+        "media" => bpb.media = field_number(&field, &value)?,
+        "sectors_per_fat" => bpb.sectors_per_fat = field_number::<u16>(&field, &value)? as usize,
+        "sectors_per_track" => bpb.sectors_per_track = field_number::<u16>(&field, &value)? as usize,
+        "heads" => bpb.heads_per_cylinder = field_number::<u16>(&field, &value)? as usize,
+        "hidden_sectors" => bpb.hidden_sectors = field_number::<u32>(&field, &value)? as usize,
+        "total_sectors_big" => bpb.total_sectors_big = field_number::<u32>(&field, &value)? as usize,
+        "drive" => bpb.drive_number = field_number(&field, &value)?,
+        _ => return Err(FatError::BadArgument(format!("Unknown BPB field {}", field))),
+    }
+
+    // A BPB that doesn't parse would quietly be replaced by the default layout from then on
+    let mut boot_sector = vec![0; BPB_END];
+    bpb.write_to(&mut boot_sector);
+    if BiosParameterBlock::parse(&boot_sector).is_none() {
+        return Err(FatError::InvalidBootSector(format!(
+            "Setting {} to {} would leave the BPB invalid",
+            field, value
+        )));
+    }
+
+    let mut volume = shell_state.volume_mut()?;
+    if let Some(label) = label {
+        set_root_volume_label(&mut volume, label)?;
+    }
+    volume.set_bpb(bpb)?;
+    println!("Set {} to {}!", field, value);
+    Ok(shell_state)
+}
+
+/// A number for a BPB field stored as `T`, refusing one that doesn't fit rather than cutting it down
+fn field_number<T: TryFrom<usize>>(field: &str, value: &str) -> Result<T> {
+    T::try_from(expect_number(value, "Value")?)
+        .map_err(|_| FatError::BadArgument(format!("{} is out of range for {}", value, field)))
+}
+
+/// label [NAME]
+pub fn label(mut shell_state: ShellState, args: &Args) -> Result<ShellState> {
+    let bpb = BiosParameterBlock::from_image(&shell_state.image().boot_sector()?);
+
+    let Some(label) = args.get(1) else {
+        println!("Volume label is \"{}\"", String::from_utf8_lossy(&bpb.volume_label).trim_end());
+        return Ok(shell_state);
+    };
+
+    let mut volume = shell_state.volume_mut()?;
+    set_volume_label(&mut volume, &label)?;
+
+    let bpb = volume.bpb();
+    println!("Volume label set to \"{}\"!", String::from_utf8_lossy(&bpb.volume_label).trim_end());
+    Ok(shell_state)
+}
+
+/// Sets the label in both the extended BPB and the root directory
+pub fn set_volume_label<D: BlockDevice>(volume: &mut Volume<D>, label: &str) -> Result<()> {
+    let mut bpb = volume.bpb().clone();
+    bpb.volume_label = text_field(label, true)?;
+    let label = bpb.volume_label;
+    volume.set_bpb(bpb)?;
+    set_root_volume_label(volume, label)
+}
+
+/// Space pads text into a fixed size field, refusing anything that won't fit
+fn text_field<const N: usize>(text: &str, uppercase: bool) -> Result<[u8; N]> {
+    if text.len() > N {
+        return Err(FatError::BadArgument(format!("{} is longer than {} characters", text, N)));
+    }
+    if !text.is_ascii() {
+        return Err(FatError::BadArgument(format!("{} isn't ASCII", text)));
+    }
+
+    let mut field = [b' '; N];
+    for (i, byte) in text.bytes().enumerate() {
+        field[i] = if uppercase { byte.to_ascii_uppercase() } else { byte };
+    }
+    Ok(field)
+}
+
+/// Index of the root entry holding the volume label
+fn find_volume_label_entry<D: BlockDevice>(volume: &mut Volume<D>) -> Result<Option<usize>> {
+    for root_entry_index in 0..root_entry_count(volume) {
+        let root_entry = read_root_entry(volume, root_entry_index)?;
+        if root_entry[0] != 0
+            && root_entry[0] != DELETED_ENTRY
+            && root_entry[11] & ATTR_VOLUME_LABEL != 0
+            && root_entry[11] != ATTR_LONG_NAME
+        {
+            return Ok(Some(root_entry_index));
+        }
+    }
+    Ok(None)
+}
+
+/// The label stored in the root directory, which DOS shows in preference to the BPB one
+fn root_volume_label<D: BlockDevice>(volume: &mut Volume<D>) -> Result<Option<Vec<u8>>> {
+    match find_volume_label_entry(volume)? {
+        Some(index) => Ok(Some(read_root_entry(volume, index)?[0..11].to_vec())),
+        None => Ok(None),
+    }
+}
+
+/// Creates or renames the volume label entry of the root directory
+fn set_root_volume_label<D: BlockDevice>(volume: &mut Volume<D>, label: [u8; 11]) -> Result<()> {
+    let root_entry_index = match find_volume_label_entry(volume)? {
+        Some(index) => index,
+        None => get_first_free_root_entry(volume)?.ok_or(FatError::RootDirectoryFull)?,
+    };
+    let entry_start = root_entry_start(volume, root_entry_index);
+
+    // The label is stored raw, it isn't split into a name and extension
+    let mut entry = vec![0; BYTES_PER_DIRECTORY_ENTRY];
+    entry[0..11].copy_from_slice(&label);
+    entry[11] = ATTR_VOLUME_LABEL;
+
+    write_directory_entry(volume, entry_start, &entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 360K floppy held in memory, with an empty root directory
+    fn shell_state() -> ShellState {
+        let bpb = BiosParameterBlock::for_sectors(720);
+        let mut bytes = vec![0; bpb.sectors() * bpb.bytes_per_sector];
+        bpb.write_to(&mut bytes);
+        ShellState::new().set_bytes(bytes)
+    }
+
+    fn bpb_of(shell_state: &ShellState) -> BiosParameterBlock {
+        BiosParameterBlock::parse(&shell_state.image().boot_sector().unwrap()).unwrap()
+    }
+
+    fn set(shell_state: ShellState, field: &str, value: &str) -> Result<ShellState> {
+        set_bpb(shell_state, &Args::new(&["setbpb", field, value]))
+    }
+
+    #[test]
+    fn sets_numbers_that_fit_their_field() {
+        let shell_state = set(shell_state(), "heads", "65535").unwrap();
+        let shell_state = set(shell_state, "hidden_sectors", "0x10000").unwrap();
+        let shell_state = set(shell_state, "media", "0xF0").unwrap();
+        let bpb = bpb_of(&shell_state);
+        assert_eq!((bpb.heads_per_cylinder, bpb.hidden_sectors, bpb.media), (65535, 0x10000, 0xF0));
+    }
+
+    #[test]
+    fn refuses_numbers_too_big_for_their_field() {
+        for (field, value) in [("heads", "65536"), ("fats", "256"), ("media", "0x100"), ("drive", "300")] {
+            let result = set(shell_state(), field, value);
+            assert!(matches!(result, Err(FatError::BadArgument(_))), "{} {}", field, value);
+        }
+        assert!(set(shell_state(), "hidden_sectors", "0x100000000").is_err());
+    }
+
+    #[test]
+    fn refuses_values_leaving_the_bpb_invalid() {
+        for (field, value) in [("bytes_per_sector", "500"), ("fats", "0"), ("sectors_per_cluster", "3")] {
+            let result = set(shell_state(), field, value);
+            assert!(matches!(result, Err(FatError::InvalidBootSector(_))), "{} {}", field, value);
+        }
+        assert!(matches!(set(shell_state(), "size", "1"), Err(FatError::BadArgument(_))));
+        assert!(matches!(set(shell_state(), "oem", "TOO LONG!"), Err(FatError::BadArgument(_))));
+    }
+
+    #[test]
+    fn label_goes_in_the_bpb_and_root_directory() {
+        let shell_state = label(shell_state(), &Args::new(&["label", "my disk"])).unwrap();
+        assert_eq!(&bpb_of(&shell_state).volume_label, b"MY DISK    ");
+        let root_label = root_volume_label(&mut shell_state.volume().unwrap()).unwrap();
+        assert_eq!(root_label.as_deref(), Some(&b"MY DISK    "[..]));
+
+        // Renamed in place rather than added again
+        let mut shell_state = set(shell_state, "label", "other").unwrap();
+        let mut volume = shell_state.volume_mut().unwrap();
+        assert_eq!(find_volume_label_entry(&mut volume).unwrap(), Some(0));
+        assert_eq!(read_root_entry(&mut volume, 0).unwrap()[0..12], *b"OTHER      \x08");
+        assert_eq!(read_root_entry(&mut volume, 1).unwrap()[0], 0);
+    }
+
+    #[test]
+    fn fields_survive_a_round_trip() {
+        let shell_state = set(shell_state(), "serial", "1234-ABCD").unwrap();
+        let shell_state = set(shell_state, "oem", "MSWIN4.1").unwrap();
+        let shell_state = set(shell_state, "total_sectors_big", "70000").unwrap();
+        let boot_sector = shell_state.image().boot_sector().unwrap();
+
+        let bpb = BiosParameterBlock::parse(&boot_sector).unwrap();
+        assert_eq!((bpb.serial_number, &bpb.oem, bpb.total_sectors_big), (0x1234ABCD, b"MSWIN4.1", 70000));
+        assert_eq!(bpb.to_bytes(), boot_sector[3..BPB_END]);
+        assert_eq!(BiosParameterBlock::parse(&boot_sector[..BPB_END]).unwrap().to_bytes(), bpb.to_bytes());
+    }
+}